@@ -0,0 +1,295 @@
+//! Compares the speed of Mijit's native code with that of its bytecode
+//! interpreter `Target` ([`mijit::target::interpret`]) and of equivalent Rust
+//! code, using the example virtual machines.
+//!
+//! Run with `cargo bench`, optionally followed by `-- <filter>` to run only
+//! the benchmarks whose names contain `<filter>`. For each way of running
+//! each benchmark, it reports the compile time, the run time, and the run
+//! time relative to Rust.
+
+use std::time::{Duration, Instant};
+
+use mijit::target::{Target, native, interpret};
+use mijit::jit::factorial::{Factorial};
+use mijit::beetle::{Beetle, Registers};
+use mijit::beetle::examples::{ACKERMANN};
+use mijit::{brainfuck, wasm};
+use wasm::{Module, examples};
+
+/// Prevents the compiler from optimizing using the value of `x`.
+fn opaque<T: Copy>(x: T) -> T {
+    // Safety: `x` is a valid `T`.
+    unsafe { std::ptr::read_volatile(&x) }
+}
+
+/// Runs `callback` and returns its result and how long it took.
+fn time<R>(callback: impl FnOnce() -> R) -> (R, Duration) {
+    let start = Instant::now();
+    let result = callback();
+    (result, start.elapsed())
+}
+
+/// The time taken to compile and run a benchmark.
+#[derive(Debug, Copy, Clone)]
+struct Timing {
+    /// `None` for Rust code.
+    compile: Option<Duration>,
+    run: Duration,
+}
+
+/// The ways of running a benchmark.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Engine {
+    /// Equivalent Rust code.
+    Rust,
+    /// Mijit with the native `Target`.
+    Native,
+    /// Mijit with the bytecode interpreter `Target`.
+    Interpret,
+}
+
+const ENGINES: [(Engine, &str); 3] = [
+    (Engine::Rust, "rust"),
+    (Engine::Native, "native"),
+    (Engine::Interpret, "bytecode"),
+];
+
+//-----------------------------------------------------------------------------
+
+const FACTORIAL_N: u64 = 2_000_000;
+
+/// Multiplies together the numbers from `1` to `FACTORIAL_N`, modulo `2^32`.
+fn factorial(engine: Engine) -> Timing {
+    fn jit(target: impl Target) -> (u64, Timing) {
+        let (mut factorial, compile) = time(|| Factorial::new(target));
+        let (result, run) = time(|| factorial.run(FACTORIAL_N));
+        (result, Timing {compile: Some(compile), run})
+    }
+    let (result, timing) = match engine {
+        Engine::Rust => {
+            let (result, run) = time(|| {
+                (1..=opaque(FACTORIAL_N)).fold(1u32, |r, i| r.wrapping_mul(i as u32))
+            });
+            (u64::from(result), Timing {compile: None, run})
+        },
+        Engine::Native => jit(native()),
+        Engine::Interpret => jit(interpret::Target),
+    };
+    assert_eq!(result, 0);
+    timing
+}
+
+//-----------------------------------------------------------------------------
+
+const ACKERMANN_M: u32 = 3;
+const ACKERMANN_N: u32 = 6;
+
+/// Computes the Ackermann function of `ACKERMANN_M` and `ACKERMANN_N`,
+/// using Beetle.
+fn ackermann(engine: Engine) -> Timing {
+    fn rust(m: u32, n: u32) -> u32 {
+        if m == 0 { return n + 1; }
+        if n == 0 { return rust(m - 1, 1); }
+        rust(m - 1, rust(m, n - 1))
+    }
+    fn jit(target: impl Target) -> (u32, Timing) {
+        const HALT_ADDR: u32 = 0x100;
+        const MEMORY_CELLS: usize = 0x10000;
+        let (mut beetle, compile) = time(|| Beetle::new(target));
+        let mut memory = vec![0u32; MEMORY_CELLS];
+        memory[..ACKERMANN.len()].copy_from_slice(&ACKERMANN);
+        memory[(HALT_ADDR >> 2) as usize] = 0x5519; // `0 HALT`.
+        let mut registers = Registers::default();
+        registers.r0 = (MEMORY_CELLS * 4) as u32;
+        registers.s0 = registers.r0 / 2;
+        registers.sp = registers.s0 - 8;
+        registers.rp = registers.r0 - 4;
+        memory[(registers.sp >> 2) as usize + 1] = ACKERMANN_M;
+        memory[(registers.sp >> 2) as usize] = ACKERMANN_N;
+        memory[(registers.rp >> 2) as usize] = HALT_ADDR;
+        let (result, run) = time(|| {
+            // Safety: Beetle checks every address against the size of `memory`.
+            unsafe { beetle.run(&mut registers, &mut memory) }
+        });
+        assert_eq!(result, Ok(()));
+        // `HALT` leaves its code on the stack above the result.
+        assert_eq!((registers.sp, registers.rp), (registers.s0 - 8, registers.r0));
+        assert_eq!(memory[(registers.sp >> 2) as usize], 0);
+        (memory[(registers.sp >> 2) as usize + 1], Timing {compile: Some(compile), run})
+    }
+    let (result, timing) = match engine {
+        Engine::Rust => {
+            let (result, run) = time(|| rust(opaque(ACKERMANN_M), opaque(ACKERMANN_N)));
+            (result, Timing {compile: None, run})
+        },
+        Engine::Native => jit(native()),
+        Engine::Interpret => jit(interpret::Target),
+    };
+    assert_eq!(result, (1 << (ACKERMANN_N + 3)) - 3);
+    timing
+}
+
+//-----------------------------------------------------------------------------
+
+/// Computes `60 * 60 * 60` mod `256`, slowly, and outputs it.
+const SLOW: &str = "
+    >++++++[<++++++++++>-]<
+    [>>++++++[<++++++++++>-]<
+        [>>++++++[<++++++++++>-]<
+            [>>+<<-]
+        <-]
+    <-]
+    >>>>.
+";
+
+/// Runs `SLOW` using Brainfuck. The Rust equivalent is
+/// [`brainfuck::interpret()`].
+fn brainfuck(engine: Engine) -> Timing {
+    fn jit(target: impl Target) -> (Vec<u8>, Timing) {
+        let (vm, compile) = time(|| brainfuck::VM::with_target(SLOW, target));
+        let mut vm = vm.unwrap();
+        let (result, run) = time(|| vm.run(b""));
+        (result, Timing {compile: Some(compile), run})
+    }
+    let (result, timing) = match engine {
+        Engine::Rust => {
+            let program = brainfuck::parse(SLOW).unwrap();
+            let (result, run) = time(|| brainfuck::interpret(&program, b""));
+            (result, Timing {compile: None, run})
+        },
+        Engine::Native => jit(native()),
+        Engine::Interpret => jit(interpret::Target),
+    };
+    assert_eq!(result, [(60u32 * 60 * 60) as u8]);
+    timing
+}
+
+//-----------------------------------------------------------------------------
+
+const FIB_N: u64 = 25;
+
+/// Computes the `FIB_N`th Fibonacci number recursively, using WebAssembly.
+fn fib(engine: Engine) -> Timing {
+    fn rust(n: u64) -> u64 {
+        if n < 2 { return n; }
+        rust(n - 1) + rust(n - 2)
+    }
+    fn jit(target: impl Target) -> (u64, Timing) {
+        let module = Module {memory_pages: 0, functions: vec![examples::fib()]};
+        let (vm, compile) = time(|| wasm::VM::with_target(module, target));
+        let mut vm = vm.unwrap();
+        let (result, run) = time(|| vm.call(0, &[FIB_N]));
+        (result.unwrap().unwrap(), Timing {compile: Some(compile), run})
+    }
+    let (result, timing) = match engine {
+        Engine::Rust => {
+            let (result, run) = time(|| rust(opaque(FIB_N)));
+            (result, Timing {compile: None, run})
+        },
+        Engine::Native => jit(native()),
+        Engine::Interpret => jit(interpret::Target),
+    };
+    assert_eq!(result, 75025);
+    timing
+}
+
+/// The size of the sieve, which must be small enough that `i * i` does not
+/// overflow.
+const SIEVE_N: u64 = 1 << 16;
+
+/// The number of times to run the sieve.
+const SIEVE_REPEATS: usize = 2;
+
+/// Counts the primes less than `SIEVE_N`, `SIEVE_REPEATS` times, using
+/// WebAssembly.
+fn sieve(engine: Engine) -> Timing {
+    fn rust(memory: &mut [u8], n: u64) -> u64 {
+        let mut count = 0;
+        for i in 2..n {
+            if memory[i as usize] != 0 { continue; }
+            count += 1;
+            let mut j = i * i;
+            while j < n {
+                memory[j as usize] = 1;
+                j += i;
+            }
+        }
+        count
+    }
+    fn jit(target: impl Target) -> (u64, Timing) {
+        let module = Module {memory_pages: 1, functions: vec![examples::sieve()]};
+        let (vm, compile) = time(|| wasm::VM::with_target(module, target));
+        let mut vm = vm.unwrap();
+        let (result, run) = time(|| {
+            let mut result = 0;
+            for _ in 0..SIEVE_REPEATS {
+                vm.memory_mut().fill(0);
+                result = vm.call(0, &[SIEVE_N]).unwrap().unwrap();
+            }
+            result
+        });
+        (result, Timing {compile: Some(compile), run})
+    }
+    let (result, timing) = match engine {
+        Engine::Rust => {
+            let mut memory = vec![0; SIEVE_N as usize];
+            let (result, run) = time(|| {
+                let mut result = 0;
+                for _ in 0..SIEVE_REPEATS {
+                    memory.fill(0);
+                    result = rust(&mut memory, opaque(SIEVE_N));
+                }
+                result
+            });
+            (result, Timing {compile: None, run})
+        },
+        Engine::Native => jit(native()),
+        Engine::Interpret => jit(interpret::Target),
+    };
+    assert_eq!(result, 6542);
+    timing
+}
+
+//-----------------------------------------------------------------------------
+
+/// Runs a benchmark using an [`Engine`], checks the result, and returns the
+/// [`Timing`].
+type Benchmark = fn(Engine) -> Timing;
+
+const BENCHMARKS: [(&str, Benchmark); 5] = [
+    ("factorial", factorial),
+    ("ackermann", ackermann),
+    ("brainfuck", brainfuck),
+    ("fib", fib),
+    ("sieve", sieve),
+];
+
+/// Formats `duration` in milliseconds.
+fn millis(duration: Duration) -> String {
+    format!("{:.3}ms", duration.as_secs_f64() * 1e3)
+}
+
+fn main() {
+    // Ignore the flags passed by `cargo bench`.
+    let filters: Vec<String> = std::env::args().skip(1).filter(|arg| !arg.starts_with('-')).collect();
+    println!("{:<12}{:<12}{:>14}{:>14}{:>12}", "benchmark", "engine", "compile", "run", "run / rust");
+    for (name, benchmark) in BENCHMARKS {
+        if !filters.is_empty() && !filters.iter().any(|filter| name.contains(filter.as_str())) {
+            continue;
+        }
+        let mut rust_run = None;
+        for (engine, engine_name) in ENGINES {
+            let timing = benchmark(engine);
+            if engine == Engine::Rust { rust_run = Some(timing.run); }
+            let ratio = timing.run.as_secs_f64() / rust_run.expect("Rust goes first").as_secs_f64();
+            println!(
+                "{:<12}{:<12}{:>14}{:>14}{:>12.2}",
+                name,
+                engine_name,
+                timing.compile.map_or_else(|| "-".into(), millis),
+                millis(timing.run),
+                ratio,
+            );
+        }
+    }
+}
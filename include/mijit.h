@@ -0,0 +1,260 @@
+/*
+ * The C API of Mijit. See `src/capi.rs` for the documentation of each
+ * function, and `tests/c/factorial.c` for an example. This file is written by
+ * hand; `tests/c/header.c` checks that it matches `src/capi.rs`.
+ *
+ * Link with `libmijit.so`, or with `libmijit.a` and the libraries listed by
+ * `cargo rustc --lib --crate-type staticlib -- --print native-static-libs`.
+ * On Linux, these are `-lgcc_s -lutil -lrt -lpthread -lm -ldl`.
+ *
+ * Objects must be freed by calling the corresponding `_free()` function,
+ * unless they are passed to a function that consumes them. Invalid arguments
+ * abort the process.
+ */
+
+#ifndef MIJIT_H
+#define MIJIT_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* The outcome of a fallible function. */
+typedef enum mijit_status {
+    MIJIT_OK = 0,
+    MIJIT_ALREADY_DEFINED = 1,
+    MIJIT_NEGATIVE_EXIT_VALUE = 2,
+    MIJIT_STACK_UNDERFLOW = 3,
+    MIJIT_SLOTS_MISMATCH = 4,
+    MIJIT_GLOBALS_MISMATCH = 5,
+    MIJIT_INVALID = 6,
+    MIJIT_OUT_OF_MEMORY = 7,
+    MIJIT_MEMORY = 8,
+    MIJIT_POISONED = 9,
+} mijit_status;
+
+/* Opaque types. */
+typedef struct mijit_jit mijit_jit;
+typedef struct mijit_pool mijit_pool;
+typedef struct mijit_builder mijit_builder;
+typedef struct mijit_ebb mijit_ebb;
+
+/* Identifies an entry point of a `mijit_jit`. */
+typedef size_t mijit_entry;
+
+/* A register, global or spill slot. */
+typedef struct mijit_variable {
+    uint32_t kind;
+    uint32_t index;
+} mijit_variable;
+
+enum {
+    MIJIT_KIND_REGISTER = 0,
+    MIJIT_KIND_GLOBAL = 1,
+    MIJIT_KIND_SLOT = 2,
+};
+
+/* The number of registers guaranteed to exist. `mijit_register(0)` is used
+ * as a temporary by many `mijit_builder_*()` functions. */
+#define MIJIT_NUM_REGISTERS 12
+
+static inline mijit_variable mijit_register(uint32_t index) {
+    mijit_variable v = {MIJIT_KIND_REGISTER, index};
+    return v;
+}
+
+static inline mijit_variable mijit_global(uint32_t index) {
+    mijit_variable v = {MIJIT_KIND_GLOBAL, index};
+    return v;
+}
+
+static inline mijit_variable mijit_slot(uint32_t index) {
+    mijit_variable v = {MIJIT_KIND_SLOT, index};
+    return v;
+}
+
+/* Unary arithmetic operations. */
+enum {
+    MIJIT_ABS = 0,
+    MIJIT_NEGATE = 1,
+    MIJIT_NOT = 2,
+};
+
+/* Binary arithmetic operations. */
+enum {
+    MIJIT_ADD = 0,
+    MIJIT_SUB = 1,
+    MIJIT_MUL = 2,
+    MIJIT_UDIV = 3,
+    MIJIT_SDIV = 4,
+    MIJIT_LSL = 5,
+    MIJIT_LSR = 6,
+    MIJIT_ASR = 7,
+    MIJIT_AND = 8,
+    MIJIT_OR = 9,
+    MIJIT_XOR = 10,
+    MIJIT_LT = 11,
+    MIJIT_ULT = 12,
+    MIJIT_EQ = 13,
+    MIJIT_MAX = 14,
+    MIJIT_MIN = 15,
+};
+
+/* The number of bytes transferred by a memory access. */
+enum {
+    MIJIT_ONE = 0,
+    MIJIT_TWO = 1,
+    MIJIT_FOUR = 2,
+    MIJIT_EIGHT = 3,
+};
+
+/* Auto-increment modes. */
+enum {
+    MIJIT_IA = 0,
+    MIJIT_IB = 1,
+    MIJIT_DA = 2,
+    MIJIT_DB = 3,
+};
+
+const char *mijit_error_message(void);
+
+/* Jit. */
+mijit_jit *mijit_jit_new(size_t num_globals);
+void mijit_jit_free(mijit_jit *jit);
+size_t mijit_jit_num_globals(const mijit_jit *jit);
+mijit_status mijit_jit_new_entry(
+    mijit_jit *jit,
+    mijit_builder *prologue,
+    mijit_builder *epilogue,
+    int64_t exit_value,
+    mijit_entry *entry
+);
+mijit_status mijit_jit_define(mijit_jit *jit, mijit_entry entry, mijit_ebb *ebb);
+mijit_status mijit_jit_run(
+    mijit_jit *jit,
+    mijit_pool *pool,
+    mijit_entry entry,
+    int64_t *exit_value
+);
+
+/* Pool. */
+mijit_pool *mijit_pool_new(const mijit_jit *jit);
+void mijit_pool_free(mijit_pool *pool);
+uint64_t mijit_pool_get(const mijit_pool *pool, size_t global);
+void mijit_pool_set(mijit_pool *pool, size_t global, uint64_t value);
+
+/* Builder. */
+mijit_builder *mijit_builder_new(void);
+void mijit_builder_free(mijit_builder *b);
+void mijit_builder_move(mijit_builder *b, mijit_variable dest, mijit_variable src);
+void mijit_builder_const(mijit_builder *b, mijit_variable dest, int64_t value);
+void mijit_builder_unary64(mijit_builder *b, uint32_t op, mijit_variable dest, mijit_variable src);
+void mijit_builder_unary32(mijit_builder *b, uint32_t op, mijit_variable dest, mijit_variable src);
+void mijit_builder_binary64(
+    mijit_builder *b,
+    uint32_t op,
+    mijit_variable dest,
+    mijit_variable src1,
+    mijit_variable src2
+);
+void mijit_builder_binary32(
+    mijit_builder *b,
+    uint32_t op,
+    mijit_variable dest,
+    mijit_variable src1,
+    mijit_variable src2
+);
+void mijit_builder_const_binary64(
+    mijit_builder *b,
+    uint32_t op,
+    mijit_variable dest,
+    mijit_variable src,
+    int64_t value
+);
+void mijit_builder_const_binary32(
+    mijit_builder *b,
+    uint32_t op,
+    mijit_variable dest,
+    mijit_variable src,
+    int32_t value
+);
+void mijit_builder_load(
+    mijit_builder *b,
+    mijit_variable dest,
+    mijit_variable base,
+    int64_t offset,
+    uint32_t width,
+    uint32_t alias_mask
+);
+void mijit_builder_store(
+    mijit_builder *b,
+    mijit_variable src,
+    mijit_variable base,
+    int64_t offset,
+    uint32_t width,
+    uint32_t alias_mask
+);
+void mijit_builder_array_load(
+    mijit_builder *b,
+    mijit_variable dest,
+    mijit_variable base,
+    mijit_variable index,
+    uint32_t width,
+    uint32_t alias_mask
+);
+void mijit_builder_array_store(
+    mijit_builder *b,
+    mijit_variable src,
+    mijit_variable base,
+    mijit_variable index,
+    uint32_t width,
+    uint32_t alias_mask
+);
+void mijit_builder_increment_load(
+    mijit_builder *b,
+    uint32_t increment,
+    mijit_variable dest,
+    mijit_variable addr,
+    uint32_t width,
+    uint32_t alias_mask
+);
+void mijit_builder_increment_store(
+    mijit_builder *b,
+    uint32_t increment,
+    mijit_variable src,
+    mijit_variable addr,
+    uint32_t width,
+    uint32_t alias_mask
+);
+void mijit_builder_debug(mijit_builder *b, mijit_variable src);
+void mijit_builder_guard(
+    mijit_builder *b,
+    mijit_variable condition,
+    bool expected,
+    mijit_ebb *if_fail
+);
+mijit_ebb *mijit_builder_jump(mijit_builder *b, mijit_entry target);
+mijit_ebb *mijit_builder_index(
+    mijit_builder *b,
+    mijit_variable discriminant,
+    mijit_ebb *const *cases,
+    size_t num_cases,
+    mijit_ebb *default_
+);
+mijit_ebb *mijit_builder_if(
+    mijit_builder *b,
+    mijit_variable condition,
+    mijit_ebb *if_true,
+    mijit_ebb *if_false
+);
+void mijit_ebb_free(mijit_ebb *ebb);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* MIJIT_H */
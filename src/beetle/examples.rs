@@ -0,0 +1,50 @@
+//! Example Beetle programs, used by the tests and the benchmarks.
+
+/// `ACKERMANN ( m n -- result )`, assembled at address zero.
+///
+/// Forth source:
+/// ```text
+/// : ACKERMANN   ( m n -- result )
+/// OVER 0= IF                  \ m = 0
+///     NIP 1+                  \ n+1
+/// ELSE
+///     DUP 0= IF               \ n = 0
+///         DROP 1- 1 RECURSE   \ A(m-1, 1)
+///     ELSE
+///         OVER 1- -ROT        \ m-1 m n
+///         1- RECURSE          \ m-1 A(m, n-1)
+///         RECURSE             \ A(m-1, A(m, n-1))
+///     THEN
+/// THEN ;
+/// ```
+///
+/// Beetle assembler:
+/// ```text
+/// $00: OVER
+///      0=
+/// $04: ?BRANCHI $10
+/// $08: NIP
+///      1+
+/// $0C: BRANCHI $30
+/// $10: DUP
+///      0=
+/// $14: ?BRANCHI $24
+/// $18: DROP
+///      1-
+///      1
+/// $1C: CALLI $0
+/// $20: BRANCHI $30
+/// $24: OVER
+///      1-
+///      -ROT
+///      1-
+/// $28: CALLI $0
+/// $2C: CALLI $0
+/// $30: EXIT
+/// ```
+pub const ACKERMANN: [u32; 13] = [
+    0x00001504, 0x00000245, 0x00002108, 0x00000843,
+    0x00001501, 0x00000345, 0x001A2202, 0xFFFFF849,
+    0x00000343, 0x22062204, 0xFFFFF549, 0xFFFFF449,
+    0x0000004A,
+];
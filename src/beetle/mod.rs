@@ -1,11 +1,17 @@
-//! A partial implementation of the [Beetle] virtual machine in Mijit.
+//! An implementation of the [Beetle] virtual machine in Mijit.
 //! This serves as an illustrative example as an integration test.
+//!
+//! Instructions that need the host, namely `THROW`, `HALT`, `LIB`, `LINK`
+//! and undefined opcodes, trap: the hot code exits with `A` set so that the
+//! host can perform the instruction. Division by zero also traps.
+//! [`VM`] performs these instructions, and runs Beetle programs to
+//! completion.
 //! 
 //! [Beetle]: https://github.com/rrthomas/beetle
 
 use memoffset::{offset_of};
 
-use super::code::{self, UnaryOp, BinaryOp, Width, Register, REGISTERS, Global, Marshal, Switch};
+use super::code::{self, UnaryOp, BinaryOp, Width, Register, REGISTERS, Global, Marshal, Switch, EBB};
 use UnaryOp::*;
 use BinaryOp::*;
 use Width::*;
@@ -16,6 +22,11 @@ use super::code::builder::{build, build_block, Builder};
 mod registers;
 pub use registers::{Registers};
 
+mod vm;
+pub use vm::{MEMORY_CELLS, DATA_CELLS, RETURN_CELLS, Handler, LoadError, VM};
+
+pub mod examples;
+
 /// The number of bytes in a cell.
 pub const CELL: i32 = 4;
 
@@ -33,6 +44,7 @@ const BA: Register = REGISTERS[6];
 const BSP: Register = REGISTERS[7];
 const BRP: Register = REGISTERS[8];
 const M0: Register = REGISTERS[9];
+const BMEMORY: Register = REGISTERS[10];
 
 /// Returns the address of `Registers.$field`.
 macro_rules! register {
@@ -41,8 +53,20 @@ macro_rules! register {
     }
 }
 
-/// The return code used to indicate normal exit from the hot code.
-const NOT_IMPLEMENTED: i64 = 0;
+/// The return code used to indicate that the hot code has encountered an
+/// instruction that it cannot perform. `A` holds the remaining instructions,
+/// shifted left by 8 bits, and the opcode of the instruction.
+const TRAP: i64 = 0;
+/// Beetle's exception code for an invalid address.
+pub const INVALID_ADDRESS: i32 = -9;
+/// Beetle's exception code for division by zero.
+pub const DIVISION_BY_ZERO: i32 = -10;
+/// Beetle's exception code for an unaligned address.
+pub const UNALIGNED_ADDRESS: i32 = -23;
+/// Beetle's exception code for an undefined instruction.
+pub const INVALID_OPCODE: i32 = -256;
+/// Beetle's exception code for an undefined library routine.
+pub const INVALID_LIBRARY_ROUTINE: i32 = -257;
 /// Dummy return code which should never actually occur.
 const UNDEFINED: i64 = i64::MAX;
 
@@ -55,33 +79,105 @@ const AM_REGISTER: code::AliasMask = code::AliasMask(0x2);
 
 //-----------------------------------------------------------------------------
 
-/// Computes into `BI` the native address corresponding to `addr`.
-fn native_address(b: &mut Builder<EntryId>, addr: Register) {
-    b.binary64(Add, BI, M0, addr);
+/// Generates code to access Beetle's memory. Each access checks its address,
+/// and if it is invalid, stores it in `Registers.not_address` and jumps to
+/// an entry that reports the exception.
+#[derive(Debug, Copy, Clone)]
+struct Memory {
+    /// Reports [`INVALID_ADDRESS`].
+    invalid_address: EntryId,
+    /// Reports [`UNALIGNED_ADDRESS`].
+    unaligned_address: EntryId,
 }
 
-/// Loads `dest` from `addr`. `BI` is corrupted.
-fn load(b: &mut Builder<EntryId>, dest: Register, addr: Register) {
-    native_address(b, addr);
-    b.load(dest, (BI, 0), Four, AM_MEMORY);
-}
+impl Memory {
+    /// Returns code that reports that `addr` is bad, by jumping to `entry`.
+    fn fail(addr: Register, entry: EntryId) -> EBB<EntryId> {
+        build(&|mut b| {
+            b.store(addr, register!(not_address), Four, AM_REGISTER);
+            b.jump(entry)
+        })
+    }
 
-/// Stores `dest` at `addr`. `BI` is corrupted.
-fn store(b: &mut Builder<EntryId>, src: Register, addr: Register) {
-    native_address(b, addr);
-    b.store(src, (BI, 0), Four, AM_MEMORY);
-}
+    /// Checks that `addr` is in range, and, if `aligned`, that it is a
+    /// multiple of `CELL`. Computes into `BI` the corresponding native
+    /// address.
+    fn native_address(&self, b: &mut Builder<EntryId>, addr: Register, aligned: bool) {
+        if aligned {
+            b.const_binary32(And, BI, addr, CELL - 1);
+            b.guard(BI, false, Self::fail(addr, self.unaligned_address));
+        }
+        b.binary64(Ult, BI, addr, BMEMORY);
+        b.guard(BI, true, Self::fail(addr, self.invalid_address));
+        b.binary64(Add, BI, M0, addr);
+    }
 
-/// Pops `dest` from the stack at `sp`. `BI` is corrupted.
-fn pop(b: &mut Builder<EntryId>, dest: Register, sp: Register) {
-    load(b, dest, sp);
-    b.const_binary32(Add, sp, sp, CELL);
+    /// Loads `dest` from `addr`. `BI` is corrupted.
+    fn load(&self, b: &mut Builder<EntryId>, dest: Register, addr: Register) {
+        self.native_address(b, addr, true);
+        b.load(dest, (BI, 0), Four, AM_MEMORY);
+    }
+
+    /// Stores `src` at `addr`. `BI` is corrupted.
+    fn store(&self, b: &mut Builder<EntryId>, src: Register, addr: Register) {
+        self.native_address(b, addr, true);
+        b.store(src, (BI, 0), Four, AM_MEMORY);
+    }
+
+    /// Loads `dest` from the byte at `addr`. `BI` is corrupted.
+    fn load_byte(&self, b: &mut Builder<EntryId>, dest: Register, addr: Register) {
+        self.native_address(b, addr, false);
+        b.load(dest, (BI, 0), One, AM_MEMORY);
+    }
+
+    /// Stores the low byte of `src` at `addr`. `BI` is corrupted.
+    fn store_byte(&self, b: &mut Builder<EntryId>, src: Register, addr: Register) {
+        self.native_address(b, addr, false);
+        b.store(src, (BI, 0), One, AM_MEMORY);
+    }
+
+    /// Pops `dest` from the stack at `sp`. `BI` is corrupted.
+    fn pop(&self, b: &mut Builder<EntryId>, dest: Register, sp: Register) {
+        self.load(b, dest, sp);
+        b.const_binary32(Add, sp, sp, CELL);
+    }
+
+    /// Pushes `src` to the stack at `sp`. `BI` is corrupted.
+    fn push(&self, b: &mut Builder<EntryId>, src: Register, sp: Register) {
+        b.const_binary32(Sub, sp, sp, CELL);
+        self.store(b, src, sp);
+    }
 }
 
-/// Pushes `src` to the stack at `sp`. `BI` is corrupted.
-fn push(b: &mut Builder<EntryId>, src: Register, sp: Register) {
-    b.const_binary32(Sub, sp, sp, CELL);
-    store(b, src, sp);
+//-----------------------------------------------------------------------------
+
+/// Divides `R3` by `R2`, which must be non-zero, computing the quotient into
+/// `R1` and the remainder into `R3`. Both operands are signed. The quotient
+/// is rounded towards minus infinity if `floored`, otherwise towards zero.
+/// `R2` and `BI` are corrupted.
+fn sdivmod(b: &mut Builder<EntryId>, floored: bool) {
+    // Divide 64-bit values, so that `i32::MIN / -1` does not overflow.
+    b.const_binary64(Lsl, R1, R3, 32);
+    b.const_binary64(Asr, R1, R1, 32);
+    b.const_binary64(Lsl, BI, R2, 32);
+    b.const_binary64(Asr, BI, BI, 32);
+    b.binary64(SDiv, R1, R1, BI);
+    b.binary32(Mul, BI, R1, R2);
+    b.binary32(Sub, R3, R3, BI);
+    if floored {
+        // If the remainder is non-zero and its sign differs from that of the
+        // divisor, add the divisor to it and subtract one from the quotient.
+        b.binary32(Xor, BI, R3, R2);
+        b.const_binary32(Lt, BI, BI, 0);
+        b.binary32(And, BI, BI, R2);
+        b.const_binary32(Eq, R2, R3, 0);
+        b.unary32(Not, R2, R2);
+        b.binary32(And, BI, BI, R2);
+        b.binary32(Add, R3, R3, BI);
+        b.const_binary32(Eq, R2, BI, 0);
+        b.unary32(Not, R2, R2);
+        b.binary32(Add, R1, R1, R2);
+    }
 }
 
 /// The performance-critical part of the virtual machine.
@@ -94,7 +190,7 @@ pub struct Beetle<T: Target> {
 impl<T: Target> Beetle<T> {
     #[allow(clippy::too_many_lines)]
     pub fn new(target: T) -> Self {
-        let mut jit = Jit::new(target, 2);
+        let mut jit = Jit::new(target, 3);
         let marshal = Marshal {
             prologue: build_block(&|b| {
                 b.load(BEP, register!(ep), Four, AM_REGISTER);
@@ -103,6 +199,7 @@ impl<T: Target> Beetle<T> {
                 b.load(BSP, register!(sp), Four, AM_REGISTER);
                 b.load(BRP, register!(rp), Four, AM_REGISTER);
                 b.move_(M0, Global(1));
+                b.move_(BMEMORY, Global(2));
             }),
             epilogue: build_block(&|b| {
                 b.store(BEP, register!(ep), Four, AM_REGISTER);
@@ -111,26 +208,87 @@ impl<T: Target> Beetle<T> {
                 b.store(BSP, register!(sp), Four, AM_REGISTER);
                 b.store(BRP, register!(rp), Four, AM_REGISTER);
                 b.move_(Global(1), M0);
+                b.move_(Global(2), BMEMORY);
             }),
         };
         let root = jit.new_entry(&marshal, UNDEFINED);
 
+        // Exceptions. The exit value is minus the exception code.
+        let m = Memory {
+            invalid_address: jit.new_entry(&marshal, i64::from(-INVALID_ADDRESS)),
+            unaligned_address: jit.new_entry(&marshal, i64::from(-UNALIGNED_ADDRESS)),
+        };
+
         // Immediate branch.
         let branchi = jit.new_entry(&marshal, UNDEFINED);
         jit.define(branchi, &build(&|mut b| {
             b.const_binary32(Mul, R1, BA, CELL);
             b.binary32(Add, BEP, BEP, R1);
-            pop(&mut b, BA, BEP);
+            m.pop(&mut b, BA, BEP);
             b.jump(root)
         }));
         
-        // Not implemented.
-        let not_implemented2 = jit.new_entry(&marshal, NOT_IMPLEMENTED);
-        let not_implemented = jit.new_entry(&marshal, UNDEFINED);
-        jit.define(not_implemented, &build(&|mut b| {
+        // Instructions that must be performed by the host.
+        let trap2 = jit.new_entry(&marshal, TRAP);
+        let trap = jit.new_entry(&marshal, UNDEFINED);
+        jit.define(trap, &build(&|mut b| {
             b.const_binary32(Lsl, BA, BA, 8);
             b.binary32(Or, BA, BA, BI);
-            b.jump(not_implemented2)
+            b.jump(trap2)
+        }));
+        // Traps after restoring `BI` to `opcode`.
+        let trap_with = |opcode: i64| build(&|mut b| {
+            b.const_(BI, opcode);
+            b.jump(trap)
+        });
+
+        // Branch to the address in the next cell.
+        let branch = jit.new_entry(&marshal, UNDEFINED);
+        jit.define(branch, &build(&|mut b| {
+            m.load(&mut b, BEP, BEP);
+            m.pop(&mut b, BA, BEP);
+            b.jump(root)
+        }));
+
+        // Drop the loop parameters, and skip the branch address or offset.
+        let unloop_branch = build(&|mut b| {
+            b.const_binary32(Add, BRP, BRP, 2 * CELL);
+            b.const_binary32(Add, BEP, BEP, CELL);
+            m.pop(&mut b, BA, BEP);
+            b.jump(root)
+        });
+        let unloop_next = build(&|mut b| {
+            b.const_binary32(Add, BRP, BRP, 2 * CELL);
+            m.pop(&mut b, BA, BEP);
+            b.jump(root)
+        });
+
+        // ROLL, one step at a time. The top of the stack counts the steps
+        // remaining, and the item being rolled is just below that count.
+        let roll = jit.new_entry(&marshal, UNDEFINED);
+        jit.define(roll, &build(&|mut b| {
+            m.load(&mut b, R2, BSP);
+            b.if_(
+                R2,
+                build(&|mut b| {
+                    b.const_binary32(Sub, R2, R2, 1);
+                    m.store(&mut b, R2, BSP);
+                    b.const_binary32(Mul, R1, R2, CELL);
+                    b.binary32(Add, R1, BSP, R1);
+                    b.const_binary32(Add, R1, R1, CELL);
+                    m.load(&mut b, R2, R1);
+                    b.const_binary32(Add, R3, R1, CELL);
+                    m.load(&mut b, R3, R3);
+                    m.store(&mut b, R3, R1);
+                    b.const_binary32(Add, R1, R1, CELL);
+                    m.store(&mut b, R2, R1);
+                    b.jump(roll)
+                }),
+                build(&|mut b| {
+                    b.const_binary32(Add, BSP, BSP, CELL);
+                    b.jump(root)
+                }),
+            )
         }));
 
         // Main dispatch loop.
@@ -142,14 +300,14 @@ impl<T: Target> Beetle<T> {
                 Box::new([
                     // NEXT
                     build(&|mut b| {
-                        pop(&mut b, BA, BEP);
+                        m.pop(&mut b, BA, BEP);
                         b.jump(root)
                     }),
 
                     // DUP
                     build(&|mut b| {
-                        load(&mut b, R2, BSP);
-                        push(&mut b, R2, BSP);
+                        m.load(&mut b, R2, BSP);
+                        m.push(&mut b, R2, BSP);
                         b.jump(root)
                     }),
 
@@ -161,401 +319,584 @@ impl<T: Target> Beetle<T> {
 
                     // SWAP
                     build(&|mut b| {
-                        pop(&mut b, R2, BSP);
-                        load(&mut b, R3, BSP);
-                        store(&mut b, R2, BSP);
-                        push(&mut b, R3, BSP);
+                        m.pop(&mut b, R2, BSP);
+                        m.load(&mut b, R3, BSP);
+                        m.store(&mut b, R2, BSP);
+                        m.push(&mut b, R3, BSP);
                         b.jump(root)
                     }),
 
                     // OVER
                     build(&|mut b| {
                         b.const_binary32(Add, R1, BSP, CELL);
-                        load(&mut b, R2, R1);
-                        push(&mut b, R2, BSP);
+                        m.load(&mut b, R2, R1);
+                        m.push(&mut b, R2, BSP);
                         b.jump(root)
                     }),
 
                     // ROT
                     build(&|mut b| {
-                        load(&mut b, R2, BSP);
+                        m.load(&mut b, R2, BSP);
                         b.const_binary32(Add, R1, BSP, CELL);
-                        load(&mut b, R3, R1);
-                        store(&mut b, R2, R1);
+                        m.load(&mut b, R3, R1);
+                        m.store(&mut b, R2, R1);
                         b.const_binary32(Add, R1, BSP, 2 * CELL);
-                        load(&mut b, R2, R1);
-                        store(&mut b, R3, R1);
-                        store(&mut b, R2, BSP);
+                        m.load(&mut b, R2, R1);
+                        m.store(&mut b, R3, R1);
+                        m.store(&mut b, R2, BSP);
                         b.jump(root)
                     }),
 
                     // -ROT
                     build(&|mut b| {
-                        load(&mut b, R2, BSP);
+                        m.load(&mut b, R2, BSP);
                         b.const_binary32(Add, R1, BSP, 2 * CELL);
-                        load(&mut b, R3, R1);
-                        store(&mut b, R2, R1);
+                        m.load(&mut b, R3, R1);
+                        m.store(&mut b, R2, R1);
                         b.const_binary32(Add, R1, BSP, CELL);
-                        load(&mut b, R2, R1);
-                        store(&mut b, R3, R1);
-                        store(&mut b, R2, BSP);
+                        m.load(&mut b, R2, R1);
+                        m.store(&mut b, R3, R1);
+                        m.store(&mut b, R2, BSP);
                         b.jump(root)
                     }),
 
                     // TUCK
                     build(&|mut b| {
-                        load(&mut b, R2, BSP);
+                        m.load(&mut b, R2, BSP);
                         b.const_binary32(Add, R1, BSP, CELL);
-                        load(&mut b, R3, R1);
-                        store(&mut b, R2, R1);
-                        store(&mut b, R3, BSP);
-                        push(&mut b, R2, BSP);
+                        m.load(&mut b, R3, R1);
+                        m.store(&mut b, R2, R1);
+                        m.store(&mut b, R3, BSP);
+                        m.push(&mut b, R2, BSP);
                         b.jump(root)
                     }),
 
                     // NIP
                     build(&|mut b| {
-                        pop(&mut b, R2, BSP);
-                        store(&mut b, R2, BSP);
+                        m.pop(&mut b, R2, BSP);
+                        m.store(&mut b, R2, BSP);
                         b.jump(root)
                     }),
 
                     // PICK
-                    build(&|b| { b.jump(not_implemented) }),
+                    build(&|mut b| {
+                        m.load(&mut b, R2, BSP);
+                        b.const_binary32(Add, R2, R2, 1);
+                        b.const_binary32(Mul, R2, R2, CELL);
+                        b.binary32(Add, R2, BSP, R2);
+                        m.load(&mut b, R2, R2);
+                        m.store(&mut b, R2, BSP);
+                        b.jump(root)
+                    }),
 
                     // ROLL
-                    build(&|b| { b.jump(not_implemented) }),
+                    build(&|b| { b.jump(roll) }),
 
                     // ?DUP
-                    build(&|b| { b.jump(not_implemented) }),
+                    build(&|mut b| {
+                        m.load(&mut b, R2, BSP);
+                        b.if_(
+                            R2,
+                            build(&|mut b| {
+                                m.push(&mut b, R2, BSP);
+                                b.jump(root)
+                            }),
+                            build(&|b| { b.jump(root) }),
+                        )
+                    }),
 
                     // >R
-                    build(&|b| { b.jump(not_implemented) }),
+                    build(&|mut b| {
+                        m.pop(&mut b, R2, BSP);
+                        m.push(&mut b, R2, BRP);
+                        b.jump(root)
+                    }),
 
                     // R>
-                    build(&|b| { b.jump(not_implemented) }),
+                    build(&|mut b| {
+                        m.pop(&mut b, R2, BRP);
+                        m.push(&mut b, R2, BSP);
+                        b.jump(root)
+                    }),
 
                     // R@
-                    build(&|b| { b.jump(not_implemented) }),
+                    build(&|mut b| {
+                        m.load(&mut b, R2, BRP);
+                        m.push(&mut b, R2, BSP);
+                        b.jump(root)
+                    }),
 
                     // <
                     build(&|mut b| {
-                        pop(&mut b, R2, BSP);
-                        load(&mut b, R3, BSP);
+                        m.pop(&mut b, R2, BSP);
+                        m.load(&mut b, R3, BSP);
                         b.binary32(Lt, R2, R3, R2);
-                        store(&mut b, R2, BSP);
+                        m.store(&mut b, R2, BSP);
                         b.jump(root)
                     }),
 
                     // >
                     build(&|mut b| {
-                        pop(&mut b, R2, BSP);
-                        load(&mut b, R3, BSP);
+                        m.pop(&mut b, R2, BSP);
+                        m.load(&mut b, R3, BSP);
                         b.binary32(Lt, R2, R2, R3);
-                        store(&mut b, R2, BSP);
+                        m.store(&mut b, R2, BSP);
                         b.jump(root)
                     }),
 
                     // =
                     build(&|mut b| {
-                        pop(&mut b, R2, BSP);
-                        load(&mut b, R3, BSP);
+                        m.pop(&mut b, R2, BSP);
+                        m.load(&mut b, R3, BSP);
                         b.binary32(Eq, R2, R3, R2);
-                        store(&mut b, R2, BSP);
+                        m.store(&mut b, R2, BSP);
                         b.jump(root)
                     }),
 
                     // <>
                     build(&|mut b| {
-                        pop(&mut b, R2, BSP);
-                        load(&mut b, R3, BSP);
+                        m.pop(&mut b, R2, BSP);
+                        m.load(&mut b, R3, BSP);
                         b.binary32(Eq, R2, R3, R2);
                         b.unary32(Not, R2, R2);
-                        store(&mut b, R2, BSP);
+                        m.store(&mut b, R2, BSP);
                         b.jump(root)
                     }),
 
                     // 0<
                     build(&|mut b| {
-                        load(&mut b, R2, BSP);
+                        m.load(&mut b, R2, BSP);
                         b.const_binary32(Lt, R2, R2, 0);
-                        store(&mut b, R2, BSP);
+                        m.store(&mut b, R2, BSP);
                         b.jump(root)
                     }),
 
                     // 0>
                     build(&|mut b| {
-                        load(&mut b, R2, BSP);
+                        m.load(&mut b, R2, BSP);
                         b.const_(R3, 0);
                         b.binary32(Lt, R2, R3, R2);
-                        store(&mut b, R2, BSP);
+                        m.store(&mut b, R2, BSP);
                         b.jump(root)
                     }),
 
                     // 0=
                     build(&|mut b| {
-                        load(&mut b, R2, BSP);
+                        m.load(&mut b, R2, BSP);
                         b.const_binary32(Eq, R2, R2, 0);
-                        store(&mut b, R2, BSP);
+                        m.store(&mut b, R2, BSP);
                         b.jump(root)
                     }),
 
                     // 0<>
                     build(&|mut b| {
-                        load(&mut b, R2, BSP);
+                        m.load(&mut b, R2, BSP);
                         b.const_binary32(Eq, R2, R2, 0);
                         b.unary32(Not, R2, R2);
-                        store(&mut b, R2, BSP);
+                        m.store(&mut b, R2, BSP);
                         b.jump(root)
                     }),
 
                     // U<
                     build(&|mut b| {
-                        pop(&mut b, R2, BSP);
-                        load(&mut b, R3, BSP);
+                        m.pop(&mut b, R2, BSP);
+                        m.load(&mut b, R3, BSP);
                         b.binary32(Ult, R2, R3, R2);
-                        store(&mut b, R2, BSP);
+                        m.store(&mut b, R2, BSP);
                         b.jump(root)
                     }),
 
                     // U>
                     build(&|mut b| {
-                        pop(&mut b, R2, BSP);
-                        load(&mut b, R3, BSP);
+                        m.pop(&mut b, R2, BSP);
+                        m.load(&mut b, R3, BSP);
                         b.binary32(Ult, R2, R2, R3);
-                        store(&mut b, R2, BSP);
+                        m.store(&mut b, R2, BSP);
                         b.jump(root)
                     }),
 
                     // 0
                     build(&|mut b| {
                         b.const_(R2, 0);
-                        push(&mut b, R2, BSP);
+                        m.push(&mut b, R2, BSP);
                         b.jump(root)
                     }),
 
                     // 1
                     build(&|mut b| {
                         b.const_(R2, 1);
-                        push(&mut b, R2, BSP);
+                        m.push(&mut b, R2, BSP);
                         b.jump(root)
                     }),
 
                     // -1
                     build(&|mut b| {
                         b.const_(R2, -1i32 as u32 as i64);
-                        push(&mut b, R2, BSP);
+                        m.push(&mut b, R2, BSP);
                         b.jump(root)
                     }),
 
                     // CELL
-                    build(&|b| { b.jump(not_implemented) }),
+                    build(&|mut b| {
+                        b.const_(R2, CELL as i64);
+                        m.push(&mut b, R2, BSP);
+                        b.jump(root)
+                    }),
 
                     // -CELL
-                    build(&|b| { b.jump(not_implemented) }),
+                    build(&|mut b| {
+                        b.const_(R2, (-CELL) as u32 as i64);
+                        m.push(&mut b, R2, BSP);
+                        b.jump(root)
+                    }),
 
                     // +
                     build(&|mut b| {
-                        pop(&mut b, R2, BSP);
-                        load(&mut b, R3, BSP);
+                        m.pop(&mut b, R2, BSP);
+                        m.load(&mut b, R3, BSP);
                         b.binary32(Add, R2, R3, R2);
-                        store(&mut b, R2, BSP);
+                        m.store(&mut b, R2, BSP);
                         b.jump(root)
                     }),
 
                     // -
                     build(&|mut b| {
-                        pop(&mut b, R2, BSP);
-                        load(&mut b, R3, BSP);
+                        m.pop(&mut b, R2, BSP);
+                        m.load(&mut b, R3, BSP);
                         b.binary32(Sub, R2, R3, R2);
-                        store(&mut b, R2, BSP);
+                        m.store(&mut b, R2, BSP);
                         b.jump(root)
                     }),
 
                     // >-<
                     build(&|mut b| {
-                        pop(&mut b, R2, BSP);
-                        load(&mut b, R3, BSP);
+                        m.pop(&mut b, R2, BSP);
+                        m.load(&mut b, R3, BSP);
                         b.binary32(Sub, R2, R2, R3);
-                        store(&mut b, R2, BSP);
+                        m.store(&mut b, R2, BSP);
                         b.jump(root)
                     }),
 
                     // 1+
                     build(&|mut b| {
-                        load(&mut b, R2, BSP);
+                        m.load(&mut b, R2, BSP);
                         b.const_binary32(Add, R2, R2, 1);
-                        store(&mut b, R2, BSP);
+                        m.store(&mut b, R2, BSP);
                         b.jump(root)
                     }),
 
                     // 1-
                     build(&|mut b| {
-                        load(&mut b, R2, BSP);
+                        m.load(&mut b, R2, BSP);
                         b.const_binary32(Sub, R2, R2, 1);
-                        store(&mut b, R2, BSP);
+                        m.store(&mut b, R2, BSP);
                         b.jump(root)
                     }),
 
                     // CELL+
-                    build(&|b| { b.jump(not_implemented) }),
+                    build(&|mut b| {
+                        m.load(&mut b, R2, BSP);
+                        b.const_binary32(Add, R2, R2, CELL);
+                        m.store(&mut b, R2, BSP);
+                        b.jump(root)
+                    }),
 
                     // CELL-
-                    build(&|b| { b.jump(not_implemented) }),
+                    build(&|mut b| {
+                        m.load(&mut b, R2, BSP);
+                        b.const_binary32(Sub, R2, R2, CELL);
+                        m.store(&mut b, R2, BSP);
+                        b.jump(root)
+                    }),
 
                     // *
                     build(&|mut b| {
-                        pop(&mut b, R2, BSP);
-                        load(&mut b, R3, BSP);
+                        m.pop(&mut b, R2, BSP);
+                        m.load(&mut b, R3, BSP);
                         b.binary32(Mul, R2, R3, R2);
-                        store(&mut b, R2, BSP);
+                        m.store(&mut b, R2, BSP);
                         b.jump(root)
                     }),
 
                     // /
-                    build(&|b| { b.jump(not_implemented) }),
+                    build(&|mut b| {
+                        m.load(&mut b, R2, BSP);
+                        b.guard(R2, true, trap_with(0x26));
+                        b.const_binary32(Add, BSP, BSP, CELL);
+                        m.load(&mut b, R3, BSP);
+                        sdivmod(&mut b, true);
+                        m.store(&mut b, R1, BSP);
+                        b.jump(root)
+                    }),
 
                     // MOD
-                    build(&|b| { b.jump(not_implemented) }),
+                    build(&|mut b| {
+                        m.load(&mut b, R2, BSP);
+                        b.guard(R2, true, trap_with(0x27));
+                        b.const_binary32(Add, BSP, BSP, CELL);
+                        m.load(&mut b, R3, BSP);
+                        sdivmod(&mut b, true);
+                        m.store(&mut b, R3, BSP);
+                        b.jump(root)
+                    }),
 
                     // /MOD
-                    build(&|b| { b.jump(not_implemented) }),
+                    build(&|mut b| {
+                        m.load(&mut b, R2, BSP);
+                        b.guard(R2, true, trap_with(0x28));
+                        b.const_binary32(Add, BSP, BSP, CELL);
+                        m.load(&mut b, R3, BSP);
+                        sdivmod(&mut b, true);
+                        m.store(&mut b, R3, BSP);
+                        m.push(&mut b, R1, BSP);
+                        b.jump(root)
+                    }),
 
                     // U/MOD
-                    build(&|b| { b.jump(not_implemented) }),
+                    build(&|mut b| {
+                        m.load(&mut b, R2, BSP);
+                        b.guard(R2, true, trap_with(0x29));
+                        b.const_binary32(Add, BSP, BSP, CELL);
+                        m.load(&mut b, R3, BSP);
+                        b.binary32(UDiv, R1, R3, R2);
+                        b.binary32(Mul, R2, R1, R2);
+                        b.binary32(Sub, R3, R3, R2);
+                        m.store(&mut b, R3, BSP);
+                        m.push(&mut b, R1, BSP);
+                        b.jump(root)
+                    }),
 
                     // S/REM
-                    build(&|b| { b.jump(not_implemented) }),
+                    build(&|mut b| {
+                        m.load(&mut b, R2, BSP);
+                        b.guard(R2, true, trap_with(0x2A));
+                        b.const_binary32(Add, BSP, BSP, CELL);
+                        m.load(&mut b, R3, BSP);
+                        sdivmod(&mut b, false);
+                        m.store(&mut b, R3, BSP);
+                        m.push(&mut b, R1, BSP);
+                        b.jump(root)
+                    }),
 
                     // 2/
-                    build(&|b| { b.jump(not_implemented) }),
+                    build(&|mut b| {
+                        m.load(&mut b, R2, BSP);
+                        b.const_binary32(Asr, R2, R2, 1);
+                        m.store(&mut b, R2, BSP);
+                        b.jump(root)
+                    }),
 
                     // CELLS
-                    build(&|b| { b.jump(not_implemented) }),
+                    build(&|mut b| {
+                        m.load(&mut b, R2, BSP);
+                        b.const_binary32(Mul, R2, R2, CELL);
+                        m.store(&mut b, R2, BSP);
+                        b.jump(root)
+                    }),
 
                     // ABS
                     build(&|mut b| {
-                        load(&mut b, R2, BSP);
+                        m.load(&mut b, R2, BSP);
                         b.unary32(Abs, R2, R2);
-                        store(&mut b, R2, BSP);
+                        m.store(&mut b, R2, BSP);
                         b.jump(root)
                     }),
 
                     // NEGATE
                     build(&|mut b| {
-                        load(&mut b, R2, BSP);
+                        m.load(&mut b, R2, BSP);
                         b.unary32(Negate, R2, R2);
-                        store(&mut b, R2, BSP);
+                        m.store(&mut b, R2, BSP);
                         b.jump(root)
                     }),
 
                     // MAX
                     build(&|mut b| {
-                        pop(&mut b, R2, BSP);
-                        load(&mut b, R3, BSP);
+                        m.pop(&mut b, R2, BSP);
+                        m.load(&mut b, R3, BSP);
                         b.binary32(Max, R2, R3, R2);
-                        store(&mut b, R2, BSP);
+                        m.store(&mut b, R2, BSP);
                         b.jump(root)
                     }),
 
                     // MIN
                     build(&|mut b| {
-                        pop(&mut b, R2, BSP);
-                        load(&mut b, R3, BSP);
+                        m.pop(&mut b, R2, BSP);
+                        m.load(&mut b, R3, BSP);
                         b.binary32(Min, R2, R3, R2);
-                        store(&mut b, R2, BSP);
+                        m.store(&mut b, R2, BSP);
                         b.jump(root)
                     }),
 
                     // INVERT
                     build(&|mut b| {
-                        load(&mut b, R2, BSP);
+                        m.load(&mut b, R2, BSP);
                         b.unary32(Not, R2, R2);
-                        store(&mut b, R2, BSP);
+                        m.store(&mut b, R2, BSP);
                         b.jump(root)
                     }),
 
                     // AND
-                    build(&|b| { b.jump(not_implemented) }),
+                    build(&|mut b| {
+                        m.pop(&mut b, R2, BSP);
+                        m.load(&mut b, R3, BSP);
+                        b.binary32(And, R2, R3, R2);
+                        m.store(&mut b, R2, BSP);
+                        b.jump(root)
+                    }),
 
                     // OR
-                    build(&|b| { b.jump(not_implemented) }),
+                    build(&|mut b| {
+                        m.pop(&mut b, R2, BSP);
+                        m.load(&mut b, R3, BSP);
+                        b.binary32(Or, R2, R3, R2);
+                        m.store(&mut b, R2, BSP);
+                        b.jump(root)
+                    }),
 
                     // XOR
-                    build(&|b| { b.jump(not_implemented) }),
+                    build(&|mut b| {
+                        m.pop(&mut b, R2, BSP);
+                        m.load(&mut b, R3, BSP);
+                        b.binary32(Xor, R2, R3, R2);
+                        m.store(&mut b, R2, BSP);
+                        b.jump(root)
+                    }),
 
                     // LSHIFT
-                    build(&|b| { b.jump(not_implemented) }),
+                    build(&|mut b| {
+                        m.pop(&mut b, R2, BSP);
+                        m.load(&mut b, R3, BSP);
+                        b.binary32(Lsl, R3, R3, R2);
+                        // Shifting by `CELL_BITS` or more gives zero.
+                        b.const_binary32(Ult, R2, R2, CELL_BITS);
+                        b.binary32(And, R2, R3, R2);
+                        m.store(&mut b, R2, BSP);
+                        b.jump(root)
+                    }),
 
                     // RSHIFT
-                    build(&|b| { b.jump(not_implemented) }),
+                    build(&|mut b| {
+                        m.pop(&mut b, R2, BSP);
+                        m.load(&mut b, R3, BSP);
+                        b.binary32(Lsr, R3, R3, R2);
+                        // Shifting by `CELL_BITS` or more gives zero.
+                        b.const_binary32(Ult, R2, R2, CELL_BITS);
+                        b.binary32(And, R2, R3, R2);
+                        m.store(&mut b, R2, BSP);
+                        b.jump(root)
+                    }),
 
                     // 1LSHIFT
-                    build(&|b| { b.jump(not_implemented) }),
+                    build(&|mut b| {
+                        m.load(&mut b, R2, BSP);
+                        b.const_binary32(Lsl, R2, R2, 1);
+                        m.store(&mut b, R2, BSP);
+                        b.jump(root)
+                    }),
 
                     // 1RSHIFT
-                    build(&|b| { b.jump(not_implemented) }),
+                    build(&|mut b| {
+                        m.load(&mut b, R2, BSP);
+                        b.const_binary32(Lsr, R2, R2, 1);
+                        m.store(&mut b, R2, BSP);
+                        b.jump(root)
+                    }),
 
                     // @
                     build(&|mut b| {
-                        load(&mut b, R2, BSP);
-                        load(&mut b, R2, R2);
-                        store(&mut b, R2, BSP);
+                        m.load(&mut b, R2, BSP);
+                        m.load(&mut b, R2, R2);
+                        m.store(&mut b, R2, BSP);
                         b.jump(root)
                     }),
 
                     // !
                     build(&|mut b| {
-                        pop(&mut b, R2, BSP);
-                        pop(&mut b, R3, BSP);
-                        store(&mut b, R3, R2);
+                        m.pop(&mut b, R2, BSP);
+                        m.pop(&mut b, R3, BSP);
+                        m.store(&mut b, R3, R2);
                         b.jump(root)
                     }),
 
                     // C@
-                    build(&|b| { b.jump(not_implemented) }),
+                    build(&|mut b| {
+                        m.load(&mut b, R2, BSP);
+                        m.load_byte(&mut b, R2, R2);
+                        m.store(&mut b, R2, BSP);
+                        b.jump(root)
+                    }),
 
                     // C!
-                    build(&|b| { b.jump(not_implemented) }),
+                    build(&|mut b| {
+                        m.pop(&mut b, R2, BSP);
+                        m.pop(&mut b, R3, BSP);
+                        m.store_byte(&mut b, R3, R2);
+                        b.jump(root)
+                    }),
 
                     // +!
                     build(&|mut b| {
-                        pop(&mut b, R2, BSP);
-                        pop(&mut b, R3, BSP);
-                        load(&mut b, R1, R2);
+                        m.pop(&mut b, R2, BSP);
+                        m.pop(&mut b, R3, BSP);
+                        m.load(&mut b, R1, R2);
                         b.binary32(Add, R3, R1, R3);
-                        store(&mut b, R3, R2);
+                        m.store(&mut b, R3, R2);
                         b.jump(root)
                     }),
 
                     // SP@
-                    build(&|b| { b.jump(not_implemented) }),
+                    build(&|mut b| {
+                        b.move_(R2, BSP);
+                        m.push(&mut b, R2, BSP);
+                        b.jump(root)
+                    }),
 
                     // SP!
-                    build(&|b| { b.jump(not_implemented) }),
+                    build(&|mut b| {
+                        m.load(&mut b, BSP, BSP);
+                        b.jump(root)
+                    }),
 
                     // RP@
-                    build(&|b| { b.jump(not_implemented) }),
+                    build(&|mut b| {
+                        m.push(&mut b, BRP, BSP);
+                        b.jump(root)
+                    }),
 
                     // RP!
-                    build(&|b| { b.jump(not_implemented) }),
+                    build(&|mut b| {
+                        m.pop(&mut b, BRP, BSP);
+                        b.jump(root)
+                    }),
 
                     // BRANCH
-                    build(&|b| { b.jump(not_implemented) }),
+                    build(&|b| { b.jump(branch) }),
 
                     // BRANCHI
                     build(&|b| { b.jump(branchi) }),
 
                     // ?BRANCH
-                    build(&|b| { b.jump(not_implemented) }),
+                    build(&|mut b| {
+                        m.pop(&mut b, BI, BSP);
+                        b.if_(
+                            BI,
+                            build(&|mut b| {
+                                b.const_binary32(Add, BEP, BEP, CELL);
+                                m.pop(&mut b, BA, BEP);
+                                b.jump(root)
+                            }),
+                            build(&|b| { b.jump(branch) }),
+                        )
+                    }),
 
                     // ?BRANCHI
                     build(&|mut b| {
-                        pop(&mut b, BI, BSP);
+                        m.pop(&mut b, BI, BSP);
                         b.switch(Switch::if_(
                             BI.into(),
                             build(&|mut b| {
-                                pop(&mut b, BA, BEP);
+                                m.pop(&mut b, BA, BEP);
                                 b.jump(root)
                         }),
                             build(&|b| { b.jump(branchi) }),
@@ -563,74 +904,178 @@ impl<T: Target> Beetle<T> {
                     }),
 
                     // EXECUTE
-                    build(&|b| { b.jump(not_implemented) }),
+                    build(&|mut b| {
+                        m.push(&mut b, BEP, BRP);
+                        m.pop(&mut b, BEP, BSP);
+                        m.pop(&mut b, BA, BEP);
+                        b.jump(root)
+                    }),
 
                     // @EXECUTE
-                    build(&|b| { b.jump(not_implemented) }),
+                    build(&|mut b| {
+                        m.push(&mut b, BEP, BRP);
+                        m.pop(&mut b, R2, BSP);
+                        m.load(&mut b, BEP, R2);
+                        m.pop(&mut b, BA, BEP);
+                        b.jump(root)
+                    }),
 
                     // CALL
-                    build(&|b| { b.jump(not_implemented) }),
+                    build(&|mut b| {
+                        b.const_binary32(Add, R2, BEP, CELL);
+                        m.push(&mut b, R2, BRP);
+                        b.jump(branch)
+                    }),
 
                     // CALLI
                     build(&|mut b| {
-                        push(&mut b, BEP, BRP);
+                        m.push(&mut b, BEP, BRP);
                         b.jump(branchi)
                     }),
 
                     // EXIT
                     build(&|mut b| {
-                        pop(&mut b, BEP, BRP);
-                        pop(&mut b, BA, BEP);
+                        m.pop(&mut b, BEP, BRP);
+                        m.pop(&mut b, BA, BEP);
                         b.jump(root)
                     }),
 
                     // (DO)
-                    build(&|b| { b.jump(not_implemented) }),
+                    build(&|mut b| {
+                        m.pop(&mut b, R2, BSP);
+                        m.pop(&mut b, R3, BSP);
+                        m.push(&mut b, R3, BRP);
+                        m.push(&mut b, R2, BRP);
+                        b.jump(root)
+                    }),
 
                     // (LOOP)
-                    build(&|b| { b.jump(not_implemented) }),
+                    build(&|mut b| {
+                        m.load(&mut b, R2, BRP);
+                        b.const_binary32(Add, R2, R2, 1);
+                        m.store(&mut b, R2, BRP);
+                        b.const_binary32(Add, R3, BRP, CELL);
+                        m.load(&mut b, R3, R3);
+                        b.binary32(Eq, R2, R2, R3);
+                        b.if_(R2, unloop_branch.clone(), build(&|b| { b.jump(branch) }))
+                    }),
 
                     // (LOOP)I
-                    build(&|b| { b.jump(not_implemented) }),
+                    build(&|mut b| {
+                        m.load(&mut b, R2, BRP);
+                        b.const_binary32(Add, R2, R2, 1);
+                        m.store(&mut b, R2, BRP);
+                        b.const_binary32(Add, R3, BRP, CELL);
+                        m.load(&mut b, R3, R3);
+                        b.binary32(Eq, R2, R2, R3);
+                        b.if_(R2, unloop_next.clone(), build(&|b| { b.jump(branchi) }))
+                    }),
 
                     // (+LOOP)
-                    build(&|b| { b.jump(not_implemented) }),
+                    build(&|mut b| {
+                        m.pop(&mut b, R1, BSP);
+                        m.load(&mut b, R2, BRP);
+                        b.const_binary32(Add, R3, BRP, CELL);
+                        m.load(&mut b, R3, R3);
+                        b.binary32(Sub, R3, R2, R3);
+                        b.binary32(Add, R2, R2, R1);
+                        m.store(&mut b, R2, BRP);
+                        // Exit if the index crosses the boundary between
+                        // `limit - 1` and `limit`.
+                        b.binary32(Add, R2, R3, R1);
+                        b.binary32(Xor, R2, R2, R3);
+                        b.binary32(Xor, R3, R3, R1);
+                        b.binary32(And, R2, R2, R3);
+                        b.const_binary32(Lt, R2, R2, 0);
+                        b.if_(R2, unloop_branch.clone(), build(&|b| { b.jump(branch) }))
+                    }),
 
                     // (+LOOP)I
-                    build(&|b| { b.jump(not_implemented) }),
+                    build(&|mut b| {
+                        m.pop(&mut b, R1, BSP);
+                        m.load(&mut b, R2, BRP);
+                        b.const_binary32(Add, R3, BRP, CELL);
+                        m.load(&mut b, R3, R3);
+                        b.binary32(Sub, R3, R2, R3);
+                        b.binary32(Add, R2, R2, R1);
+                        m.store(&mut b, R2, BRP);
+                        // Exit if the index crosses the boundary between
+                        // `limit - 1` and `limit`.
+                        b.binary32(Add, R2, R3, R1);
+                        b.binary32(Xor, R2, R2, R3);
+                        b.binary32(Xor, R3, R3, R1);
+                        b.binary32(And, R2, R2, R3);
+                        b.const_binary32(Lt, R2, R2, 0);
+                        b.if_(R2, unloop_next.clone(), build(&|b| { b.jump(branchi) }))
+                    }),
 
                     // UNLOOP
-                    build(&|b| { b.jump(not_implemented) }),
+                    build(&|mut b| {
+                        b.const_binary32(Add, BRP, BRP, 2 * CELL);
+                        b.jump(root)
+                    }),
 
                     // J
-                    build(&|b| { b.jump(not_implemented) }),
+                    build(&|mut b| {
+                        b.const_binary32(Add, R2, BRP, 2 * CELL);
+                        m.load(&mut b, R2, R2);
+                        m.push(&mut b, R2, BSP);
+                        b.jump(root)
+                    }),
 
                     // (LITERAL)
-                    build(&|b| { b.jump(not_implemented) }),
+                    build(&|mut b| {
+                        m.pop(&mut b, R2, BEP);
+                        m.push(&mut b, R2, BSP);
+                        b.jump(root)
+                    }),
 
                     // (LITERAL)I
                     build(&|mut b| {
-                        push(&mut b, BA, BSP);
-                        pop(&mut b, BA, BEP);
+                        m.push(&mut b, BA, BSP);
+                        m.pop(&mut b, BA, BEP);
+                        b.jump(root)
+                    }),
+
+                    // THROW
+                    build(&|b| { b.jump(trap) }),
+
+                    // HALT
+                    build(&|b| { b.jump(trap) }),
+
+                    // EP@
+                    build(&|mut b| {
+                        m.push(&mut b, BEP, BSP);
                         b.jump(root)
                     }),
                 ]),
-                build(&|b| { b.jump(not_implemented) }),
+                // LIB, LINK and undefined opcodes.
+                build(&|b| { b.jump(trap) }),
             ))
         }));
 
         Self {jit, root}
     }
 
-    pub fn global_mut(&mut self, global: Global) -> &mut Word {
-        self.jit.global_mut(global)
-    }
-
-    pub unsafe fn run(&mut self, registers: &mut Registers, m0: &mut[u32]) {
-        *self.jit.global_mut(Global(0)) = Word {mp: (registers as *mut Registers).cast()};
-        *self.jit.global_mut(Global(1)) = Word {mp: (m0.as_mut_ptr()).cast()};
-        let result = self.jit.run(self.root);
-        assert_eq!(result, Word {s: NOT_IMPLEMENTED});
+    /// Runs the Beetle virtual machine whose state is `registers` and `m0`.
+    /// Any number of virtual machines can share `self`.
+    ///
+    /// Returns `Ok(())` if the virtual machine reaches an instruction that
+    /// the caller must perform, or `Err(code)` if an instruction raises the
+    /// Beetle exception `code`. In the latter case, `registers.not_address`
+    /// holds the offending address.
+    pub unsafe fn run(&mut self, registers: &mut Registers, m0: &mut[u32]) -> Result<(), i32> {
+        let memory = m0.len() * CELL as usize;
+        assert!(memory as u64 <= 1 << 32, "Beetle's memory is too large");
+        let mut pool = self.jit.new_pool();
+        pool[Global(0)] = Word {mp: (registers as *mut Registers).cast()};
+        pool[Global(1)] = Word {mp: (m0.as_mut_ptr()).cast()};
+        pool[Global(2)] = Word {u: memory as u64};
+        let result = self.jit.run(&mut pool, self.root).s;
+        match result {
+            TRAP => Ok(()),
+            _ => Err(-result as i32),
+        }
     }
 }
 
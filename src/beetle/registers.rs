@@ -7,6 +7,16 @@ pub struct Registers {
     pub a: u32,
     pub sp: u32,
     pub rp: u32,
+    /// The initial value of `sp`.
+    pub s0: u32,
+    /// The initial value of `rp`.
+    pub r0: u32,
+    /// The address of the exception handler, or zero if there is none.
+    pub throw: u32,
+    /// The value of `ep` when the last exception was raised.
+    pub bad: u32,
+    /// The last address that caused an invalid or unaligned address exception.
+    pub not_address: u32,
 }
 
 impl std::fmt::Debug for Registers {
@@ -17,6 +27,11 @@ impl std::fmt::Debug for Registers {
             .field("a", &format!("{:#x}", self.a))
             .field("sp", &format!("{:#x}", self.sp))
             .field("rp", &format!("{:#x}", self.rp))
+            .field("s0", &format!("{:#x}", self.s0))
+            .field("r0", &format!("{:#x}", self.r0))
+            .field("throw", &format!("{:#x}", self.throw))
+            .field("bad", &format!("{:#x}", self.bad))
+            .field("not_address", &format!("{:#x}", self.not_address))
             .finish()
     }
 }
@@ -1,223 +1,402 @@
-use super::super::target::{Native, native};
-
-use super::{Registers, CELL, Beetle};
-
-/// The suggested size of the Beetle memory, in cells.
-pub const MEMORY_CELLS: u32 = 1 << 20;
-/// The suggested size of the Beetle data stack, in cells.
-pub const DATA_CELLS: u32 = 1 << 18;
-/// The suggested size of the Beetle return stack, in cells.
-pub const RETURN_CELLS: u32 = 1 << 18;
-
-pub struct VM {
-    /// The compiled code.
-    beetle: super::Beetle<Native>,
-    /// The Beetle state (other than the memory).
-    state: Registers,
-    /// The Beetle memory.
-    memory: Vec<u32>,
-    /// The amount of unallocated memory, in cells.
-    free_cells: u32,
-    /// The address of a HALT instruction.
-    halt_addr: u32,
-}
-
-impl VM {
-    /// Constructs a Beetle virtual machine with the specified parameters.
-    ///
-    /// The memory is `memory_cells` cells. The data stack occupies the last
-    /// `data_cells` cells of the memory, and the return stack occupies
-    /// the last `return_cells` cells before that. The cells before that
-    /// are free for the program's use.
-    pub fn new(
-        memory_cells: u32,
-        data_cells: u32,
-        return_cells: u32,
-    ) -> Self {
-        let mut vm = VM {
-            beetle: Beetle::new(native()),
-            state: Registers::default(),
-            memory: vec![0; memory_cells as usize],
-            free_cells: memory_cells,
-            halt_addr: 0,
-        };
-        // Allocate the return stack.
-        let rp = vm.allocate(return_cells).1;
-        vm.registers_mut().rp = rp;
-        // Allocate the data stack.
-        let sp = vm.allocate(data_cells).1;
-        vm.registers_mut().sp = sp;
-        // Allocate a word to hold a HALT instruction.
-        vm.halt_addr = vm.allocate(1).0;
-        vm.store(vm.halt_addr, 0x5519);
-        vm
-    }
+use super::{
+    MEMORY_CELLS, DATA_CELLS, RETURN_CELLS, VM, LoadError,
+    INVALID_ADDRESS, DIVISION_BY_ZERO, UNALIGNED_ADDRESS, INVALID_OPCODE,
+    INVALID_LIBRARY_ROUTINE,
+};
+use super::examples::{ACKERMANN};
 
-    /// Read the public registers.
-    pub fn registers(&self) -> &Registers { &self.state }
-
-    /// Read or write the public registers.
-    pub fn registers_mut(&mut self) -> &mut Registers { &mut self.state }
-
-    /// Read the `M0` register.
-    pub fn memory(&self) -> &[u32] { &self.memory }
-
-    /// Allocate `cells` cells and return a (start, end) Beetle pointer pair.
-    /// Allocation starts at the top of memory and is permanent.
-    pub fn allocate(&mut self, cells: u32) -> (u32, u32) {
-        assert!(cells <= self.free_cells);
-        let end = self.free_cells.checked_mul(CELL as u32)
-            .expect("Address out of range");
-        self.free_cells = self.free_cells.checked_sub(cells)
-            .expect("Out of memory");
-        let start = self.free_cells.checked_mul(CELL as u32)
-            .expect("Address out of range");
-        (start, end)
-    }
+#[test]
+pub fn halt() {
+    let mut vm = VM::new(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS);
+    let initial_sp = vm.registers().sp;
+    let initial_rp = vm.registers().rp;
+    let entry_address = vm.halt_addr();
+    let exit = vm.run(entry_address);
+    assert_eq!(exit, Ok(0));
+    assert_eq!(vm.registers().sp, initial_sp);
+    assert_eq!(vm.registers().rp, initial_rp);
+}
 
-    /// Load `object` at address zero, i.e. in the unallocated memory.
-    pub fn load_object(&mut self, object: &[u32]) {
-        assert!(object.len() <= self.free_cells as usize);
-        for (i, &cell) in object.iter().enumerate() {
-            self.memory[i] = cell;
-        }
-    }
+#[test]
+pub fn ackermann() {
+    let mut vm = VM::new(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS);
+    vm.store_cells(0, &ACKERMANN).unwrap();
+    let initial_sp = vm.registers().sp;
+    let initial_rp = vm.registers().rp;
+    vm.push(3).unwrap();
+    vm.push(5).unwrap();
+    vm.rpush(vm.halt_addr()).unwrap();
+    let exit = vm.run(0);
+    assert_eq!(exit, Ok(0));
+    let result = vm.pop().unwrap();
+    assert_eq!(vm.registers().sp, initial_sp);
+    assert_eq!(vm.registers().rp, initial_rp);
+    assert_eq!(result, 253);
+}
 
-    /// Return the value of the word at address `addr`.
-    pub fn load(&self, addr: u32) -> u32 {
-        assert_eq!(addr & 0x3, 0);
-        self.memory[(addr >> 2) as usize]
-    }
+//-----------------------------------------------------------------------------
 
-    /// Set the word at address `addr` to `value`.
-    pub fn store(&mut self, addr: u32, value: u32) {
-        assert_eq!(addr & 0x3, 0);
-        self.memory[(addr >> 2) as usize] = value;
-    }
+/// `0 HALT`. Test programs end with this instruction cell.
+const HALT: u32 = 0x5519;
 
-    /// Push `item` onto the data stack.
-    pub fn push(&mut self, item: u32) {
-        self.registers_mut().sp -= CELL as u32;
-        self.store(self.registers().sp, item);
-    }
+/// The value of a true flag.
+const TRUE: u32 = 0xFFFF_FFFF;
 
-    /// Pop an item from the data stack.
-    pub fn pop(&mut self) -> u32 {
-        let item = self.load(self.registers().sp);
-        self.registers_mut().sp += CELL as u32;
-        item
-    }
+/// Runs `code`, loaded at address zero, with `before` on the data stack (top
+/// last). Checks that it `HALT`s, and returns the `VM`.
+fn run_code(code: &[u32], before: &[u32]) -> VM {
+    let mut vm = VM::new(0x1000, 0x100, 0x100);
+    vm.store_cells(0, code).unwrap();
+    for &item in before { vm.push(item).unwrap(); }
+    let exit = vm.run(0);
+    assert_eq!(exit, Ok(0), "{:?}", vm);
+    vm
+}
 
-    /// Push `item` onto the return stack.
-    pub fn rpush(&mut self, item: u32) {
-        self.registers_mut().rp -= CELL as u32;
-        self.store(self.registers().rp, item);
+/// Runs each of `cases`, which give the code, the stack before, and the
+/// expected stack after.
+fn check_stack(cases: &[(&[u32], &[u32], &[u32])]) {
+    for &(code, before, after) in cases {
+        let vm = run_code(code, before);
+        assert_eq!(vm.stack(), after, "code = {:x?}, before = {:x?}", code, before);
+        assert_eq!(vm.registers().rp, vm.registers().r0);
     }
+}
 
-    /// Run the code at address `ep`. If it `HALT`s, return the code.
-    pub unsafe fn run(&mut self, ep: u32) -> Option<u32> {
-        assert!(Self::is_aligned(ep));
-        self.registers_mut().ep = ep;
-        self.beetle.run(&mut self.state, self.memory.as_mut());
-        if self.registers_mut().a & 0xFF == 0x55 {
-            // Halt.
-            self.registers_mut().a >>= 8;
-            Some(self.pop())
-        } else {
-            // Some other not implemented case.
-            None
-        }
-    }
+/// Converts a signed cell to unsigned.
+fn s(value: i32) -> u32 { value as u32 }
+
+#[test]
+pub fn stack_ops() {
+    check_stack(&[
+        (&[0x01, HALT], &[1, 2], &[1, 2, 2]), // DUP
+        (&[0x02, HALT], &[1, 2], &[1]), // DROP
+        (&[0x03, HALT], &[1, 2], &[2, 1]), // SWAP
+        (&[0x04, HALT], &[1, 2], &[1, 2, 1]), // OVER
+        (&[0x05, HALT], &[1, 2, 3], &[2, 3, 1]), // ROT
+        (&[0x06, HALT], &[1, 2, 3], &[3, 1, 2]), // -ROT
+        (&[0x07, HALT], &[1, 2], &[2, 1, 2]), // TUCK
+        (&[0x08, HALT], &[1, 2], &[2]), // NIP
+        (&[0x09, HALT], &[10, 11, 0], &[10, 11, 11]), // PICK
+        (&[0x09, HALT], &[10, 11, 12, 2], &[10, 11, 12, 10]),
+        (&[0x0A, HALT], &[10, 11, 0], &[10, 11]), // ROLL
+        (&[0x0A, HALT], &[10, 11, 1], &[11, 10]),
+        (&[0x0A, HALT], &[10, 11, 12, 13, 3], &[11, 12, 13, 10]),
+        (&[0x0B, HALT], &[0], &[0]), // ?DUP
+        (&[0x0B, HALT], &[3], &[3, 3]),
+    ]);
+}
+
+#[test]
+pub fn comparisons() {
+    check_stack(&[
+        (&[0x0F, HALT], &[s(-1), 1], &[TRUE]), // <
+        (&[0x0F, HALT], &[1, s(-1)], &[0]),
+        (&[0x10, HALT], &[1, s(-1)], &[TRUE]), // >
+        (&[0x10, HALT], &[1, 1], &[0]),
+        (&[0x11, HALT], &[3, 3], &[TRUE]), // =
+        (&[0x11, HALT], &[3, 4], &[0]),
+        (&[0x12, HALT], &[3, 4], &[TRUE]), // <>
+        (&[0x12, HALT], &[3, 3], &[0]),
+        (&[0x13, HALT], &[s(-1)], &[TRUE]), // 0<
+        (&[0x13, HALT], &[0], &[0]),
+        (&[0x14, HALT], &[1], &[TRUE]), // 0>
+        (&[0x14, HALT], &[s(-1)], &[0]),
+        (&[0x15, HALT], &[0], &[TRUE]), // 0=
+        (&[0x15, HALT], &[5], &[0]),
+        (&[0x16, HALT], &[5], &[TRUE]), // 0<>
+        (&[0x16, HALT], &[0], &[0]),
+        (&[0x17, HALT], &[1, s(-1)], &[TRUE]), // U<
+        (&[0x17, HALT], &[s(-1), 1], &[0]),
+        (&[0x18, HALT], &[s(-1), 1], &[TRUE]), // U>
+        (&[0x18, HALT], &[1, s(-1)], &[0]),
+    ]);
+}
+
+#[test]
+pub fn constants() {
+    check_stack(&[
+        (&[0x19, HALT], &[], &[0]), // 0
+        (&[0x1A, HALT], &[], &[1]), // 1
+        (&[0x1B, HALT], &[], &[TRUE]), // -1
+        (&[0x1C, HALT], &[], &[4]), // CELL
+        (&[0x1D, HALT], &[], &[s(-4)]), // -CELL
+        (&[0x52, 42, HALT], &[], &[42]), // (LITERAL)
+        (&[0x2A53, HALT], &[], &[42]), // (LITERAL)I
+        (&[0x56, HALT], &[], &[4]), // EP@
+    ]);
+}
+
+#[test]
+pub fn arithmetic() {
+    check_stack(&[
+        (&[0x1E, HALT], &[2, 3], &[5]), // +
+        (&[0x1F, HALT], &[2, 3], &[s(-1)]), // -
+        (&[0x20, HALT], &[2, 3], &[1]), // >-<
+        (&[0x21, HALT], &[2], &[3]), // 1+
+        (&[0x22, HALT], &[2], &[1]), // 1-
+        (&[0x23, HALT], &[1], &[5]), // CELL+
+        (&[0x24, HALT], &[5], &[1]), // CELL-
+        (&[0x25, HALT], &[s(-2), 3], &[s(-6)]), // *
+        (&[0x2B, HALT], &[s(-7)], &[s(-4)]), // 2/
+        (&[0x2C, HALT], &[3], &[12]), // CELLS
+        (&[0x2D, HALT], &[s(-3)], &[3]), // ABS
+        (&[0x2E, HALT], &[3], &[s(-3)]), // NEGATE
+        (&[0x2F, HALT], &[s(-1), 2], &[2]), // MAX
+        (&[0x30, HALT], &[s(-1), 2], &[s(-1)]), // MIN
+    ]);
+}
+
+#[test]
+pub fn division() {
+    check_stack(&[
+        (&[0x26, HALT], &[7, 2], &[3]), // /
+        (&[0x26, HALT], &[s(-7), 2], &[s(-4)]),
+        (&[0x26, HALT], &[7, s(-2)], &[s(-4)]),
+        (&[0x26, HALT], &[s(-7), s(-2)], &[3]),
+        (&[0x26, HALT], &[s(i32::MIN), s(-1)], &[s(i32::MIN)]),
+        (&[0x27, HALT], &[7, 2], &[1]), // MOD
+        (&[0x27, HALT], &[s(-7), 2], &[1]),
+        (&[0x27, HALT], &[7, s(-2)], &[s(-1)]),
+        (&[0x27, HALT], &[s(-7), s(-2)], &[s(-1)]),
+        (&[0x27, HALT], &[6, s(-2)], &[0]),
+        (&[0x28, HALT], &[s(-7), 2], &[1, s(-4)]), // /MOD
+        (&[0x28, HALT], &[s(-6), 2], &[0, s(-3)]),
+        (&[0x29, HALT], &[TRUE, 16], &[15, 0x0FFF_FFFF]), // U/MOD
+        (&[0x2A, HALT], &[s(-7), 2], &[s(-1), s(-3)]), // S/REM
+        (&[0x2A, HALT], &[7, s(-2)], &[1, s(-3)]),
+    ]);
+}
+
+#[test]
+pub fn logic() {
+    check_stack(&[
+        (&[0x31, HALT], &[0], &[TRUE]), // INVERT
+        (&[0x32, HALT], &[0b1100, 0b1010], &[0b1000]), // AND
+        (&[0x33, HALT], &[0b1100, 0b1010], &[0b1110]), // OR
+        (&[0x34, HALT], &[0b1100, 0b1010], &[0b0110]), // XOR
+        (&[0x35, HALT], &[1, 4], &[16]), // LSHIFT
+        (&[0x35, HALT], &[1, 32], &[0]),
+        (&[0x36, HALT], &[TRUE, 28], &[15]), // RSHIFT
+        (&[0x36, HALT], &[TRUE, 32], &[0]),
+        (&[0x37, HALT], &[3], &[6]), // 1LSHIFT
+        (&[0x38, HALT], &[TRUE], &[0x7FFF_FFFF]), // 1RSHIFT
+    ]);
+}
 
-    /// Indicate whether an address is cell-aligned.
-    pub fn is_aligned(addr: u32) -> bool {
-        addr & 0x3 == 0
+#[test]
+pub fn memory() {
+    const ADDR: u32 = 0x100;
+    let setup = |vm: &mut VM| vm.store(ADDR, 0x1234_5678).unwrap();
+    let cases: &[(u32, &[u32], &[u32], u32)] = &[
+        (0x39, &[ADDR], &[0x1234_5678], 0x1234_5678), // @
+        (0x3A, &[42, ADDR], &[], 42), // !
+        (0x3B, &[ADDR + 1], &[0x56], 0x1234_5678), // C@
+        (0x3C, &[0xAB, ADDR + 2], &[], 0x12AB_5678), // C!
+        (0x3D, &[2, ADDR], &[], 0x1234_567A), // +!
+    ];
+    for &(opcode, before, after, value) in cases {
+        let mut vm = VM::new(0x1000, 0x100, 0x100);
+        vm.store_cells(0, &[opcode, HALT]).unwrap();
+        setup(&mut vm);
+        for &item in before { vm.push(item).unwrap(); }
+        assert_eq!(vm.run(0), Ok(0));
+        assert_eq!(vm.stack(), after, "opcode = {:x}", opcode);
+        assert_eq!(vm.load(ADDR), Ok(value), "opcode = {:x}", opcode);
     }
 }
 
-impl std::fmt::Debug for VM {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
-        f.debug_struct("VM")
-            .field("state", &self.state)
-            .field("m0", &format!("{:#x}", self.memory().as_ptr() as u64))
-            .finish()
+#[test]
+pub fn stack_pointers() {
+    // SP@
+    let vm = run_code(&[0x3E, HALT], &[1]);
+    assert_eq!(vm.stack(), [1, vm.registers().s0 - 4]);
+    // SP!
+    let s0 = run_code(&[HALT], &[]).registers().s0;
+    let vm = run_code(&[0x3F, HALT], &[1, 2, 3, s0 - 4]);
+    assert_eq!(vm.stack(), [1]);
+    // RP@
+    let vm = run_code(&[0x40, HALT], &[]);
+    assert_eq!(vm.stack(), [vm.registers().r0]);
+    // RP!
+    let r0 = run_code(&[HALT], &[]).registers().r0;
+    let vm = run_code(&[0x41, HALT], &[r0 - 8]);
+    assert_eq!(vm.registers().rp, r0 - 8);
+    assert_eq!(vm.stack(), []);
+}
+
+#[test]
+pub fn return_stack() {
+    // >R
+    let vm = run_code(&[0x0C, HALT], &[5]);
+    assert_eq!((vm.stack(), vm.rstack()), (vec![], vec![5]));
+    // R>
+    let vm = run_code(&[0x0C0C, 0x0D, HALT], &[5, 6]);
+    assert_eq!((vm.stack(), vm.rstack()), (vec![5], vec![6]));
+    // R@
+    let vm = run_code(&[0x0C, 0x0E, HALT], &[5]);
+    assert_eq!((vm.stack(), vm.rstack()), (vec![5], vec![5]));
+    // (DO)
+    let vm = run_code(&[0x4B, HALT], &[10, 0]);
+    assert_eq!((vm.stack(), vm.rstack()), (vec![], vec![10, 0]));
+    // UNLOOP
+    let vm = run_code(&[0x4B, 0x50, HALT], &[10, 0]);
+    assert_eq!((vm.stack(), vm.rstack()), (vec![], vec![]));
+    // J
+    let vm = run_code(&[0x4B4B, 0x51, HALT], &[10, 3, 7, 1]);
+    assert_eq!((vm.stack(), vm.rstack()), (vec![1], vec![7, 1, 10, 3]));
+}
+
+#[test]
+pub fn branches() {
+    check_stack(&[
+        (&[0x42, 12, 0x1A, HALT], &[], &[]), // BRANCH
+        (&[0x0143, 0x1A, HALT], &[], &[]), // BRANCHI
+        (&[0x44, 12, 0x1A, HALT], &[0], &[]), // ?BRANCH
+        (&[0x44, 12, 0x1A, HALT], &[5], &[1]),
+        (&[0x0145, 0x1A, HALT], &[0], &[]), // ?BRANCHI
+        (&[0x0145, 0x1A, HALT], &[5], &[1]),
+        (&[0x46, HALT, 0x4A1A], &[8], &[1]), // EXECUTE, EXIT
+        (&[0x47, HALT, 0x4A1A, 8], &[12], &[1]), // @EXECUTE
+        (&[0x48, 12, HALT, 0x4A1A], &[], &[1]), // CALL
+        (&[0x0149, HALT, 0x4A1A], &[], &[1]), // CALLI
+    ]);
+}
+
+#[test]
+pub fn loops() {
+    check_stack(&[
+        // (DO) 1+ (LOOP)
+        (&[0x4B, 0x21, 0x4C, 4, HALT], &[0, 3, 0], &[3]),
+        // (DO) 1+ (LOOP)I
+        (&[0x4B, 0x21, 0xFFFF_FE4D, HALT], &[0, 3, 0], &[3]),
+        // (DO) 1+ CELL (+LOOP)
+        (&[0x4B, 0x1C21, 0x4E, 4, HALT], &[0, 10, 0], &[3]),
+        // (DO) 1+ -CELL (+LOOP)
+        (&[0x4B, 0x1D21, 0x4E, 4, HALT], &[0, 0, 8], &[3]),
+        // (DO) 1+ CELL (+LOOP)I
+        (&[0x4B, 0x1C21, 0xFFFF_FE4F, HALT], &[0, 10, 0], &[3]),
+    ]);
+}
+
+#[test]
+pub fn traps() {
+    let cases: &[(u32, &[u32], i32, &[u32])] = &[
+        (0x54, &[1], 1, &[]), // THROW
+        (0x57, &[1], INVALID_LIBRARY_ROUTINE, &[]), // LIB
+        (0xFE, &[1], INVALID_OPCODE, &[1]), // Undefined.
+        (0x26, &[7, 0], DIVISION_BY_ZERO, &[7, 0]), // / by zero.
+        (0x27, &[7, 0], DIVISION_BY_ZERO, &[7, 0]), // MOD by zero.
+        (0x28, &[7, 0], DIVISION_BY_ZERO, &[7, 0]), // /MOD by zero.
+        (0x29, &[7, 0], DIVISION_BY_ZERO, &[7, 0]), // U/MOD by zero.
+        (0x2A, &[7, 0], DIVISION_BY_ZERO, &[7, 0]), // S/REM by zero.
+    ];
+    for &(opcode, before, code, after) in cases {
+        let mut vm = VM::new(0x1000, 0x100, 0x100);
+        vm.store_cells(0, &[0x1A00 | opcode, HALT]).unwrap();
+        for &item in before { vm.push(item).unwrap(); }
+        assert_eq!(vm.run(0), Err(code), "opcode = {:x}", opcode);
+        assert_eq!(vm.registers().a, 0x1A, "opcode = {:x}", opcode);
+        assert_eq!(vm.registers().ep, 4, "opcode = {:x}", opcode);
+        assert_eq!(vm.stack(), after, "opcode = {:x}", opcode);
     }
 }
 
-//-----------------------------------------------------------------------------
+#[test]
+pub fn exceptions() {
+    // The memory is `0x4000` bytes.
+    let cases: &[(&[u32], &[u32], i32, u32)] = &[
+        (&[0x39, HALT], &[0x101], UNALIGNED_ADDRESS, 0x101), // @
+        (&[0x39, HALT], &[0x4000], INVALID_ADDRESS, 0x4000),
+        (&[0x3A, HALT], &[0, 0xFFFF_FFFC], INVALID_ADDRESS, 0xFFFF_FFFC), // !
+        (&[0x3B, HALT], &[0x4000], INVALID_ADDRESS, 0x4000), // C@
+        (&[0x3C, HALT], &[0, 0x4001], INVALID_ADDRESS, 0x4001), // C!
+        (&[0x42, 0x4000], &[], INVALID_ADDRESS, 0x4000), // BRANCH
+        (&[0x42, 0x0002], &[], UNALIGNED_ADDRESS, 0x0002),
+        (&[0x0D, HALT], &[], INVALID_ADDRESS, 0x4000), // R>
+    ];
+    for &(code, before, code_, address) in cases {
+        let mut vm = VM::new(0x1000, 0x100, 0x100);
+        vm.store_cells(0, code).unwrap();
+        for &item in before { vm.push(item).unwrap(); }
+        assert_eq!(vm.run(0), Err(code_), "code = {:x?}", code);
+        assert_eq!(vm.registers().not_address, address, "code = {:x?}", code);
+    }
+    // Bytes are not aligned, and the last byte is valid.
+    let vm = run_code(&[0x3B, HALT], &[0x3FFF]);
+    assert_eq!(vm.stack(), [0]);
+}
 
-pub fn ackermann_object() -> Vec<u32> {
-    // Forth source:
-    // : ACKERMANN   ( m n -- result )
-    // OVER 0= IF                  \ m = 0
-    //     NIP 1+                  \ n+1
-    // ELSE
-    //     DUP 0= IF               \ n = 0
-    //         DROP 1- 1 RECURSE   \ A(m-1, 1)
-    //     ELSE
-    //         OVER 1- -ROT        \ m-1 m n
-    //         1- RECURSE          \ m-1 A(m, n-1)
-    //         RECURSE             \ A(m-1, A(m, n-1))
-    //     THEN
-    // THEN ;
-
-    // Beetle assembler:
-    // $00: OVER
-    //      0=
-    // $04: ?BRANCHI $10
-    // $08: NIP
-    //      1+
-    // $0C: BRANCHI $30
-    // $10: DUP
-    //      0=
-    // $14: ?BRANCHI $24
-    // $18: DROP
-    //      1-
-    //      1
-    // $1C: CALLI $0
-    // $20: BRANCHI $30
-    // $24: OVER
-    //      1-
-    //      -ROT
-    //      1-
-    // $28: CALLI $0
-    // $2C: CALLI $0
-    // $30: EXIT
-
-    // Beetle object code:
-    vec![
-        0x00001504, 0x00000245, 0x00002108, 0x00000843,
-        0x00001501, 0x00000345, 0x001A2202, 0xFFFFF849,
-        0x00000343, 0x22062204, 0xFFFFF549, 0xFFFFF449,
-        0x0000004A,
-    ]
+#[test]
+pub fn handlers() {
+    let mut vm = VM::new(0x1000, 0x100, 0x100);
+    // Library routine 3 adds two numbers.
+    vm.set_lib(3, |vm| {
+        let x = vm.pop()?;
+        let y = vm.pop()?;
+        vm.push(x + y)
+    });
+    // Opcode `0xF0` pushes `42`.
+    vm.set_extra(0xF0, |vm| vm.push(42));
+    // Opcode `0xF1` raises an exception.
+    vm.set_extra(0xF1, |_| Err(-1));
+    vm.store_cells(0, &[0xF057, HALT, 0xF1, HALT]).unwrap();
+    for item in [2, 5, 3] { vm.push(item).unwrap(); }
+    assert_eq!(vm.run(0), Ok(0));
+    assert_eq!(vm.stack(), [7, 42]);
+    assert_eq!(vm.run(8), Err(-1));
+    assert_eq!(vm.stack(), [7, 42]);
 }
 
 #[test]
-pub fn halt() {
-    let mut vm = VM::new(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS);
-    let initial_sp = vm.registers().sp;
-    let initial_rp = vm.registers().rp;
-    let entry_address = vm.halt_addr;
-    let exit = unsafe { vm.run(entry_address) };
-    assert_eq!(exit, Some(0));
-    assert_eq!(vm.registers().sp, initial_sp);
-    assert_eq!(vm.registers().rp, initial_rp);
+pub fn throw() {
+    let mut vm = VM::new(0x1000, 0x100, 0x100);
+    // The exception handler `HALT`s with the exception code.
+    vm.store_cells(0x100, &[0x55]).unwrap();
+    vm.registers_mut().throw = 0x100;
+    let cases: &[(&[u32], &[u32], i32)] = &[
+        (&[0x54, HALT], &[5], 5), // THROW
+        (&[0xFE, HALT], &[], INVALID_OPCODE),
+        (&[0x39, HALT], &[0x4000], INVALID_ADDRESS), // @
+        (&[0x26, HALT], &[7, 0], DIVISION_BY_ZERO), // /
+    ];
+    for &(code, before, code_) in cases {
+        vm.store_cells(0, code).unwrap();
+        for &item in before { vm.push(item).unwrap(); }
+        assert_eq!(vm.run(0), Ok(code_ as u32), "code = {:x?}", code);
+        assert_eq!(vm.registers().bad, 4, "code = {:x?}", code);
+        vm.registers_mut().sp = vm.registers().s0;
+    }
+}
+
+/// Returns a Beetle object file containing `cells`.
+fn object_file(big_endian: bool, cells: &[u32]) -> Vec<u8> {
+    let to_bytes = if big_endian { u32::to_be_bytes } else { u32::to_le_bytes };
+    let mut file = b"BEETLE\0".to_vec();
+    file.push(big_endian.into());
+    file.extend(to_bytes(cells.len() as u32));
+    for &cell in cells { file.extend(to_bytes(cell)); }
+    file
 }
 
 #[test]
-pub fn ackermann() {
-    let mut vm = VM::new(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS);
-    vm.load_object(ackermann_object().as_ref());
-    let initial_sp = vm.registers().sp;
-    let initial_rp = vm.registers().rp;
-    vm.push(3);
-    vm.push(5);
-    vm.rpush(vm.halt_addr);
-    let exit = unsafe { vm.run(0) };
-    assert_eq!(exit, Some(0));
-    let result = vm.pop();
-    assert_eq!(vm.registers().sp, initial_sp);
-    assert_eq!(vm.registers().rp, initial_rp);
-    assert_eq!(result, 253);
+pub fn load_object() {
+    for big_endian in [false, true] {
+        let mut vm = VM::new(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS);
+        let file = object_file(big_endian, &ACKERMANN);
+        vm.load_object(&mut &file[..], 0).unwrap();
+        vm.push(2).unwrap();
+        vm.push(3).unwrap();
+        vm.rpush(vm.halt_addr()).unwrap();
+        assert_eq!(vm.run(0), Ok(0));
+        assert_eq!(vm.stack(), [9]);
+    }
+    let mut vm = VM::new(0x1000, 0x100, 0x100);
+    let file = object_file(false, &[1, 2, 3]);
+    assert!(matches!(vm.load_object(&mut &file[1..], 0), Err(LoadError::BadHeader)));
+    assert!(matches!(vm.load_object(&mut &file[..14], 0), Err(LoadError::Io(_))));
+    assert!(matches!(vm.load_object(&mut &file[..], 2), Err(LoadError::BadAddress)));
+    assert!(matches!(vm.load_object(&mut &file[..], 0x3FF8), Err(LoadError::BadAddress)));
+    vm.load_object(&mut &file[..], 0x3FF4).unwrap();
+    assert_eq!(vm.memory()[0xFFD..], [1, 2, 3]);
 }
@@ -0,0 +1,381 @@
+use std::collections::{HashMap};
+use std::fmt::{self, Debug, Display, Formatter};
+use std::io::{self, Read};
+
+use super::super::target::{Native, native};
+use super::{
+    Registers, Beetle, CELL,
+    INVALID_ADDRESS, DIVISION_BY_ZERO, UNALIGNED_ADDRESS, INVALID_OPCODE,
+    INVALID_LIBRARY_ROUTINE,
+};
+
+/// The suggested size of the Beetle memory, in cells.
+pub const MEMORY_CELLS: u32 = 1 << 20;
+/// The suggested size of the Beetle data stack, in cells.
+pub const DATA_CELLS: u32 = 1 << 18;
+/// The suggested size of the Beetle return stack, in cells.
+pub const RETURN_CELLS: u32 = 1 << 18;
+
+/// The first bytes of a Beetle object file. The next byte is `0` if the
+/// object file is little-endian, or `1` if it is big-endian.
+const MAGIC: &[u8; 7] = b"BEETLE\0";
+
+const THROW: u32 = 0x54;
+const HALT: u32 = 0x55;
+const LIB: u32 = 0x57;
+/// The first opcode that [`VM`] passes to an extra instruction handler.
+/// This is `LINK`, followed by the undefined opcodes.
+const FIRST_EXTRA: u32 = 0x58;
+
+/// Performs a library routine or an extra instruction on behalf of a [`VM`].
+/// Returns `Err(code)` to raise an exception.
+pub type Handler = Box<dyn FnMut(&mut VM) -> Result<(), i32>>;
+
+//-----------------------------------------------------------------------------
+
+/// The ways in which [`VM::load_object()`] can fail.
+#[derive(Debug)]
+pub enum LoadError {
+    /// The object file could not be read.
+    Io(io::Error),
+    /// The object file does not start with a valid header.
+    BadHeader,
+    /// The address is unaligned, or the object does not fit in the memory.
+    BadAddress,
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            LoadError::Io(e) => write!(f, "Could not read object file: {}", e),
+            LoadError::BadHeader => write!(f, "Not a Beetle object file"),
+            LoadError::BadAddress => write!(f, "Object does not fit in memory"),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LoadError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for LoadError {
+    fn from(e: io::Error) -> Self { LoadError::Io(e) }
+}
+
+//-----------------------------------------------------------------------------
+
+/// A Beetle virtual machine, with its own memory, which runs on the host.
+///
+/// Instructions that trap are performed by the `VM`. `LIB` pops a routine
+/// number and calls the [`Handler`] registered with [`set_lib()`]. `LINK`
+/// and undefined opcodes call the `Handler` registered with [`set_extra()`].
+/// Exceptions, including those raised by `THROW` and by `Handler`s, push
+/// the exception code and jump to the address in [`Registers::throw`].
+///
+/// [`set_lib()`]: VM::set_lib
+/// [`set_extra()`]: VM::set_extra
+pub struct VM {
+    /// The compiled code.
+    beetle: Beetle<Native>,
+    /// The Beetle state (other than the memory).
+    state: Registers,
+    /// The Beetle memory.
+    memory: Vec<u32>,
+    /// The amount of unallocated memory, in cells.
+    free_cells: u32,
+    /// The address of a `0 HALT` instruction sequence.
+    halt_addr: u32,
+    /// The library routines, indexed by routine number.
+    libs: HashMap<u32, Handler>,
+    /// The extra instructions, indexed by opcode.
+    extras: HashMap<u32, Handler>,
+}
+
+impl VM {
+    /// Constructs a Beetle virtual machine with the specified parameters.
+    ///
+    /// The memory is `memory_cells` cells. The return stack occupies the
+    /// last `return_cells` cells of the memory, and the data stack occupies
+    /// the last `data_cells` cells before that. The cells before that
+    /// are free for the program's use.
+    pub fn new(
+        memory_cells: u32,
+        data_cells: u32,
+        return_cells: u32,
+    ) -> Self {
+        let mut vm = VM {
+            beetle: Beetle::new(native()),
+            state: Registers::default(),
+            memory: vec![0; memory_cells as usize],
+            free_cells: memory_cells,
+            halt_addr: 0,
+            libs: HashMap::new(),
+            extras: HashMap::new(),
+        };
+        // Allocate the return stack.
+        let rp = vm.allocate(return_cells).1;
+        vm.state.rp = rp;
+        vm.state.r0 = rp;
+        // Allocate the data stack.
+        let sp = vm.allocate(data_cells).1;
+        vm.state.sp = sp;
+        vm.state.s0 = sp;
+        // Allocate a word to hold `0 HALT`.
+        vm.halt_addr = vm.allocate(1).0;
+        vm.memory[(vm.halt_addr >> 2) as usize] = 0x5519;
+        vm
+    }
+
+    /// Read the public registers.
+    pub fn registers(&self) -> &Registers { &self.state }
+
+    /// Read or write the public registers.
+    pub fn registers_mut(&mut self) -> &mut Registers { &mut self.state }
+
+    /// Read the memory.
+    pub fn memory(&self) -> &[u32] { &self.memory }
+
+    /// Read or write the memory.
+    pub fn memory_mut(&mut self) -> &mut [u32] { &mut self.memory }
+
+    /// The address of a `0 HALT` instruction sequence. Pushing it to the
+    /// return stack makes `EXIT` halt the `VM` with code `0`.
+    pub fn halt_addr(&self) -> u32 { self.halt_addr }
+
+    /// Allocate `cells` cells and return a (start, end) Beetle pointer pair.
+    /// Allocation starts at the top of memory and is permanent.
+    pub fn allocate(&mut self, cells: u32) -> (u32, u32) {
+        assert!(cells <= self.free_cells);
+        let end = self.free_cells.checked_mul(CELL as u32)
+            .expect("Address out of range");
+        self.free_cells = self.free_cells.checked_sub(cells)
+            .expect("Out of memory");
+        let start = self.free_cells.checked_mul(CELL as u32)
+            .expect("Address out of range");
+        (start, end)
+    }
+
+    /// Registers `handler` to perform library routine number `routine`.
+    pub fn set_lib(
+        &mut self,
+        routine: u32,
+        handler: impl FnMut(&mut VM) -> Result<(), i32> + 'static,
+    ) {
+        self.libs.insert(routine, Box::new(handler));
+    }
+
+    /// Registers `handler` to perform `opcode`, which must be `LINK` or an
+    /// undefined opcode.
+    pub fn set_extra(
+        &mut self,
+        opcode: u8,
+        handler: impl FnMut(&mut VM) -> Result<(), i32> + 'static,
+    ) {
+        let opcode = u32::from(opcode);
+        assert!(opcode >= FIRST_EXTRA, "Opcode {:#x} is already defined", opcode);
+        self.extras.insert(opcode, Box::new(handler));
+    }
+
+    /// Loads a Beetle object file from `file` into memory at `addr`.
+    pub fn load_object(&mut self, file: &mut impl Read, addr: u32) -> Result<(), LoadError> {
+        let mut header = [0; 8];
+        file.read_exact(&mut header)?;
+        if &header[..7] != MAGIC { return Err(LoadError::BadHeader); }
+        let from_bytes: fn([u8; 4]) -> u32 = match header[7] {
+            0 => u32::from_le_bytes,
+            1 => u32::from_be_bytes,
+            _ => return Err(LoadError::BadHeader),
+        };
+        let mut cell = [0; 4];
+        file.read_exact(&mut cell)?;
+        let length = from_bytes(cell) as usize;
+        if !Self::is_aligned(addr) { return Err(LoadError::BadAddress); }
+        let start = (addr >> 2) as usize;
+        if length > self.memory.len().saturating_sub(start) { return Err(LoadError::BadAddress); }
+        for i in start..(start + length) {
+            file.read_exact(&mut cell)?;
+            self.memory[i] = from_bytes(cell);
+        }
+        Ok(())
+    }
+
+    /// Store `cells` in memory starting at address `addr`.
+    pub fn store_cells(&mut self, addr: u32, cells: &[u32]) -> Result<(), i32> {
+        for (i, &cell) in cells.iter().enumerate() {
+            self.store(addr.wrapping_add(i as u32 * CELL as u32), cell)?;
+        }
+        Ok(())
+    }
+
+    /// Checks that `addr` is in range, and, if `aligned`, that it is a
+    /// multiple of `CELL`. Returns the index of the cell containing `addr`,
+    /// or raises an exception.
+    fn cell_index(&mut self, addr: u32, aligned: bool) -> Result<usize, i32> {
+        let index = (addr >> 2) as usize;
+        let code = if aligned && !Self::is_aligned(addr) {
+            UNALIGNED_ADDRESS
+        } else if index >= self.memory.len() {
+            INVALID_ADDRESS
+        } else {
+            return Ok(index);
+        };
+        self.state.not_address = addr;
+        Err(code)
+    }
+
+    /// Return the value of the word at address `addr`.
+    pub fn load(&mut self, addr: u32) -> Result<u32, i32> {
+        let index = self.cell_index(addr, true)?;
+        Ok(self.memory[index])
+    }
+
+    /// Set the word at address `addr` to `value`.
+    pub fn store(&mut self, addr: u32, value: u32) -> Result<(), i32> {
+        let index = self.cell_index(addr, true)?;
+        self.memory[index] = value;
+        Ok(())
+    }
+
+    /// Return the value of the byte at address `addr`.
+    pub fn load_byte(&mut self, addr: u32) -> Result<u8, i32> {
+        let index = self.cell_index(addr, false)?;
+        Ok(self.memory[index].to_ne_bytes()[(addr & 0x3) as usize])
+    }
+
+    /// Set the byte at address `addr` to `value`.
+    pub fn store_byte(&mut self, addr: u32, value: u8) -> Result<(), i32> {
+        let index = self.cell_index(addr, false)?;
+        let mut bytes = self.memory[index].to_ne_bytes();
+        bytes[(addr & 0x3) as usize] = value;
+        self.memory[index] = u32::from_ne_bytes(bytes);
+        Ok(())
+    }
+
+    /// Push `item` onto the data stack.
+    pub fn push(&mut self, item: u32) -> Result<(), i32> {
+        let sp = self.state.sp.wrapping_sub(CELL as u32);
+        self.store(sp, item)?;
+        self.state.sp = sp;
+        Ok(())
+    }
+
+    /// Pop an item from the data stack.
+    pub fn pop(&mut self) -> Result<u32, i32> {
+        let item = self.load(self.state.sp)?;
+        self.state.sp = self.state.sp.wrapping_add(CELL as u32);
+        Ok(item)
+    }
+
+    /// Push `item` onto the return stack.
+    pub fn rpush(&mut self, item: u32) -> Result<(), i32> {
+        let rp = self.state.rp.wrapping_sub(CELL as u32);
+        self.store(rp, item)?;
+        self.state.rp = rp;
+        Ok(())
+    }
+
+    /// Pop an item from the return stack.
+    pub fn rpop(&mut self) -> Result<u32, i32> {
+        let item = self.load(self.state.rp)?;
+        self.state.rp = self.state.rp.wrapping_add(CELL as u32);
+        Ok(item)
+    }
+
+    /// Returns the cells from `start` to `end` as a stack, with the top item
+    /// last. Panics if they are not in the memory.
+    fn cells(&self, start: u32, end: u32) -> Vec<u32> {
+        self.memory[(start >> 2) as usize..(end >> 2) as usize].iter().rev().copied().collect()
+    }
+
+    /// Returns the contents of the data stack, with the top item last.
+    pub fn stack(&self) -> Vec<u32> { self.cells(self.state.sp, self.state.s0) }
+
+    /// Returns the contents of the return stack, with the top item last.
+    pub fn rstack(&self) -> Vec<u32> { self.cells(self.state.rp, self.state.r0) }
+
+    /// Calls the `Handler` for `key` in the field `handlers` of `self`.
+    /// Raises `code` if there is none.
+    fn call(
+        &mut self,
+        handlers: fn(&mut Self) -> &mut HashMap<u32, Handler>,
+        key: u32,
+        code: i32,
+    ) -> Result<(), i32> {
+        // Remove the `Handler` while it runs, so that it can borrow `self`.
+        let mut handler = handlers(self).remove(&key).ok_or(code)?;
+        let result = handler(self);
+        handlers(self).insert(key, handler);
+        result
+    }
+
+    /// Performs the instruction that caused the compiled code to trap.
+    /// Returns `Ok(Some(code))` if the instruction is `HALT`.
+    fn trap(&mut self) -> Result<Option<u32>, i32> {
+        let opcode = self.state.a & 0xFF;
+        self.state.a = ((self.state.a as i32) >> 8) as u32;
+        match opcode {
+            THROW => Err(self.pop()? as i32),
+            HALT => Ok(Some(self.pop()?)),
+            LIB => {
+                let routine = self.pop()?;
+                self.call(|vm| &mut vm.libs, routine, INVALID_LIBRARY_ROUTINE)?;
+                Ok(None)
+            },
+            // `/`, `MOD`, `/MOD`, `U/MOD` and `S/REM` trap on division by zero.
+            0x26..=0x2A => Err(DIVISION_BY_ZERO),
+            _ => {
+                assert!(opcode >= FIRST_EXTRA, "Opcode {:#x} trapped", opcode);
+                self.call(|vm| &mut vm.extras, opcode, INVALID_OPCODE)?;
+                Ok(None)
+            },
+        }
+    }
+
+    /// Raises exception `code`. If there is an exception handler, pushes
+    /// `code`, saves `ep` in `bad` and jumps to the handler. Otherwise,
+    /// returns `Err(code)`.
+    fn throw(&mut self, code: i32) -> Result<(), i32> {
+        if self.state.throw == 0 { return Err(code); }
+        self.push(code as u32).map_err(|_| code)?;
+        self.state.bad = self.state.ep;
+        self.state.ep = self.state.throw;
+        self.state.a = 0;
+        Ok(())
+    }
+
+    /// Run the code at address `ep` until it `HALT`s, and return the code.
+    /// If it raises an exception that is not handled, return `Err(code)`.
+    pub fn run(&mut self, ep: u32) -> Result<u32, i32> {
+        self.state.ep = ep;
+        self.state.a = 0;
+        loop {
+            // Safety: the compiled code checks every address against the
+            // size of `memory`.
+            let result = unsafe { self.beetle.run(&mut self.state, &mut self.memory) };
+            match result.and_then(|()| self.trap()) {
+                Ok(Some(code)) => return Ok(code),
+                Ok(None) => {},
+                Err(code) => self.throw(code)?,
+            }
+        }
+    }
+
+    /// Indicate whether an address is cell-aligned.
+    pub fn is_aligned(addr: u32) -> bool {
+        addr & 0x3 == 0
+    }
+}
+
+impl Debug for VM {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("VM")
+            .field("state", &self.state)
+            .field("m0", &format!("{:#x}", self.memory().as_ptr() as u64))
+            .finish()
+    }
+}
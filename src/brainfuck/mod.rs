@@ -0,0 +1,363 @@
+//! An implementation of [Brainfuck] in Mijit.
+//! This serves as a small but complete example of a [`Machine`].
+//!
+//! The program is first translated into a list of instructions, with the
+//! matching brackets resolved. The tape is `TAPE_CELLS` bytes, and the tape
+//! pointer wraps around at each end. Input and output are performed by the
+//! host, using [`Trap`]s. At the end of the input, `,` reads `0`.
+//!
+//! [Brainfuck]: https://en.wikipedia.org/wiki/Brainfuck
+
+use std::fmt::{self, Debug, Display, Formatter};
+
+use super::code::{self, BinaryOp, Width, Register, REGISTERS, Global, Marshal, Machine, Case, Switch};
+use BinaryOp::*;
+use Width::*;
+use code::builder::{build_block, Builder};
+use super::target::{Native, Target, Word, Pool, native};
+use super::jit::{MachineJit};
+
+/// The number of bytes in the tape.
+pub const TAPE_CELLS: usize = 1 << 16;
+
+// Opcodes. The rest of an instruction word is the index of the instruction
+// after the matching bracket, if any.
+const END: u32 = 0;
+const INC: u32 = 1;
+const DEC: u32 = 2;
+const LEFT: u32 = 3;
+const RIGHT: u32 = 4;
+const OPEN: u32 = 5;
+const CLOSE: u32 = 6;
+const OUTPUT: u32 = 7;
+const INPUT: u32 = 8;
+
+/// The number of bits of an instruction word that hold the opcode.
+const OPCODE_BITS: u32 = 4;
+
+//-----------------------------------------------------------------------------
+
+/// The ways in which a Brainfuck program can be malformed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// The `[` at this byte offset has no matching `]`.
+    UnmatchedOpen(usize),
+    /// The `]` at this byte offset has no matching `[`.
+    UnmatchedClose(usize),
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            ParseError::UnmatchedOpen(pos) => write!(f, "Unmatched '[' at offset {}", pos),
+            ParseError::UnmatchedClose(pos) => write!(f, "Unmatched ']' at offset {}", pos),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Translates `source` into a list of instruction words, ending with `END`.
+/// Characters other than the eight Brainfuck commands are ignored.
+pub fn parse(source: &str) -> Result<Vec<u32>, ParseError> {
+    let mut program = Vec::new();
+    // The byte offset and instruction index of each unmatched `[`.
+    let mut opens = Vec::new();
+    for (pos, c) in source.bytes().enumerate() {
+        let opcode = match c {
+            b'+' => INC,
+            b'-' => DEC,
+            b'<' => LEFT,
+            b'>' => RIGHT,
+            b'[' => {
+                opens.push((pos, program.len()));
+                OPEN
+            },
+            b']' => {
+                let (_, open) = opens.pop().ok_or(ParseError::UnmatchedClose(pos))?;
+                program[open] |= ((program.len() + 1) as u32) << OPCODE_BITS;
+                CLOSE | ((open + 1) as u32) << OPCODE_BITS
+            },
+            b'.' => OUTPUT,
+            b',' => INPUT,
+            _ => continue,
+        };
+        program.push(opcode);
+    }
+    if let Some(&(pos, _)) = opens.last() { return Err(ParseError::UnmatchedOpen(pos)); }
+    program.push(END);
+    Ok(program)
+}
+
+/// Runs `program`, which was returned by [`parse()`], with `input`, using
+/// a plain Rust interpreter. Returns the output.
+pub fn interpret(program: &[u32], input: &[u8]) -> Vec<u8> {
+    let mut tape = vec![0u8; TAPE_CELLS];
+    let mut input = input.iter();
+    let mut output = Vec::new();
+    let mut pc = 0;
+    let mut tp = 0;
+    loop {
+        let word = program[pc];
+        let target = (word >> OPCODE_BITS) as usize;
+        pc += 1;
+        match word & ((1 << OPCODE_BITS) - 1) {
+            END => return output,
+            INC => { tape[tp] = tape[tp].wrapping_add(1); },
+            DEC => { tape[tp] = tape[tp].wrapping_sub(1); },
+            LEFT => { tp = tp.wrapping_sub(1) & (TAPE_CELLS - 1); },
+            RIGHT => { tp = (tp + 1) & (TAPE_CELLS - 1); },
+            OPEN => if tape[tp] == 0 { pc = target; },
+            CLOSE => if tape[tp] != 0 { pc = target; },
+            OUTPUT => { output.push(tape[tp]); },
+            INPUT => { tape[tp] = input.next().copied().unwrap_or(0); },
+            opcode => panic!("Invalid opcode {}", opcode),
+        }
+    }
+}
+
+//-----------------------------------------------------------------------------
+
+const R1: Register = REGISTERS[1];
+const PROGRAM: Register = REGISTERS[2];
+const PC: Register = REGISTERS[3];
+const TAPE: Register = REGISTERS[4];
+const TP: Register = REGISTERS[5];
+const OP: Register = REGISTERS[6];
+
+/// The `Global`s used by the [`Brainfuck`] machine.
+pub mod global {
+    use super::{Global};
+    /// The address of the program.
+    pub const PROGRAM: Global = Global(0);
+    /// The index of the current instruction.
+    pub const PC: Global = Global(1);
+    /// The address of the tape.
+    pub const TAPE: Global = Global(2);
+    /// The index of the current tape cell.
+    pub const TP: Global = Global(3);
+    /// The value tested by the current [`State`](super::State).
+    pub const OP: Global = Global(4);
+}
+
+/// The program, which is read-only.
+const AM_PROGRAM: code::AliasMask = code::AliasMask(0x1);
+
+/// The tape.
+const AM_TAPE: code::AliasMask = code::AliasMask(0x2);
+
+/// The states of the [`Brainfuck`] machine. In each `State`, `OP` holds
+/// the value to test.
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+pub enum State {
+    /// About to execute the instruction at `PC`, whose opcode is in `OP`.
+    Root,
+    /// Executing `[`. `OP` holds the current tape cell.
+    Open,
+    /// Executing `]`. `OP` holds the current tape cell.
+    Close,
+}
+
+/// The reasons for the [`Brainfuck`] machine to return to the host.
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+pub enum Trap {
+    /// The program has finished.
+    Halt,
+    /// The host should output the current tape cell, then continue in
+    /// [`State::Root`].
+    Output,
+    /// The host should read a byte into the current tape cell, then continue
+    /// in [`State::Root`].
+    Input,
+}
+
+/// Assembles code to load `OP` from the tape.
+fn load_cell(b: &mut Builder<()>) {
+    b.binary64(Add, R1, TAPE, TP);
+    b.load(OP, (R1, 0), One, AM_TAPE);
+}
+
+/// Assembles code to move `PC` to the instruction after the matching bracket.
+fn jump(b: &mut Builder<()>) {
+    b.array_load(PC, (PROGRAM, PC), Four, AM_PROGRAM);
+    b.const_binary32(Lsr, PC, PC, OPCODE_BITS as i32);
+}
+
+/// Assembles code to load into `OP` the opcode of the instruction at `PC`.
+fn fetch(b: &mut Builder<()>) {
+    b.array_load(OP, (PROGRAM, PC), Four, AM_PROGRAM);
+    b.const_binary32(And, OP, OP, (1 << OPCODE_BITS) - 1);
+}
+
+/// Returns a `Case` that runs `callback`, fetches the next instruction, and
+/// goes to `new_state`.
+fn case(callback: impl Fn(&mut Builder<()>), new_state: Result<State, Trap>) -> Case<Result<State, Trap>> {
+    let actions = build_block(&|b| {
+        callback(b);
+        b.const_binary32(Add, PC, PC, 1);
+        fetch(b);
+    });
+    Case {actions: actions.into(), new_state}
+}
+
+/// Returns a `Case` that runs `callback`, fetches the instruction at `PC`,
+/// and goes to `State::Root`.
+fn root(callback: impl Fn(&mut Builder<()>)) -> Case<Result<State, Trap>> {
+    Case {actions: build_block(&|b| { callback(b); fetch(b); }).into(), new_state: Ok(State::Root)}
+}
+
+/// The specification of the Brainfuck virtual machine.
+#[derive(Debug)]
+pub struct Brainfuck;
+
+impl Machine for Brainfuck {
+    type State = State;
+    type Trap = Trap;
+
+    fn num_globals(&self) -> usize { 5 }
+
+    fn marshal(&self, _: Self::State) -> Marshal {
+        Marshal {
+            prologue: build_block(&|b| {
+                b.move_(PROGRAM, global::PROGRAM);
+                b.move_(PC, global::PC);
+                b.move_(TAPE, global::TAPE);
+                b.move_(TP, global::TP);
+                b.move_(OP, global::OP);
+            }),
+            epilogue: build_block(&|b| {
+                b.move_(global::PROGRAM, PROGRAM);
+                b.move_(global::PC, PC);
+                b.move_(global::TAPE, TAPE);
+                b.move_(global::TP, TP);
+                b.move_(global::OP, OP);
+            }),
+        }
+    }
+
+    fn code(&self, state: Self::State) -> Switch<Case<Result<Self::State, Self::Trap>>> {
+        match state {
+            State::Root => Switch::new(
+                OP.into(),
+                Box::new([
+                    // END
+                    Case {actions: Vec::new(), new_state: Err(Trap::Halt)},
+                    // +
+                    case(|b| {
+                        load_cell(b);
+                        b.const_binary32(Add, OP, OP, 1);
+                        b.store(OP, (R1, 0), One, AM_TAPE);
+                    }, Ok(State::Root)),
+                    // -
+                    case(|b| {
+                        load_cell(b);
+                        b.const_binary32(Sub, OP, OP, 1);
+                        b.store(OP, (R1, 0), One, AM_TAPE);
+                    }, Ok(State::Root)),
+                    // <
+                    case(|b| {
+                        b.const_binary32(Sub, TP, TP, 1);
+                        b.const_binary32(And, TP, TP, TAPE_CELLS as i32 - 1);
+                    }, Ok(State::Root)),
+                    // >
+                    case(|b| {
+                        b.const_binary32(Add, TP, TP, 1);
+                        b.const_binary32(And, TP, TP, TAPE_CELLS as i32 - 1);
+                    }, Ok(State::Root)),
+                    // [
+                    Case {actions: build_block(&load_cell).into(), new_state: Ok(State::Open)},
+                    // ]
+                    Case {actions: build_block(&load_cell).into(), new_state: Ok(State::Close)},
+                    // .
+                    case(|_| {}, Err(Trap::Output)),
+                    // ,
+                    case(|_| {}, Err(Trap::Input)),
+                ]),
+                Case {actions: Vec::new(), new_state: Err(Trap::Halt)},
+            ),
+            State::Open => Switch::if_(
+                OP.into(),
+                case(|_| {}, Ok(State::Root)),
+                root(jump),
+            ),
+            State::Close => Switch::if_(
+                OP.into(),
+                root(jump),
+                case(|_| {}, Ok(State::Root)),
+            ),
+        }
+    }
+
+    fn initial_states(&self) -> Vec<Self::State> { vec![State::Root] }
+}
+
+//-----------------------------------------------------------------------------
+
+/// A Brainfuck virtual machine, which runs a program using Mijit.
+pub struct VM<T: Target = Native> {
+    /// The compiled code.
+    jit: MachineJit<Brainfuck, T>,
+    /// The `Global`s.
+    pool: Pool,
+    /// The instructions returned by [`parse()`].
+    program: Vec<u32>,
+    /// The tape.
+    tape: Vec<u8>,
+}
+
+impl VM {
+    /// Compiles the Brainfuck machine for the [`Native`] target, and loads
+    /// `source`.
+    pub fn new(source: &str) -> Result<Self, ParseError> {
+        Self::with_target(source, native())
+    }
+}
+
+impl<T: Target> VM<T> {
+    /// Compiles the Brainfuck machine for `target`, and loads `source`.
+    pub fn with_target(source: &str, target: T) -> Result<Self, ParseError> {
+        let jit = MachineJit::new(Brainfuck, target);
+        let pool = jit.new_pool();
+        let program = parse(source)?;
+        let tape = vec![0; TAPE_CELLS];
+        Ok(VM {jit, pool, program, tape})
+    }
+
+    /// Runs the program from the beginning with an empty tape and `input`.
+    /// Returns the output.
+    pub fn run(&mut self, input: &[u8]) -> Vec<u8> {
+        self.tape.fill(0);
+        self.pool[global::PROGRAM] = Word {mp: self.program.as_mut_ptr().cast()};
+        self.pool[global::PC] = Word {u: 0};
+        self.pool[global::TAPE] = Word {mp: self.tape.as_mut_ptr().cast()};
+        self.pool[global::TP] = Word {u: 0};
+        self.pool[global::OP] = Word {u: u64::from(self.program[0] & ((1 << OPCODE_BITS) - 1))};
+        let mut input = input.iter();
+        let mut output = Vec::new();
+        loop {
+            // Safety: `PC` is always the index of an instruction in `program`,
+            // and `TP` is always the index of a byte of `tape`.
+            let trap = unsafe { self.jit.run(&mut self.pool, &State::Root) };
+            let tp = unsafe { self.pool[global::TP].u } as usize;
+            match trap {
+                Trap::Halt => return output,
+                Trap::Output => { output.push(self.tape[tp]); },
+                Trap::Input => { self.tape[tp] = input.next().copied().unwrap_or(0); },
+            }
+        }
+    }
+}
+
+impl<T: Target> Debug for VM<T> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("VM")
+            .field("program", &self.program)
+            .field("tp", &unsafe { self.pool[global::TP].u })
+            .finish()
+    }
+}
+
+//-----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,68 @@
+use std::time::{Instant};
+
+use super::*;
+
+const HELLO: &str = "
+    ++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]
+    >>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.
+";
+
+/// Copies its input to its output, stopping at a zero byte.
+const CAT: &str = ",[.,]";
+
+/// Reverses its input, which must not contain a zero byte.
+const REVERSE: &str = ">,[>,]<[.<]";
+
+/// Computes `200 * 200 * 200` mod `256`, slowly, and outputs it.
+const SLOW: &str = "
+    >++++++++++[<++++++++++++++++++++>-]<
+    [>>++++++++++[<++++++++++++++++++++>-]<
+        [>>++++++++++[<++++++++++++++++++++>-]<
+            [>>+<<-]
+        <-]
+    <-]
+    >>>.
+";
+
+#[test]
+pub fn parse_errors() {
+    assert_eq!(parse("+[-[]"), Err(ParseError::UnmatchedOpen(1)));
+    assert_eq!(parse("+]"), Err(ParseError::UnmatchedClose(1)));
+    assert_eq!(parse("a[b]c"), Ok(vec![OPEN | 2 << OPCODE_BITS, CLOSE | 1 << OPCODE_BITS, END]));
+}
+
+#[test]
+pub fn programs() {
+    let cases: &[(&str, &[u8], &[u8])] = &[
+        (HELLO, b"", b"Hello World!\n"),
+        (CAT, b"Mijit", b"Mijit"),
+        (CAT, b"", b""),
+        (REVERSE, b"Mijit", b"tijiM"),
+        ("-.<.>>.", b"", b"\xFF\x00\x00"),
+        (",+.,-.", b"a", b"b\xFF"),
+    ];
+    for &(source, input, output) in cases {
+        let program = parse(source).unwrap();
+        assert_eq!(interpret(&program, input), output, "source = {}", source);
+        let mut vm = VM::new(source).unwrap();
+        assert_eq!(vm.run(input), output, "source = {}", source);
+        // A `VM` can run its program again.
+        assert_eq!(vm.run(input), output, "source = {}", source);
+    }
+}
+
+/// Compares the speed of Mijit with that of `interpret()`.
+/// Run with `cargo test --release -- --ignored --nocapture`.
+#[test]
+#[ignore]
+pub fn speed() {
+    let expected = [(200u32 * 200 * 200) as u8];
+    let start = Instant::now();
+    let program = parse(SLOW).unwrap();
+    assert_eq!(interpret(&program, b""), expected);
+    println!("interpret(): {:?}", start.elapsed());
+    let start = Instant::now();
+    let mut vm = VM::new(SLOW).unwrap();
+    assert_eq!(vm.run(b""), expected);
+    println!("VM::run(): {:?}", start.elapsed());
+}
@@ -0,0 +1,233 @@
+use std::io;
+use std::os::raw::{c_char, c_int, c_void};
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU32, Ordering};
+use crate::error::{Error};
+use super::{Buffer, Execute, Stable, page_size, whole_pages, sync_icache};
+
+/// The default maximum length of a [`Memfd`].
+pub const DEFAULT_CAPACITY: usize = 1 << 30;
+
+/// Returns the last OS error as an [`Error`].
+fn last_error() -> Error { io::Error::last_os_error().into() }
+
+/// Rounds `len` up to a whole number of pages.
+fn round_up(len: usize) -> Result<usize, Error> {
+    let mask = page_size() - 1;
+    Ok(len.checked_add(mask).ok_or(Error::OutOfMemory)? & !mask)
+}
+
+/// Reserves `len` bytes of address space, without committing any memory.
+unsafe fn reserve(len: usize) -> Result<*mut u8, Error> {
+    let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE;
+    let ptr = libc::mmap(std::ptr::null_mut(), len, libc::PROT_NONE, flags, -1, 0);
+    if ptr == libc::MAP_FAILED { Err(last_error()) } else { Ok(ptr as *mut u8) }
+}
+
+/// Maps bytes `start..end` of `fd` with protection `prot` at `base + start`,
+/// replacing part of a reservation.
+unsafe fn map(base: *mut u8, fd: c_int, start: usize, end: usize, prot: c_int) -> Result<(), Error> {
+    let address = base.add(start) as *mut c_void;
+    let flags = libc::MAP_SHARED | libc::MAP_FIXED;
+    let ptr = libc::mmap(address, end - start, prot, flags, fd, start as libc::off_t);
+    if ptr == libc::MAP_FAILED { Err(last_error()) } else { Ok(()) }
+}
+
+/// Represents a block of memory backed by an anonymous file created using
+/// `memfd_create()`. The file is mapped twice: once writeable and once
+/// executable. No mapping is ever both writeable and executable, and there is
+/// no need to change permissions in order to execute the code, so code can
+/// be modified while it is executing.
+///
+/// Address space for both views is reserved up front, and memory is committed
+/// as the `Memfd` grows, so the code never moves. The `Memfd` cannot grow
+/// beyond its capacity.
+pub struct Memfd {
+    /// The file descriptor of the anonymous file.
+    fd: c_int,
+    /// The length of the file and of the committed part of both views.
+    len: usize,
+    /// The length of the reserved address range of both views.
+    capacity: usize,
+    /// The writeable view.
+    rw: *mut u8,
+    /// The executable view.
+    rx: *mut u8,
+}
+
+// The pointers are owned by the `Memfd`.
+unsafe impl Send for Memfd {}
+
+impl Memfd {
+    /// Allocates a fresh `Memfd` that can grow to `capacity` bytes.
+    pub fn try_with_capacity(capacity: usize) -> Result<Self, Error> {
+        let capacity = round_up(std::cmp::max(capacity, 1))?;
+        let fd = unsafe { libc::memfd_create(b"mijit\0".as_ptr() as *const c_char, libc::MFD_CLOEXEC) };
+        if fd < 0 { return Err(last_error()); }
+        let mut memfd = Self {fd, len: 0, capacity, rw: std::ptr::null_mut(), rx: std::ptr::null_mut()};
+        memfd.rw = unsafe { reserve(capacity)? };
+        memfd.rx = unsafe { reserve(capacity)? };
+        memfd.try_resize(1)?;
+        Ok(memfd)
+    }
+
+    /// Like [`try_with_capacity()`], but panics on error.
+    ///
+    /// [`try_with_capacity()`]: Self::try_with_capacity
+    pub fn with_capacity(capacity: usize) -> Self {
+        crate::error::expect(Self::try_with_capacity(capacity))
+    }
+
+    /// The maximum length of this `Memfd`.
+    pub fn capacity(&self) -> usize { self.capacity }
+}
+
+impl Drop for Memfd {
+    fn drop(&mut self) {
+        unsafe {
+            for view in [self.rw, self.rx] {
+                if !view.is_null() { libc::munmap(view as *mut c_void, self.capacity); }
+            }
+            libc::close(self.fd);
+        }
+    }
+}
+
+impl Deref for Memfd {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { std::slice::from_raw_parts(self.rw, self.len) }
+    }
+}
+
+impl DerefMut for Memfd {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { std::slice::from_raw_parts_mut(self.rw, self.len) }
+    }
+}
+
+impl Buffer for Memfd {
+    fn try_new() -> Result<Self, Error> {
+        Self::try_with_capacity(DEFAULT_CAPACITY)
+    }
+
+    fn try_resize(&mut self, min_length: usize) -> Result<(), Error> {
+        if min_length > self.len {
+            let len = round_up(min_length)?;
+            if len > self.capacity { return Err(Error::OutOfMemory); }
+            // Growing the file preserves its contents.
+            if unsafe { libc::ftruncate(self.fd, len as libc::off_t) } != 0 {
+                return Err(last_error());
+            }
+            unsafe {
+                map(self.rw, self.fd, self.len, len, libc::PROT_READ | libc::PROT_WRITE)?;
+                map(self.rx, self.fd, self.len, len, libc::PROT_READ | libc::PROT_EXEC)?;
+            }
+            self.len = len;
+        }
+        Ok(())
+    }
+
+    /// Returns the address of the executable view.
+    fn executable_address(&self) -> Option<usize> {
+        Some(self.executable().as_ptr() as usize)
+    }
+
+    /// Punches a hole in the file.
+    fn release(&mut self, start: usize, end: usize) {
+        let pages = whole_pages(start, std::cmp::min(end, self.len));
+        if !pages.is_empty() {
+            // This is only a hint, so ignore errors.
+            let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+            unsafe { libc::fallocate(self.fd, mode, pages.start as libc::off_t, pages.len() as libc::off_t) };
+        }
+    }
+
+    fn write_atomic(&mut self, pos: usize, value: u32) {
+        if pos % 4 == 0 && pos + 4 <= self.len {
+            let word = unsafe { &*(self.rw.add(pos) as *const AtomicU32) };
+            word.store(value.to_le(), Ordering::Release);
+        } else {
+            self.write(pos, u64::from(value), 4);
+        }
+    }
+
+    /// Writes through the writeable view are visible in the executable view,
+    /// but instruction caches might need to be told.
+    fn sync(&mut self, start: usize, end: usize) {
+        let end = std::cmp::min(end, self.len);
+        if start < end {
+            unsafe { sync_icache(self.rw.add(start), self.rx.add(start), end - start) };
+        }
+    }
+}
+
+impl Execute for Memfd {
+    /// Pass the executable view to `callback`. This never fails.
+    fn try_execute<T>(&mut self, callback: impl FnOnce(&[u8]) -> T) -> Result<T, Error> {
+        Ok(callback(self.executable()))
+    }
+}
+
+impl Stable for Memfd {
+    fn executable(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.rx, self.len) }
+    }
+}
+
+//-----------------------------------------------------------------------------
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn api() {
+        let buffer = Memfd::new();
+        super::super::tests::api(buffer)
+    }
+
+    #[test]
+    fn release() {
+        super::super::tests::release(Memfd::new(), true)
+    }
+
+    #[test]
+    fn write_atomic() {
+        let mut buffer = Memfd::new();
+        buffer.write_atomic(4, 0x03020100);
+        buffer.write_atomic(9, 0x07060504);
+        buffer.sync(0, 16);
+        assert_eq!(buffer.executable()[4..13], [0, 1, 2, 3, 0, 4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn execute() {
+        let mut buffer = Memfd::new();
+        buffer.write(0, 0x0001020304050607, 8);
+        let result = buffer.execute(|bytes| u64::from_le_bytes(bytes[..8].try_into().unwrap()));
+        assert_eq!(result, 0x0001020304050607);
+        // Both views grow, and the contents are preserved.
+        buffer.write(0x2000, 0x08090A0B0C0D0E0F, 8);
+        let result = buffer.execute(|bytes| (
+            u64::from_le_bytes(bytes[..8].try_into().unwrap()),
+            u64::from_le_bytes(bytes[0x2000..0x2008].try_into().unwrap()),
+        ));
+        assert_eq!(result, (0x0001020304050607, 0x08090A0B0C0D0E0F));
+    }
+
+    #[test]
+    fn stable() {
+        let capacity = 4 * page_size();
+        let mut buffer = Memfd::with_capacity(capacity);
+        assert_eq!(buffer.capacity(), capacity);
+        let (rw, rx) = (buffer.as_ptr(), buffer.executable().as_ptr());
+        buffer.write(capacity - 8, 0x0001020304050607, 8);
+        assert_eq!(buffer.len(), capacity);
+        assert_eq!((buffer.as_ptr(), buffer.executable().as_ptr()), (rw, rx));
+        assert!(matches!(buffer.try_resize(capacity + 1), Err(Error::OutOfMemory)));
+        assert!(matches!(buffer.try_write(capacity - 4, 0x08090A0B0C0D0E0F, 8), Err(Error::OutOfMemory)));
+        assert_eq!(buffer.read(capacity - 8, 8), 0x0001020304050607);
+    }
+}
@@ -1,6 +1,7 @@
 use std::ops::{Deref, DerefMut};
 use memmap::{MmapMut, Mmap as MmapExec};
-use super::{Buffer};
+use crate::error::{Error, expect};
+use super::{Buffer, Execute};
 
 /// Represents a block of memory claimed from the operating system using
 /// `mmap()`. Memory allocated in this way can be made executable.
@@ -11,48 +12,46 @@ pub enum Mmap {
 }
 
 impl Mmap {
-    /// Make this [`Mmap`] executable, pass it to `callback`, then make it
-    /// writeable again.
-    ///
-    /// If we can't change the buffer permissions, you get an [`Err`] and the [`Mmap`]
-    /// is gone. T can itself be a Result if necessary to represent errors
-    /// returned by `callback`
-    pub fn execute<T>(&mut self, callback: impl FnOnce(&[u8]) -> T) -> T {
-        let m: &mut MmapExec = self.as_mut();
-        callback(&*m)
-    }
-}
-
-impl AsMut<MmapMut> for Mmap {
-    fn as_mut(&mut self) -> &mut MmapMut {
+    /// Make this [`Mmap`] writeable if necessary, and return it.
+    fn try_as_mut(&mut self) -> Result<&mut MmapMut, Error> {
         let mut new_self = Self::Poisoned;
         std::mem::swap(self, &mut new_self);
         *self = match new_self {
-            Self::Exec(m) => Self::Mut(m.make_mut().expect("mprotect failed")),
+            Self::Exec(m) => Self::Mut(m.make_mut()?),
             x => x,
         };
         match self {
-            Self::Mut(ref mut m) => m,
-            _ => panic!("Poisoned by an earlier error"),
+            Self::Mut(ref mut m) => Ok(m),
+            _ => Err(Error::Poisoned),
         }
     }
-}
 
-impl AsMut<MmapExec> for Mmap {
-    fn as_mut(&mut self) -> &mut MmapExec {
+    /// Make this [`Mmap`] executable if necessary, and return it.
+    fn try_as_exec(&mut self) -> Result<&mut MmapExec, Error> {
         let mut new_self = Self::Poisoned;
         std::mem::swap(self, &mut new_self);
         *self = match new_self {
-            Self::Mut(m) => Self::Exec(m.make_exec().expect("mprotect failed")),
+            Self::Mut(m) => Self::Exec(m.make_exec()?),
             x => x,
         };
         match self {
-            Self::Exec(ref mut m) => m,
-            _ => panic!("Poisoned by an earlier error"),
+            Self::Exec(ref mut m) => Ok(m),
+            _ => Err(Error::Poisoned),
         }
     }
 }
 
+impl AsMut<MmapMut> for Mmap {
+    fn as_mut(&mut self) -> &mut MmapMut {
+        expect(self.try_as_mut())
+    }
+}
+
+impl AsMut<MmapExec> for Mmap {
+    fn as_mut(&mut self) -> &mut MmapExec {
+        expect(self.try_as_exec())
+    }
+}
 impl Deref for Mmap {
     type Target = [u8];
 
@@ -73,18 +72,66 @@ impl DerefMut for Mmap {
 }
 
 impl Buffer for Mmap {
-    fn new() -> Self {
-        let memory = MmapMut::map_anon(0x1000).expect("Out of memory");
-        Self::Mut(memory)
+    fn try_new() -> Result<Self, Error> {
+        let memory = MmapMut::map_anon(0x1000)?;
+        Ok(Self::Mut(memory))
     }
 
-    fn resize(&mut self, min_length: usize) {
-        let memory: &mut MmapMut = self.as_mut();
+    fn try_resize(&mut self, min_length: usize) -> Result<(), Error> {
+        let memory: &mut MmapMut = self.try_as_mut()?;
         if min_length > memory.len() {
-            let mut new_memory = MmapMut::map_anon(min_length).expect("Out of memory");
+            let mut new_memory = MmapMut::map_anon(min_length)?;
             new_memory[..memory.len()].copy_from_slice(memory);
             *memory = new_memory;
         }
+        Ok(())
+    }
+
+    /// Uses `madvise(MADV_REMOVE)`, because the memory is a shared mapping.
+    /// Note that [`try_resize()`] copies the released pages, which commits
+    /// them again.
+    ///
+    /// [`try_resize()`]: Self::try_resize
+    #[cfg(target_os = "linux")]
+    fn release(&mut self, start: usize, end: usize) {
+        if let Self::Poisoned = self { return; }
+        let pages = super::whole_pages(start, std::cmp::min(end, self.len()));
+        if !pages.is_empty() {
+            // This is only a hint, so ignore errors.
+            unsafe {
+                let address = self.as_ptr().add(pages.start) as *mut libc::c_void;
+                libc::madvise(address, pages.len(), libc::MADV_REMOVE);
+            }
+        }
+    }
+
+    /// The same mapping is writeable or executable at different times.
+    fn executable_address(&self) -> Option<usize> {
+        if let Self::Poisoned = self { return None; }
+        Some(self.as_ptr() as usize)
+    }
+
+    fn sync(&mut self, start: usize, end: usize) {
+        if let Self::Poisoned = self { return; }
+        let end = std::cmp::min(end, self.len());
+        if start < end {
+            unsafe {
+                let address = self.as_ptr().add(start);
+                super::sync_icache(address, address, end - start);
+            }
+        }
+    }
+}
+
+impl Execute for Mmap {
+    /// Make this [`Mmap`] executable, pass it to `callback`, then make it
+    /// writeable again. This costs two `mprotect()` system calls.
+    ///
+    /// If we can't change the buffer permissions, you get an [`Err`] and the
+    /// [`Mmap`] is gone.
+    fn try_execute<T>(&mut self, callback: impl FnOnce(&[u8]) -> T) -> Result<T, Error> {
+        let m: &mut MmapExec = self.try_as_exec()?;
+        Ok(callback(&*m))
     }
 }
 
@@ -100,10 +147,23 @@ pub mod tests {
         super::super::tests::api(buffer)
     }
 
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn release() {
+        super::super::tests::release(Mmap::new(), true)
+    }
+
     #[test]
     fn execute() {
         let mut buffer = Mmap::new();
         let result = buffer.execute(|_bytes| 42);
         assert_eq!(result, 42);
     }
+
+    #[test]
+    fn poisoned() {
+        let mut buffer = Mmap::Poisoned;
+        assert!(matches!(buffer.try_execute(|_bytes| ()), Err(Error::Poisoned)));
+        assert!(matches!(buffer.try_resize(0x2000), Err(Error::Poisoned)));
+    }
 }
@@ -1,42 +1,98 @@
 use std::ops::{DerefMut};
 
+use crate::error::{Error, expect};
+
 mod mmap;
 pub use mmap::{Mmap};
 
+#[cfg(target_os = "linux")]
+mod memfd;
+#[cfg(target_os = "linux")]
+pub use memfd::{Memfd, DEFAULT_CAPACITY};
+
 /// An auto-growing array of bytes. In addition to the usual slice API, methods
 /// are provided for reading or writing up to 8 bytes at a time using a `u64`.
 ///
 /// [`Vec<u8>`] implements this trait, which is useful for testing.
 ///
 /// [`Mmap`] implements this trait and allows the bytes to be executed as code.
+/// So does [`Memfd`], on Linux.
 pub trait Buffer: Sized + DerefMut<Target=[u8]> {
     /// Allocates a fresh `Buffer` with a default (small) length.
-    fn new() -> Self;
+    fn try_new() -> Result<Self, Error>;
+
+    /// Like [`try_new()`], but panics if the allocation fails.
+    ///
+    /// [`try_new()`]: Self::try_new
+    fn new() -> Self { expect(Self::try_new()) }
 
     /// Reallocate this `Buffer` if necessary to ensure that it holds at
-    /// least `min_length` bytes. Panics if the reallocation fails.
-    fn resize(&mut self, min_length: usize);
+    /// least `min_length` bytes. On failure, the contents are unchanged.
+    fn try_resize(&mut self, min_length: usize) -> Result<(), Error>;
+
+    /// Like [`try_resize()`], but panics if the reallocation fails.
+    ///
+    /// [`try_resize()`]: Self::try_resize
+    fn resize(&mut self, min_length: usize) { expect(self.try_resize(min_length)) }
 
     /// Writes a single byte at `pos`.
     /// Writes beyond [`len()`] resize the buffer to a power-of-two length.
-    fn write_byte(&mut self, pos: usize, byte: u8) {
+    /// If that fails, nothing is written.
+    fn try_write_byte(&mut self, pos: usize, byte: u8) -> Result<(), Error> {
         if pos >= self.len() {
-            self.resize(std::cmp::max(pos + 1, 0x1000).checked_next_power_of_two().unwrap());
+            let min_length = std::cmp::max(pos + 1, 0x1000).checked_next_power_of_two();
+            self.try_resize(min_length.ok_or(Error::OutOfMemory)?)?;
         }
         self[pos] = byte;
+        Ok(())
     }
 
-    /// Writes up to 8 bytes at `pos`, as if using [`write_byte()`] repeatedly,
-    /// incrementing `pos` after each call.
-    fn write(&mut self, pos: usize, mut bytes: u64, len: usize) {
+    /// Like [`try_write_byte()`], but panics if the buffer cannot grow.
+    ///
+    /// [`try_write_byte()`]: Self::try_write_byte
+    fn write_byte(&mut self, pos: usize, byte: u8) { expect(self.try_write_byte(pos, byte)) }
+
+    /// Writes up to 8 bytes at `pos`, as if using [`try_write_byte()`]
+    /// repeatedly, incrementing `pos` after each call. If the buffer cannot
+    /// grow, nothing is written.
+    ///
+    /// [`try_write_byte()`]: Self::try_write_byte
+    fn try_write(&mut self, pos: usize, mut bytes: u64, len: usize) -> Result<(), Error> {
         assert!(len <= 8);
+        if len > 0 {
+            // Grow the buffer first, so that failure writes nothing.
+            self.try_write_byte(pos + len - 1, (bytes >> (8 * (len - 1))) as u8)?;
+        }
         for i in 0..len {
-            self.write_byte(pos + i, bytes as u8);
+            self[pos + i] = bytes as u8;
             bytes >>= 8;
         }
         assert_eq!(bytes, 0);
+        Ok(())
     }
 
+    /// Like [`try_write()`], but panics if the buffer cannot grow.
+    ///
+    /// [`try_write()`]: Self::try_write
+    fn write(&mut self, pos: usize, bytes: u64, len: usize) {
+        expect(self.try_write(pos, bytes, len))
+    }
+
+    /// Like `write(pos, value, 4)`, except that if `pos` is a multiple of 4
+    /// the bytes are written atomically: code running concurrently on another
+    /// thread sees either the old or the new bytes, and nothing in between.
+    /// Call [`sync()`] afterwards.
+    ///
+    /// [`sync()`]: Self::sync
+    fn write_atomic(&mut self, pos: usize, value: u32) {
+        self.write(pos, u64::from(value), 4);
+    }
+
+    /// Makes bytes `start..end`, which have been written, visible to
+    /// instruction fetch on all threads. By default, this does nothing, which
+    /// is correct for buffers that cannot be executed.
+    fn sync(&mut self, _start: usize, _end: usize) {}
+
     /// Reads a single byte. Reading beyond [`len()`] gives `0`.
     fn read_byte(&self, pos: usize) -> u8 {
         if pos < self.len() {
@@ -46,6 +102,16 @@ pub trait Buffer: Sized + DerefMut<Target=[u8]> {
         }
     }
 
+    /// Returns the address at which the bytes can be executed, which might
+    /// differ from the address of the slice, or `None` if they cannot be
+    /// executed. By default, returns `None`.
+    fn executable_address(&self) -> Option<usize> { None }
+
+    /// Tells the `Buffer` that bytes `start..end` will never be used again.
+    /// It may return any whole pages in that range to the operating system,
+    /// after which they read as zero. By default, this does nothing.
+    fn release(&mut self, _start: usize, _end: usize) {}
+
     /// Reads up to 8 bytes, as if using [`read_byte()`] repeatedly.
     fn read(&self, pos: usize, len: usize) -> u64 {
         assert!(len <= 8);
@@ -59,18 +125,136 @@ pub trait Buffer: Sized + DerefMut<Target=[u8]> {
 }
 
 impl Buffer for Vec<u8> {
-    fn new() -> Self { Vec::new() }
+    fn try_new() -> Result<Self, Error> { Ok(Vec::new()) }
+
+    fn try_resize(&mut self, min_length: usize) -> Result<(), Error> {
+        if min_length > self.len() {
+            self.try_reserve(min_length - self.len()).map_err(|_| Error::OutOfMemory)?;
+            self.resize(min_length, 0);
+        }
+        Ok(())
+    }
+}
+
+/// Returns the granularity of `mmap()`.
+#[cfg(target_os = "linux")]
+fn page_size() -> usize {
+    (unsafe { libc::sysconf(libc::_SC_PAGESIZE) }) as usize
+}
+
+/// Makes `len` bytes written at `data` visible to instruction fetch at
+/// `code`, which maps the same memory, possibly at the same address.
+#[allow(unused_variables)]
+unsafe fn sync_icache(data: *const u8, code: *const u8, len: usize) {
+    #[cfg(target_arch = "aarch64")]
+    {
+        // Clean the data cache and invalidate the instruction cache.
+        // These instructions broadcast to all cores.
+        use std::arch::{asm};
+        let ctr: u64;
+        asm!("mrs {}, ctr_el0", out(reg) ctr, options(nomem, nostack, preserves_flags));
+        let d_line = 4usize << ((ctr >> 16) & 0xF);
+        let i_line = 4usize << (ctr & 0xF);
+        let mut p = data as usize & !(d_line - 1);
+        while p < data as usize + len {
+            asm!("dc cvau, {}", in(reg) p, options(nostack, preserves_flags));
+            p += d_line;
+        }
+        asm!("dsb ish", options(nostack, preserves_flags));
+        let mut p = code as usize & !(i_line - 1);
+        while p < code as usize + len {
+            asm!("ic ivau, {}", in(reg) p, options(nostack, preserves_flags));
+            p += i_line;
+        }
+        asm!("dsb ish", "isb", options(nostack, preserves_flags));
+    }
+    #[cfg(all(target_arch = "riscv64", target_os = "linux"))]
+    {
+        // `fence.i` only affects the current hart, so ask Linux to do all of
+        // them. `libc` does not define `__NR_riscv_flush_icache`.
+        const SYS_RISCV_FLUSH_ICACHE: std::os::raw::c_long = 259;
+        libc::syscall(SYS_RISCV_FLUSH_ICACHE, code, code.add(len), 0usize);
+    }
+    // x86_64 keeps instruction fetch coherent with data writes.
+}
+
+/// Returns the whole pages within `start..end`, which may be empty.
+#[cfg(target_os = "linux")]
+fn whole_pages(start: usize, end: usize) -> std::ops::Range<usize> {
+    let mask = page_size() - 1;
+    let start = start.saturating_add(mask) & !mask;
+    let end = std::cmp::max(start, end & !mask);
+    start..end
+}
+
+//-----------------------------------------------------------------------------
+
+/// A [`Buffer`] whose bytes can be executed as code.
+pub trait Execute: Buffer {
+    /// Pass an executable view of the bytes to `callback`. Depending on the
+    /// implementation, this might make the memory temporarily unwriteable.
+    ///
+    /// If we can't make the memory executable, you get an [`Err`] and the
+    /// `Buffer` might be poisoned. `T` can itself be a `Result` if necessary to
+    /// represent errors returned by `callback`.
+    fn try_execute<T>(&mut self, callback: impl FnOnce(&[u8]) -> T) -> Result<T, Error>;
 
-    fn resize(&mut self, min_length: usize) {
-        self.resize(min_length, 0);
+    /// Like [`try_execute()`], but panics on error.
+    ///
+    /// [`try_execute()`]: Self::try_execute
+    fn execute<T>(&mut self, callback: impl FnOnce(&[u8]) -> T) -> T {
+        expect(self.try_execute(callback))
     }
 }
 
+/// An [`Execute`] whose executable bytes never move, and are always
+/// executable. Pointers to the code remain valid, even while the `Buffer` is
+/// modified or grows, until the `Buffer` is dropped.
+pub trait Stable: Execute {
+    /// Returns the executable view of the bytes.
+    fn executable(&self) -> &[u8];
+}
+
 //-----------------------------------------------------------------------------
 
 #[cfg(test)]
 pub mod tests {
     use super::*;
+    use std::ops::{Deref};
+
+    /// A [`Buffer`] that cannot grow beyond [`Limited::LIMIT`] bytes, for
+    /// testing out-of-memory errors. Its bytes cannot actually be executed.
+    #[derive(Debug)]
+    pub struct Limited(Vec<u8>);
+
+    impl Limited {
+        pub const LIMIT: usize = 0x1000;
+    }
+
+    impl Deref for Limited {
+        type Target = [u8];
+
+        fn deref(&self) -> &Self::Target { &self.0 }
+    }
+
+    impl DerefMut for Limited {
+        fn deref_mut(&mut self) -> &mut Self::Target { &mut self.0 }
+    }
+
+    impl Buffer for Limited {
+        fn try_new() -> Result<Self, Error> { Ok(Limited(Vec::new())) }
+
+        fn try_resize(&mut self, min_length: usize) -> Result<(), Error> {
+            if min_length > Self::LIMIT { return Err(Error::OutOfMemory); }
+            Buffer::try_resize(&mut self.0, min_length)
+        }
+    }
+
+    impl Execute for Limited {
+        fn try_execute<T>(&mut self, callback: impl FnOnce(&[u8]) -> T) -> Result<T, Error> {
+            Ok(callback(&self.0))
+        }
+    }
 
     /// Any tests of the [`Buffer`] API, for use by submodule tests.
     pub fn api(mut buffer: impl Buffer) {
@@ -85,8 +269,31 @@ pub mod tests {
         assert_eq!(buffer.read(len+4, 8), 0x08090A0B0C0D0E0F);
     }
 
+    /// Tests [`Buffer::release()`], for use by submodule tests.
+    ///  - `releases` - `true` if `buffer` actually releases memory.
+    #[cfg(target_os = "linux")]
+    pub fn release(mut buffer: impl Buffer, releases: bool) {
+        let page = page_size();
+        for i in 0..3 * page { buffer.write_byte(i, 0xAA); }
+        buffer.release(page / 2, 5 * page / 2);
+        assert_eq!(buffer.read_byte(page - 1), 0xAA);
+        assert_eq!(buffer.read_byte(page), if releases { 0 } else { 0xAA });
+        assert_eq!(buffer.read_byte(2 * page - 1), if releases { 0 } else { 0xAA });
+        assert_eq!(buffer.read_byte(2 * page), 0xAA);
+    }
+
     #[test]
     fn vec() {
         api(Vec::<u8>::new());
+        #[cfg(target_os = "linux")]
+        release(Vec::<u8>::new(), false);
+    }
+
+    #[test]
+    fn limited() {
+        let mut buffer = Limited::new();
+        buffer.write(Limited::LIMIT - 8, 0x0001020304050607, 8);
+        assert!(matches!(buffer.try_write(Limited::LIMIT - 4, 0x08090A0B0C0D0E0F, 8), Err(Error::OutOfMemory)));
+        assert_eq!(buffer.read(Limited::LIMIT - 8, 8), 0x0001020304050607);
     }
 }
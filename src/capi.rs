@@ -0,0 +1,489 @@
+//! A C API, for embedding Mijit in an interpreter written in C. The
+//! declarations are in `include/mijit.h`.
+//!
+//! Objects are passed to C as pointers, and must be freed by calling the
+//! corresponding `_free()` function, unless they are passed to a function
+//! that consumes them. The caller must pass valid pointers and must not use
+//! an object after it has been consumed or freed. Invalid arguments that
+//! Mijit detects, such as an unknown [`EntryId`] or a [`Global`] that does
+//! not exist, abort the process; panics never unwind into C.
+//!
+//! Fallible functions return a [`Status`]. If it is not `MIJIT_OK`,
+//! `mijit_error_message()` describes the [`Error`].
+
+// The safety requirements of every function are described above.
+#![allow(clippy::missing_safety_doc)]
+
+use std::cell::{RefCell};
+use std::ffi::{CString};
+use std::os::raw::{c_char};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use super::{Error};
+use super::util::{AsUsize};
+use super::code::{UnaryOp, BinaryOp, Width, AliasMask, Register, REGISTERS, Global, Slot, Variable, Marshal, EBB};
+use super::code::builder::{Builder, Increment};
+use super::target::{Native, native, Pool, Word};
+use super::jit::{Jit, EntryId};
+use UnaryOp::*;
+use BinaryOp::*;
+use Width::*;
+use Increment::*;
+
+/// Runs `callback`, aborting the process if it panics.
+fn no_unwind<T>(callback: impl FnOnce() -> T) -> T {
+    catch_unwind(AssertUnwindSafe(callback)).unwrap_or_else(|_| std::process::abort())
+}
+
+//-----------------------------------------------------------------------------
+
+/// The outcome of a fallible function. Apart from `Ok`, each value
+/// corresponds to a variant of [`Error`].
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Status {
+    Ok = 0,
+    AlreadyDefined = 1,
+    NegativeExitValue = 2,
+    StackUnderflow = 3,
+    SlotsMismatch = 4,
+    GlobalsMismatch = 5,
+    Invalid = 6,
+    OutOfMemory = 7,
+    Memory = 8,
+    Poisoned = 9,
+}
+
+thread_local! {
+    /// Describes the last [`Error`] returned on this thread.
+    static ERROR_MESSAGE: RefCell<CString> = RefCell::new(CString::default());
+}
+
+/// Converts `result` to a [`Status`], remembering the error message, if any.
+fn status(result: Result<(), Error>) -> Status {
+    let e = match result {
+        Ok(()) => return Status::Ok,
+        Err(e) => e,
+    };
+    let message = CString::new(e.to_string()).unwrap_or_default();
+    ERROR_MESSAGE.with(|m| *m.borrow_mut() = message);
+    match e {
+        Error::AlreadyDefined(_) => Status::AlreadyDefined,
+        Error::NegativeExitValue(_) => Status::NegativeExitValue,
+        Error::StackUnderflow => Status::StackUnderflow,
+        Error::SlotsMismatch {..} => Status::SlotsMismatch,
+        Error::GlobalsMismatch {..} => Status::GlobalsMismatch,
+        Error::Invalid(_) => Status::Invalid,
+        Error::OutOfMemory => Status::OutOfMemory,
+        Error::Memory(_) => Status::Memory,
+        Error::Poisoned => Status::Poisoned,
+    }
+}
+
+/// Returns a description of the last error returned on this thread, or an
+/// empty string. It is valid until the next error on this thread.
+#[no_mangle]
+pub extern "C" fn mijit_error_message() -> *const c_char {
+    ERROR_MESSAGE.with(|m| m.borrow().as_ptr())
+}
+
+//-----------------------------------------------------------------------------
+
+/// A [`Variable`] as represented in C.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct CVariable {
+    /// `0` for a [`Register`], `1` for a [`Global`], or `2` for a [`Slot`].
+    pub kind: u32,
+    /// For a `Register`, an index into [`REGISTERS`].
+    pub index: u32,
+}
+
+impl From<CVariable> for Variable {
+    fn from(v: CVariable) -> Self {
+        let index = v.index as usize;
+        match v.kind {
+            0 => REGISTERS.get(index).copied().unwrap_or_else(|| panic!("Invalid register {}", index)).into(),
+            1 => Global(index).into(),
+            2 => Slot(index).into(),
+            kind => panic!("Invalid kind of variable {}", kind),
+        }
+    }
+}
+
+/// Converts `v`, which must represent a [`Register`].
+fn register(v: CVariable) -> Register {
+    Register::try_from(Variable::from(v)).unwrap_or_else(|v| panic!("{:?} is not a register", v))
+}
+
+/// Looks up `index` in `values`.
+fn decode<T: Copy>(values: &[T], index: u32, what: &str) -> T {
+    *values.get(index as usize).unwrap_or_else(|| panic!("Invalid {} {}", what, index))
+}
+
+fn unary_op(op: u32) -> UnaryOp { decode(&[Abs, Negate, Not], op, "UnaryOp") }
+
+fn binary_op(op: u32) -> BinaryOp {
+    decode(&[
+        Add, Sub, Mul, UDiv, SDiv, Lsl, Lsr, Asr,
+        And, Or, Xor, Lt, Ult, Eq, Max, Min,
+    ], op, "BinaryOp")
+}
+
+fn width(width: u32) -> Width { decode(&[One, Two, Four, Eight], width, "Width") }
+
+fn increment(increment: u32) -> Increment {
+    match increment {
+        0 => IA,
+        1 => IB,
+        2 => DA,
+        3 => DB,
+        _ => panic!("Invalid Increment {}", increment),
+    }
+}
+
+/// Converts `entry` to an [`EntryId`].
+fn entry_id(entry: usize) -> EntryId {
+    EntryId::new(entry).expect("Invalid entry")
+}
+
+//-----------------------------------------------------------------------------
+
+/// Constructs a [`Jit`] for the [`Native`] target.
+#[no_mangle]
+pub extern "C" fn mijit_jit_new(num_globals: usize) -> *mut Jit<Native> {
+    no_unwind(|| Box::into_raw(Box::new(Jit::new(native(), num_globals))))
+}
+
+/// Frees `jit`, which may be null.
+#[no_mangle]
+pub unsafe extern "C" fn mijit_jit_free(jit: *mut Jit<Native>) {
+    no_unwind(|| if !jit.is_null() { drop(Box::from_raw(jit)); })
+}
+
+/// Returns the number of [`Global`]s in each [`Pool`] used with `jit`.
+#[no_mangle]
+pub unsafe extern "C" fn mijit_jit_num_globals(jit: *const Jit<Native>) -> usize {
+    no_unwind(|| (*jit).num_globals())
+}
+
+/// Constructs a new entry, which initially exits returning `exit_value`, and
+/// stores it in `*entry`. Consumes `prologue` and `epilogue`, which may be
+/// null, and which must not contain guards. See [`Jit::try_new_entry()`].
+#[no_mangle]
+pub unsafe extern "C" fn mijit_jit_new_entry(
+    jit: *mut Jit<Native>,
+    prologue: *mut Builder<EntryId>,
+    epilogue: *mut Builder<EntryId>,
+    exit_value: i64,
+    entry: *mut usize,
+) -> Status {
+    no_unwind(|| {
+        let block = |b: *mut Builder<EntryId>| {
+            if b.is_null() { Box::new([]) } else { Box::from_raw(b).block() }
+        };
+        let marshal = Marshal {prologue: block(prologue), epilogue: block(epilogue)};
+        status((*jit).try_new_entry(&marshal, exit_value).map(|id| { *entry = id.as_usize(); }))
+    })
+}
+
+/// Replaces the code at `entry` with `ebb`, which is consumed. See
+/// [`Jit::try_define()`].
+#[no_mangle]
+pub unsafe extern "C" fn mijit_jit_define(
+    jit: *mut Jit<Native>,
+    entry: usize,
+    ebb: *mut EBB<EntryId>,
+) -> Status {
+    no_unwind(|| {
+        let ebb = Box::from_raw(ebb);
+        status((*jit).try_define(entry_id(entry), &ebb))
+    })
+}
+
+/// Runs the code at `entry` using `pool`, and stores the exit value in
+/// `*exit_value`. See [`Jit::try_run()`].
+#[no_mangle]
+pub unsafe extern "C" fn mijit_jit_run(
+    jit: *mut Jit<Native>,
+    pool: *mut Pool,
+    entry: usize,
+    exit_value: *mut i64,
+) -> Status {
+    no_unwind(|| {
+        status((*jit).try_run(&mut *pool, entry_id(entry)).map(|w| { *exit_value = w.s; }))
+    })
+}
+
+//-----------------------------------------------------------------------------
+
+/// Constructs a [`Pool`] suitable for `jit`, with all [`Global`]s zero.
+#[no_mangle]
+pub unsafe extern "C" fn mijit_pool_new(jit: *const Jit<Native>) -> *mut Pool {
+    no_unwind(|| Box::into_raw(Box::new((*jit).new_pool())))
+}
+
+/// Frees `pool`, which may be null.
+#[no_mangle]
+pub unsafe extern "C" fn mijit_pool_free(pool: *mut Pool) {
+    no_unwind(|| if !pool.is_null() { drop(Box::from_raw(pool)); })
+}
+
+/// Returns the value of `Global(global)` in `pool`.
+#[no_mangle]
+pub unsafe extern "C" fn mijit_pool_get(pool: *const Pool, global: usize) -> u64 {
+    no_unwind(|| (&*pool)[Global(global)].u)
+}
+
+/// Sets the value of `Global(global)` in `pool`.
+#[no_mangle]
+pub unsafe extern "C" fn mijit_pool_set(pool: *mut Pool, global: usize, value: u64) {
+    no_unwind(|| { (&mut *pool)[Global(global)] = Word {u: value}; })
+}
+
+//-----------------------------------------------------------------------------
+
+/// Constructs an empty [`Builder`].
+#[no_mangle]
+pub extern "C" fn mijit_builder_new() -> *mut Builder<EntryId> {
+    no_unwind(|| Box::into_raw(Box::new(Builder::new())))
+}
+
+/// Frees `b`, which may be null.
+#[no_mangle]
+pub unsafe extern "C" fn mijit_builder_free(b: *mut Builder<EntryId>) {
+    no_unwind(|| if !b.is_null() { drop(Box::from_raw(b)); })
+}
+
+/// See [`Builder::move_()`].
+#[no_mangle]
+pub unsafe extern "C" fn mijit_builder_move(b: *mut Builder<EntryId>, dest: CVariable, src: CVariable) {
+    no_unwind(|| (*b).move_(Variable::from(dest), Variable::from(src)))
+}
+
+/// See [`Builder::const_()`].
+#[no_mangle]
+pub unsafe extern "C" fn mijit_builder_const(b: *mut Builder<EntryId>, dest: CVariable, value: i64) {
+    no_unwind(|| (*b).const_(register(dest), value))
+}
+
+/// See [`Builder::unary64()`].
+#[no_mangle]
+pub unsafe extern "C" fn mijit_builder_unary64(b: *mut Builder<EntryId>, op: u32, dest: CVariable, src: CVariable) {
+    no_unwind(|| (*b).unary64(unary_op(op), register(dest), Variable::from(src)))
+}
+
+/// See [`Builder::unary32()`].
+#[no_mangle]
+pub unsafe extern "C" fn mijit_builder_unary32(b: *mut Builder<EntryId>, op: u32, dest: CVariable, src: CVariable) {
+    no_unwind(|| (*b).unary32(unary_op(op), register(dest), Variable::from(src)))
+}
+
+/// See [`Builder::binary64()`].
+#[no_mangle]
+pub unsafe extern "C" fn mijit_builder_binary64(
+    b: *mut Builder<EntryId>,
+    op: u32,
+    dest: CVariable,
+    src1: CVariable,
+    src2: CVariable,
+) {
+    no_unwind(|| (*b).binary64(binary_op(op), register(dest), Variable::from(src1), Variable::from(src2)))
+}
+
+/// See [`Builder::binary32()`].
+#[no_mangle]
+pub unsafe extern "C" fn mijit_builder_binary32(
+    b: *mut Builder<EntryId>,
+    op: u32,
+    dest: CVariable,
+    src1: CVariable,
+    src2: CVariable,
+) {
+    no_unwind(|| (*b).binary32(binary_op(op), register(dest), Variable::from(src1), Variable::from(src2)))
+}
+
+/// See [`Builder::const_binary64()`].
+#[no_mangle]
+pub unsafe extern "C" fn mijit_builder_const_binary64(
+    b: *mut Builder<EntryId>,
+    op: u32,
+    dest: CVariable,
+    src: CVariable,
+    value: i64,
+) {
+    no_unwind(|| (*b).const_binary64(binary_op(op), register(dest), Variable::from(src), value))
+}
+
+/// See [`Builder::const_binary32()`].
+#[no_mangle]
+pub unsafe extern "C" fn mijit_builder_const_binary32(
+    b: *mut Builder<EntryId>,
+    op: u32,
+    dest: CVariable,
+    src: CVariable,
+    value: i32,
+) {
+    no_unwind(|| (*b).const_binary32(binary_op(op), register(dest), Variable::from(src), value))
+}
+
+/// See [`Builder::load()`].
+#[no_mangle]
+pub unsafe extern "C" fn mijit_builder_load(
+    b: *mut Builder<EntryId>,
+    dest: CVariable,
+    base: CVariable,
+    offset: i64,
+    width_: u32,
+    alias_mask: u32,
+) {
+    no_unwind(|| (*b).load(register(dest), (Variable::from(base), offset), width(width_), AliasMask(alias_mask)))
+}
+
+/// See [`Builder::store()`].
+#[no_mangle]
+pub unsafe extern "C" fn mijit_builder_store(
+    b: *mut Builder<EntryId>,
+    src: CVariable,
+    base: CVariable,
+    offset: i64,
+    width_: u32,
+    alias_mask: u32,
+) {
+    no_unwind(|| (*b).store(Variable::from(src), (Variable::from(base), offset), width(width_), AliasMask(alias_mask)))
+}
+
+/// See [`Builder::array_load()`].
+#[no_mangle]
+pub unsafe extern "C" fn mijit_builder_array_load(
+    b: *mut Builder<EntryId>,
+    dest: CVariable,
+    base: CVariable,
+    index: CVariable,
+    width_: u32,
+    alias_mask: u32,
+) {
+    no_unwind(|| (*b).array_load(
+        register(dest),
+        (Variable::from(base), Variable::from(index)),
+        width(width_),
+        AliasMask(alias_mask),
+    ))
+}
+
+/// See [`Builder::array_store()`].
+#[no_mangle]
+pub unsafe extern "C" fn mijit_builder_array_store(
+    b: *mut Builder<EntryId>,
+    src: CVariable,
+    base: CVariable,
+    index: CVariable,
+    width_: u32,
+    alias_mask: u32,
+) {
+    no_unwind(|| (*b).array_store(
+        Variable::from(src),
+        (Variable::from(base), Variable::from(index)),
+        width(width_),
+        AliasMask(alias_mask),
+    ))
+}
+
+/// See [`Builder::increment_load()`].
+#[no_mangle]
+pub unsafe extern "C" fn mijit_builder_increment_load(
+    b: *mut Builder<EntryId>,
+    increment_: u32,
+    dest: CVariable,
+    addr: CVariable,
+    width_: u32,
+    alias_mask: u32,
+) {
+    no_unwind(|| (*b).increment_load(
+        increment(increment_),
+        register(dest),
+        register(addr),
+        width(width_),
+        AliasMask(alias_mask),
+    ))
+}
+
+/// See [`Builder::increment_store()`].
+#[no_mangle]
+pub unsafe extern "C" fn mijit_builder_increment_store(
+    b: *mut Builder<EntryId>,
+    increment_: u32,
+    src: CVariable,
+    addr: CVariable,
+    width_: u32,
+    alias_mask: u32,
+) {
+    no_unwind(|| (*b).increment_store(
+        increment(increment_),
+        Variable::from(src),
+        register(addr),
+        width(width_),
+        AliasMask(alias_mask),
+    ))
+}
+
+/// See [`Builder::debug()`].
+#[no_mangle]
+pub unsafe extern "C" fn mijit_builder_debug(b: *mut Builder<EntryId>, src: CVariable) {
+    no_unwind(|| (*b).debug(Variable::from(src)))
+}
+
+/// Consumes `if_fail`. See [`Builder::guard()`].
+#[no_mangle]
+pub unsafe extern "C" fn mijit_builder_guard(
+    b: *mut Builder<EntryId>,
+    condition: CVariable,
+    expected: bool,
+    if_fail: *mut EBB<EntryId>,
+) {
+    no_unwind(|| (*b).guard(Variable::from(condition), expected, *Box::from_raw(if_fail)))
+}
+
+/// Consumes `b`. See [`Builder::jump()`].
+#[no_mangle]
+pub unsafe extern "C" fn mijit_builder_jump(b: *mut Builder<EntryId>, target: usize) -> *mut EBB<EntryId> {
+    no_unwind(|| Box::into_raw(Box::new(Box::from_raw(b).jump(entry_id(target)))))
+}
+
+/// Consumes `b`, `default_`, and the `num_cases` elements of `cases`. See
+/// [`Builder::index()`].
+#[no_mangle]
+pub unsafe extern "C" fn mijit_builder_index(
+    b: *mut Builder<EntryId>,
+    discriminant: CVariable,
+    cases: *const *mut EBB<EntryId>,
+    num_cases: usize,
+    default_: *mut EBB<EntryId>,
+) -> *mut EBB<EntryId> {
+    no_unwind(|| {
+        let cases = if num_cases == 0 { &[] } else { std::slice::from_raw_parts(cases, num_cases) };
+        let cases: Box<[EBB<EntryId>]> = cases.iter().map(|&ebb| *Box::from_raw(ebb)).collect();
+        let ebb = Box::from_raw(b).index(Variable::from(discriminant), cases, *Box::from_raw(default_));
+        Box::into_raw(Box::new(ebb))
+    })
+}
+
+/// Consumes `b`, `if_true` and `if_false`. See [`Builder::if_()`].
+#[no_mangle]
+pub unsafe extern "C" fn mijit_builder_if(
+    b: *mut Builder<EntryId>,
+    condition: CVariable,
+    if_true: *mut EBB<EntryId>,
+    if_false: *mut EBB<EntryId>,
+) -> *mut EBB<EntryId> {
+    no_unwind(|| {
+        let ebb = Box::from_raw(b).if_(Variable::from(condition), *Box::from_raw(if_true), *Box::from_raw(if_false));
+        Box::into_raw(Box::new(ebb))
+    })
+}
+
+/// Frees `ebb`, which may be null.
+#[no_mangle]
+pub unsafe extern "C" fn mijit_ebb_free(ebb: *mut EBB<EntryId>) {
+    no_unwind(|| if !ebb.is_null() { drop(Box::from_raw(ebb)); })
+}
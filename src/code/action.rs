@@ -40,10 +40,16 @@ pub enum Action {
 #[cfg(test)]
 pub mod tests {
     use super::*;
-    use std::collections::{HashMap};
+    use std::collections::{HashMap, HashSet};
+    use super::super::{Global, Slot};
+    use super::super::interpreter::{State};
 
-    /// An emulator for a subset of Mijit code, useful for testing
-    /// automatically-generated code.
+    /// An emulator for Mijit code without memory accesses, useful for
+    /// testing automatically-generated code. A thin wrapper around
+    /// [`interpreter::State`] which additionally panics if the code reads a
+    /// `Variable` that has not been written.
+    ///
+    /// [`interpreter::State`]: super::super::interpreter::State
     pub struct Emulator {
         variables: Vec<Variable>,
     }
@@ -53,35 +59,83 @@ pub mod tests {
             Emulator {variables}
         }
 
+        /// Runs `actions` in a state where `variables[i]` is `1000 + i`.
+        /// Returns the final values of the `variables` and of all
+        /// `Variable`s written by `actions`.
         pub fn execute(&self, actions: &[Action]) -> HashMap<Variable, i64> {
-            let mut state: HashMap<Variable, i64> = self.variables.iter().enumerate().map(|(i, v)| {
-                (*v, 1000 + i as i64)
-            }).collect();
-            for action in actions {
+            let mut state = State::default();
+            for &v in &self.variables {
+                match v {
+                    Variable::Global(Global(g)) if g >= state.globals.len() => {
+                        state.globals.resize(g + 1, 0);
+                    },
+                    Variable::Slot(Slot(s)) if s >= state.slots.len() => {
+                        state.slots.resize(s + 1, 0);
+                    },
+                    _ => {},
+                }
+            }
+            for (i, &v) in self.variables.iter().enumerate() {
+                state.set(v, 1000 + i as u64);
+            }
+            let mut written: HashSet<Variable> = self.variables.iter().copied().collect();
+            let read = |written: &HashSet<Variable>, v: Variable| {
+                assert!(written.contains(&v), "Missing from state: {:?}", v);
+            };
+            for &action in actions {
+                let n = state.slots.len();
                 match action {
-                    &Action::Move(dest, src) => {
-                        let x = *state.get(&src).expect("Missing from state");
-                        state.insert(dest, x);
+                    Action::Move(_, src) |
+                    Action::Unary(_, _, _, src) |
+                    Action::Load(_, (src, _), _) |
+                    Action::Debug(src) => read(&written, src),
+                    Action::Binary(_, _, _, src1, src2) |
+                    Action::Store(_, src1, (src2, _), _) => {
+                        read(&written, src1);
+                        read(&written, src2);
                     },
-                    &Action::Constant(Precision::P64, dest, imm) => {
-                        state.insert(dest.into(), imm);
+                    Action::Push(src1, src2) => {
+                        for src in src1.into_iter().chain(src2) { read(&written, src); }
                     },
-                    &Action::Unary(UnaryOp::Not, Precision::P64, dest, src) => {
-                        let x = *state.get(&src).expect("Missing from state");
-                        state.insert(dest.into(), !x);
+                    Action::Pop(dest1, dest2) => {
+                        if dest1.is_some() { read(&written, Slot(n - 1).into()); }
+                        if dest2.is_some() { read(&written, Slot(n - 2).into()); }
                     },
-                    &Action::Binary(BinaryOp::Add, Precision::P64, dest, src1, src2) => {
-                        let x = *state.get(&src1).expect("Missing from state");
-                        let y = *state.get(&src2).expect("Missing from state");
-                        state.insert(dest.into(), x + y);
+                    Action::Constant(..) | Action::DropMany(_) => {},
+                }
+                state.action(action, &mut [][..]);
+                match action {
+                    Action::Move(dest, _) => { written.insert(dest); },
+                    Action::Constant(_, dest, _) |
+                    Action::Unary(_, _, dest, _) |
+                    Action::Binary(_, _, dest, _, _) |
+                    Action::Load(dest, _, _) |
+                    Action::Store(dest, _, _, _) => { written.insert(dest.into()); },
+                    Action::Push(src1, src2) => {
+                        if src2.is_some() { written.insert(Slot(n).into()); }
+                        if src1.is_some() { written.insert(Slot(n + 1).into()); }
+                    },
+                    Action::Pop(dest1, dest2) => {
+                        written.extend(dest1.into_iter().chain(dest2).map(Variable::from));
                     },
-                    _ => panic!("Don't know how to execute {:#?}", action),
+                    Action::DropMany(_) | Action::Debug(_) => {},
                 }
+                written.retain(|v| match *v {
+                    Variable::Slot(Slot(s)) => s < state.slots.len(),
+                    _ => true,
+                });
             }
-            state
+            written.into_iter().map(|v| (v, state.get(v) as i64)).collect()
         }
     }
 
+    #[test]
+    #[should_panic(expected = "Missing from state")]
+    fn emulator_missing() {
+        let emulator = Emulator::new(vec![Global(0).into()]);
+        emulator.execute(&[Action::Move(Global(0).into(), Global(1).into())]);
+    }
+
     /// Ensure the linker symbol `debug_word` is included in the binary.
     #[test]
     fn not_really_a_test() {
@@ -297,6 +297,13 @@ impl<T> Builder<T> {
         self.guards.push(Guard {actions, condition: condition.into(), expected, if_fail});
     }
 
+    /// Consume this `Builder` and return the [`Action`]s of a basic block.
+    /// Panics if [`guard()`] has been called.
+    pub fn block(self) -> Box<[Action]> {
+        assert!(self.guards.is_empty(), "A basic block cannot contain a guard");
+        self.actions.into()
+    }
+
     /// Consume this `Builder` and return the finished `EBB`.
     /// Usually, you will prefer to call one of [`jump()`], [`index()`] or
     /// [`if_()`] which call this.
@@ -1,6 +1,6 @@
 use std::collections::{HashSet};
 
-use super::{Global, Variable, IntoVariable, Action, Switch};
+use super::{Global, Slot, Variable, IntoVariable, Action, Switch};
 
 /// Represents the convention by which code passes values to a label. The
 /// concept is similar to a calling convention, but it's for a jump, not a call.
@@ -138,6 +138,8 @@ impl Propagator {
                     self.insert(src);
                 }
                 self.slots_used -= 2;
+                self.remove(Slot(self.slots_used));
+                self.remove(Slot(self.slots_used + 1));
             },
             Pop(dest1, dest2) => {
                 if let Some(dest) = dest1 {
@@ -147,6 +149,12 @@ impl Propagator {
                     self.remove(dest);
                 }
                 self.slots_used += 2;
+                if dest1.is_some() {
+                    self.insert(Slot(self.slots_used - 1));
+                }
+                if dest2.is_some() {
+                    self.insert(Slot(self.slots_used - 2));
+                }
             },
             DropMany(n) => {
                 self.slots_used += 2 * n;
@@ -165,3 +173,38 @@ impl Propagator {
         }
     }
 }
+
+//-----------------------------------------------------------------------------
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use super::super::{REGISTERS};
+    use Action::*;
+
+    /// Returns the live values of `convention`, in a canonical order.
+    fn live_set(convention: &Convention) -> HashSet<Variable> {
+        convention.live_values.iter().copied().collect()
+    }
+
+    /// Regression test from Beetle. Propagating backwards through a `Pop`
+    /// must make the popped `Slot`s live, and propagating backwards through
+    /// a `Push` must kill the pushed `Slot`s, which no longer exist.
+    #[test]
+    fn push_pop() {
+        let (r0, r1) = (REGISTERS[0], REGISTERS[1]);
+        let after = Convention {
+            live_values: Box::new([r0.into(), Slot(0).into()]),
+            slots_used: 2,
+        };
+        let mut propagator = Propagator::new(&after);
+        propagator.action(Pop(Some(r0), None));
+        let before_pop = propagator.before();
+        assert_eq!(before_pop.slots_used, 4);
+        assert_eq!(live_set(&before_pop), [Slot(0).into(), Slot(3).into()].into_iter().collect());
+        propagator.action(Push(Some(r1.into()), None));
+        let before_push = propagator.before();
+        assert_eq!(before_push.slots_used, 2);
+        assert_eq!(live_set(&before_push), [Slot(0).into(), r1.into()].into_iter().collect());
+    }
+}
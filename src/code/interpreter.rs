@@ -0,0 +1,344 @@
+//! A reference interpreter for Mijit code.
+//!
+//! This defines the meaning of every [`Action`], and is used as the oracle
+//! when testing the [`Target`]s and the optimizer. It can also run code
+//! directly on machines for which Mijit has no [`Target`].
+//!
+//! Some operations are undefined in Mijit code, e.g. division by zero. The
+//! interpreter chooses a result for them, but compiled code may differ.
+//!
+//! [`Target`]: crate::target::Target
+
+use super::{
+    Precision, UnaryOp, BinaryOp, Width, Global, Slot, Variable,
+    Action, EBB, Ending, Switch, debug_word,
+};
+use crate::util::{AsUsize};
+use Precision::*;
+
+/// Returns `x` truncated to `prec` and zero-extended.
+fn truncate(prec: Precision, x: u64) -> u64 {
+    match prec { P32 => x as u32 as u64, P64 => x }
+}
+
+/// Returns `x` truncated to `prec` and sign-extended.
+fn sign_extend(prec: Precision, x: u64) -> i64 {
+    match prec { P32 => x as i32 as i64, P64 => x as i64 }
+}
+
+/// Returns `-1` truncated to `prec` if `b` is `true`, otherwise `0`.
+fn boolean(prec: Precision, b: bool) -> u64 {
+    if b { truncate(prec, !0) } else { 0 }
+}
+
+/// Computes the result of a [`UnaryOp`].
+pub fn unary(op: UnaryOp, prec: Precision, x: u64) -> u64 {
+    let sx = sign_extend(prec, x);
+    truncate(prec, match op {
+        UnaryOp::Abs => sx.wrapping_abs() as u64,
+        UnaryOp::Negate => sx.wrapping_neg() as u64,
+        UnaryOp::Not => !x,
+    })
+}
+
+/// Computes the result of a [`BinaryOp`].
+///
+/// Shift amounts are taken modulo the number of bits in `prec`. Division by
+/// zero gives zero.
+pub fn binary(op: BinaryOp, prec: Precision, x: u64, y: u64) -> u64 {
+    let (ux, uy) = (truncate(prec, x), truncate(prec, y));
+    let (sx, sy) = (sign_extend(prec, x), sign_extend(prec, y));
+    let shift = (y as u32) & (prec.bits() as u32 - 1);
+    truncate(prec, match op {
+        BinaryOp::Add => ux.wrapping_add(uy),
+        BinaryOp::Sub => ux.wrapping_sub(uy),
+        BinaryOp::Mul => ux.wrapping_mul(uy),
+        BinaryOp::UDiv => ux.checked_div(uy).unwrap_or(0),
+        BinaryOp::SDiv => if sy == 0 { 0 } else { truncate(prec, sx.wrapping_div(sy) as u64) },
+        BinaryOp::Lsl => ux << shift,
+        BinaryOp::Lsr => ux >> shift,
+        BinaryOp::Asr => (sx >> shift) as u64,
+        BinaryOp::And => ux & uy,
+        BinaryOp::Or => ux | uy,
+        BinaryOp::Xor => ux ^ uy,
+        BinaryOp::Lt => boolean(prec, sx < sy),
+        BinaryOp::Ult => boolean(prec, ux < uy),
+        BinaryOp::Eq => boolean(prec, ux == uy),
+        BinaryOp::Max => std::cmp::max(sx, sy) as u64,
+        BinaryOp::Min => std::cmp::min(sx, sy) as u64,
+    })
+}
+
+/// Returns the number of bytes transferred by a memory access of `width`.
+pub fn width_bytes(width: Width) -> usize { 1 << (width as usize) }
+
+//-----------------------------------------------------------------------------
+
+/// The memory accessed by [`Action::Load`] and [`Action::Store`].
+pub trait Memory {
+    /// Returns the `width` bytes at `address`, zero-extended.
+    fn load(&mut self, address: u64, width: Width) -> u64;
+
+    /// Writes the low `width` bytes of `value` to `address`.
+    fn store(&mut self, address: u64, value: u64, width: Width);
+}
+
+/// The memory of the current process. Addresses are pointers.
+#[derive(Debug)]
+pub struct NativeMemory(());
+
+impl NativeMemory {
+    /// # Safety
+    ///
+    /// Every address accessed must be valid, and suitably aligned.
+    pub unsafe fn new() -> Self { NativeMemory(()) }
+}
+
+impl Memory for NativeMemory {
+    fn load(&mut self, address: u64, width: Width) -> u64 {
+        let p = address as usize;
+        unsafe { match width {
+            Width::One => *(p as *const u8) as u64,
+            Width::Two => *(p as *const u16) as u64,
+            Width::Four => *(p as *const u32) as u64,
+            Width::Eight => *(p as *const u64),
+        } }
+    }
+
+    fn store(&mut self, address: u64, value: u64, width: Width) {
+        let p = address as usize;
+        unsafe { match width {
+            Width::One => *(p as *mut u8) = value as u8,
+            Width::Two => *(p as *mut u16) = value as u16,
+            Width::Four => *(p as *mut u32) = value as u32,
+            Width::Eight => *(p as *mut u64) = value,
+        } }
+    }
+}
+
+/// A byte array used as memory. Addresses are indices into it, and values
+/// are little-endian. Accesses out of bounds panic.
+impl Memory for [u8] {
+    fn load(&mut self, address: u64, width: Width) -> u64 {
+        let a = address as usize;
+        let mut bytes = [0u8; 8];
+        bytes[..width_bytes(width)].copy_from_slice(&self[a..a + width_bytes(width)]);
+        u64::from_le_bytes(bytes)
+    }
+
+    fn store(&mut self, address: u64, value: u64, width: Width) {
+        let a = address as usize;
+        self[a..a + width_bytes(width)].copy_from_slice(&value.to_le_bytes()[..width_bytes(width)]);
+    }
+}
+
+//-----------------------------------------------------------------------------
+
+/// The values of all [`Variable`]s.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct State {
+    /// Indexed by [`Register`]. Grows as necessary.
+    pub registers: Vec<u64>,
+    /// Indexed by [`Global`].
+    pub globals: Vec<u64>,
+    /// Indexed by [`Slot`]. `Slot(0)` was pushed first.
+    pub slots: Vec<u64>,
+}
+
+impl State {
+    /// Constructs a `State` with no `Register`s or `Slot`s, and with the
+    /// specified values of the `Global`s.
+    pub fn new(globals: Vec<u64>) -> Self {
+        State {registers: Vec::new(), globals, slots: Vec::new()}
+    }
+
+    /// Returns the value of `v`. `Register`s that have never been written
+    /// are zero. Panics if `v` does not exist.
+    pub fn get(&self, v: impl Into<Variable>) -> u64 {
+        match v.into() {
+            Variable::Register(r) => self.registers.get(r.as_usize()).copied().unwrap_or(0),
+            Variable::Global(Global(g)) => self.globals[g],
+            Variable::Slot(Slot(s)) => self.slots[s],
+        }
+    }
+
+    /// Sets the value of `v`. Panics if `v` does not exist.
+    pub fn set(&mut self, v: impl Into<Variable>, x: u64) {
+        match v.into() {
+            Variable::Register(r) => {
+                let r = r.as_usize();
+                if r >= self.registers.len() {
+                    self.registers.resize(r + 1, 0);
+                }
+                self.registers[r] = x;
+            },
+            Variable::Global(Global(g)) => { self.globals[g] = x; },
+            Variable::Slot(Slot(s)) => { self.slots[s] = x; },
+        }
+    }
+
+    /// Removes the top `n` `Slot`s. Panics if there are not enough.
+    fn drop_slots(&mut self, n: usize) {
+        let len = self.slots.len().checked_sub(n).expect("Not enough slots");
+        self.slots.truncate(len);
+    }
+
+    /// Executes `action`.
+    pub fn action(&mut self, action: Action, memory: &mut (impl Memory + ?Sized)) {
+        match action {
+            Action::Move(dest, src) => {
+                let x = self.get(src);
+                self.set(dest, x);
+            },
+            Action::Constant(prec, dest, value) => {
+                self.set(dest, truncate(prec, value as u64));
+            },
+            Action::Unary(op, prec, dest, src) => {
+                let x = unary(op, prec, self.get(src));
+                self.set(dest, x);
+            },
+            Action::Binary(op, prec, dest, src1, src2) => {
+                let x = binary(op, prec, self.get(src1), self.get(src2));
+                self.set(dest, x);
+            },
+            Action::Load(dest, (addr, width), _) => {
+                let x = memory.load(self.get(addr), width);
+                self.set(dest, x);
+            },
+            Action::Store(dest, src, (addr, width), _) => {
+                // `dest` is written before `src` is read.
+                let address = self.get(addr);
+                self.set(dest, address);
+                let x = self.get(src);
+                memory.store(address, x, width);
+            },
+            Action::Push(src1, src2) => {
+                let x1 = src1.map_or(0, |src| self.get(src));
+                let x2 = src2.map_or(0, |src| self.get(src));
+                self.slots.push(x2);
+                self.slots.push(x1);
+            },
+            Action::Pop(dest1, dest2) => {
+                let len = self.slots.len();
+                assert!(len >= 2, "Not enough slots");
+                let (x1, x2) = (self.slots[len - 1], self.slots[len - 2]);
+                self.drop_slots(2);
+                if let Some(dest) = dest1 { self.set(dest, x1); }
+                if let Some(dest) = dest2 { self.set(dest, x2); }
+            },
+            Action::DropMany(n) => {
+                self.drop_slots(2 * n);
+            },
+            Action::Debug(src) => {
+                debug_word(self.get(src));
+            },
+        }
+    }
+
+    /// Executes `actions` in order.
+    pub fn actions(&mut self, actions: &[Action], memory: &mut (impl Memory + ?Sized)) {
+        for &action in actions {
+            self.action(action, memory);
+        }
+    }
+
+    /// Returns the case of `switch` that is chosen in this `State`.
+    pub fn switch<'a, C>(&self, switch: &'a Switch<C>) -> &'a C {
+        match switch {
+            Switch::Index {discriminant, cases, default_} => {
+                let index = self.get(*discriminant);
+                usize::try_from(index).ok().and_then(|i| cases.get(i)).unwrap_or(default_)
+            },
+            Switch::Always(jump) => jump,
+        }
+    }
+
+    /// Executes `ebb` until it reaches a leaf, and returns the leaf.
+    pub fn ebb<'a, L>(&mut self, mut ebb: &'a EBB<L>, memory: &mut (impl Memory + ?Sized)) -> &'a L {
+        loop {
+            self.actions(&ebb.actions, memory);
+            match &ebb.ending {
+                Ending::Leaf(leaf) => return leaf,
+                Ending::Switch(switch) => { ebb = self.switch(switch); },
+            }
+        }
+    }
+}
+
+//-----------------------------------------------------------------------------
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use super::super::{Register, REGISTERS, AliasMask};
+    use UnaryOp::*;
+    use BinaryOp::*;
+    use Width::*;
+
+    const R0: Register = REGISTERS[0];
+    const R1: Register = REGISTERS[1];
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(unary(Abs, P32, 0xFFFFFFFF_FFFFFFFE), 2);
+        assert_eq!(unary(Abs, P32, 0x80000000), 0x80000000);
+        assert_eq!(unary(Negate, P64, 1), !0);
+        assert_eq!(unary(Not, P32, 0), 0xFFFFFFFF);
+        assert_eq!(binary(Add, P32, 0xFFFFFFFF, 2), 1);
+        assert_eq!(binary(Sub, P64, 0, 1), !0);
+        assert_eq!(binary(Mul, P32, 0x10000, 0x10000), 0);
+        assert_eq!(binary(UDiv, P32, 0x1_0000_0007, 2), 3);
+        assert_eq!(binary(SDiv, P32, (-7i64) as u64, 2), (-3i32) as u32 as u64);
+        assert_eq!(binary(SDiv, P64, 7, 0), 0);
+        assert_eq!(binary(Lsl, P32, 1, 33), 2);
+        assert_eq!(binary(Lsr, P64, 0x8000_0000_0000_0000, 63), 1);
+        assert_eq!(binary(Asr, P32, 0x80000000, 31), 0xFFFFFFFF);
+        assert_eq!(binary(Lt, P32, 0xFFFFFFFF, 0), 0xFFFFFFFF);
+        assert_eq!(binary(Ult, P64, 0xFFFFFFFF, 0), 0);
+        assert_eq!(binary(Eq, P32, 0x1_0000_0000, 0), 0xFFFFFFFF);
+        assert_eq!(binary(Max, P64, !0, 1), 1);
+        assert_eq!(binary(Min, P32, 0xFFFFFFFF, 1), 0xFFFFFFFF);
+    }
+
+    #[test]
+    fn memory_and_stack() {
+        let mut memory = [0u8; 16];
+        let mut state = State::new(vec![0x0102030405060708, 4]);
+        state.actions(&[
+            Action::Store(R0, Global(0).into(), (Global(1).into(), Four), AliasMask(1)),
+            Action::Load(R1, (R0.into(), Two), AliasMask(1)),
+            Action::Push(Some(R0.into()), Some(R1.into())),
+        ], &mut memory[..]);
+        assert_eq!(&memory[4..8], &[8, 7, 6, 5]);
+        assert_eq!(state.slots, vec![0x0708, 4]);
+        assert_eq!(state.get(Slot(1)), 4);
+        state.actions(&[
+            Action::Pop(Some(R1), Some(R0)),
+            Action::Push(None, None),
+            Action::DropMany(1),
+        ], &mut memory[..]);
+        assert_eq!((state.get(R0), state.get(R1)), (0x0708, 4));
+        assert!(state.slots.is_empty());
+        // `dest` is written before `src` is read.
+        state.action(Action::Store(R1, R1.into(), (Global(1).into(), Eight), AliasMask(1)), &mut memory[..]);
+        assert_eq!(&memory[4..12], &[4, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn control_flow() {
+        let leaf = |x| EBB {actions: vec![], ending: Ending::Leaf(x)};
+        let ebb = EBB {
+            actions: vec![Action::Constant(P64, R0, 1)],
+            ending: Ending::Switch(Switch::new(
+                Global(0).into(),
+                Box::new([leaf('a'), leaf('b')]),
+                leaf('c'),
+            )),
+        };
+        for (g, expected) in [(0, 'a'), (1, 'b'), (2, 'c'), (!0, 'c')] {
+            let mut state = State::new(vec![g]);
+            assert_eq!(*state.ebb(&ebb, &mut [][..]), expected);
+            assert_eq!(state.get(R0), 1);
+        }
+    }
+}
@@ -37,8 +37,13 @@ pub use convention::{Convention, Propagator};
 mod ebb;
 pub use ebb::{EBB, Ending};
 
+mod verify;
+pub use verify::{Problem, Diagnostic, verify};
+
 pub mod builder;
 
+pub mod interpreter;
+
 //-----------------------------------------------------------------------------
 
 /// Code to be run on entry and exit from a `Machine`.
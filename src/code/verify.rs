@@ -0,0 +1,329 @@
+use std::collections::{HashSet};
+use std::fmt::{self, Display, Formatter};
+
+use crate::util::{AsUsize};
+
+use super::{Variable, Slot, Action, Convention, EBB, Ending, Switch};
+
+/// The ways in which an [`EBB`] can be malformed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// A [`Variable`] is read, but it is not live.
+    DeadRead,
+    /// A [`Slot`] is accessed, but it does not exist.
+    NoSuchSlot,
+    /// A [`Register`] is accessed, but the [`Target`] does not have it.
+    ///
+    /// [`Register`]: super::Register
+    /// [`Target`]: crate::target::Target
+    NoSuchRegister,
+    /// More spill [`Slot`]s are popped or dropped than exist.
+    StackUnderflow,
+    /// A leaf of the [`EBB`] is reached with the wrong number of spill
+    /// [`Slot`]s.
+    SlotsMismatch {expected: usize, found: usize},
+}
+
+/// Describes a problem found by [`verify()`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// The path through the [`Switch`]es from the root of the [`EBB`] to the
+    /// problem. Each element is the index of a case, or `cases.len()` for the
+    /// default case. `Switch::Always` counts as having no non-default cases.
+    pub path: Vec<usize>,
+    /// The index of the offending [`Action`], or `None` for the [`Ending`].
+    pub index: Option<usize>,
+    /// The offending [`Variable`], if any.
+    pub variable: Option<Variable>,
+    /// What is wrong.
+    pub problem: Problem,
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{:?} at path {:?}", self.problem, self.path)?;
+        match self.index {
+            Some(index) => write!(f, ", action {}", index)?,
+            None => write!(f, ", ending")?,
+        }
+        if let Some(variable) = self.variable {
+            write!(f, ", variable {:?}", variable)?;
+        }
+        Ok(())
+    }
+}
+
+//-----------------------------------------------------------------------------
+
+/// The state of the verifier at some point in an [`EBB`].
+#[derive(Clone)]
+struct State {
+    /// The path through the [`Switch`]es to this point.
+    path: Vec<usize>,
+    /// The `Variable`s that are live.
+    live: HashSet<Variable>,
+    /// The number of spill [`Slot`]s that are allocated.
+    slots_used: usize,
+}
+
+/// Accumulates [`Diagnostic`]s.
+struct Verifier {
+    num_registers: usize,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Verifier {
+    fn report(&mut self, s: &State, index: Option<usize>, variable: Option<Variable>, problem: Problem) {
+        self.diagnostics.push(Diagnostic {path: s.path.clone(), index, variable, problem});
+    }
+
+    /// Reports a problem if `v` does not exist. Returns `true` if it exists.
+    fn exists(&mut self, s: &State, index: Option<usize>, v: Variable) -> bool {
+        let problem = match v {
+            Variable::Register(r) if r.as_usize() >= self.num_registers => Problem::NoSuchRegister,
+            Variable::Slot(slot) if slot.0 >= s.slots_used => Problem::NoSuchSlot,
+            _ => return true,
+        };
+        self.report(s, index, Some(v), problem);
+        false
+    }
+
+    /// Checks that `v` exists and is live.
+    fn read(&mut self, s: &State, index: Option<usize>, v: impl Into<Variable>) {
+        let v = v.into();
+        if self.exists(s, index, v) && !s.live.contains(&v) {
+            self.report(s, index, Some(v), Problem::DeadRead);
+        }
+    }
+
+    /// Checks that `v` exists, and makes it live.
+    fn write(&mut self, s: &mut State, index: Option<usize>, v: impl Into<Variable>) {
+        let v = v.into();
+        if self.exists(s, index, v) {
+            s.live.insert(v);
+        }
+    }
+
+    /// Removes `n` spill slots. Returns `false` if there are not enough.
+    fn drop_slots(&mut self, s: &mut State, index: Option<usize>, n: usize) -> bool {
+        if n > s.slots_used {
+            self.report(s, index, None, Problem::StackUnderflow);
+            return false;
+        }
+        s.slots_used -= n;
+        for slot in s.slots_used..(s.slots_used + n) {
+            s.live.remove(&Slot(slot).into());
+        }
+        true
+    }
+
+    fn action(&mut self, s: &mut State, index: usize, action: Action) {
+        let i = Some(index);
+        match action {
+            Action::Move(dest, src) => {
+                self.read(s, i, src);
+                self.write(s, i, dest);
+            },
+            Action::Constant(_, dest, _) => {
+                self.write(s, i, dest);
+            },
+            Action::Unary(_, _, dest, src) => {
+                self.read(s, i, src);
+                self.write(s, i, dest);
+            },
+            Action::Binary(_, _, dest, src1, src2) => {
+                self.read(s, i, src1);
+                self.read(s, i, src2);
+                self.write(s, i, dest);
+            },
+            Action::Load(dest, (addr, _), _) => {
+                self.read(s, i, addr);
+                self.write(s, i, dest);
+            },
+            Action::Store(dest, src, (addr, _), _) => {
+                self.read(s, i, src);
+                self.read(s, i, addr);
+                self.write(s, i, dest);
+            },
+            Action::Push(src1, src2) => {
+                if let Some(src) = src1 { self.read(s, i, src); }
+                if let Some(src) = src2 { self.read(s, i, src); }
+                s.slots_used += 2;
+                if src1.is_some() { s.live.insert(Slot(s.slots_used - 1).into()); }
+                if src2.is_some() { s.live.insert(Slot(s.slots_used - 2).into()); }
+            },
+            Action::Pop(dest1, dest2) => {
+                if s.slots_used >= 2 {
+                    if dest1.is_some() { self.read(s, i, Slot(s.slots_used - 1)); }
+                    if dest2.is_some() { self.read(s, i, Slot(s.slots_used - 2)); }
+                }
+                if self.drop_slots(s, i, 2) {
+                    if let Some(dest) = dest1 { self.write(s, i, dest); }
+                    if let Some(dest) = dest2 { self.write(s, i, dest); }
+                }
+            },
+            Action::DropMany(n) => {
+                self.drop_slots(s, i, 2 * n);
+            },
+            Action::Debug(src) => {
+                self.read(s, i, src);
+            },
+        }
+    }
+
+    fn ebb<'a, L>(&mut self, mut s: State, ebb: &EBB<L>, after: &impl Fn(&L) -> &'a Convention) {
+        for (index, &action) in ebb.actions.iter().enumerate() {
+            self.action(&mut s, index, action);
+        }
+        match &ebb.ending {
+            Ending::Leaf(leaf) => {
+                let after = after(leaf);
+                if after.slots_used != s.slots_used {
+                    self.report(&s, None, None, Problem::SlotsMismatch {
+                        expected: after.slots_used,
+                        found: s.slots_used,
+                    });
+                }
+                for &v in after.live_values.iter() {
+                    self.read(&s, None, v);
+                }
+            },
+            Ending::Switch(switch) => {
+                if let Some(discriminant) = switch.discriminant() {
+                    self.read(&s, None, discriminant);
+                }
+                let (cases, default_) = match switch {
+                    Switch::Index {cases, default_, ..} => (&cases[..], &**default_),
+                    Switch::Always(default_) => (&[][..], &**default_),
+                };
+                for (index, child) in cases.iter().chain(std::iter::once(default_)).enumerate() {
+                    let mut child_s = s.clone();
+                    child_s.path.push(index);
+                    self.ebb(child_s, child, after);
+                }
+            },
+        }
+    }
+}
+
+/// Checks that `ebb` is well-formed. Returns a [`Diagnostic`] for every
+/// problem found. The checks are:
+///  - Every [`Variable`] read is live.
+///  - Every [`Slot`] accessed exists.
+///  - Every [`Register`] accessed is less than `num_registers`.
+///  - No more `Slot`s are popped or dropped than exist.
+///  - Every leaf is reached with the number of `Slot`s it expects.
+///
+///  - before - the [`Convention`] on entry to `ebb`.
+///  - after - returns the `Convention` on exit to each leaf of `ebb`.
+///  - num_registers - typically [`Target::NUM_REGISTERS`].
+///
+/// [`Register`]: super::Register
+/// [`Target::NUM_REGISTERS`]: crate::target::Target::NUM_REGISTERS
+pub fn verify<'a, L>(
+    before: &Convention,
+    ebb: &EBB<L>,
+    after: impl Fn(&L) -> &'a Convention,
+    num_registers: usize,
+) -> Result<(), Vec<Diagnostic>> {
+    let mut verifier = Verifier {num_registers, diagnostics: Vec::new()};
+    let state = State {
+        path: Vec::new(),
+        live: before.live_values.iter().copied().collect(),
+        slots_used: before.slots_used,
+    };
+    verifier.ebb(state, ebb, &after);
+    if verifier.diagnostics.is_empty() { Ok(()) } else { Err(verifier.diagnostics) }
+}
+
+//-----------------------------------------------------------------------------
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use super::super::{Global, REGISTERS, Precision};
+    use Precision::*;
+    use Action::*;
+
+    /// Verifies a single `Leaf` that expects the same `Convention` as `before`.
+    fn check(before: &Convention, actions: Vec<Action>) -> Result<(), Vec<Diagnostic>> {
+        let ebb = EBB {actions, ending: Ending::Leaf(())};
+        verify(before, &ebb, |_| before, 4)
+    }
+
+    fn diagnostic(index: Option<usize>, variable: Option<Variable>, problem: Problem) -> Diagnostic {
+        Diagnostic {path: vec![], index, variable, problem}
+    }
+
+    #[test]
+    fn well_formed() {
+        let convention = Convention::empty(2);
+        assert_eq!(check(&convention, vec![
+            Push(Some(Global(0).into()), None),
+            Move(REGISTERS[0].into(), Slot(1).into()),
+            Move(Slot(0).into(), REGISTERS[0].into()),
+            Pop(Some(REGISTERS[1]), Some(REGISTERS[2])),
+            Move(Global(1).into(), REGISTERS[2].into()),
+        ]), Ok(()));
+    }
+
+    #[test]
+    fn dead_read() {
+        let convention = Convention::empty(1);
+        let r0 = REGISTERS[0];
+        assert_eq!(check(&convention, vec![
+            Constant(P64, r0, 0),
+            Move(Global(0).into(), REGISTERS[1].into()),
+        ]), Err(vec![
+            diagnostic(Some(1), Some(REGISTERS[1].into()), Problem::DeadRead),
+        ]));
+        // A `Push(None, _)` pushes a dead value.
+        assert_eq!(check(&convention, vec![
+            Push(None, None),
+            Pop(Some(r0), None),
+        ]), Err(vec![
+            diagnostic(Some(1), Some(Slot(1).into()), Problem::DeadRead),
+        ]));
+    }
+
+    #[test]
+    fn bounds() {
+        let convention = Convention::empty(1);
+        assert_eq!(check(&convention, vec![
+            Move(Slot(0).into(), Global(0).into()),
+            Constant(P64, REGISTERS[4], 0),
+        ]), Err(vec![
+            diagnostic(Some(0), Some(Slot(0).into()), Problem::NoSuchSlot),
+            diagnostic(Some(1), Some(REGISTERS[4].into()), Problem::NoSuchRegister),
+        ]));
+    }
+
+    #[test]
+    fn unbalanced() {
+        let convention = Convention::empty(0);
+        assert_eq!(check(&convention, vec![Pop(None, None)]), Err(vec![
+            diagnostic(Some(0), None, Problem::StackUnderflow),
+        ]));
+        assert_eq!(check(&convention, vec![Push(None, None)]), Err(vec![
+            diagnostic(None, None, Problem::SlotsMismatch {expected: 0, found: 2}),
+        ]));
+    }
+
+    #[test]
+    fn path() {
+        let convention = Convention::empty(1);
+        let leaf = |actions| EBB {actions, ending: Ending::Leaf(())};
+        let ebb = EBB {
+            actions: vec![],
+            ending: Ending::Switch(Switch::new(
+                Global(0).into(),
+                Box::new([leaf(vec![]), leaf(vec![Debug(REGISTERS[0].into())])]),
+                leaf(vec![DropMany(1)]),
+            )),
+        };
+        assert_eq!(verify(&convention, &ebb, |_| &convention, 4), Err(vec![
+            Diagnostic {path: vec![1], index: Some(0), variable: Some(REGISTERS[0].into()), problem: Problem::DeadRead},
+            Diagnostic {path: vec![2], index: Some(0), variable: None, problem: Problem::StackUnderflow},
+        ]));
+    }
+}
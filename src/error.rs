@@ -0,0 +1,93 @@
+use std::fmt::{self, Display, Formatter};
+use std::io;
+
+use super::code::{Diagnostic};
+use super::jit::{EntryId};
+
+/// The ways in which Mijit can fail without panicking.
+///
+/// Most methods that can fail have two variants: one that returns a
+/// `Result<_, Error>`, named with a `try_` prefix, and one that panics.
+#[derive(Debug)]
+pub enum Error {
+    /// [`Jit::define()`] was called twice for the same entry.
+    ///
+    /// [`Jit::define()`]: crate::jit::Jit::define
+    AlreadyDefined(EntryId),
+    /// An exit value was negative.
+    NegativeExitValue(i64),
+    /// Some code pops or drops a spill [`Slot`] that does not exist.
+    ///
+    /// [`Slot`]: crate::code::Slot
+    StackUnderflow,
+    /// The cases of a [`Switch`] expect different numbers of spill [`Slot`]s.
+    ///
+    /// [`Switch`]: crate::code::Switch
+    /// [`Slot`]: crate::code::Slot
+    SlotsMismatch {expected: usize, found: usize},
+    /// A [`Pool`] has the wrong number of [`Global`]s for the compiled code.
+    ///
+    /// [`Pool`]: crate::target::Pool
+    /// [`Global`]: crate::code::Global
+    GlobalsMismatch {expected: usize, found: usize},
+    /// New code failed [`verify()`]. This includes reading a [`Variable`]
+    /// that is not live in the [`Convention`] of the code it replaces.
+    ///
+    /// [`verify()`]: crate::code::verify
+    /// [`Variable`]: crate::code::Variable
+    /// [`Convention`]: crate::code::Convention
+    Invalid(Vec<Diagnostic>),
+    /// A memory allocation failed.
+    OutOfMemory,
+    /// The operating system refused to allocate memory or to change its
+    /// permissions.
+    Memory(io::Error),
+    /// An earlier [`Error`] left a buffer unusable.
+    Poisoned,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Error::AlreadyDefined(entry) => write!(f, "{:?} is already defined", entry),
+            Error::NegativeExitValue(value) => write!(f, "Exit value {} is negative", value),
+            Error::StackUnderflow => write!(f, "Code pops a spill slot that does not exist"),
+            Error::SlotsMismatch {expected, found} => write!(
+                f, "Expected {} spill slots but found {}", expected, found,
+            ),
+            Error::GlobalsMismatch {expected, found} => write!(
+                f, "Expected a pool with {} globals but found {}", expected, found,
+            ),
+            Error::Invalid(diagnostics) => {
+                write!(f, "Invalid code: ")?;
+                for (i, d) in diagnostics.iter().enumerate() {
+                    if i > 0 { write!(f, "; ")?; }
+                    write!(f, "{}", d)?;
+                }
+                Ok(())
+            },
+            Error::OutOfMemory => write!(f, "Out of memory"),
+            Error::Memory(e) => write!(f, "Memory error: {}", e),
+            Error::Poisoned => write!(f, "Poisoned by an earlier error"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Memory(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self { Error::Memory(e) }
+}
+
+/// Unwraps `result`, or panics with the [`Display`] form of the [`Error`].
+/// This is how the panicking variants of fallible methods are implemented.
+pub(crate) fn expect<T>(result: Result<T, Error>) -> T {
+    result.unwrap_or_else(|e| panic!("{}", e))
+}
@@ -0,0 +1,154 @@
+use std::sync::{Mutex, mpsc};
+use std::thread;
+
+use crate::error::{Error};
+use super::{code, Jit, EntryId};
+use super::target::{Target};
+use code::{EBB};
+
+/// A request to define an entry.
+type Request = (EntryId, EBB<EntryId>);
+
+/// The `Jit`, and the requests that failed.
+type Outcome<T> = (Jit<T>, Vec<(EntryId, Error)>);
+
+/// Owns a [`Jit`] and compiles code for it on a background thread, so that
+/// threads running the compiled code do not have to pause while the
+/// optimizer runs.
+///
+/// Typical use is to obtain [`entry_point()`]s from the `Jit`, then to move
+/// it into a `Background`. Threads that call the entry points and find that
+/// an entry is not yet defined (because it exits with its `exit_value`) can
+/// request a definition and carry on running the old code. When the
+/// definition has been compiled, the entry's label is patched, and later
+/// executions of the entry use the new code.
+///
+/// The definitions must come from the caller. Mijit does not yet find and
+/// optimize hot paths by itself, so nothing is compiled unless requested.
+///
+/// [`entry_point()`]: Jit::entry_point
+#[derive(Debug)]
+pub struct Background<T: Target> {
+    /// Sends requests to the background thread. `None` after `join()`.
+    sender: Mutex<Option<mpsc::Sender<Request>>>,
+    /// The background thread, which returns the `Jit` and any errors.
+    thread: thread::JoinHandle<Outcome<T>>,
+}
+
+impl<T: Target + Send + 'static> Background<T> where T::Lowerer: Send {
+    /// Moves `jit` to a new thread, which waits for requests.
+    ///
+    /// Clears the [code budget] of `jit`, because evicting an entry would
+    /// discard code that other threads might be running.
+    ///
+    /// [code budget]: Jit::set_code_budget
+    pub fn new(mut jit: Jit<T>) -> Self {
+        jit.set_code_budget(None);
+        let (sender, receiver) = mpsc::channel::<Request>();
+        let thread = thread::spawn(move || {
+            let mut errors = Vec::new();
+            for (entry, ebb) in receiver {
+                if jit.is_defined(entry) { continue; }
+                if let Err(e) = jit.try_define(entry, &ebb) {
+                    errors.push((entry, e));
+                }
+            }
+            (jit, errors)
+        });
+        Self {sender: Mutex::new(Some(sender)), thread}
+    }
+
+    /// Queues a request to [`define()`] `entry` as `ebb`, and returns
+    /// immediately. Requests are processed in order. A request is ignored if
+    /// `entry` is already defined, so it is harmless for several threads to
+    /// request the same definition.
+    ///
+    /// [`define()`]: Jit::define
+    pub fn define(&self, entry: EntryId, ebb: EBB<EntryId>) {
+        let sender = self.sender.lock().unwrap();
+        let sender = sender.as_ref().expect("Background has finished");
+        sender.send((entry, ebb)).expect("Background thread panicked");
+    }
+
+    /// Waits for all queued requests to be processed, then returns the `Jit`,
+    /// along with the `EntryId` and [`Error`] of each request that failed.
+    pub fn join(self) -> (Jit<T>, Vec<(EntryId, Error)>) {
+        // Dropping the `Sender` ends the background thread's loop.
+        drop(self.sender.lock().unwrap().take());
+        self.thread.join().expect("Background thread panicked")
+    }
+}
+
+//-----------------------------------------------------------------------------
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use super::super::target::{native};
+    use code::{Action, Ending};
+    use Action::*;
+
+    use super::super::factorial::*;
+
+    #[test]
+    pub fn define() {
+        let factorial = Factorial::new(native());
+        let ebb = factorial.loop_ebb();
+        let Factorial {mut jit, pool, start, loop_, halt} = factorial;
+        jit.invalidate(loop_);
+        // The budget would evict `start`, but `Background` clears it.
+        jit.set_code_budget(Some(jit.live_code_size()));
+        let background = Background::new(jit);
+        background.define(loop_, ebb.clone());
+        // A second request is ignored.
+        background.define(loop_, ebb);
+        // An invalid request is reported.
+        let bad = EBB {actions: vec![Pop(None, None)], ending: Ending::Leaf(halt)};
+        background.define(halt, bad);
+        let (jit, errors) = background.join();
+        assert!(jit.is_defined(start));
+        assert!(jit.is_defined(loop_));
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, halt);
+        assert!(matches!(errors[0].1, Error::Invalid(_)));
+        let mut factorial = Factorial {jit, pool, start, loop_, halt};
+        assert_eq!(factorial.run(5), 120);
+    }
+
+    #[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+    #[test]
+    pub fn concurrent() {
+        use super::super::target::{Word};
+        use super::super::target::tests::{MemfdNative};
+        use code::{Global};
+        let factorial = Factorial::from_jit(Jit::new(MemfdNative::default(), 2));
+        let ebb = factorial.loop_ebb();
+        let (start, loop_) = (factorial.start, factorial.loop_);
+        let mut jit = factorial.jit;
+        jit.invalidate(loop_);
+        let f = jit.entry_point(start);
+        let pools: Vec<_> = (0..4).map(|_| jit.new_pool()).collect();
+        let background = Background::new(jit);
+        thread::scope(|scope| {
+            for mut pool in pools {
+                let (background, ebb) = (&background, &ebb);
+                scope.spawn(move || loop {
+                    pool[Global(0)] = Word {u: 5};
+                    let exit_value = unsafe { f(pool.as_mut().as_mut_ptr()) };
+                    let result = unsafe { pool[Global(1)].u };
+                    if exit_value == (Word {s: HALT}) {
+                        assert_eq!(result, 120);
+                        break;
+                    }
+                    // `loop_` is not yet defined. Ask for it, and try again.
+                    assert_eq!(exit_value, Word {s: LOOP});
+                    assert_eq!(result, 1);
+                    background.define(loop_, ebb.clone());
+                });
+            }
+        });
+        let (jit, errors) = background.join();
+        assert!(jit.is_defined(loop_));
+        assert!(errors.is_empty());
+    }
+}
@@ -1,11 +1,17 @@
 use std::fmt::{Debug};
-use std::ops::{Index, IndexMut};
+use std::io;
+use std::ops::{Index, IndexMut, Range};
 use std::marker::{PhantomData};
 
 use crate::util::{AsUsize};
-use super::target::{Label, Word, Pool, Lower, Execute, Target, RESULT};
-use super::code::{Precision, Global, Switch, Action, Convention, Marshal, Propagator, EBB, Ending};
+use crate::error::{Error, expect};
+use super::target::{Label, Word, Pool, Lower, ExecuteFn, Execute, EntryPoint, Target, RESULT};
+use super::code::{Precision, Switch, Action, Convention, Marshal, Propagator, EBB, Ending, verify};
 use super::optimizer::{LookupLeaf, optimize};
+use super::perf::{PerfMap, Region, Role};
+#[cfg(feature = "gdb")]
+use super::gdb::{GdbJit};
+use super::observer::{Event, Observer};
 use Precision::*;
 
 // CaseId.
@@ -53,9 +59,30 @@ struct Case {
     retire: Option<Retire>,
     /// The `Fetch`, if any.
     fetch: Option<Fetch>,
+    /// The code of the `Retire`, if any.
+    retire_code: Range<usize>,
+    /// The code of the `Fetch`, if any.
+    fetch_code: Range<usize>,
+    /// A human-readable name for this `Case`, if any. Used for profiling and
+    /// debugging.
+    name: Option<String>,
 }
 
 impl Case {
+    /// Constructs a `Case` with an undefined `label` and no code.
+    fn new(fetch_parent: Option<CaseId>) -> Self {
+        Case {
+            fetch_parent,
+            before: None,
+            label: Label::new(None),
+            retire: None,
+            fetch: None,
+            retire_code: 0..0,
+            fetch_code: 0..0,
+            name: None,
+        }
+    }
+
     pub fn convention(&self) -> &Convention {
         assert!(self.retire.is_some() || self.fetch.is_some());
         self.before.as_ref().expect("Incompletely constructed")
@@ -83,6 +110,10 @@ struct Internals {
     /// The [`Case`]s in the order they were compiled, excluding the root.
     /// Indexed by [`CaseId`].
     cases: Vec<Case>,
+    /// [`Case`]s freed by `invalidate()`, which can be reused.
+    free: Vec<CaseId>,
+    /// Receives [`Event`]s, if any.
+    observer: Option<Box<dyn Observer + Send>>,
 }
 
 impl Internals {
@@ -93,15 +124,56 @@ impl Internals {
     ///    new `Case`.
     ///  - convention - the [`Convention`] in effect on entry to the new `Case`.
     fn new_case(&mut self, fetch_parent: impl Into<Option<CaseId>>) -> CaseId {
-        let id = CaseId::new(self.cases.len()).unwrap();
-        self.cases.push(Case {
-            fetch_parent: fetch_parent.into(),
-            before: None,
-            label: Label::new(None),
-            retire: None,
-            fetch: None,
-        });
-        id
+        let case = Case::new(fetch_parent.into());
+        if let Some(id) = self.free.pop() {
+            self[id] = case;
+            id
+        } else {
+            let id = CaseId::new(self.cases.len()).unwrap();
+            self.cases.push(case);
+            id
+        }
+    }
+
+    /// Returns a human-readable name for `id`. If `id` has no name, it is
+    /// named after its nearest named `fetch_parent`, if any.
+    fn name(&self, id: CaseId) -> String {
+        let mut ancestor = Some(id);
+        while let Some(a) = ancestor {
+            if let Some(name) = &self[a].name {
+                return if a == id { name.clone() } else { format!("{}/{:?}", name, id) };
+            }
+            ancestor = self[a].fetch_parent;
+        }
+        format!("{:?}", id)
+    }
+
+    /// Passes `event` to the [`Observer`], if any.
+    fn observe(&mut self, event: Event) {
+        if let Some(observer) = &mut self.observer {
+            observer.event(&event);
+        }
+    }
+
+    /// Redirects all jumps to `id` to `new`, which becomes its new `label`.
+    fn redirect(&mut self, lo: &mut impl Lower, id: CaseId, mut new: Label) {
+        let old_target = self[id].label.target();
+        let new_target = new.target();
+        self.observe(Event::LabelStolen {case: id, old_target, new_target});
+        let patches: Vec<_> = self[id].label.drain().collect();
+        for patch in patches {
+            lo.patch(patch, old_target, new_target);
+            self.observe(Event::Patched {address: patch.address(), old_target, new_target});
+            new.push(patch);
+        }
+        self[id].label = new;
+    }
+
+    /// Redirects all jumps to `id` to the current assembly address, which
+    /// becomes its new `label`.
+    fn intercept(&mut self, lo: &mut impl Lower, id: CaseId) {
+        let here = lo.here();
+        self.redirect(lo, id, here);
     }
 
     /// Find the [`Convention`] for a [`CaseId`] allowing for `None`.
@@ -109,8 +181,23 @@ impl Internals {
         id.into().map_or(&self.convention, |id| self[id].convention())
     }
 
-    /// Add a [`Retire`] to a [`Case`] that doesn't have a [`Fetch`].
+    /// Checks that `ebb` can replace the code of `id` without breaking the
+    /// [`Convention`]s of `id` or of the `Case`s it jumps to.
+    fn check<L: Clone>(
+        &self,
+        id: CaseId,
+        ebb: &EBB<L>,
+        to_case: &impl Fn(L) -> CaseId,
+        num_registers: usize,
+    ) -> Result<(), Error> {
+        let after = |leaf: &L| self.convention(to_case(leaf.clone()));
+        verify(self[id].convention(), ebb, after, num_registers).map_err(Error::Invalid)
+    }
+
+    /// Add a [`Retire`] to a [`Case`] that has neither a `Retire` nor a
+    /// [`Fetch`].
     fn add_retire(&mut self, lo: &mut impl Lower, id: CaseId, retire: Retire) {
+        assert!(self[id].retire.is_none());
         assert!(self[id].fetch.is_none());
         // Compute the `before` convention.
         let mut propagator = Propagator::new(self.convention(retire.jump));
@@ -120,9 +207,7 @@ impl Internals {
         let before = propagator.before();
         *lo.slots_used_mut() = before.slots_used;
         // Intercept all jumps to `id`.
-        let mut here = lo.here();
-        lo.steal(&mut self[id].label, &mut here);
-        self[id].label = here;
+        self.intercept(lo, id);
         // Compile `retire`.
         lo.actions(&*retire.actions);
         let slots_used = *lo.slots_used_mut();
@@ -135,6 +220,7 @@ impl Internals {
             lo.epilogue()
         }
         self[id].set_convention(before);
+        self.observe(Event::RetireAdded {case: id, num_actions: retire.actions.len(), jump: retire.jump});
         self[id].retire = Some(retire);
     }
 
@@ -154,9 +240,7 @@ impl Internals {
         let before = propagator.before();
         *lo.slots_used_mut() = before.slots_used;
         // Intercept all jumps to `id`.
-        let mut here = lo.here();
-        lo.steal(&mut self[id].label, &mut here);
-        self[id].label = here;
+        self.intercept(lo, id);
         // Compile `fetch`.
         lo.actions(&*fetch.actions);
         let slots_used = *lo.slots_used_mut();
@@ -166,12 +250,20 @@ impl Internals {
         };
         match fetch.switch {
             Switch::Index {discriminant, ref cases, ref default_} => {
-                for (index, &case) in cases.iter().enumerate() {
+                for &case in cases.iter() {
                     check_child(&self[case]);
-                    lo.if_eq((discriminant, index as u64), &mut self[case].label);
                 }
                 check_child(&self[**default_]);
-                lo.jump(&mut self[**default_].label);
+                // `switch()` needs all the `Label`s at once, so borrow them.
+                let mut labels: Vec<Label> = cases.iter().map(|&case| {
+                    std::mem::take(&mut self[case].label)
+                }).collect();
+                let mut default_label = std::mem::take(&mut self[**default_].label);
+                lo.switch(discriminant, &mut labels, &mut default_label);
+                for (&case, label) in cases.iter().zip(labels) {
+                    self[case].label = label;
+                }
+                self[**default_].label = default_label;
             },
             Switch::Always(ref jump) => {
                 check_child(&self[**jump]);
@@ -179,8 +271,81 @@ impl Internals {
             },
         }
         self[id].set_convention(before);
+        let children = children(&fetch.switch);
+        self.observe(Event::FetchAdded {case: id, num_actions: fetch.actions.len(), children: &children});
         self[id].fetch = Some(fetch);
     }
+
+    /// Discards the [`Fetch`] of `id`, which must also have a [`Retire`], and
+    /// redirects all jumps to `id` to the `Retire`. Frees all descendants of
+    /// `id`, which must not be reachable except via `id`.
+    ///
+    /// Returns the code that can no longer run.
+    fn invalidate(&mut self, lo: &mut impl Lower, id: CaseId) -> Vec<Range<usize>> {
+        assert!(self[id].retire.is_some());
+        let fetch = self[id].fetch.take().expect("Case has no Fetch");
+        let mut garbage = vec![std::mem::replace(&mut self[id].fetch_code, 0..0)];
+        // Find the descendants.
+        let mut freed = Vec::new();
+        let mut stack = children(&fetch.switch);
+        while let Some(child) = stack.pop() {
+            if let Some(fetch) = &self[child].fetch {
+                stack.extend(children(&fetch.switch));
+            }
+            garbage.push(self[child].retire_code.clone());
+            garbage.push(self[child].fetch_code.clone());
+            freed.push(child);
+        }
+        let is_garbage = |address: usize| garbage.iter().any(|r| r.contains(&address));
+        // Forget jumps from the garbage to `Case`s that remain live.
+        for &child in &freed {
+            if let Some(jump) = self[child].retire.as_ref().and_then(|r| r.jump) {
+                self[jump].label.retain(|patch| !is_garbage(patch.address()));
+            }
+        }
+        // Revert to the `Retire`.
+        let retire_label = Label::new(Some(self[id].retire_code.start));
+        self.redirect(lo, id, retire_label);
+        // Free the descendants.
+        for &child in &freed {
+            assert!(
+                self[child].label.patches().iter().all(|patch| is_garbage(patch.address())),
+                "{:?} is reachable other than via {:?}", child, id,
+            );
+            self[child] = Case::new(None);
+        }
+        self.free.extend(freed);
+        garbage.retain(|r| !r.is_empty());
+        garbage
+    }
+}
+
+/// Returns the [`Case`]s that `switch` can jump to.
+fn children(switch: &Switch<CaseId>) -> Vec<CaseId> {
+    match *switch {
+        Switch::Index {ref cases, ref default_, ..} => {
+            cases.iter().copied().chain(std::iter::once(**default_)).collect()
+        },
+        Switch::Always(ref jump) => vec![**jump],
+    }
+}
+
+/// Returns the number of spill slots after `actions`.
+///  - slots_used - the number of spill slots before `actions`.
+fn count_slots(mut slots_used: usize, actions: &[Action]) -> Result<usize, Error> {
+    for &action in actions {
+        match action {
+            Action::Push(_, _) => { slots_used += 2; },
+            Action::Pop(_, _) => {
+                slots_used = slots_used.checked_sub(2).ok_or(Error::StackUnderflow)?;
+            },
+            Action::DropMany(n) => {
+                slots_used = slots_used.checked_sub(2 * n).ok_or(Error::StackUnderflow)?;
+            },
+            _ => {},
+        }
+    }
+    Ok(slots_used)
 }
 
 impl Index<CaseId> for Internals {
@@ -209,6 +374,26 @@ pub struct Engine<T: Target> {
     lowerer: T::Lowerer,
     /// This nested struct can be borrowed independently of `lowerer`.
     i: Internals,
+    /// The code that belongs to each [`Case`], in the order it was compiled,
+    /// excluding code discarded by [`invalidate()`].
+    ///
+    /// [`invalidate()`]: Self::invalidate
+    regions: Vec<Region>,
+    /// Reports the compiled code to `perf`, if enabled.
+    perf: Option<PerfMap>,
+    /// Reports the compiled code to GDB, if enabled.
+    #[cfg(feature = "gdb")]
+    gdb: Option<GdbJit>,
+    /// The size of the code buffer when it was last reported to the
+    /// [`Observer`].
+    code_size: usize,
+    /// The address of the code buffer when it was last reported to the
+    /// `Observer`.
+    code_address: usize,
+    /// The number of bytes of code that could still run.
+    live_code: usize,
+    /// The code that can no longer run, sorted and merged.
+    garbage: Vec<Range<usize>>,
 }
 
 impl<T: Target> std::fmt::Debug for Engine<T> {
@@ -222,37 +407,165 @@ impl<T: Target> std::fmt::Debug for Engine<T> {
 impl<T: Target> Engine<T> {
     /// Constructs an `Engine`, initially with no entries.
     ///  - num_globals - the number of [`Global`]s needed to pass values to and
-    ///    from the compiled code.
+    ///    from the compiled code. Each [`Pool`] passed to `try_run()` must
+    ///    have this many `Global`s.
+    ///
+    /// [`Global`]: super::code::Global
     pub fn new(target: T, num_globals: usize) -> Self {
-        let pool = Pool::new(num_globals);
-        let lowerer = target.lowerer(pool);
+        let lowerer = target.lowerer(num_globals);
+        let code_address = lowerer.code().as_ptr() as usize;
         let i = Internals {
             convention: Convention::empty(num_globals),
             cases: Vec::new(),
+            free: Vec::new(),
+            observer: None,
         };
-        Engine {_target: target, lowerer, i}
+        Engine {
+            _target: target, lowerer, i,
+            regions: Vec::new(), perf: None,
+            #[cfg(feature = "gdb")]
+            gdb: None,
+            code_size: 0, code_address, live_code: 0, garbage: Vec::new(),
+        }
     }
 
-    /// Borrows the value of variable `global`.
-    pub fn global_mut(&mut self, global: Global) -> &mut Word {
-        &mut self.lowerer.pool_mut()[global]
+    /// Start reporting the compiled code to the Linux `perf` tool.
+    ///  - jitdump - `true` to write a jitdump file as well as a perf map.
+    ///
+    /// If a later write fails, reporting stops and the [`Observer`] receives
+    /// [`Event::PerfMapFailed`].
+    pub fn enable_perf_map(&mut self, jitdump: bool) -> io::Result<()> {
+        if self.perf.is_none() {
+            self.perf = Some(PerfMap::new(jitdump)?);
+        }
+        Ok(())
     }
 
+    /// Start reporting the compiled code to GDB.
+    #[cfg(feature = "gdb")]
+    pub fn enable_gdb(&mut self) {
+        if self.gdb.is_none() {
+            self.gdb = Some(GdbJit::new());
+        }
+    }
+
+    /// Sends [`Event`]s to `observer` from now on.
+    pub fn set_observer(&mut self, observer: impl Observer + Send + 'static) {
+        self.i.observer = Some(Box::new(observer));
+    }
+
+    /// Gives `id` a human-readable name, used for profiling and debugging.
+    pub fn set_name(&mut self, id: CaseId, name: impl Into<String>) {
+        self.i[id].name = Some(name.into());
+    }
+
+    /// Remembers that the code from `start` to the current assembly address
+    /// belongs to `case`. Returns the code.
+    fn add_region(&mut self, start: &Label, case: CaseId, role: Role) -> Range<usize> {
+        let start = start.target().expect("Label is not defined");
+        let end = self.lowerer.here().target().expect("Label is not defined");
+        if start < end {
+            self.regions.push(Region {start, end, case, role});
+            self.i.observe(Event::CodeEmitted {case, role, start, end});
+            self.live_code += end - start;
+        }
+        let code = self.lowerer.code();
+        let (size, address) = (code.len(), code.as_ptr() as usize);
+        if size != self.code_size {
+            let moved = address != self.code_address;
+            self.i.observe(Event::BufferGrown {old_size: self.code_size, new_size: size, moved});
+            self.code_size = size;
+            self.code_address = address;
+        }
+        start..end
+    }
+
+    /// Calls [`Internals::add_retire()`] and remembers the code it generates.
+    fn add_retire(&mut self, id: CaseId, retire: Retire) {
+        let start = self.lowerer.here();
+        self.i.add_retire(&mut self.lowerer, id, retire);
+        self.i[id].retire_code = self.add_region(&start, id, Role::Retire);
+    }
+
+    /// Calls [`Internals::add_fetch()`] and remembers the code it generates.
+    fn add_fetch(&mut self, id: CaseId, fetch: Fetch) {
+        let start = self.lowerer.here();
+        self.i.add_fetch(&mut self.lowerer, id, fetch);
+        self.i[id].fetch_code = self.add_region(&start, id, Role::Fetch);
+    }
+
+    /// Adds `ranges` to `self.garbage`, and releases the memory they occupy.
+    fn add_garbage(&mut self, ranges: &[Range<usize>]) {
+        let mut all = std::mem::take(&mut self.garbage);
+        all.extend(ranges.iter().cloned());
+        all.sort_by_key(|r| r.start);
+        for r in all {
+            match self.garbage.last_mut() {
+                Some(last) if r.start <= last.end => {
+                    last.end = std::cmp::max(last.end, r.end);
+                },
+                _ => self.garbage.push(r),
+            }
+        }
+        // Merging may have created new whole pages.
+        for r in &self.garbage {
+            if ranges.iter().any(|new| r.start <= new.start && new.end <= r.end) {
+                self.lowerer.release(r.start, r.end);
+            }
+        }
+    }
+
+    /// Returns the number of bytes of code that could still run. This excludes
+    /// code discarded by [`invalidate()`].
+    ///
+    /// [`invalidate()`]: Self::invalidate
+    pub fn live_code_size(&self) -> usize { self.live_code }
+
+    /// Returns the number of [`Global`]s in each [`Pool`].
+    ///
+    /// [`Global`]: super::code::Global
+    pub fn num_globals(&self) -> usize { self.lowerer.num_globals() }
+
     /// Define the code for case `id`.
     ///
     ///  - id - the case to modify.
     ///  - ebb - the extended basic block defining the desired behaviour.
     ///  - to_case - called for every leaf of the EBB to determine where to
     ///    jump to.
-    pub fn build<L: Clone + Debug>(
+    ///
+    /// Returns an [`Error`] without modifying `self` if `ebb` fails
+    /// [`verify()`] given the [`Convention`]s of `id` and of the `Case`s it
+    /// jumps to. Also returns an `Error` if the code buffer cannot grow, in
+    /// which case `self` cannot be used again: all later calls return
+    /// [`Error::Poisoned`].
+    pub fn try_build<L: Clone + Debug>(
         &mut self,
         id: CaseId,
         ebb: &EBB<L>,
         to_case: &impl Fn(L) -> CaseId,
-    ) {
+    ) -> Result<(), Error> {
+        self.lowerer.take_error()?;
+        self.i.check(id, ebb, to_case, T::NUM_REGISTERS)?;
+        self.i.observe(Event::OptimizerInput {case: id, ebb});
         let engine_wrapper = EngineWrapper {engine: &*self, to_case, _l: PhantomData};
         let ebb = optimize(self.i.convention(id), ebb, &engine_wrapper);
-        self.build_inner(id, &ebb, to_case)
+        self.i.observe(Event::OptimizerOutput {case: id, ebb: &ebb});
+        self.build_inner(id, &ebb, to_case);
+        self.lowerer.take_error()?;
+        self.i.observe(Event::CaseBuilt {case: id});
+        Ok(())
+    }
+
+    /// Like [`try_build()`], but panics on error.
+    ///
+    /// [`try_build()`]: Self::try_build
+    pub fn build<L: Clone + Debug>(
+        &mut self,
+        id: CaseId,
+        ebb: &EBB<L>,
+        to_case: &impl Fn(L) -> CaseId,
+    ) {
+        expect(self.try_build(id, ebb, to_case))
     }
 
     fn build_inner<L: Clone>(
@@ -265,7 +578,15 @@ impl<T: Target> Engine<T> {
         match &ebb.ending {
             Ending::Leaf(leaf) => {
                 let jump = to_case(leaf.clone());
-                self.i.add_retire(&mut self.lowerer, id, Retire {actions: ebb_actions, jump: Some(jump)});
+                let retire = Retire {actions: ebb_actions, jump: Some(jump)};
+                if self.i[id].retire.is_some() {
+                    // Keep the existing `Retire`, for `invalidate()`.
+                    let child = self.i.new_case(Some(id));
+                    self.add_retire(child, retire);
+                    self.add_fetch(id, Fetch {actions: Box::new([]), switch: Switch::Always(Box::new(child))});
+                } else {
+                    self.add_retire(id, retire);
+                }
             },
             Ending::Switch(switch) => {
                 let switch = switch.map(|child_ebb| {
@@ -273,30 +594,43 @@ impl<T: Target> Engine<T> {
                     self.build_inner(child, child_ebb, to_case);
                     child
                 });
-                self.i.add_fetch(&mut self.lowerer, id, Fetch {actions: ebb_actions, switch});
+                self.add_fetch(id, Fetch {actions: ebb_actions, switch});
             },
         }
     }
 
     /// Construct an entry to this [`Engine`]. Initially, the code at the
     /// entry will immediately return `exit_value`. To change this behaviour,
-    /// use [`build()`].
+    /// use [`try_build()`].
     ///
     ///  - marshal.prologue - executed on every entry to the compiled code.
     ///  - marshal.epilogue - executed on every exit from the compiled code.
     ///  - exit_value - returned to the caller on exit. Must be non-negative.
     ///
     /// Returns:
-    ///  - label - the external entry point, which can be passed to `run()`.
+    ///  - label - the external entry point, which can be passed to `try_run()`.
     ///  - id - the `CaseId` corresponding to the entry.
-    pub fn new_entry(&mut self, marshal: &Marshal, exit_value: i64) -> (Label, CaseId) {
-        assert!(exit_value >= 0);
+    ///
+    /// Returns an [`Error`] without modifying `self` if `exit_value` is
+    /// negative, or if `marshal.epilogue` does not drop exactly the spill
+    /// slots pushed by `marshal.prologue`. Like [`try_build()`], returns an
+    /// `Error` and poisons `self` if the code buffer cannot grow.
+    pub fn try_new_entry(&mut self, marshal: &Marshal, exit_value: i64) -> Result<(Label, CaseId), Error> {
+        self.lowerer.take_error()?;
+        if exit_value < 0 {
+            return Err(Error::NegativeExitValue(exit_value));
+        }
+        let slots_used = count_slots(0, &marshal.prologue)?;
+        let slots_used = count_slots(slots_used, &marshal.epilogue)?;
+        if slots_used != 0 {
+            return Err(Error::SlotsMismatch {expected: 0, found: slots_used});
+        }
         let id = self.i.new_case(None);
         // Compile the epilogue.
         let mut actions = Vec::new();
         actions.extend(marshal.epilogue.iter().copied());
         actions.push(Action::Constant(P64, RESULT, exit_value));
-        self.i.add_retire(&mut self.lowerer, id, Retire {actions: actions.into(), jump: None});
+        self.add_retire(id, Retire {actions: actions.into(), jump: None});
         // Compile the prologue.
         let lo = &mut self.lowerer;
         *lo.slots_used_mut() = 0;
@@ -305,8 +639,11 @@ impl<T: Target> Engine<T> {
         lo.actions(&marshal.prologue);
         assert_eq!(*lo.slots_used_mut(), self.i[id].convention().slots_used);
         lo.jump(&mut self.i[id].label);
+        self.add_region(&label, id, Role::Prologue);
+        self.lowerer.take_error()?;
+        self.i.observe(Event::EntryCreated {case: id, label: label.target().unwrap()});
         // Return.
-        (label, id)
+        Ok((label, id))
     }
 
     /// Returns a copy of the hot path starting at `id` up to the next
@@ -360,19 +697,101 @@ impl<T: Target> Engine<T> {
         }
     }
 
-    /// Call the compiled code starting at `label`, passing the [`Pool`].
+    /// Reverts `id` to its [`Retire`], discarding its [`Fetch`]. All the
+    /// descendants of `id` are freed, and the memory occupied by their code
+    /// is returned to the operating system where possible. Afterwards, `id`
+    /// can be built again.
+    ///
+    /// `id` must have both a `Retire` and a `Fetch`, and its descendants must
+    /// not be reachable except via `id`. For example, `id` could be an entry.
+    pub fn invalidate(&mut self, id: CaseId) {
+        // Report the code before it disappears.
+        self.flush();
+        let garbage = self.i.invalidate(&mut self.lowerer, id);
+        let num_bytes: usize = garbage.iter().map(|r| r.len()).sum();
+        self.live_code -= num_bytes;
+        // Forget the `Region`s, whose `CaseId`s might be reused.
+        let is_garbage = |r: &Region| garbage.iter().any(|g| g.start < r.end && r.start < g.end);
+        if let Some(perf) = &mut self.perf {
+            perf.remove(&self.regions, is_garbage);
+        }
+        #[cfg(feature = "gdb")]
+        if let Some(gdb) = &mut self.gdb {
+            gdb.remove(&self.regions, is_garbage);
+        }
+        self.regions.retain(|r| !is_garbage(r));
+        self.add_garbage(&garbage);
+        self.i.observe(Event::CaseInvalidated {case: id, num_bytes});
+    }
+
+    /// Reports any new code to `perf` and GDB, if enabled. Does nothing if
+    /// the code is not machine code, e.g. for [`interpret::Target`].
+    ///
+    /// [`interpret::Target`]: super::target::interpret::Target
+    fn flush(&mut self) {
+        let base = match self.lowerer.executable_address() {
+            Some(base) => base,
+            None => return,
+        };
+        if let Some(perf) = &mut self.perf {
+            let i = &self.i;
+            if let Err(error) = perf.flush(base, self.lowerer.code(), &self.regions, |id| i.name(id)) {
+                // Profiling is best-effort. Stop trying.
+                self.i.observe(Event::PerfMapFailed {error: &error});
+                self.perf = None;
+            }
+        }
+        #[cfg(feature = "gdb")]
+        if let Some(gdb) = &mut self.gdb {
+            let i = &self.i;
+            let lo = &self.lowerer;
+            gdb.flush(base, &self.regions, |id| i.name(id), |region| {
+                lo.call_frame_info(match region.role {
+                    Role::Prologue => None,
+                    Role::Retire | Role::Fetch => Some(i[region.case].convention().slots_used),
+                })
+            });
+        }
+    }
+
+    /// Call the compiled code starting at `label`, passing `pool`, which
+    /// holds the state of one virtual machine instance.
+    ///
+    /// Returns an [`Error`] without running the code if `pool` does not have
+    /// [`num_globals()`] `Global`s, or if an earlier `Error` left the code
+    /// incomplete.
     ///
     /// # Safety
     ///
     /// This will crash if the code is compiled for the wrong [`Target`] or if
     /// the code is invalid.
-    pub unsafe fn run(&mut self, label: &Label) -> Word {
-        self.lowerer.execute(label, |f, pool| {
+    ///
+    /// [`num_globals()`]: Self::num_globals
+    pub unsafe fn try_run(&mut self, pool: &mut Pool, label: &Label) -> Result<Word, Error> {
+        if pool.num_globals() != self.num_globals() {
+            return Err(Error::GlobalsMismatch {expected: self.num_globals(), found: pool.num_globals()});
+        }
+        self.lowerer.take_error()?;
+        self.flush();
+        self.lowerer.try_execute(label, |f| {
             let pool = pool.as_mut().as_mut_ptr();
             // Here is a good place to set a debugger breakpoint.
             f(pool)
         })
     }
+
+    /// Returns a pointer to the compiled code starting at `label`. It can be
+    /// called on any thread until `self` is dropped, passing the words of a
+    /// [`Pool`] with [`num_globals()`] `Global`s. Other threads see code
+    /// compiled later as soon as a jump to it is patched.
+    ///
+    /// [`num_globals()`]: Self::num_globals
+    pub fn entry_point(&mut self, label: &Label) -> ExecuteFn where T::Lowerer: EntryPoint {
+        expect(self.lowerer.take_error());
+        self.flush();
+        self.lowerer.publish();
+        self.lowerer.entry_point(label)
+    }
 }
 
 struct EngineWrapper<'a, T: Target, L: Clone, F: Fn(L) -> CaseId> {
@@ -392,3 +811,32 @@ impl<'a, T: Target, L: Clone, F: Fn(L) -> CaseId> LookupLeaf<L> for EngineWrappe
         1  // FIXME
     }
 }
+
+//-----------------------------------------------------------------------------
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use super::super::target::{native};
+    use super::super::code::{Global};
+
+    #[test]
+    pub fn invalidate_regions() {
+        let mut engine = Engine::new(native(), 1);
+        let marshal = Marshal {prologue: Box::new([]), epilogue: Box::new([])};
+        let (_, entry) = engine.try_new_entry(&marshal, 0).unwrap();
+        let (_, exit) = engine.try_new_entry(&marshal, 1).unwrap();
+        let leaf = || EBB {actions: vec![], ending: Ending::Leaf(exit)};
+        let ebb = EBB {actions: vec![], ending: Ending::Switch(Switch::if_(Global(0).into(), leaf(), leaf()))};
+        let num_unbuilt = engine.regions.len();
+        engine.build(entry, &ebb, &|c| c);
+        let num_built = engine.regions.len();
+        assert!(num_built > num_unbuilt);
+        for _ in 0..10 {
+            engine.invalidate(entry);
+            assert_eq!(engine.regions.len(), num_unbuilt);
+            engine.build(entry, &ebb, &|c| c);
+            assert_eq!(engine.regions.len(), num_built);
+        }
+    }
+}
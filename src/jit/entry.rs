@@ -1,7 +1,9 @@
 use crate::util::{AsUsize};
+use crate::error::{Error, expect};
 use super::{code, Engine, CaseId};
-use super::target::{Label, Word, Target};
-use code::{Global, Marshal, EBB};
+use super::observer::{Observer};
+use super::target::{Label, Word, Pool, ExecuteFn, EntryPoint, Target};
+use code::{Marshal, EBB};
 
 // EntryId.
 array_index! {
@@ -19,6 +21,8 @@ struct Entry {
     label: Label,
     case: CaseId,
     is_defined: bool,
+    /// The value of [`Jit::clock`] when this `Entry` was last defined or run.
+    last_used: u64,
 }
 
 //-----------------------------------------------------------------------------
@@ -28,6 +32,12 @@ pub struct Jit<T: Target> {
     engine: Engine<T>,
     /// Indexed by `EntryId`.
     entries: Vec<Entry>,
+    /// Counts calls to `define()` and `run()`.
+    clock: u64,
+    /// The maximum desirable [`live_code_size()`], if any.
+    ///
+    /// [`live_code_size()`]: Self::live_code_size
+    budget: Option<usize>,
 }
 
 // Use a macro, not a method, to keep the borrow-checker happy.
@@ -40,12 +50,59 @@ impl<T: Target> Jit<T> {
         Self {
             engine: Engine::new(target, num_globals),
             entries: Vec::new(),
+            clock: 0,
+            budget: None,
         }
     }
 
-    /// Borrows the value of variable `global`.
-    pub fn global_mut(&mut self, global: Global) -> &mut Word {
-        self.engine.global_mut(global)
+    /// Returns the number of [`Global`]s in each [`Pool`].
+    ///
+    /// [`Global`]: code::Global
+    pub fn num_globals(&self) -> usize { self.engine.num_globals() }
+
+    /// Constructs a [`Pool`] suitable for passing to [`run()`], with all
+    /// [`Global`]s zero. Each `Pool` holds the state of one virtual machine
+    /// instance; any number of instances can share the compiled code.
+    ///
+    /// [`run()`]: Self::run
+    /// [`Global`]: code::Global
+    pub fn new_pool(&self) -> Pool { Pool::new(self.num_globals()) }
+
+    /// Start reporting the compiled code to the Linux `perf` tool, by writing
+    /// `/tmp/perf-<pid>.map` and optionally a jitdump file.
+    /// Code is named after the [`EntryId`]s from which it is reachable, or
+    /// the names passed to [`name_entry()`]. Fails on platforms other than
+    /// unix.
+    ///
+    /// [`name_entry()`]: Self::name_entry
+    pub fn enable_perf_map(&mut self, jitdump: bool) -> std::io::Result<()> {
+        self.engine.enable_perf_map(jitdump)
+    }
+
+    /// Start reporting the compiled code to GDB, using its JIT interface.
+    /// Code is named in the same way as for [`enable_perf_map()`].
+    ///
+    /// Requires the `gdb` feature, which is off by default because it exports
+    /// the `__jit_debug_descriptor` and `__jit_debug_register_code` symbols
+    /// that GDB looks for. A process can contain only one definition of
+    /// each, so the feature must be off if another JIT defines them.
+    ///
+    /// [`enable_perf_map()`]: Self::enable_perf_map
+    #[cfg(feature = "gdb")]
+    pub fn enable_gdb(&mut self) {
+        self.engine.enable_gdb()
+    }
+
+    /// Sends [`Event`]s describing the compilation process to `observer`.
+    ///
+    /// [`Event`]: super::observer::Event
+    pub fn set_observer(&mut self, observer: impl Observer + Send + 'static) {
+        self.engine.set_observer(observer)
+    }
+
+    /// Gives `entry` a human-readable name, used for profiling and debugging.
+    pub fn name_entry(&mut self, entry: EntryId, name: impl Into<String>) {
+        self.engine.set_name(get!(self, entry).case, name);
     }
 
     /// Constructs a new entry/exit point. Initially, the code at the entry
@@ -55,32 +112,154 @@ impl<T: Target> Jit<T> {
     ///  - exit_value - `run()` will return this value to its caller if
     ///    execution ends at this entry/exit point. Must be non-negative.
     // TODO: Document `marshal` and `exit_value`.
-    pub fn new_entry(&mut self, marshal: &Marshal, exit_value: i64) -> EntryId {
-        let (label, case) = self.engine.new_entry(marshal, exit_value);
+    pub fn try_new_entry(&mut self, marshal: &Marshal, exit_value: i64) -> Result<EntryId, Error> {
+        let (label, case) = self.engine.try_new_entry(marshal, exit_value)?;
         let id = EntryId::new(self.entries.len()).unwrap();
-        self.entries.push(Entry {label, case, is_defined: false});
-        id
+        self.engine.set_name(case, format!("{:?}", id));
+        self.entries.push(Entry {label, case, is_defined: false, last_used: 0});
+        Ok(id)
     }
 
-    /// Replace the code at `entry`. Each `EntryId` may only be defined once.
+    /// Like [`try_new_entry()`], but panics on error.
+    ///
+    /// [`try_new_entry()`]: Self::try_new_entry
+    pub fn new_entry(&mut self, marshal: &Marshal, exit_value: i64) -> EntryId {
+        expect(self.try_new_entry(marshal, exit_value))
+    }
+
+    /// Replace the code at `entry`. Each `EntryId` may only be defined once,
+    /// unless it is [`invalidate()`]d.
     ///
     ///  - entry - the entry point to modify.
     ///  - ebb - the extended basic block defining the desired behaviour.
-    pub fn define(&mut self, entry: EntryId, ebb: &EBB<EntryId>) {
-        assert!(!get!(self, entry).is_defined);
-        self.engine.build(get!(self, entry).case, ebb, &|e| get!(self, e).case);
+    ///
+    /// On error, `entry` is unchanged. However, if the code buffer could not
+    /// grow, the `Jit` cannot be used again: all later calls return
+    /// [`Error::Poisoned`].
+    ///
+    /// [`invalidate()`]: Self::invalidate
+    pub fn try_define(&mut self, entry: EntryId, ebb: &EBB<EntryId>) -> Result<(), Error> {
+        if get!(self, entry).is_defined {
+            return Err(Error::AlreadyDefined(entry));
+        }
+        self.engine.try_build(get!(self, entry).case, ebb, &|e| get!(self, e).case)?;
         get!(self, entry).is_defined = true;
+        self.touch(entry);
+        self.evict(Some(entry));
+        Ok(())
+    }
+
+    /// Like [`try_define()`], but panics on error.
+    ///
+    /// [`try_define()`]: Self::try_define
+    pub fn define(&mut self, entry: EntryId, ebb: &EBB<EntryId>) {
+        expect(self.try_define(entry, ebb))
+    }
+
+    /// Tests whether `entry` has been defined (and not since invalidated).
+    pub fn is_defined(&self, entry: EntryId) -> bool {
+        get!(self, entry).is_defined
+    }
+
+    /// Forgets the definition of `entry`, if any, and frees the code compiled
+    /// for it. Afterwards, the code at `entry` will immediately exit,
+    /// returning its `exit_value`, as if it had never been defined. Use
+    /// `define()` to define it again.
+    pub fn invalidate(&mut self, entry: EntryId) {
+        if get!(self, entry).is_defined {
+            self.engine.invalidate(get!(self, entry).case);
+            get!(self, entry).is_defined = false;
+        }
+    }
+
+    /// Returns the number of bytes of compiled code that could still run.
+    pub fn live_code_size(&self) -> usize {
+        self.engine.live_code_size()
+    }
+
+    /// Sets the maximum desirable [`live_code_size()`]. While it is exceeded,
+    /// the least recently defined or run entries are [`invalidate()`]d.
+    /// Callers should be prepared to define them again when they exit.
+    /// `None` means no limit.
+    ///
+    /// [`live_code_size()`]: Self::live_code_size
+    /// [`invalidate()`]: Self::invalidate
+    pub fn set_code_budget(&mut self, budget: Option<usize>) {
+        self.budget = budget;
+        self.evict(None);
+    }
+
+    /// Records that `entry` has just been used.
+    fn touch(&mut self, entry: EntryId) {
+        self.clock += 1;
+        get!(self, entry).last_used = self.clock;
+    }
+
+    /// Invalidates the least recently used entries other than `keep` until
+    /// the code budget is met, or until there are no more candidates.
+    fn evict(&mut self, keep: Option<EntryId>) {
+        if let Some(budget) = self.budget {
+            while self.live_code_size() > budget {
+                let victim = (0..self.entries.len())
+                    .map(|i| EntryId::new(i).unwrap())
+                    .filter(|&e| get!(self, e).is_defined && Some(e) != keep)
+                    .min_by_key(|&e| get!(self, e).last_used);
+                if let Some(victim) = victim {
+                    self.invalidate(victim);
+                } else {
+                    break;
+                }
+            }
+        }
     }
 
-    /// Call the compiled code starting at `entry`.
+    /// Call the compiled code starting at `entry`, passing `pool`, which
+    /// holds the state of one virtual machine instance.
+    ///
+    /// Returns an [`Error`] without running the code if `pool` does not have
+    /// [`num_globals()`] `Global`s, or if an earlier `Error` left the code
+    /// incomplete.
     ///
     /// # Safety
     ///
     /// This will crash if the code is compiled for the wrong [`Target`] or if
     /// the code is invalid.
-    pub unsafe fn run(&mut self, entry: EntryId) -> Word {
+    ///
+    /// [`num_globals()`]: Self::num_globals
+    pub unsafe fn try_run(&mut self, pool: &mut Pool, entry: EntryId) -> Result<Word, Error> {
+        self.touch(entry);
         let label = &get!(self, entry).label;
-        self.engine.run(label)
+        self.engine.try_run(pool, label)
+    }
+
+    /// Like [`try_run()`], but panics on error.
+    ///
+    /// # Safety
+    ///
+    /// See [`try_run()`].
+    ///
+    /// [`try_run()`]: Self::try_run
+    pub unsafe fn run(&mut self, pool: &mut Pool, entry: EntryId) -> Word {
+        expect(self.try_run(pool, entry))
+    }
+
+    /// Returns a pointer to the code at `entry`, which other threads can call
+    /// while `self` compiles more code. Each call must pass the words of a
+    /// [`Pool`] that no other thread is using. The pointer is valid until
+    /// `self` is dropped.
+    ///
+    /// Code compiled by [`define()`] becomes visible to running threads
+    /// atomically. This is not available on RISC-V, which cannot patch its
+    /// jumps atomically.
+    /// Similarly, [`invalidate()`] and [`set_code_budget()`] discard code,
+    /// and must not be used while other threads might be running it.
+    ///
+    /// [`define()`]: Self::define
+    /// [`invalidate()`]: Self::invalidate
+    /// [`set_code_budget()`]: Self::set_code_budget
+    pub fn entry_point(&mut self, entry: EntryId) -> ExecuteFn where T::Lowerer: EntryPoint {
+        let label = &get!(self, entry).label;
+        self.engine.entry_point(label)
     }
 }
 
@@ -88,7 +267,10 @@ impl<T: Target> Jit<T> {
 
 #[cfg(test)]
 pub mod tests {
-    use super::super::target::{native};
+    use super::*;
+    use super::super::target::{native, Native};
+    use code::{Global, Action, Ending, Problem, REGISTERS};
+    use Action::*;
 
     use super::super::factorial::*;
 
@@ -98,4 +280,153 @@ pub mod tests {
         let result = jit.run(5);
         assert_eq!(result, 120);
     }
+
+    #[test]
+    pub fn errors() {
+        let marshal = Marshal {prologue: Box::new([]), epilogue: Box::new([])};
+        let mut factorial = Factorial::new(native());
+        let jit = &mut factorial.jit;
+        assert!(matches!(jit.try_new_entry(&marshal, -1), Err(Error::NegativeExitValue(-1))));
+        let unbalanced = Marshal {prologue: Box::new([Push(None, None)]), epilogue: Box::new([])};
+        assert!(matches!(jit.try_new_entry(&unbalanced, 3), Err(Error::SlotsMismatch {expected: 0, found: 2})));
+        let ebb = EBB {actions: vec![], ending: Ending::Leaf(factorial.start)};
+        assert!(matches!(jit.try_define(factorial.start, &ebb), Err(Error::AlreadyDefined(_))));
+        let entry = jit.new_entry(&marshal, 3);
+        let problem = |jit: &mut Jit<_>, actions| match jit.try_define(entry, &EBB {actions, ending: Ending::Leaf(entry)}) {
+            Err(Error::Invalid(diagnostics)) => diagnostics[0].problem,
+            result => panic!("{:?}", result),
+        };
+        assert_eq!(problem(jit, vec![Pop(None, None)]), Problem::StackUnderflow);
+        assert_eq!(problem(jit, vec![Push(None, None)]), Problem::SlotsMismatch {expected: 0, found: 2});
+        assert_eq!(problem(jit, vec![Move(Global(0).into(), REGISTERS[0].into())]), Problem::DeadRead);
+        // After all those errors, `entry` can still be defined.
+        assert!(jit.try_define(entry, &EBB {actions: vec![], ending: Ending::Leaf(factorial.start)}).is_ok());
+        assert_eq!(factorial.run(5), 120);
+    }
+
+    /// Fills the code buffer of a `Jit` for `target`, then checks that it is
+    /// poisoned. The code is never run, so `target` need not be native.
+    fn out_of_memory(target: impl Target) {
+        let marshal = Marshal {prologue: Box::new([]), epilogue: Box::new([])};
+        let mut jit = Jit::new(target, 0);
+        let entry = jit.new_entry(&marshal, 0);
+        let error = (1..).find_map(|i| jit.try_new_entry(&marshal, i).err()).unwrap();
+        assert!(matches!(error, Error::OutOfMemory), "{:?}", error);
+        assert!(matches!(jit.try_new_entry(&marshal, 0), Err(Error::Poisoned)));
+        let ebb = EBB {actions: vec![], ending: Ending::Leaf(entry)};
+        assert!(matches!(jit.try_define(entry, &ebb), Err(Error::Poisoned)));
+    }
+
+    #[test]
+    pub fn out_of_memory_all_targets() {
+        use super::super::target::{x86_64, aarch64, riscv64};
+        use crate::buffer::tests::{Limited};
+        out_of_memory(x86_64::BufferTarget::<Limited>::default());
+        out_of_memory(aarch64::BufferTarget::<Limited>::default());
+        out_of_memory(riscv64::BufferTarget::<Limited>::default());
+    }
+
+    #[test]
+    pub fn instances() {
+        let mut factorial = Factorial::new(native());
+        let jit = &mut factorial.jit;
+        let mut pools: Vec<Pool> = (0..3).map(|_| jit.new_pool()).collect();
+        for (n, pool) in pools.iter_mut().enumerate() {
+            pool[Global(0)] = Word {u: n as u64 + 3};
+            pool[Global(1)] = Word {u: 1};
+        }
+        for pool in &mut pools {
+            assert_eq!(unsafe { jit.run(pool, factorial.loop_) }, Word {s: HALT});
+        }
+        let results: Vec<u64> = pools.iter().map(|pool| unsafe { pool[Global(1)].u }).collect();
+        assert_eq!(results, [6, 24, 120]);
+        // The state of `factorial.pool` is unaffected.
+        assert_eq!(factorial.pool.as_ref(), jit.new_pool().as_ref());
+        // A `Pool` of the wrong size is rejected.
+        let mut wrong = Pool::new(1);
+        assert!(matches!(
+            unsafe { jit.try_run(&mut wrong, factorial.start) },
+            Err(Error::GlobalsMismatch {expected: 2, found: 1}),
+        ));
+    }
+
+    #[test]
+    pub fn invalidate() {
+        let mut factorial = Factorial::new(native());
+        let live_code_size = factorial.jit.live_code_size();
+        let ebb = factorial.loop_ebb();
+        let jit = &mut factorial.jit;
+        jit.invalidate(factorial.loop_);
+        assert!(!jit.is_defined(factorial.loop_));
+        assert!(jit.live_code_size() < live_code_size);
+        factorial.pool[Global(0)] = Word {u: 5};
+        assert_eq!(unsafe { jit.run(&mut factorial.pool, factorial.start) }, Word {s: LOOP});
+        // Invalidating again does nothing.
+        let invalid_code_size = jit.live_code_size();
+        jit.invalidate(factorial.loop_);
+        assert_eq!(jit.live_code_size(), invalid_code_size);
+        for _ in 0..10 {
+            factorial.jit.define(factorial.loop_, &ebb);
+            assert_eq!(factorial.jit.live_code_size(), live_code_size);
+            assert_eq!(factorial.run(5), 120);
+            factorial.jit.invalidate(factorial.loop_);
+        }
+    }
+
+    #[test]
+    pub fn code_budget() {
+        let mut factorial = Factorial::new(native());
+        let ebb = factorial.loop_ebb();
+        let jit = &mut factorial.jit;
+        jit.invalidate(factorial.loop_);
+        jit.set_code_budget(Some(jit.live_code_size()));
+        assert!(jit.is_defined(factorial.start));
+        // Defining `loop_` exceeds the budget, so `start` is evicted.
+        jit.define(factorial.loop_, &ebb);
+        assert!(jit.is_defined(factorial.loop_));
+        assert!(!jit.is_defined(factorial.start));
+        assert_eq!(unsafe { jit.run(&mut factorial.pool, factorial.start) }, Word {s: START});
+        // A budget of zero evicts everything.
+        jit.set_code_budget(Some(0));
+        assert!(!jit.is_defined(factorial.loop_));
+    }
+
+    #[test]
+    pub fn send() {
+        fn is_send<T: Send>() {}
+        is_send::<Jit<Native>>();
+        #[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64", target_arch = "riscv64")))]
+        is_send::<Jit<super::super::target::tests::MemfdNative>>();
+    }
+
+    #[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+    #[test]
+    pub fn concurrent() {
+        use super::super::target::tests::{MemfdNative};
+        let mut factorial = Factorial::from_jit(Jit::new(MemfdNative::default(), 2));
+        let ebb = factorial.loop_ebb();
+        let jit = &mut factorial.jit;
+        jit.invalidate(factorial.loop_);
+        let f = jit.entry_point(factorial.start);
+        let pools: Vec<Pool> = (0..4).map(|_| jit.new_pool()).collect();
+        std::thread::scope(|scope| {
+            for mut pool in pools {
+                scope.spawn(move || loop {
+                    // Until `loop_` is defined, `start` exits at `loop_`.
+                    pool[Global(0)] = Word {u: 5};
+                    let exit_value = unsafe { f(pool.as_mut().as_mut_ptr()) };
+                    let result = unsafe { pool[Global(1)].u };
+                    if exit_value == (Word {s: HALT}) {
+                        assert_eq!(result, 120);
+                        break;
+                    }
+                    assert_eq!(exit_value, Word {s: LOOP});
+                    assert_eq!(result, 1);
+                });
+            }
+            // Let the threads run the old code for a while.
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            jit.define(factorial.loop_, &ebb);
+        });
+    }
 }
@@ -1,34 +1,58 @@
+//! An example [`Jit`] that computes factorials, used by the tests and the
+//! benchmarks.
+
 use super::code::*;
 use Action::*;
 use BinaryOp::*;
 use Precision::*;
-use super::target::{Target, Word};
+use super::target::{Target, Word, Pool};
 use super::{EntryId, Jit};
 
 const R0: Register = REGISTERS[0];
 
+/// The [`Global`]s used by [`Factorial`].
 pub mod reg {
     use super::{Global, Variable};
+    /// The number whose factorial is computed. It counts down to zero.
     pub const N: Variable = Variable::Global(Global(0));
+    /// The factorial, modulo `2^32`.
     pub const RESULT: Variable = Variable::Global(Global(1));
 }
 
-/// An example that can be used as a test fixture.
+/// A `Jit` that multiplies [`reg::RESULT`] by [`reg::N`] and decrements
+/// `N` until it is zero.
 #[derive(Debug)]
 pub struct Factorial<T: Target> {
     /// The state of the JIT compiler.
     pub jit: Jit<T>,
+    /// The state of the virtual machine.
+    pub pool: Pool,
     /// The entry point.
     pub start: EntryId,
+    /// The loop, defined by [`loop_ebb()`].
+    ///
+    /// [`loop_ebb()`]: Self::loop_ebb
+    pub loop_: EntryId,
+    /// The exit point.
+    pub halt: EntryId,
 }
 
-const START: i64 = 0;
-const LOOP: i64 = 1;
-const HALT: i64 = 2;
+/// Returned by [`Factorial::start`] if it is not defined.
+pub const START: i64 = 0;
+/// Returned by [`Factorial::loop_`] if it is not defined.
+pub const LOOP: i64 = 1;
+/// Returned by [`Factorial::halt`] if it is not defined.
+pub const HALT: i64 = 2;
 
 impl<T: Target> Factorial<T> {
+    /// Compiles the example for `target`.
     pub fn new(target: T) -> Factorial<T> {
-        let mut jit = Jit::new(target, 2);
+        Self::from_jit(Jit::new(target, 2))
+    }
+
+    /// Compiles the example using `jit`, which must be new and have two
+    /// `Global`s.
+    pub fn from_jit(mut jit: Jit<T>) -> Factorial<T> {
         let marshal = Marshal {prologue: Box::new([]), epilogue: Box::new([])};
         let start = jit.new_entry(&marshal, START);
         let loop_ = jit.new_entry(&marshal, LOOP);
@@ -40,7 +64,16 @@ impl<T: Target> Factorial<T> {
             ],
             ending: Ending::Leaf(loop_),
         });
-        jit.define(loop_, &EBB {
+        let pool = jit.new_pool();
+        let mut factorial = Factorial {jit, pool, start, loop_, halt};
+        let ebb = factorial.loop_ebb();
+        factorial.jit.define(loop_, &ebb);
+        factorial
+    }
+
+    /// Returns the definition of `self.loop_`.
+    pub fn loop_ebb(&self) -> EBB<EntryId> {
+        EBB {
             actions: vec![],
             ending: Ending::Switch(Switch::if_(
                 reg::N,
@@ -52,24 +85,25 @@ impl<T: Target> Factorial<T> {
                         Binary(Sub, P32, R0, reg::N, R0.into()),
                         Move(reg::N, R0.into()),
                     ],
-                    ending: Ending::Leaf(loop_),
+                    ending: Ending::Leaf(self.loop_),
                 },
                 EBB {
                     actions: vec![],
-                    ending: Ending::Leaf(halt),
+                    ending: Ending::Leaf(self.halt),
                 },
             )),
-        });
-        Factorial {jit, start}
+        }
     }
 
+    /// Returns the factorial of `n`, modulo `2^32`. Panics if the code does
+    /// not reach `self.halt`, e.g. because `self.loop_` is not defined.
     pub fn run(&mut self, n: u64) -> u64 {
         let n_global = Global::try_from(reg::N).unwrap();
         let result_global = Global::try_from(reg::RESULT).unwrap();
-        *self.jit.global_mut(n_global) = Word {u: n};
-        let exit_value = unsafe {self.jit.run(self.start)};
+        self.pool[n_global] = Word {u: n};
+        let exit_value = unsafe {self.jit.run(&mut self.pool, self.start)};
         assert_eq!(exit_value, Word {s: HALT});
-        let result = *self.jit.global_mut(result_global);
+        let result = self.pool[result_global];
         unsafe {result.u}
     }
 }
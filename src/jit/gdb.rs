@@ -0,0 +1,440 @@
+//! Tells GDB about the code that Mijit generates, using GDB's JIT interface.
+//!
+//! GDB sets a breakpoint on `__jit_debug_register_code()`. Each time it is
+//! called, GDB reads `__jit_debug_descriptor` to find an in-memory ELF object
+//! file that has been added or removed. The object files we make contain no
+//! code, only a symbol for each region of code, and call frame information
+//! (CFI) that allows GDB to unwind through Mijit's stack frames.
+//!
+//! See "JIT Compilation Interface" in the GDB manual.
+
+use std::ptr::{null_mut, addr_of_mut};
+use std::sync::{Mutex};
+
+use super::target::{CallFrameInfo, write_uleb128, write_sleb128};
+use super::perf::{ELF_MACHINE, Region};
+use super::{CaseId};
+
+//-----------------------------------------------------------------------------
+
+/// A node of the doubly-linked list of object files known to GDB.
+#[repr(C)]
+struct JitCodeEntry {
+    next_entry: *mut JitCodeEntry,
+    prev_entry: *mut JitCodeEntry,
+    symfile_addr: *const u8,
+    symfile_size: u64,
+}
+
+/// The value of `action_flag` between calls to `__jit_debug_register_code()`.
+const JIT_NOACTION: u32 = 0;
+
+/// The value of `action_flag` when `relevant_entry` has just been added.
+const JIT_REGISTER_FN: u32 = 1;
+
+/// The value of `action_flag` when `relevant_entry` has just been removed.
+const JIT_UNREGISTER_FN: u32 = 2;
+
+/// The type of `__jit_debug_descriptor`.
+#[repr(C)]
+pub struct JitDescriptor {
+    version: u32,
+    action_flag: u32,
+    relevant_entry: *mut JitCodeEntry,
+    first_entry: *mut JitCodeEntry,
+}
+
+/// GDB reads this to find our object files. The name and layout are fixed by
+/// GDB. Access it only while holding [`LOCK`].
+#[no_mangle]
+#[allow(non_upper_case_globals)]
+pub static mut __jit_debug_descriptor: JitDescriptor = JitDescriptor {
+    version: 1,
+    action_flag: JIT_NOACTION,
+    relevant_entry: null_mut(),
+    first_entry: null_mut(),
+};
+
+/// GDB sets a breakpoint on this function. The name is fixed by GDB.
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn __jit_debug_register_code() {
+    // Prevent the compiler from optimizing away calls.
+    unsafe { std::arch::asm!("", options(nostack, preserves_flags)) };
+}
+
+/// Serializes modifications of `__jit_debug_descriptor`.
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// An object file that has been passed to GDB.
+struct Registration {
+    entry: JitCodeEntry,
+    /// The object file. `entry` points into this.
+    _symfile: Box<[u8]>,
+}
+
+// The pointers in `entry` are only dereferenced while holding `LOCK`.
+unsafe impl Send for Registration {}
+
+impl Registration {
+    /// Adds `symfile` to the list, and tells GDB.
+    fn new(symfile: Box<[u8]>) -> Box<Self> {
+        let mut ret = Box::new(Registration {
+            entry: JitCodeEntry {
+                next_entry: null_mut(),
+                prev_entry: null_mut(),
+                symfile_addr: symfile.as_ptr(),
+                symfile_size: symfile.len() as u64,
+            },
+            _symfile: symfile,
+        });
+        let _lock = LOCK.lock().unwrap();
+        unsafe {
+            let descriptor = addr_of_mut!(__jit_debug_descriptor);
+            let entry: *mut JitCodeEntry = &mut ret.entry;
+            let first = (*descriptor).first_entry;
+            (*entry).next_entry = first;
+            if !first.is_null() { (*first).prev_entry = entry; }
+            (*descriptor).first_entry = entry;
+            (*descriptor).relevant_entry = entry;
+            (*descriptor).action_flag = JIT_REGISTER_FN;
+            __jit_debug_register_code();
+            (*descriptor).action_flag = JIT_NOACTION;
+        }
+        ret
+    }
+}
+
+impl Drop for Registration {
+    /// Removes `self` from the list, and tells GDB.
+    fn drop(&mut self) {
+        let _lock = LOCK.lock().unwrap();
+        unsafe {
+            let descriptor = addr_of_mut!(__jit_debug_descriptor);
+            let entry: *mut JitCodeEntry = &mut self.entry;
+            let (prev, next) = ((*entry).prev_entry, (*entry).next_entry);
+            if prev.is_null() {
+                (*descriptor).first_entry = next;
+            } else {
+                (*prev).next_entry = next;
+            }
+            if !next.is_null() { (*next).prev_entry = prev; }
+            (*descriptor).relevant_entry = entry;
+            (*descriptor).action_flag = JIT_UNREGISTER_FN;
+            __jit_debug_register_code();
+            (*descriptor).action_flag = JIT_NOACTION;
+        }
+    }
+}
+
+//-----------------------------------------------------------------------------
+
+/// Appends `value` to `out` in the byte order of the host.
+fn write_u16(out: &mut Vec<u8>, value: u16) { out.extend(value.to_ne_bytes()); }
+fn write_u32(out: &mut Vec<u8>, value: u32) { out.extend(value.to_ne_bytes()); }
+fn write_u64(out: &mut Vec<u8>, value: u64) { out.extend(value.to_ne_bytes()); }
+
+/// Appends `DW_CFA_nop`s to `out` until its length is a multiple of 8.
+fn align(out: &mut Vec<u8>) {
+    while out.len() & 7 != 0 { out.push(0); }
+}
+
+/// Appends `name` to a string table, and returns its offset.
+fn add_string(table: &mut Vec<u8>, name: &str) -> u32 {
+    let ret = table.len() as u32;
+    table.extend(name.as_bytes());
+    table.push(0);
+    ret
+}
+
+/// Appends a `.debug_frame` entry to `out`, with a length field that covers
+/// `body` plus padding.
+fn write_frame_entry(out: &mut Vec<u8>, body: &[u8]) {
+    let start = out.len();
+    write_u32(out, 0); // length
+    out.extend(body);
+    align(out);
+    let length = (out.len() - start - 4) as u32;
+    out[start..start+4].copy_from_slice(&length.to_ne_bytes());
+}
+
+/// Constructs the contents of a `.debug_frame` section.
+///  - frames - `(address, size, cfi)` for each function. All must share the
+///    same `return_address`, `code_alignment` and `data_alignment`.
+fn debug_frame(frames: &[(u64, u64, CallFrameInfo)]) -> Vec<u8> {
+    let mut out = Vec::new();
+    if let Some((_, _, first)) = frames.first() {
+        // CIE.
+        let mut cie = Vec::new();
+        write_u32(&mut cie, 0xFFFFFFFF); // CIE_id
+        cie.push(1); // version
+        cie.push(0); // augmentation
+        write_uleb128(&mut cie, first.code_alignment.into());
+        write_sleb128(&mut cie, first.data_alignment.into());
+        cie.push(first.return_address);
+        write_frame_entry(&mut out, &cie);
+        // FDEs.
+        for (address, size, cfi) in frames {
+            let mut fde = Vec::new();
+            write_u32(&mut fde, 0); // CIE_pointer
+            write_u64(&mut fde, *address);
+            write_u64(&mut fde, *size);
+            fde.extend(&cfi.instructions);
+            write_frame_entry(&mut out, &fde);
+        }
+    }
+    out
+}
+
+/// Constructs an ELF object file containing no code, but describing code at
+/// `text_address`.
+///  - text_address - the address of the code.
+///  - text_size - the size of the code.
+///  - symbols - `(name, address, size)` for each function.
+///  - frames - passed to [`debug_frame()`].
+fn symfile(
+    text_address: u64,
+    text_size: u64,
+    symbols: &[(String, u64, u64)],
+    frames: &[(u64, u64, CallFrameInfo)],
+) -> Vec<u8> {
+    const EHDR_SIZE: usize = 64;
+    const SHDR_SIZE: usize = 64;
+    const SYM_SIZE: usize = 24;
+    // Section indices.
+    const TEXT: u16 = 1;
+    const STRTAB: u32 = 4;
+    const SHSTRTAB: u16 = 5;
+    const NUM_SECTIONS: u16 = 6;
+    // Construct the section contents.
+    let debug_frame = debug_frame(frames);
+    let mut strtab = vec![0];
+    let mut symtab = vec![0; SYM_SIZE];
+    for (name, address, size) in symbols {
+        let name = add_string(&mut strtab, name);
+        write_u32(&mut symtab, name);
+        symtab.push(0x12); // STB_GLOBAL, STT_FUNC
+        symtab.push(0); // STV_DEFAULT
+        write_u16(&mut symtab, TEXT);
+        write_u64(&mut symtab, *address);
+        write_u64(&mut symtab, *size);
+    }
+    let mut shstrtab = vec![0];
+    let text_name = add_string(&mut shstrtab, ".text");
+    let debug_frame_name = add_string(&mut shstrtab, ".debug_frame");
+    let symtab_name = add_string(&mut shstrtab, ".symtab");
+    let strtab_name = add_string(&mut shstrtab, ".strtab");
+    let shstrtab_name = add_string(&mut shstrtab, ".shstrtab");
+    // Lay out the file.
+    let mut out = vec![0; EHDR_SIZE];
+    let mut place = |data: &[u8]| {
+        let offset = out.len();
+        out.extend(data);
+        align(&mut out);
+        (offset as u64, data.len() as u64)
+    };
+    let debug_frame = place(&debug_frame);
+    let symtab = place(&symtab);
+    let strtab = place(&strtab);
+    let shstrtab = place(&shstrtab);
+    let shoff = out.len();
+    // Write the section headers.
+    let mut section = |name, type_, flags, address, (offset, size), link, info, align, entsize| {
+        write_u32(&mut out, name);
+        write_u32(&mut out, type_);
+        write_u64(&mut out, flags);
+        write_u64(&mut out, address);
+        write_u64(&mut out, offset);
+        write_u64(&mut out, size);
+        write_u32(&mut out, link);
+        write_u32(&mut out, info);
+        write_u64(&mut out, align);
+        write_u64(&mut out, entsize);
+    };
+    section(0, 0, 0, 0, (0, 0), 0, 0, 0, 0);
+    section(text_name, 8, 6, text_address, (0, text_size), 0, 0, 16, 0); // SHT_NOBITS, SHF_ALLOC | SHF_EXECINSTR
+    section(debug_frame_name, 1, 0, 0, debug_frame, 0, 0, 8, 0); // SHT_PROGBITS
+    section(symtab_name, 2, 0, 0, symtab, STRTAB, 1, 8, SYM_SIZE as u64); // SHT_SYMTAB
+    section(strtab_name, 3, 0, 0, strtab, 0, 0, 1, 0); // SHT_STRTAB
+    section(shstrtab_name, 3, 0, 0, shstrtab, 0, 0, 1, 0); // SHT_STRTAB
+    // Write the ELF header.
+    let mut header = Vec::with_capacity(EHDR_SIZE);
+    header.extend(b"\x7FELF");
+    header.push(2); // ELFCLASS64
+    header.push(if cfg!(target_endian = "little") { 1 } else { 2 });
+    header.push(1); // EV_CURRENT
+    header.extend([0; 9]); // OS ABI and padding
+    write_u16(&mut header, 2); // ET_EXEC
+    write_u16(&mut header, ELF_MACHINE as u16);
+    write_u32(&mut header, 1); // EV_CURRENT
+    write_u64(&mut header, 0); // e_entry
+    write_u64(&mut header, 0); // e_phoff
+    write_u64(&mut header, shoff as u64);
+    write_u32(&mut header, 0); // e_flags
+    write_u16(&mut header, EHDR_SIZE as u16);
+    write_u16(&mut header, 56); // e_phentsize
+    write_u16(&mut header, 0); // e_phnum
+    write_u16(&mut header, SHDR_SIZE as u16);
+    write_u16(&mut header, NUM_SECTIONS);
+    write_u16(&mut header, SHSTRTAB);
+    out[..EHDR_SIZE].copy_from_slice(&header);
+    out
+}
+
+//-----------------------------------------------------------------------------
+
+/// Reports to GDB the [`Region`]s of code that an [`Engine`] has generated.
+///
+/// Like [`PerfMap`], regions are reported lazily, by `flush()`, which the
+/// `Engine` calls before running the code. Each call to `flush()` that has
+/// something to report makes one new object file. If the code has moved, or
+/// if reported code has been discarded, all the object files are withdrawn
+/// and all remaining `Region`s are reported again.
+///
+/// [`Engine`]: super::Engine
+/// [`PerfMap`]: super::perf::PerfMap
+#[derive(Default)]
+pub struct GdbJit {
+    /// The base address of the code at the last call to `flush()`.
+    base: Option<usize>,
+    /// The number of `Region`s that have been reported at `base`.
+    num_flushed: usize,
+    /// The object files that describe the `Region`s reported at `base`.
+    /// They are boxed because GDB holds pointers to them.
+    #[allow(clippy::vec_box)]
+    registrations: Vec<Box<Registration>>,
+}
+
+impl std::fmt::Debug for GdbJit {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        f.debug_struct("GdbJit")
+            .field("base", &self.base)
+            .field("num_flushed", &self.num_flushed)
+            .field("num_registrations", &self.registrations.len())
+            .finish()
+    }
+}
+
+impl GdbJit {
+    pub fn new() -> Self { Default::default() }
+
+    /// Reports all `Region`s that have not yet been reported at `base`.
+    ///  - base - the address at which the code runs.
+    ///  - regions - every `Region` of the code, in the order it was
+    ///    generated. Each call must pass the list passed to the previous call,
+    ///    minus any `Region`s passed to `remove()`, followed by any new
+    ///    `Region`s.
+    ///  - name - returns a name for a `Case`.
+    ///  - cfi - describes the stack frame of a `Region`, if possible.
+    pub fn flush(
+        &mut self,
+        base: usize,
+        regions: &[Region],
+        name: impl Fn(CaseId) -> String,
+        cfi: impl Fn(&Region) -> Option<CallFrameInfo>,
+    ) {
+        if self.base != Some(base) {
+            // The code has moved. Report everything again.
+            self.base = Some(base);
+            self.num_flushed = 0;
+            self.registrations.clear();
+        }
+        let new_regions = &regions[self.num_flushed..];
+        if new_regions.is_empty() {
+            return;
+        }
+        let start = new_regions.iter().map(|r| r.start).min().unwrap();
+        let end = new_regions.iter().map(|r| r.end).max().unwrap();
+        let mut symbols = Vec::new();
+        let mut frames = Vec::new();
+        for region in new_regions {
+            let address = (base + region.start) as u64;
+            let size = (region.end - region.start) as u64;
+            symbols.push((format!("mijit:{}:{:?}", name(region.case), region.role), address, size));
+            if let Some(cfi) = cfi(region) {
+                frames.push((address, size, cfi));
+            }
+        }
+        let symfile = symfile((base + start) as u64, (end - start) as u64, &symbols, &frames);
+        self.registrations.push(Registration::new(symfile.into()));
+        self.num_flushed = regions.len();
+    }
+
+    /// Forgets the `Region`s for which `removed` returns `true`, which the
+    /// caller is about to remove from `regions`. If any of them have been
+    /// reported, all the object files are withdrawn, and the remaining
+    /// `Region`s will be reported again by the next call to `flush()`.
+    pub fn remove(&mut self, regions: &[Region], removed: impl Fn(&Region) -> bool) {
+        if regions[..self.num_flushed].iter().any(removed) {
+            self.num_flushed = 0;
+            self.registrations.clear();
+        }
+    }
+}
+
+//-----------------------------------------------------------------------------
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use super::super::target::{native};
+    use super::super::factorial::*;
+
+    /// Returns a copy of every object file currently known to GDB.
+    fn symfiles() -> Vec<Vec<u8>> {
+        let _lock = LOCK.lock().unwrap();
+        let mut ret = Vec::new();
+        unsafe {
+            let mut entry = (*addr_of_mut!(__jit_debug_descriptor)).first_entry;
+            while !entry.is_null() {
+                let e = &*entry;
+                ret.push(std::slice::from_raw_parts(e.symfile_addr, e.symfile_size as usize).to_vec());
+                entry = e.next_entry;
+            }
+        }
+        ret
+    }
+
+    /// Returns `true` if `haystack` contains `needle`.
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|w| w == needle)
+    }
+
+    #[test]
+    pub fn register() {
+        let needle = b"mijit:gdb_factorial_start:Prologue\0";
+        let mut factorial = Factorial::new(native());
+        factorial.jit.name_entry(factorial.start, "gdb_factorial_start");
+        factorial.jit.enable_gdb();
+        assert_eq!(factorial.run(5), 120);
+        let registered: Vec<_> = symfiles().into_iter().filter(|s| contains(s, needle)).collect();
+        assert_eq!(registered.len(), 1);
+        let symfile = &registered[0];
+        assert_eq!(&symfile[0..4], b"\x7FELF");
+        assert!(contains(symfile, b".debug_frame\0"));
+        assert!(contains(symfile, b"mijit:EntryId(1)/CaseId("));
+        drop(factorial);
+        assert!(!symfiles().iter().any(|s| contains(s, needle)));
+    }
+
+    #[test]
+    pub fn invalidate() {
+        let needle = b"mijit:gdb_factorial_loop";
+        let count = || symfiles().iter().map(|s| {
+            s.windows(needle.len()).filter(|&w| w == needle).count()
+        }).sum::<usize>();
+        let mut factorial = Factorial::new(native());
+        factorial.jit.name_entry(factorial.loop_, "gdb_factorial_loop");
+        factorial.jit.enable_gdb();
+        assert_eq!(factorial.run(5), 120);
+        let expected = count();
+        for _ in 0..3 {
+            // Discarded code is withdrawn, and replaced by the new code.
+            let ebb = factorial.loop_ebb();
+            factorial.jit.invalidate(factorial.loop_);
+            factorial.jit.define(factorial.loop_, &ebb);
+            assert_eq!(factorial.run(5), 120);
+            assert_eq!(count(), expected);
+        }
+    }
+}
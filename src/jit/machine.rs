@@ -0,0 +1,97 @@
+use std::collections::{HashMap};
+
+use super::{code, Jit, EntryId};
+use super::target::{Pool, Target};
+use code::{Machine, EBB, Ending};
+
+/// The exit value of the entries for [`Machine::State`]s. It is only
+/// returned if an entry is [`invalidate()`]d.
+///
+/// [`invalidate()`]: Jit::invalidate
+const UNDEFINED: i64 = i64::MAX;
+
+/// Compiles a [`Machine`] using a [`Jit`].
+///
+/// Each `State` reachable from [`Machine::initial_states()`] becomes an entry
+/// defined by [`Machine::code()`]. Each transition to a `Trap` jumps to an
+/// entry that exits, using the [`Marshal`] of the `State` it came from.
+///
+/// [`Marshal`]: code::Marshal
+#[derive(Debug)]
+pub struct MachineJit<M: Machine, T: Target> {
+    /// The specification of the virtual machine.
+    machine: M,
+    /// The compiled code.
+    jit: Jit<T>,
+    /// The entry for each reachable `State`.
+    states: HashMap<M::State, EntryId>,
+    /// The `Trap` corresponding to each exit value.
+    traps: Vec<M::Trap>,
+}
+
+impl<M: Machine, T: Target> MachineJit<M, T> {
+    /// Compiles `machine` for `target`.
+    pub fn new(machine: M, target: T) -> Self {
+        let mut jit = Jit::new(target, machine.num_globals());
+        let mut states = HashMap::new();
+        let mut traps = Vec::new();
+        let mut todo = Vec::new();
+        for state in machine.initial_states() {
+            states.entry(state.clone()).or_insert_with(|| {
+                todo.push(state.clone());
+                jit.new_entry(&machine.marshal(state), UNDEFINED)
+            });
+        }
+        // Make all the entries and exits before defining any of them.
+        let mut exits = HashMap::new();
+        let mut definitions = Vec::new();
+        while let Some(state) = todo.pop() {
+            let marshal = machine.marshal(state.clone());
+            let switch = machine.code(state.clone()).map(|case| {
+                let target = match case.new_state {
+                    Ok(ref new_state) => *states.entry(new_state.clone()).or_insert_with(|| {
+                        todo.push(new_state.clone());
+                        jit.new_entry(&machine.marshal(new_state.clone()), UNDEFINED)
+                    }),
+                    Err(ref trap) => *exits.entry((state.clone(), trap.clone())).or_insert_with(|| {
+                        traps.push(trap.clone());
+                        jit.new_entry(&marshal, (traps.len() - 1) as i64)
+                    }),
+                };
+                EBB {actions: case.actions.clone(), ending: Ending::Leaf(target)}
+            });
+            definitions.push((states[&state], EBB {actions: Vec::new(), ending: Ending::Switch(switch)}));
+        }
+        for (entry, ebb) in definitions {
+            jit.define(entry, &ebb);
+        }
+        Self {machine, jit, states, traps}
+    }
+
+    /// Returns the specification of the virtual machine.
+    pub fn machine(&self) -> &M { &self.machine }
+
+    /// Returns the underlying [`Jit`], e.g. to enable profiling.
+    pub fn jit_mut(&mut self) -> &mut Jit<T> { &mut self.jit }
+
+    /// Constructs a [`Pool`] suitable for passing to [`run()`].
+    ///
+    /// [`run()`]: Self::run
+    pub fn new_pool(&self) -> Pool { self.jit.new_pool() }
+
+    /// Runs the virtual machine whose [`Global`]s are in `pool`, starting in
+    /// `state`, until it reaches a `Trap`, and returns the `Trap`.
+    ///
+    /// # Safety
+    ///
+    /// The compiled code can do anything that `machine` does, including
+    /// accessing memory.
+    ///
+    /// [`Global`]: code::Global
+    pub unsafe fn run(&mut self, pool: &mut Pool, state: &M::State) -> M::Trap {
+        let entry = *self.states.get(state).expect("Unreachable State");
+        let exit_value = self.jit.run(pool, entry).s;
+        assert_ne!(exit_value, UNDEFINED, "An entry was invalidated");
+        self.traps[exit_value as usize].clone()
+    }
+}
@@ -1,10 +1,23 @@
 use super::{code, target, optimizer};
 
 mod engine;
-use engine::{Engine, CaseId};
+pub use engine::{CaseId};
+use engine::{Engine};
 
 mod entry;
 pub use entry::{Jit, EntryId};
 
-#[cfg(test)]
+mod background;
+pub use background::{Background};
+
+mod machine;
+pub use machine::{MachineJit};
+
+pub mod perf;
+
+#[cfg(feature = "gdb")]
+pub mod gdb;
+
+pub mod observer;
+
 pub mod factorial;
@@ -0,0 +1,296 @@
+//! Reports what an [`Engine`] compiles, and when.
+//!
+//! [`Engine`]: super::Engine
+
+use std::fmt::{Debug};
+use std::io::{self, Write};
+
+use crate::util::{AsUsize};
+use super::perf::{Role};
+use super::{CaseId};
+
+//-----------------------------------------------------------------------------
+
+/// Something that happened inside an [`Engine`].
+///
+/// Addresses are expressed as byte offsets into the compiled code.
+///
+/// [`Engine`]: super::Engine
+#[derive(Debug, Copy, Clone)]
+pub enum Event<'a> {
+    /// A new entry point was created at `label`, with its own `case`.
+    EntryCreated {case: CaseId, label: usize},
+    /// The code for `case` was replaced by `build()`.
+    CaseBuilt {case: CaseId},
+    /// A `Retire` was compiled for `case`.
+    RetireAdded {case: CaseId, num_actions: usize, jump: Option<CaseId>},
+    /// A `Fetch` was compiled for `case`, with a `Switch` to `children`.
+    FetchAdded {case: CaseId, num_actions: usize, children: &'a [CaseId]},
+    /// All jumps to `case` were redirected from `old_target` to `new_target`.
+    LabelStolen {case: CaseId, old_target: Option<usize>, new_target: Option<usize>},
+    /// The jump instruction at `address` was modified.
+    Patched {address: usize, old_target: Option<usize>, new_target: Option<usize>},
+    /// The optimizer was passed `ebb`, the desired behaviour of `case`.
+    OptimizerInput {case: CaseId, ebb: &'a dyn Debug},
+    /// The optimizer returned `ebb`, which will be compiled for `case`.
+    OptimizerOutput {case: CaseId, ebb: &'a dyn Debug},
+    /// The code from `start` to `end` was generated for `case`.
+    CodeEmitted {case: CaseId, role: Role, start: usize, end: usize},
+    /// The buffer holding the compiled code grew from `old_size` to
+    /// `new_size` bytes. `moved` is `true` if the code is now at a different
+    /// address, e.g. because the buffer was reallocated.
+    BufferGrown {old_size: usize, new_size: usize, moved: bool},
+    /// The `Fetch` of `case` and all its descendants were discarded, freeing
+    /// `num_bytes` of code.
+    CaseInvalidated {case: CaseId, num_bytes: usize},
+    /// Writing the perf map or jitdump file failed with `error`, so the
+    /// `Engine` has stopped reporting code to `perf`.
+    PerfMapFailed {error: &'a io::Error},
+}
+
+/// Receives [`Event`]s from an [`Engine`].
+///
+/// [`Engine`]: super::Engine
+pub trait Observer {
+    /// Called after `event` happens.
+    fn event(&mut self, event: &Event);
+}
+
+impl Debug for dyn Observer + Send {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        f.write_str("Observer")
+    }
+}
+
+//-----------------------------------------------------------------------------
+
+/// Returns `s` as a JSON string literal.
+fn json_string(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len() + 2);
+    ret.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => ret.push_str("\\\""),
+            '\\' => ret.push_str("\\\\"),
+            '\n' => ret.push_str("\\n"),
+            c if (c as u32) < 0x20 => ret.push_str(&format!("\\u{:04x}", c as u32)),
+            c => ret.push(c),
+        }
+    }
+    ret.push('"');
+    ret
+}
+
+/// Returns `x` as a JSON number, or `null`.
+fn json_option(x: Option<usize>) -> String {
+    x.map_or_else(|| "null".into(), |x| x.to_string())
+}
+
+impl<'a> Event<'a> {
+    /// Returns `self` as a single-line JSON object.
+    pub fn to_json(&self) -> String {
+        use Event::*;
+        let fields = match *self {
+            EntryCreated {case, label} => format!(
+                "\"case\":{},\"label\":{}",
+                case.as_usize(), label,
+            ),
+            CaseBuilt {case} => format!(
+                "\"case\":{}",
+                case.as_usize(),
+            ),
+            RetireAdded {case, num_actions, jump} => format!(
+                "\"case\":{},\"num_actions\":{},\"jump\":{}",
+                case.as_usize(), num_actions, json_option(jump.map(CaseId::as_usize)),
+            ),
+            FetchAdded {case, num_actions, children} => format!(
+                "\"case\":{},\"num_actions\":{},\"children\":[{}]",
+                case.as_usize(), num_actions,
+                children.iter().map(|c| c.as_usize().to_string()).collect::<Vec<_>>().join(","),
+            ),
+            LabelStolen {case, old_target, new_target} => format!(
+                "\"case\":{},\"old_target\":{},\"new_target\":{}",
+                case.as_usize(), json_option(old_target), json_option(new_target),
+            ),
+            Patched {address, old_target, new_target} => format!(
+                "\"address\":{},\"old_target\":{},\"new_target\":{}",
+                address, json_option(old_target), json_option(new_target),
+            ),
+            OptimizerInput {case, ebb} | OptimizerOutput {case, ebb} => format!(
+                "\"case\":{},\"ebb\":{}",
+                case.as_usize(), json_string(&format!("{:?}", ebb)),
+            ),
+            CodeEmitted {case, role, start, end} => format!(
+                "\"case\":{},\"role\":\"{:?}\",\"start\":{},\"end\":{}",
+                case.as_usize(), role, start, end,
+            ),
+            BufferGrown {old_size, new_size, moved} => format!(
+                "\"old_size\":{},\"new_size\":{},\"moved\":{}",
+                old_size, new_size, moved,
+            ),
+            CaseInvalidated {case, num_bytes} => format!(
+                "\"case\":{},\"num_bytes\":{}",
+                case.as_usize(), num_bytes,
+            ),
+            PerfMapFailed {error} => format!(
+                "\"error\":{}",
+                json_string(&error.to_string()),
+            ),
+        };
+        format!("{{\"event\":\"{}\",{}}}", self.name(), fields)
+    }
+
+    /// Returns the name of the variant of `self`.
+    pub fn name(&self) -> &'static str {
+        use Event::*;
+        match self {
+            EntryCreated {..} => "EntryCreated",
+            CaseBuilt {..} => "CaseBuilt",
+            RetireAdded {..} => "RetireAdded",
+            FetchAdded {..} => "FetchAdded",
+            LabelStolen {..} => "LabelStolen",
+            Patched {..} => "Patched",
+            OptimizerInput {..} => "OptimizerInput",
+            OptimizerOutput {..} => "OptimizerOutput",
+            CodeEmitted {..} => "CodeEmitted",
+            BufferGrown {..} => "BufferGrown",
+            CaseInvalidated {..} => "CaseInvalidated",
+            PerfMapFailed {..} => "PerfMapFailed",
+        }
+    }
+}
+
+/// An [`Observer`] that writes each [`Event`] to a [`Write`] as a line of
+/// JSON.
+///
+/// Tracing is best-effort: after the first I/O error, later [`Event`]s are
+/// discarded. The error is kept, and can be retrieved using `error()` or
+/// `into_inner()`.
+#[derive(Debug)]
+pub struct JsonLines<W: Write> {
+    writer: W,
+    /// The first error that occurred, if any.
+    error: Option<io::Error>,
+}
+
+impl<W: Write> JsonLines<W> {
+    pub fn new(writer: W) -> Self {
+        JsonLines {writer, error: None}
+    }
+
+    /// Returns the first error that occurred, if any.
+    pub fn error(&self) -> Option<&io::Error> { self.error.as_ref() }
+
+    /// Returns the [`Write`], or the first error that occurred.
+    pub fn into_inner(self) -> io::Result<W> {
+        match self.error {
+            None => Ok(self.writer),
+            Some(e) => Err(e),
+        }
+    }
+}
+
+impl<W: Write> Observer for JsonLines<W> {
+    fn event(&mut self, event: &Event) {
+        if self.error.is_none() {
+            if let Err(e) = writeln!(self.writer, "{}", event.to_json()) {
+                self.error = Some(e);
+            }
+        }
+    }
+}
+
+//-----------------------------------------------------------------------------
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use super::super::target::{native};
+    use super::super::factorial::*;
+    use super::super::{Jit};
+
+    /// A [`Write`] whose output can be read while it is in use.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
+    }
+
+    #[test]
+    fn json_string_escapes() {
+        assert_eq!(json_string("a\"b\\c\nd\x01"), "\"a\\\"b\\\\c\\nd\\u0001\"");
+    }
+
+    #[test]
+    pub fn json_lines() {
+        let log = Shared::default();
+        let mut jit = Jit::new(native(), 2);
+        jit.set_observer(JsonLines::new(log.clone()));
+        let mut factorial = Factorial::from_jit(jit);
+        assert_eq!(factorial.run(5), 120);
+        let log = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+        for line in log.lines() {
+            assert!(line.starts_with("{\"event\":\"") && line.ends_with('}'), "{}", line);
+        }
+        for name in [
+            "EntryCreated", "CaseBuilt", "RetireAdded", "FetchAdded",
+            "LabelStolen", "Patched", "OptimizerInput", "OptimizerOutput",
+            "CodeEmitted", "BufferGrown",
+        ] {
+            let needle = format!("{{\"event\":\"{}\",", name);
+            assert!(log.lines().any(|line| line.starts_with(&needle)), "No {} event", name);
+        }
+        assert_eq!(log.lines().filter(|line| line.contains("\"EntryCreated\"")).count(), 3);
+    }
+
+    #[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64", target_arch = "riscv64")))]
+    #[test]
+    pub fn buffer_grown() {
+        use super::super::target::tests::{MemfdNative};
+        let log = Shared::default();
+        let mut jit = Jit::new(MemfdNative::default(), 2);
+        jit.set_observer(JsonLines::new(log.clone()));
+        let mut factorial = Factorial::from_jit(jit);
+        assert_eq!(factorial.run(5), 120);
+        let log = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+        let grown: Vec<_> = log.lines().filter(|line| line.contains("\"BufferGrown\"")).collect();
+        assert!(!grown.is_empty());
+        // `Memfd` grows in place.
+        for line in grown {
+            assert!(line.ends_with(",\"moved\":false}"), "{}", line);
+        }
+    }
+
+    /// A [`Write`] that accepts `limit` bytes and then fails.
+    struct Limited {
+        written: Vec<u8>,
+        limit: usize,
+    }
+
+    impl Write for Limited {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let n = std::cmp::min(buf.len(), self.limit - self.written.len());
+            if n == 0 { return Err(std::io::Error::new(std::io::ErrorKind::Other, "Full")); }
+            self.written.extend(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
+    }
+
+    #[test]
+    fn json_lines_error() {
+        let mut log = JsonLines::new(Limited {written: Vec::new(), limit: 10});
+        let event = Event::CaseBuilt {case: CaseId::new(0).unwrap()};
+        log.event(&event);
+        assert_eq!(log.error().unwrap().to_string(), "Full");
+        log.event(&event);
+        assert_eq!(log.into_inner().err().unwrap().to_string(), "Full");
+    }
+}
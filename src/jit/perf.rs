@@ -0,0 +1,309 @@
+//! Tells the Linux `perf` tool about the code that Mijit generates.
+//!
+//! `perf` cannot find symbols for code that does not come from a file. There
+//! are two standard ways to fix this, both of which are supported here:
+//!  - A "perf map" is a text file called `/tmp/perf-<pid>.map` containing one
+//!    line for each region of code, giving its address, size and name.
+//!  - A "jitdump" is a binary file called `jit-<pid>.dump` which additionally
+//!    contains a copy of the code. Use `perf record -k mono` followed by
+//!    `perf inject --jit` to use it.
+//!
+//! See `tools/perf/Documentation/jit-interface.txt` and
+//! `tools/perf/Documentation/jitdump-specification.txt` in the Linux sources.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::{Mutex};
+use memmap::{Mmap, MmapOptions};
+
+use super::{CaseId};
+
+//-----------------------------------------------------------------------------
+
+/// Returns the value of `CLOCK_MONOTONIC` in nanoseconds.
+/// This is the clock that `perf record -k mono` uses.
+#[cfg(unix)]
+fn timestamp() -> u64 {
+    let mut ts = libc::timespec {tv_sec: 0, tv_nsec: 0};
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    (ts.tv_sec as u64) * 1_000_000_000 + (ts.tv_nsec as u64)
+}
+
+/// There is no `perf` on this platform, so [`PerfMap::new()`] always fails.
+#[cfg(not(unix))]
+fn timestamp() -> u64 { 0 }
+
+/// Returns the ID of the current thread.
+#[cfg(target_os = "linux")]
+fn thread_id() -> u32 {
+    unsafe { libc::syscall(libc::SYS_gettid) as u32 }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn thread_id() -> u32 {
+    std::process::id()
+}
+
+/// The `e_machine` field of an ELF header for the host.
+#[cfg(target_arch = "x86_64")]
+pub(super) const ELF_MACHINE: u32 = 62;
+#[cfg(target_arch = "aarch64")]
+pub(super) const ELF_MACHINE: u32 = 183;
+#[cfg(target_arch = "riscv64")]
+pub(super) const ELF_MACHINE: u32 = 243;
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64", target_arch = "riscv64")))]
+pub(super) const ELF_MACHINE: u32 = 0;
+
+/// The magic number at the start of a jitdump file.
+const JITDUMP_MAGIC: u32 = 0x4A695444;
+
+/// The jitdump record type of a `JIT_CODE_LOAD` record.
+const JIT_CODE_LOAD: u32 = 0;
+
+/// The jitdump record type of a `JIT_CODE_CLOSE` record.
+const JIT_CODE_CLOSE: u32 = 3;
+
+/// An open jitdump file.
+struct JitDump {
+    file: File,
+    /// `perf record` notices the jitdump file because we map it executable.
+    /// The mapping must remain in existence as long as the file is in use.
+    _marker: Mmap,
+    /// The number of `JIT_CODE_LOAD` records written so far.
+    code_index: u64,
+}
+
+impl JitDump {
+    /// Creates `jit-<pid>.dump` in `directory` and writes its header.
+    fn new(directory: &str) -> io::Result<Self> {
+        let pid = std::process::id();
+        let file = OpenOptions::new()
+            .read(true).write(true).create(true).truncate(true)
+            .open(format!("{}/jit-{}.dump", directory, pid))?;
+        let mut header = Vec::with_capacity(40);
+        header.extend(JITDUMP_MAGIC.to_ne_bytes());
+        header.extend(1u32.to_ne_bytes()); // version
+        header.extend(40u32.to_ne_bytes()); // total_size
+        header.extend(ELF_MACHINE.to_ne_bytes());
+        header.extend(0u32.to_ne_bytes()); // pad1
+        header.extend(pid.to_ne_bytes());
+        header.extend(timestamp().to_ne_bytes());
+        header.extend(0u64.to_ne_bytes()); // flags
+        (&file).write_all(&header)?;
+        let marker = unsafe { MmapOptions::new().len(header.len()).map_exec(&file)? };
+        Ok(JitDump {file, _marker: marker, code_index: 0})
+    }
+
+    /// Writes a `JIT_CODE_LOAD` record.
+    fn code_load(&mut self, address: usize, code: &[u8], name: &str) -> io::Result<()> {
+        let total_size = 16 + 40 + name.len() + 1 + code.len();
+        let mut record = Vec::with_capacity(total_size);
+        record.extend(JIT_CODE_LOAD.to_ne_bytes());
+        record.extend((total_size as u32).to_ne_bytes());
+        record.extend(timestamp().to_ne_bytes());
+        record.extend(std::process::id().to_ne_bytes());
+        record.extend(thread_id().to_ne_bytes());
+        record.extend((address as u64).to_ne_bytes()); // vma
+        record.extend((address as u64).to_ne_bytes()); // code_addr
+        record.extend((code.len() as u64).to_ne_bytes());
+        record.extend(self.code_index.to_ne_bytes());
+        record.extend(name.as_bytes());
+        record.push(0);
+        record.extend(code);
+        self.code_index += 1;
+        self.file.write_all(&record)
+    }
+}
+
+impl Drop for JitDump {
+    fn drop(&mut self) {
+        let mut record = Vec::with_capacity(16);
+        record.extend(JIT_CODE_CLOSE.to_ne_bytes());
+        record.extend(16u32.to_ne_bytes());
+        record.extend(timestamp().to_ne_bytes());
+        let _ = self.file.write_all(&record);
+    }
+}
+
+/// The files shared by all [`PerfMap`]s in the process.
+struct Files {
+    /// `/tmp/perf-<pid>.map`.
+    map: File,
+    /// The jitdump file, if enabled.
+    dump: Option<JitDump>,
+}
+
+/// The process has at most one perf map file and at most one jitdump file,
+/// even if it has many [`Engine`]s.
+///
+/// [`Engine`]: super::Engine
+static FILES: Mutex<Option<Files>> = Mutex::new(None);
+
+//-----------------------------------------------------------------------------
+
+/// The purpose of a [`Region`] of code.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Role {
+    /// The code that enters Mijit at an entry point.
+    Prologue,
+    /// The `Retire` of a `Case`.
+    Retire,
+    /// The `Fetch` of a `Case`.
+    Fetch,
+}
+
+/// A contiguous range of compiled code.
+#[derive(Debug, Clone)]
+pub struct Region {
+    /// The offset of the start of the code.
+    pub start: usize,
+    /// The offset of the end of the code.
+    pub end: usize,
+    /// The `Case` to which the code belongs.
+    pub case: CaseId,
+    /// What the code does.
+    pub role: Role,
+}
+
+/// Reports to `perf` the [`Region`]s of code that an [`Engine`] has
+/// generated.
+///
+/// Regions are reported lazily, by `flush()`, which the `Engine` calls before
+/// running the code. This allows names to be given to `Case`s after their code
+/// is generated, and it allows us to notice if the code has moved. If it has
+/// moved, all the `Region`s are reported again at their new address.
+///
+/// [`Engine`]: super::Engine
+#[derive(Debug)]
+pub struct PerfMap {
+    /// The base address of the code at the last call to `flush()`.
+    base: Option<usize>,
+    /// The number of `Region`s that have been reported at `base`.
+    num_flushed: usize,
+}
+
+impl PerfMap {
+    /// Opens `/tmp/perf-<pid>.map` if it is not already open, and constructs a
+    /// `PerfMap` that appends to it.
+    ///  - jitdump - `true` to also write a jitdump file in `/tmp`.
+    ///
+    /// Returns [`io::ErrorKind::Unsupported`] on platforms other than unix.
+    pub fn new(jitdump: bool) -> io::Result<Self> {
+        if cfg!(not(unix)) {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "perf is only supported on unix"));
+        }
+        let mut files = FILES.lock().unwrap();
+        if files.is_none() {
+            let map = OpenOptions::new()
+                .write(true).create(true).truncate(true)
+                .open(format!("/tmp/perf-{}.map", std::process::id()))?;
+            *files = Some(Files {map, dump: None});
+        }
+        let files = files.as_mut().unwrap();
+        if jitdump && files.dump.is_none() {
+            files.dump = Some(JitDump::new("/tmp")?);
+        }
+        Ok(PerfMap {base: None, num_flushed: 0})
+    }
+
+    /// Reports all `Region`s that have not yet been reported at `base`.
+    ///  - base - the address at which `code` runs.
+    ///  - code - all the compiled code.
+    ///  - regions - every `Region` of `code`, in the order it was generated.
+    ///    Each call must pass the list passed to the previous call, minus any
+    ///    `Region`s passed to `remove()`, followed by any new `Region`s.
+    ///  - name - returns a name for a `Case`.
+    pub fn flush(
+        &mut self,
+        base: usize,
+        code: &[u8],
+        regions: &[Region],
+        name: impl Fn(CaseId) -> String,
+    ) -> io::Result<()> {
+        if self.base != Some(base) {
+            // The code has moved. Report everything again.
+            self.base = Some(base);
+            self.num_flushed = 0;
+        }
+        if self.num_flushed == regions.len() {
+            return Ok(());
+        }
+        let mut files = FILES.lock().unwrap();
+        let files = files.as_mut().expect("PerfMap exists but its file does not");
+        let mut lines = String::new();
+        for region in &regions[self.num_flushed..] {
+            let name = format!("mijit:{}:{:?}", name(region.case), region.role);
+            let address = base + region.start;
+            lines.push_str(&format!("{:x} {:x} {}\n", address, region.end - region.start, name));
+            if let Some(dump) = &mut files.dump {
+                dump.code_load(address, &code[region.start..region.end], &name)?;
+            }
+        }
+        files.map.write_all(lines.as_bytes())?;
+        self.num_flushed = regions.len();
+        Ok(())
+    }
+
+    /// Forgets the `Region`s for which `removed` returns `true`, which the
+    /// caller is about to remove from `regions`. The perf map cannot forget
+    /// them, but they will not be reported again if the code moves.
+    pub fn remove(&mut self, regions: &[Region], removed: impl Fn(&Region) -> bool) {
+        let num_removed = regions[..self.num_flushed].iter().filter(|&r| removed(r)).count();
+        self.num_flushed -= num_removed;
+    }
+}
+
+//-----------------------------------------------------------------------------
+
+#[cfg(test)]
+pub mod tests {
+    use super::super::target::{native, interpret};
+    use super::super::factorial::*;
+
+    /// Returns the contents of the perf map file.
+    fn read_map() -> String {
+        std::fs::read_to_string(format!("/tmp/perf-{}.map", std::process::id())).unwrap()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    pub fn perf_map() {
+        let mut factorial = Factorial::new(native());
+        factorial.jit.name_entry(factorial.start, "factorial_start");
+        factorial.jit.enable_perf_map(true).unwrap();
+        assert_eq!(factorial.run(5), 120);
+        let map = read_map();
+        assert!(map.lines().any(|line| line.ends_with(" mijit:factorial_start:Prologue")));
+        assert!(map.lines().any(|line| line.contains(" mijit:EntryId(1)/CaseId(")));
+        let dump = std::fs::read(format!("/tmp/jit-{}.dump", std::process::id())).unwrap();
+        assert_eq!(&dump[0..4], &0x4A695444u32.to_ne_bytes());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    pub fn interpret() {
+        // Bytecode is not reported.
+        let mut factorial = Factorial::new(interpret::Target);
+        factorial.jit.name_entry(factorial.start, "interpret_factorial_start");
+        factorial.jit.enable_perf_map(false).unwrap();
+        assert_eq!(factorial.run(5), 120);
+        assert!(!read_map().contains(" mijit:interpret_factorial_start:"));
+    }
+
+    #[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+    #[test]
+    pub fn memfd() {
+        use super::super::{Jit};
+        use super::super::target::tests::{MemfdNative};
+        let mut factorial = Factorial::from_jit(Jit::new(MemfdNative::default(), 2));
+        factorial.jit.name_entry(factorial.start, "memfd_factorial_start");
+        factorial.jit.enable_perf_map(false).unwrap();
+        // `entry_point()` indexes `Stable::executable()`, not the writeable
+        // view of the code.
+        let f = factorial.jit.entry_point(factorial.start);
+        let prefix = format!("{:x} ", f as usize);
+        assert!(read_map().lines().any(|line| {
+            line.starts_with(&prefix) && line.ends_with(" mijit:memfd_factorial_start:Prologue")
+        }));
+    }
+}
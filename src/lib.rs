@@ -1,6 +1,9 @@
 #[macro_use]
 pub mod util;
 
+mod error;
+pub use error::{Error};
+
 pub mod target;
 
 pub mod code;
@@ -11,4 +14,9 @@ pub mod jit;
 
 pub mod beetle;
 
+pub mod brainfuck;
+pub mod wasm;
+
 pub mod buffer;
+
+pub mod capi;
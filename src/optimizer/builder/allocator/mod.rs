@@ -142,6 +142,17 @@ impl<'a> Allocator<'a> {
         self.regs[reg].time.max_with(time);
     }
 
+    /// Free the `Register` containing `out` if `out` won't be used again.
+    fn free_if_unused(&mut self, out: Out) {
+        if self.usage.first(out).is_none() {
+            if let Some(reg) = self.current_reg(out) {
+                if !self.pool.is_clean(reg) {
+                    self.pool.free(reg);
+                }
+            }
+        }
+    }
+
     /// Select a `Register` to spill and free it.
     fn free_a_register(&mut self) -> Register {
         let i = map_filter_max(all_registers(), |reg| {
@@ -172,18 +183,18 @@ impl<'a> Allocator<'a> {
     }
 
     /// Called for each [`Node`] in the [`Usage`] in forwards order.
-    pub fn add_node(&mut self, node: Node) {
+    ///
+    ///  - keep_alives - if `node` is a guard, the [`Out`]s that must be
+    ///    computed before it.
+    pub fn add_node(&mut self, node: Node, keep_alives: Option<&HashSet<Out>>) {
         let df: &'a Dataflow = self.dataflow;
         let mut time = EARLY; // Earliest time (in cycles) when we can place `node`.
+        // A `Store` writes its output before reading its first input, so the
+        // two must not share a `Register`.
+        let num_late_ins = if matches!(df.op(node), Op::Store(_, _)) { 1 } else { 0 };
         // Free every input `Register` that won't be used again.
-        for &in_ in df.ins(node) {
-            if self.usage.first(in_).is_none() {
-                if let Some(reg) = self.current_reg(in_) {
-                    if !self.pool.is_clean(reg) {
-                        self.pool.free(reg);
-                    }
-                }
-            }
+        for &in_ in &df.ins(node)[num_late_ins..] {
+            self.free_if_unused(in_);
         }
         // Spill until we have enough registers to hold the outputs of `node`.
         self.spill_until(df.num_outs(node));
@@ -195,6 +206,10 @@ impl<'a> Allocator<'a> {
         for (&in_, &latency) in df.ins(node).iter().zip(df.cost(node).input_latencies) {
             time.max_with(self.outs[in_].time.expect("Not computed yet") + latency as usize);
         }
+        // Bump `time` until the keep-alives are available.
+        for &out in keep_alives.into_iter().flatten() {
+            time.max_with(self.outs[out].time.expect("Not computed yet"));
+        }
         // Bump `time` until some destination registers are available.
         for out in df.outs(node) {
             let reg = self.pool.allocate();
@@ -207,12 +222,11 @@ impl<'a> Allocator<'a> {
             // We can't be sure it's not still in a `Register`; this is a guess.
             resources += SLOT_COST;
         }
-        // FIXME: A long series of zero-cost nodes will crash the placer.
         self.placer.add_item(Node(node), resources, &mut time);
         // Record the node's placement.
         self.node_times[node] = Some(time);
-        // Record when the inputs were used.
-        for &in_ in df.ins(node) {
+        // Record when the inputs and keep-alives were used.
+        for &in_ in df.ins(node).iter().chain(keep_alives.into_iter().flatten()) {
             if let Some(reg) = self.current_reg(in_) {
                 self.use_reg(reg, time);
             }
@@ -222,6 +236,9 @@ impl<'a> Allocator<'a> {
             self.regs[self.outs[out].reg.unwrap()] = RegInfo {time: time, out: Some(out)};
             self.outs[out].time = Some(time + latency as usize);
         }
+        for &in_ in &df.ins(node)[..num_late_ins] {
+            self.free_if_unused(in_);
+        }
     }
 
     /// Returns the finished [`Instruction`] order and [`Register`] allocation.
@@ -232,6 +249,39 @@ impl<'a> Allocator<'a> {
     }
 }
 
+/// Reorders `nodes`, which must be topologically sorted, so that each guard
+/// also comes after the [`Node`]s that compute its keep-alives. Otherwise
+/// preserves the order.
+fn sort_keep_alives<'a>(
+    dataflow: &Dataflow,
+    nodes: &[Node],
+    get_keep_alives: &impl Fn(Node) -> Option<&'a HashSet<Out>>,
+) -> Vec<Node> {
+    let mut unvisited: HashSet<Node> = nodes.iter().copied().collect();
+    let mut sorted = Vec::with_capacity(nodes.len());
+    fn visit<'a>(
+        dataflow: &Dataflow,
+        get_keep_alives: &impl Fn(Node) -> Option<&'a HashSet<Out>>,
+        unvisited: &mut HashSet<Node>,
+        sorted: &mut Vec<Node>,
+        node: Node,
+    ) {
+        if !unvisited.remove(&node) { return; }
+        let keep_alives = get_keep_alives(node).into_iter().flatten();
+        for &out in dataflow.ins(node).iter().chain(keep_alives) {
+            visit(dataflow, get_keep_alives, unvisited, sorted, dataflow.out(out).0);
+        }
+        for &dep in dataflow.deps(node) {
+            visit(dataflow, get_keep_alives, unvisited, sorted, dep);
+        }
+        sorted.push(node);
+    }
+    for &node in nodes {
+        visit(dataflow, get_keep_alives, &mut unvisited, &mut sorted, node);
+    }
+    sorted
+}
+
 /// Choose the execution order and allocate [`Register`]s.
 ///
 /// - effects - [`Node`]s representing side-effects that have already occurred.
@@ -239,6 +289,8 @@ impl<'a> Allocator<'a> {
 /// - dataflow - The dataflow graph.
 /// - nodes - The [`Node`]s that need to be executed on the hot path,
 ///   topologically sorted. Includes the exit node.
+/// - get_keep_alives - For each guard [`Node`], the [`Out`]s that must be
+///   computed before it.
 ///
 /// Returns:
 /// - instructions - the execution order. Excludes the exit node.
@@ -253,6 +305,7 @@ pub fn allocate<'a>(
     Vec<Instruction>,
     ArrayMap<Out, Option<Register>>
 ) {
+    let nodes = sort_keep_alives(dataflow, nodes, &get_keep_alives);
     let mut usage = Usage::default();
     for &node in nodes.iter().rev() {
         let mut keep_alives: Vec<_> = dataflow.ins(node).iter().copied().collect();
@@ -265,7 +318,7 @@ pub fn allocate<'a>(
     // Call `add_node()` for all `Node`s except the exit node.
     while let Some(node) = a.usage.pop() {
         if !matches!(dataflow.op(node), Op::Convention) {
-            a.add_node(node);
+            a.add_node(node, get_keep_alives(node));
         }
     }
     a.finish()
@@ -69,6 +69,9 @@ impl<T> Cycle<T> {
         }
     }
 
+    /// Tests whether this `Cycle` has room for no more items.
+    pub fn is_full(&self) -> bool { self.num_items == MAX_ITEMS }
+
     /// Append `item` to this `Cycle`.
     /// Panics if `num_items` exceeds `MAX_ITEMS`.
     pub fn push(&mut self, item: T) {
@@ -117,13 +120,21 @@ impl<T: Debug> Placer<T> {
 
     /// Decide when to place `item`. On entry, `*time` is the least time
     /// that is acceptable. `*time` is increased as necessary to find a clock
-    /// cycle that can afford `cost`.
+    /// cycle that can afford `cost` and that is not full. If no clock cycle
+    /// can afford `cost`, `item` is placed in an otherwise unused clock cycle.
     pub fn add_item(&mut self, item: T, cost: Resources, time: &mut Time) {
-        #[allow(clippy::neg_cmp_op_on_partial_ord)]
-        while !(cost <= self.at(*time).remaining) {
-            *time += 1;
+        if cost <= BUDGET {
+            #[allow(clippy::neg_cmp_op_on_partial_ord)]
+            while !(cost <= self.at(*time).remaining) || self.at(*time).is_full() {
+                *time += 1;
+            }
+            self.at(*time).remaining -= cost;
+        } else {
+            while self.at(*time).remaining != BUDGET || self.at(*time).is_full() {
+                *time += 1;
+            }
+            self.at(*time).remaining = Resources::new(0);
         }
-        self.at(*time).remaining -= cost;
         self.at(*time).push(item);
     }
 
@@ -149,4 +160,29 @@ mod tests {
             p.add_item('A', SPILL_COST, &mut time);
         }
     }
+
+    #[test]
+    fn over_budget() {
+        let mut p = Placer::new();
+        let mut time = LEAST;
+        p.add_item('A', SPILL_COST, &mut time);
+        assert_eq!(time, LEAST);
+        // Exceed `BUDGET` in a single item.
+        p.add_item('B', BUDGET + SPILL_COST, &mut time);
+        assert_eq!(time, LEAST + 1);
+        p.add_item('C', SPILL_COST, &mut time);
+        assert_eq!(time, LEAST + 2);
+        assert_eq!(p.iter().copied().collect::<String>(), "ABC");
+    }
+
+    #[test]
+    fn zero_cost() {
+        let mut p = Placer::new();
+        // Overflow `MAX_ITEMS` to force the Placer to grow.
+        for _ in 0..100 {
+            let mut time = LEAST;
+            p.add_item('A', Resources::new(0), &mut time);
+        }
+        assert_eq!(p.iter().count(), 100);
+    }
 }
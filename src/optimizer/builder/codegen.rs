@@ -68,19 +68,20 @@ impl<'a> CodeGen<'a> {
             if let Variable::Register(r) = src { uses[r] += 2; }
         }
         let temp = all_registers().min_by_key(|&r| uses[r]).unwrap();
-        let mut temp_replacement = Variable::from(Slot(slots_used));
 
-        // If `temp` is used, spill it and replace all mentions of it.
-        if uses[temp] == 1 {
-            // `temp` is a destination only.
-            stored_actions.push(Action::Push(None, None));
+        // If `temp` is used, spill it and replace all mentions of it, so that
+        // `moves()` is free to use it.
+        if uses[temp] != 0 {
+            // The lower `Slot` holds the old value of `temp`, if any.
+            // The upper `Slot` holds the new value of `temp`, if any.
+            let old_temp = if uses[temp] >= 2 { Some(temp.into()) } else { None };
+            stored_actions.push(Action::Push(None, old_temp));
+            dest_to_src = dest_to_src.into_iter().map(|(mut dest, mut src)| {
+                if src == temp.into() { src = Slot(slots_used).into(); }
+                if dest == temp.into() { dest = Slot(slots_used + 1).into(); }
+                (dest, src)
+            }).collect();
             slots_used += 2;
-        } else if uses[temp] >= 2 {
-            // `temp` is used as a source.
-            stored_actions.push(Action::Push(None, Some(temp.into())));
-            slots_used += 2;
-        } else {
-            temp_replacement = Variable::from(temp);
         }
 
         assert_eq!(stored_actions.len() * 2, slots_used.wrapping_sub(self.slots_used));
@@ -88,16 +89,12 @@ impl<'a> CodeGen<'a> {
         // Move all live values into the expected `Variable`s.
         // TODO: Find a way to schedule these `Move`s properly or to eliminate them.
         stored_actions.extend(moves(dest_to_src, &temp.into()).map(
-            |(mut dest, mut src)| {
-                if src == temp.into() { src = temp_replacement; }
-                if dest == temp.into() { dest = temp_replacement; }
-                Action::Move(dest, src)
-            }
+            |(dest, src)| Action::Move(dest, src)
         ));
 
         if uses[temp] & 1 != 0 {
             // `temp` is a destination.
-            stored_actions.push(Action::Move(temp.into(), temp_replacement));
+            stored_actions.push(Action::Move(temp.into(), Slot(slots_used - 1).into()));
         }
 
         // Drop now-unused slots.
@@ -145,13 +142,15 @@ impl<'a> CodeGen<'a> {
         self.variables[&out]
     }
 
-    /// Generate an [`Action`] to spill `out_x` and `out_y`.
+    /// Generate an [`Action`] to spill `out1` and `out2`, into
+    /// `Slot(slots_used)` and `Slot(slots_used + 1)` respectively.
     pub fn add_spill(&mut self, out1: Out, out2: Out) {
         self.slots_used -= 1;
         let r2 = self.spill(out2).map(|r| r.into());
         self.slots_used -= 1;
         let r1 = self.spill(out1).map(|r| r.into());
-        self.actions_rev.push(Action::Push(r1.into(), r2.into()));
+        // `Push` puts its second operand in the lower `Slot`.
+        self.actions_rev.push(Action::Push(r2, r1));
     }
 
     /// Generate an [`Action`] to execute `n`.
@@ -24,7 +24,7 @@ pub use codegen::{CodeGen};
 
 use code::{Register};
 
-const NUM_REGISTERS: usize = target::x86_64::ALLOCATABLE_REGISTERS.len();
+pub(super) const NUM_REGISTERS: usize = target::x86_64::ALLOCATABLE_REGISTERS.len();
 
 fn all_registers() -> impl Iterator<Item=Register> {
     (0..NUM_REGISTERS).map(|i| Register::new(i as u8).unwrap())
@@ -49,6 +49,39 @@ impl<'a> Builder<'a> {
         matches!(self.dataflow.op(node), Op::Guard)
     }
 
+    /// Finds the [`Out`]s that the cold paths of a guard take as inputs from
+    /// the marked paths. On the path containing the guard, these `Out`s must
+    /// be computed before the guard and kept alive until it.
+    ///
+    /// The precomputed [`GuardFailure::keep_alives`] are not sufficient,
+    /// because guards can be reordered, and so the same guard can be reached
+    /// along different paths.
+    ///
+    /// - gf - what to do if the guard fails.
+    /// - guard_failure - as for `walk()`.
+    /// - coldness - the coldness of the path containing the guard.
+    /// - keep_alives - accumulates the `Out`s.
+    fn keep_alives<'w, L: Debug + Clone>(
+        &mut self,
+        gf: &'w GuardFailure<L>,
+        guard_failure: &dyn Fn(Node) -> &'w GuardFailure<L>,
+        coldness: usize,
+        keep_alives: &mut HashSet<Out>,
+    ) {
+        for child in &*gf.cold.colds {
+            let guard_failure = |guard_node| child.children.get(&guard_node).unwrap_or_else(|| guard_failure(guard_node));
+            let mut inputs = HashSet::new();
+            let nodes = flood(self.dataflow, &mut self.marks, coldness + 1, &mut inputs, &mut HashSet::new(), child.exit);
+            for &node in &*nodes {
+                if self.is_guard(node) {
+                    self.keep_alives(guard_failure(node), &guard_failure, coldness + 1, &mut inputs);
+                }
+            }
+            for &node in &*nodes { self.marks[node] = 0; }
+            keep_alives.extend(inputs.into_iter().filter(|&out| self.marks[self.dataflow.out(out).0] != 0));
+        }
+    }
+
     /// Converts a [`HotPathTree`] into an [`EBB`]. Optimises the hot path in
     /// isolation, and recurses on the cold paths, passing information about
     /// [`Variable`] allocation and instruction scheduling.
@@ -81,13 +114,15 @@ impl<'a> Builder<'a> {
         let mut inputs = HashSet::new();
         let mut effects = HashSet::new();
         let nodes = flood(self.dataflow, &mut self.marks, coldness, &mut inputs, &mut effects, exit);
+        let mut keep_alives = HashMap::new();
         for &node in &*nodes {
             if self.is_guard(node) {
-                for &out in &guard_failure(node).keep_alives {
-                    if self.marks[self.dataflow.out(out).0] < coldness {
-                        inputs.insert(out);
-                    }
+                let mut outs = HashSet::new();
+                self.keep_alives(guard_failure(node), guard_failure, coldness, &mut outs);
+                for &out in &outs {
+                    if self.marks[self.dataflow.out(out).0] < coldness { inputs.insert(out); }
                 }
+                keep_alives.insert(node, outs);
             }
         }
         let inputs: Box<[_]> = inputs.into_iter().collect(); // Define an order.
@@ -101,7 +136,7 @@ impl<'a> Builder<'a> {
             &variables,
             self.dataflow,
             &*nodes,
-            |node| if self.is_guard(node) { Some(&guard_failure(node).keep_alives) } else { None },
+            |node| keep_alives.get(&node),
         );
 
         // Allocate spill slots on the hot path.
@@ -316,4 +351,37 @@ mod tests {
         let _observed = build(&convention, &dataflow, &cft, lookup_leaf);
         // TODO: Expected output.
     }
+
+    /// Counts the leaves of `ebb`.
+    fn count_leaves<L>(ebb: &EBB<L>) -> usize {
+        match &ebb.ending {
+            Ending::Leaf(_) => 1,
+            Ending::Switch(switch) => {
+                let mut count = 0;
+                switch.map(|child| { count += count_leaves(child); });
+                count
+            },
+        }
+    }
+
+    /// Regression test from Beetle. A series of guards whose conditions are
+    /// all known on entry must not be reordered, because the cold path of a
+    /// guard that is hoisted above another must repeat the other's test.
+    #[test]
+    fn guards_in_order() {
+        const N: usize = 10;
+        let convention = Convention {
+            slots_used: 0,
+            live_values: (0..N).map(|i| Variable::Global(Global(i))).collect(),
+        };
+        let ebb = builder::build(&|mut b| {
+            for i in 0..N {
+                b.guard(Global(i), true, builder::build(&|b| { b.jump(i) }));
+            }
+            b.jump(N)
+        });
+        let (dataflow, cft) = super::super::simulate(&convention, &ebb, &convention);
+        let observed = build(&convention, &dataflow, &cft, &convention);
+        assert_eq!(count_leaves(&observed), N + 1);
+    }
 }
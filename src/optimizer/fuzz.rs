@@ -0,0 +1,363 @@
+//! Differential testing of [`optimize()`] against the reference interpreter
+//! in [`code::interpreter`].
+//!
+//! We generate random [`EBB`]s with guards and several leaves, each with a
+//! random [`Convention`], and run them before and after optimization. We
+//! compare the leaf reached, the values that its `Convention` says are live,
+//! and a scratch buffer. When they disagree we shrink the `EBB` before
+//! reporting it.
+
+use std::fmt::{Write};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use super::{code, target, optimize, LookupLeaf};
+use super::builder::{NUM_REGISTERS};
+use code::{
+    Precision, BinaryOp, REGISTERS, Global, Slot, Variable, Action, Switch,
+    Convention, EBB, Ending, verify,
+};
+use code::interpreter::{State};
+use target::fuzz::{
+    Random, Inputs, Scratch, Generator, NUM_GLOBALS, SCRATCH_WORDS, is_defined,
+    minimize,
+};
+
+/// The address of the scratch buffer. The interpreter never dereferences it.
+pub const BASE_ADDRESS: u64 = 0x1000;
+
+/// The [`Convention`] and weight of each leaf of a test case.
+#[derive(Debug, Clone, Default)]
+pub struct Leaves(pub Vec<(Convention, usize)>);
+
+impl LookupLeaf<usize> for Leaves {
+    fn after(&self, leaf: &usize) -> &Convention {
+        &self.0[*leaf].0
+    }
+
+    fn weight(&self, leaf: &usize) -> usize {
+        self.0[*leaf].1
+    }
+}
+
+/// A test case for [`optimize()`].
+#[derive(Debug, Clone)]
+pub struct Case {
+    /// The `Convention` on entry to `ebb`.
+    pub before: Convention,
+    /// The initial values of the live `Variable`s of `before`.
+    pub initial: State,
+    /// The initial contents of the scratch buffer.
+    pub scratch: [u64; SCRATCH_WORDS],
+    /// The code to optimize.
+    pub ebb: EBB<usize>,
+    pub leaves: Leaves,
+}
+
+/// The final state of a test case.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Outcome {
+    /// The leaf that was reached.
+    pub leaf: usize,
+    /// The value of every `Variable` that is live in the leaf's `Convention`.
+    pub live_values: Vec<(Variable, u64)>,
+    pub scratch: [u64; SCRATCH_WORDS],
+}
+
+impl Outcome {
+    fn new(leaf: usize, leaves: &Leaves, state: &State, scratch: &Scratch) -> Self {
+        let live_values = leaves.after(&leaf).live_values.iter().map(|&v| (v, state.get(v))).collect();
+        Outcome {leaf, live_values, scratch: scratch.words()}
+    }
+}
+
+//-----------------------------------------------------------------------------
+
+/// Returns a random `Convention` including all the `Global`s, and the
+/// initial values of its live `Variable`s. Also returns a [`Generator`] that
+/// will generate code which can follow it.
+fn generate_before(random: &mut Random, inputs: &Inputs) -> (Convention, State, Generator) {
+    let mut state = State::new(inputs.all_globals(BASE_ADDRESS));
+    let mut live_values: Vec<Variable> = (0..NUM_GLOBALS).map(|g| Global(g).into()).collect();
+    let mut live = Vec::new();
+    for &r in &REGISTERS {
+        if random.below(2) == 0 {
+            state.set(r, random.value());
+            live_values.push(r.into());
+            live.push(r);
+        }
+    }
+    let slots_used = 2 * random.below(3);
+    for i in 0..slots_used {
+        state.slots.push(random.value());
+        live_values.push(Slot(i).into());
+    }
+    let before = Convention {live_values: live_values.into(), slots_used};
+    let generator = Generator::new(state.clone(), Scratch::new(BASE_ADDRESS, &inputs.scratch), live);
+    (before, state, generator)
+}
+
+/// Returns a random [`EBB`] that continues the code generated by `g`, with
+/// [`Switch`]es nested at most `depth` deep. Appends its leaves to `leaves`.
+fn generate_ebb(
+    random: &mut Random,
+    mut g: Generator,
+    depth: usize,
+    leaves: &mut Leaves,
+) -> EBB<usize> {
+    let length = random.below(12);
+    while g.body.len() < length {
+        g.push_random(random);
+    }
+    if depth > 0 && random.below(3) != 0 {
+        // Choose a discriminant which is likely to select each case.
+        let discriminant = g.register(random);
+        let src = g.src(random);
+        g.try_push(vec![
+            Action::Constant(Precision::P64, discriminant, 3),
+            Action::Binary(BinaryOp::And, Precision::P64, discriminant, src, discriminant.into()),
+        ]);
+        let actions = std::mem::take(&mut g.body);
+        let num_cases = 1 + random.below(3);
+        let cases: Box<[_]> = (0..num_cases).map(|_| {
+            generate_ebb(random, g.clone(), depth - 1, leaves)
+        }).collect();
+        let default_ = generate_ebb(random, g, depth - 1, leaves);
+        EBB {actions, ending: Ending::Switch(Switch::new(discriminant.into(), cases, default_))}
+    } else {
+        if random.below(3) == 0 { g.drop_slots(); }
+        let mut live_values: Vec<Variable> = (0..NUM_GLOBALS).map(|g| Global(g).into()).collect();
+        for &r in &g.live {
+            if random.below(2) == 0 { live_values.push(r.into()); }
+        }
+        for i in 0..g.slots_used {
+            if random.below(2) == 0 { live_values.push(Slot(i).into()); }
+        }
+        let after = Convention {live_values: live_values.into(), slots_used: g.slots_used};
+        leaves.0.push((after, 1 + random.below(10)));
+        EBB {actions: g.body, ending: Ending::Leaf(leaves.0.len() - 1)}
+    }
+}
+
+/// Returns a random test case, with `Switch`es nested at most `depth` deep.
+pub fn generate(random: &mut Random, depth: usize) -> Case {
+    let inputs = Inputs::random(random);
+    let (before, initial, generator) = generate_before(random, &inputs);
+    let mut leaves = Leaves::default();
+    let ebb = generate_ebb(random, generator, depth, &mut leaves);
+    Case {before, initial, scratch: inputs.scratch, ebb, leaves}
+}
+
+//-----------------------------------------------------------------------------
+
+/// Runs `case.ebb` using the reference interpreter. Returns `None` if it is
+/// malformed or its behaviour is undefined.
+pub fn reference(case: &Case) -> Option<Outcome> {
+    verify(&case.before, &case.ebb, |leaf| case.leaves.after(leaf), REGISTERS.len()).ok()?;
+    let mut state = case.initial.clone();
+    let mut scratch = Scratch::new(BASE_ADDRESS, &case.scratch);
+    let mut ebb = &case.ebb;
+    loop {
+        for &action in &ebb.actions {
+            if !is_defined(&state, action) { return None; }
+            state.action(action, &mut scratch);
+            if !scratch.is_ok { return None; }
+        }
+        match &ebb.ending {
+            Ending::Leaf(leaf) => return Some(Outcome::new(*leaf, &case.leaves, &state, &scratch)),
+            Ending::Switch(switch) => { ebb = state.switch(switch); },
+        }
+    }
+}
+
+/// Optimizes `case.ebb`. Returns an error message if [`optimize()`] panics or
+/// returns malformed code.
+pub fn optimized(case: &Case) -> Result<EBB<usize>, String> {
+    let ebb = catch_unwind(AssertUnwindSafe(|| {
+        optimize(&case.before, &case.ebb, &case.leaves)
+    })).map_err(|_| "optimize() panicked".to_string())?;
+    verify(&case.before, &ebb, |leaf| case.leaves.after(leaf), NUM_REGISTERS).map_err(|diagnostics| {
+        diagnostics.iter().map(|d| d.to_string()).collect::<Vec<_>>().join("\n")
+    })?;
+    Ok(ebb)
+}
+
+/// Optimizes `case.ebb` and runs the result using the interpreter.
+pub fn run(case: &Case) -> Result<Outcome, String> {
+    let ebb = optimized(case)?;
+    let mut state = case.initial.clone();
+    let mut scratch = Scratch::new(BASE_ADDRESS, &case.scratch);
+    let leaf = *state.ebb(&ebb, &mut scratch);
+    if !scratch.is_ok { return Err("Invalid memory access".into()); }
+    Ok(Outcome::new(leaf, &case.leaves, &state, &scratch))
+}
+
+/// Tests whether `case` is a valid test case on which [`optimize()`] fails.
+fn fails(case: &Case) -> bool {
+    reference(case).map_or(false, |expected| run(case) != Ok(expected))
+}
+
+//-----------------------------------------------------------------------------
+
+/// Returns the number of nodes in `ebb`.
+fn count_nodes<L>(ebb: &EBB<L>) -> usize {
+    1 + children(ebb).iter().map(|child| count_nodes(child)).sum::<usize>()
+}
+
+/// Returns the children of `ebb`.
+fn children<L>(ebb: &EBB<L>) -> Vec<&EBB<L>> {
+    match &ebb.ending {
+        Ending::Leaf(_) => vec![],
+        Ending::Switch(Switch::Index {cases, default_, ..}) => {
+            cases.iter().chain(std::iter::once(&**default_)).collect()
+        },
+        Ending::Switch(Switch::Always(jump)) => vec![jump],
+    }
+}
+
+/// Returns the node of `ebb` that is `index`th in pre-order.
+fn node_mut<'a, L>(ebb: &'a mut EBB<L>, index: &mut usize) -> Option<&'a mut EBB<L>> {
+    if *index == 0 { return Some(ebb); }
+    *index -= 1;
+    match &mut ebb.ending {
+        Ending::Leaf(_) => None,
+        Ending::Switch(Switch::Index {cases, default_, ..}) => {
+            for case in cases.iter_mut() {
+                if let Some(node) = node_mut(case, index) { return Some(node); }
+            }
+            node_mut(default_, index)
+        },
+        Ending::Switch(Switch::Always(jump)) => node_mut(jump, index),
+    }
+}
+
+/// Returns a copy of `case` in which `f` has been applied to the `index`th
+/// node of `case.ebb`.
+fn modify(case: &Case, index: usize, f: impl FnOnce(&mut EBB<usize>)) -> Case {
+    let mut case = case.clone();
+    f(node_mut(&mut case.ebb, &mut {index}).expect("No such node"));
+    case
+}
+
+/// Simplifies `case.ebb` as much as possible while `fails` remains `true`.
+/// `fails(case)` must be `true` initially.
+///
+/// We try replacing each `Switch` with one of its cases, removing
+/// [`Action`]s, and removing live values from the `Convention`s.
+pub fn shrink(mut case: Case, mut fails: impl FnMut(&Case) -> bool) -> Case {
+    'progress: loop {
+        for index in 0..count_nodes(&case.ebb) {
+            let mut i = index;
+            let num_children = children(node_mut(&mut case.ebb, &mut i).unwrap()).len();
+            for c in 0..num_children {
+                let candidate = modify(&case, index, |node| {
+                    let child = children(node)[c].clone();
+                    node.actions.extend(child.actions);
+                    node.ending = child.ending;
+                });
+                if fails(&candidate) {
+                    case = candidate;
+                    continue 'progress;
+                }
+            }
+        }
+        let mut is_changed = false;
+        for index in 0..count_nodes(&case.ebb) {
+            let mut i = index;
+            let actions = node_mut(&mut case.ebb, &mut i).unwrap().actions.clone();
+            let old_len = actions.len();
+            let actions = minimize(actions, |actions| {
+                fails(&modify(&case, index, |node| { node.actions = actions.to_vec(); }))
+            });
+            if actions.len() < old_len {
+                case = modify(&case, index, |node| { node.actions = actions; });
+                is_changed = true;
+            }
+        }
+        for leaf in 0..case.leaves.0.len() {
+            let live_values = case.leaves.0[leaf].0.live_values.to_vec();
+            let old_len = live_values.len();
+            let live_values = minimize(live_values, |live_values| {
+                let mut candidate = case.clone();
+                candidate.leaves.0[leaf].0.live_values = live_values.into();
+                fails(&candidate)
+            });
+            if live_values.len() < old_len {
+                case.leaves.0[leaf].0.live_values = live_values.into();
+                is_changed = true;
+            }
+        }
+        let live_values = case.before.live_values.to_vec();
+        let old_len = live_values.len();
+        let live_values = minimize(live_values, |live_values| {
+            let mut candidate = case.clone();
+            candidate.before.live_values = live_values.into();
+            fails(&candidate)
+        });
+        if live_values.len() < old_len {
+            case.before.live_values = live_values.into();
+            is_changed = true;
+        }
+        if !is_changed { return case; }
+    }
+}
+
+/// Runs `num_cases` random test cases. Panics with a shrunk test case if the
+/// optimized code disagrees with [`reference()`].
+pub fn fuzz(num_cases: u64, depth: usize) {
+    for seed in 0..num_cases {
+        let mut random = Random::new(seed);
+        let case = generate(&mut random, depth);
+        let expected = reference(&case).expect("Generated code is malformed");
+        if run(&case) == Ok(expected) { continue; }
+        let case = shrink(case, fails);
+        let expected = reference(&case).unwrap();
+        let mut report = String::new();
+        writeln!(report, "optimize() changed the behaviour of an EBB (seed {}).", seed).unwrap();
+        writeln!(report, "Before: {:?}", case.before).unwrap();
+        writeln!(report, "Initial state: {:#x?}", case.initial).unwrap();
+        writeln!(report, "Scratch: {:#x?}", case.scratch).unwrap();
+        writeln!(report, "Leaves: {:?}", case.leaves).unwrap();
+        writeln!(report, "Shrunk EBB: {:#?}", case.ebb).unwrap();
+        match optimized(&case) {
+            Ok(ebb) => { writeln!(report, "Optimized EBB: {:#?}", ebb).unwrap(); },
+            Err(e) => { writeln!(report, "{}", e).unwrap(); },
+        }
+        writeln!(report, "Expected: {:#x?}", expected).unwrap();
+        writeln!(report, "Observed: {:#x?}", run(&case)).unwrap();
+        panic!("{}", report);
+    }
+}
+
+//-----------------------------------------------------------------------------
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn optimizer() {
+        fuzz(500, 3);
+    }
+
+    #[test]
+    fn generate_is_deterministic() {
+        let case1 = generate(&mut Random::new(4), 3);
+        let case2 = generate(&mut Random::new(4), 3);
+        assert_eq!(format!("{:?}", case1), format!("{:?}", case2));
+        assert!(reference(&case1).is_some());
+    }
+
+    #[test]
+    fn shrink_() {
+        // Find a case with a `Switch`, and a `Mul` before it.
+        let is_mul = |a: &Action| matches!(a, Action::Binary(BinaryOp::Mul, ..));
+        let case = (0..).map(|seed| generate(&mut Random::new(seed), 3)).find(|case| {
+            count_nodes(&case.ebb) > 1 && case.ebb.actions.iter().any(is_mul)
+        }).unwrap();
+        let shrunk = shrink(case.clone(), |case| {
+            reference(case).is_some() && case.ebb.actions.iter().any(is_mul)
+        });
+        assert_eq!(count_nodes(&shrunk.ebb), 1);
+        assert_eq!(shrunk.ebb.actions.iter().filter(|a| is_mul(a)).count(), 1);
+    }
+}
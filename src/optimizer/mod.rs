@@ -26,6 +26,9 @@ pub use simulation::{Simulation, simulate};
 mod builder;
 pub use builder::{build};
 
+#[cfg(test)]
+pub mod fuzz;
+
 /// Look up information about a control-flow merge point.
 pub trait LookupLeaf<L: Clone> {
     /// Return the convention in effect at `leaf`.
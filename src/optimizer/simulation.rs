@@ -105,12 +105,13 @@ impl Simulation {
             },
             Action::Store(dest, src, (addr, width), alias_mask) => {
                 // TODO: Use AliasMask.
+                // `dest` is written before `src` is read.
+                let src = if src == dest.into() { addr } else { src };
                 let mut deps = Vec::new();
                 std::mem::swap(&mut deps, &mut self.loads);
                 deps.push(self.sequence);
                 deps.push(self.store);
                 let node = self.op(dataflow, Op::Store(width, alias_mask), &deps, &[src, addr], &[dest]);
-                self.move_(dest.into(), src);
                 self.store = node;
             },
             Action::Push(src1, src2) => {
@@ -149,7 +150,9 @@ impl Simulation {
     pub fn guard(&mut self, dataflow: &mut Dataflow, discriminant: Variable) -> Node {
         let discriminant = self.lookup(discriminant);
         let guard = dataflow.add_node(Op::Guard, &[], &[discriminant], 0);
-        let sequence = self.op(dataflow, Op::Sequence, &[guard, self.sequence], &[], &[]);
+        // Listing `self.sequence` first keeps the guards in order, which
+        // avoids duplicating the cold paths of earlier guards.
+        let sequence = self.op(dataflow, Op::Sequence, &[self.sequence, guard], &[], &[]);
         self.sequence = sequence;
         guard
     }
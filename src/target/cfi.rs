@@ -0,0 +1,100 @@
+/// DWARF call frame information, describing how to find the caller's stack
+/// frame and registers from within code generated by a [`Lower`].
+///
+/// This is what a debugger needs in order to show a backtrace that passes
+/// through Mijit code. The `instructions` are suitable for the body of a
+/// Frame Description Entry (FDE) in a `.debug_frame` section.
+///
+/// [`Lower`]: super::Lower
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CallFrameInfo {
+    /// The DWARF register number of the column that holds the return address.
+    pub return_address: u8,
+    /// The factor by which `DW_CFA_advance_loc` operands are multiplied.
+    pub code_alignment: u8,
+    /// The factor by which `DW_CFA_offset` operands are multiplied.
+    pub data_alignment: i8,
+    /// DWARF call frame instructions.
+    pub instructions: Vec<u8>,
+}
+
+impl CallFrameInfo {
+    pub fn new(return_address: u8, code_alignment: u8, data_alignment: i8) -> Self {
+        CallFrameInfo {return_address, code_alignment, data_alignment, instructions: Vec::new()}
+    }
+
+    /// Appends `DW_CFA_def_cfa`: the Canonical Frame Address is `register`
+    /// plus `offset` bytes.
+    pub fn def_cfa(&mut self, register: u8, offset: u64) {
+        self.instructions.push(0x0C);
+        write_uleb128(&mut self.instructions, register.into());
+        write_uleb128(&mut self.instructions, offset);
+    }
+
+    /// Appends `DW_CFA_offset`: `register` is saved at the Canonical Frame
+    /// Address plus `offset` bytes. `offset` must be a multiple of
+    /// `data_alignment` with the same sign.
+    pub fn offset(&mut self, register: u8, offset: i64) {
+        let factored = offset / i64::from(self.data_alignment);
+        assert!(factored >= 0 && factored * i64::from(self.data_alignment) == offset);
+        assert!(register < 0x40);
+        self.instructions.push(0x80 | register);
+        write_uleb128(&mut self.instructions, factored as u64);
+    }
+
+    /// Appends `DW_CFA_same_value`: `register` has not been modified.
+    pub fn same_value(&mut self, register: u8) {
+        self.instructions.push(0x08);
+        write_uleb128(&mut self.instructions, register.into());
+    }
+}
+
+/// Appends `value` to `out` in unsigned LEB128 format.
+pub fn write_uleb128(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+/// Appends `value` to `out` in signed LEB128 format.
+pub fn write_sleb128(out: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+//-----------------------------------------------------------------------------
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn leb128() {
+        let mut out = Vec::new();
+        write_uleb128(&mut out, 2);
+        write_uleb128(&mut out, 127);
+        write_uleb128(&mut out, 128);
+        write_uleb128(&mut out, 624485);
+        assert_eq!(out, [0x02, 0x7F, 0x80, 0x01, 0xE5, 0x8E, 0x26]);
+        let mut out = Vec::new();
+        write_sleb128(&mut out, 2);
+        write_sleb128(&mut out, -2);
+        write_sleb128(&mut out, 127);
+        write_sleb128(&mut out, -128);
+        write_sleb128(&mut out, -8);
+        assert_eq!(out, [0x02, 0x7E, 0xFF, 0x00, 0x80, 0x7F, 0x78]);
+    }
+}
@@ -0,0 +1,523 @@
+//! Differential testing of [`Lower`] implementations against the reference
+//! interpreter in [`code::interpreter`].
+//!
+//! We generate random well-formed sequences of [`Action`]s, run them both
+//! ways, and compare the final values of all [`Global`]s, all written
+//! [`Register`]s, and a scratch buffer. When they disagree we minimize the
+//! sequence before reporting it.
+
+use std::fmt::{Write};
+
+use super::{code, Word, Pool, Lower, Execute, Target};
+use super::interpret::{ALL_PRECISIONS, ALL_UNARY_OPS, ALL_BINARY_OPS, ALL_WIDTHS};
+use super::tests::{TEST_VALUES};
+use code::{
+    Precision, BinaryOp, Width, AliasMask, Register, REGISTERS, Global, Slot,
+    Variable, Action, Convention, EBB, Ending, verify,
+};
+use code::interpreter::{State, Memory, width_bytes};
+use crate::util::{AsUsize};
+
+/// A deterministic pseudo-random number generator (xorshift64*).
+#[derive(Debug, Clone)]
+pub struct Random(u64);
+
+impl Random {
+    pub fn new(seed: u64) -> Self {
+        Random(seed.wrapping_mul(0x9E3779B97F4A7C15) | 1)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545F4914F6CDD1D)
+    }
+
+    /// Returns a number less than `n`, which must be positive.
+    pub fn below(&mut self, n: usize) -> usize {
+        assert!(n > 0);
+        (self.next_u64() % (n as u64)) as usize
+    }
+
+    pub fn choose<T: Copy>(&mut self, xs: &[T]) -> T {
+        xs[self.below(xs.len())]
+    }
+
+    /// Returns a value that is likely to exercise corner cases.
+    pub fn value(&mut self) -> u64 {
+        match self.below(3) {
+            0 => self.choose(&TEST_VALUES),
+            1 => self.below(64) as u64,
+            _ => self.next_u64(),
+        }
+    }
+}
+
+//-----------------------------------------------------------------------------
+
+/// `BASE` holds the address of the scratch buffer, and is never written.
+pub const BASE: Global = Global(0);
+
+/// The number of `Global`s, after `BASE`, that the generated code can use.
+pub const NUM_INPUTS: usize = 4;
+
+/// The `Global` into which the value of `REGISTERS[0]` is copied at the end.
+/// The other `REGISTERS` follow it.
+pub const FIRST_OUTPUT: usize = 1 + NUM_INPUTS;
+
+pub const NUM_GLOBALS: usize = FIRST_OUTPUT + REGISTERS.len();
+
+/// The size of the scratch buffer in words.
+pub const SCRATCH_WORDS: usize = 8;
+
+/// The initial state of a test case.
+#[derive(Debug, Clone)]
+pub struct Inputs {
+    /// The initial values of the `Global`s after `BASE`.
+    pub globals: [u64; NUM_INPUTS],
+    /// The initial contents of the scratch buffer.
+    pub scratch: [u64; SCRATCH_WORDS],
+}
+
+impl Inputs {
+    pub fn random(random: &mut Random) -> Self {
+        let mut inputs = Inputs {globals: [0; NUM_INPUTS], scratch: [0; SCRATCH_WORDS]};
+        for x in inputs.globals.iter_mut().chain(inputs.scratch.iter_mut()) {
+            *x = random.value();
+        }
+        inputs
+    }
+
+    /// Returns the initial values of all the `Global`s.
+    pub fn all_globals(&self, base: u64) -> Vec<u64> {
+        let mut globals = vec![0; NUM_GLOBALS];
+        globals[BASE.0] = base;
+        globals[1..FIRST_OUTPUT].copy_from_slice(&self.globals);
+        globals
+    }
+}
+
+/// The final state of a test case.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Outcome {
+    pub globals: Vec<u64>,
+    pub scratch: [u64; SCRATCH_WORDS],
+}
+
+//-----------------------------------------------------------------------------
+
+/// A copy of the scratch buffer, for the reference interpreter. Accesses
+/// that fall outside it, or are misaligned, are recorded as errors.
+#[derive(Debug, Clone)]
+pub struct Scratch {
+    base: u64,
+    bytes: Vec<u8>,
+    /// `false` if any invalid access has occurred.
+    pub is_ok: bool,
+}
+
+impl Scratch {
+    pub fn new(base: u64, scratch: &[u64; SCRATCH_WORDS]) -> Self {
+        let bytes = scratch.iter().flat_map(|w| w.to_le_bytes()).collect();
+        Scratch {base, bytes, is_ok: true}
+    }
+
+    pub fn words(&self) -> [u64; SCRATCH_WORDS] {
+        let mut words = [0; SCRATCH_WORDS];
+        for (word, bytes) in words.iter_mut().zip(self.bytes.chunks(8)) {
+            *word = u64::from_le_bytes(bytes.try_into().unwrap());
+        }
+        words
+    }
+
+    /// Returns the offset into `bytes` of `address`, if it is valid.
+    fn offset(&mut self, address: u64, width: Width) -> Option<u64> {
+        let size = width_bytes(width);
+        let offset = address.wrapping_sub(self.base);
+        if offset % (size as u64) == 0 && offset <= (self.bytes.len() - size) as u64 {
+            Some(offset)
+        } else {
+            self.is_ok = false;
+            None
+        }
+    }
+}
+
+impl Memory for Scratch {
+    fn load(&mut self, address: u64, width: Width) -> u64 {
+        self.offset(address, width).map_or(0, |offset| self.bytes[..].load(offset, width))
+    }
+
+    fn store(&mut self, address: u64, value: u64, width: Width) {
+        if let Some(offset) = self.offset(address, width) {
+            self.bytes[..].store(offset, value, width);
+        }
+    }
+}
+
+/// Tests whether the result of `action` is defined in `state`.
+pub fn is_defined(state: &State, action: Action) -> bool {
+    if let Action::Binary(op, prec, _, src1, src2) = action {
+        let (x, y) = (state.get(src1), state.get(src2));
+        let (sx, sy, min) = match prec {
+            Precision::P32 => (x as i32 as i64, y as i32 as i64, i32::MIN as i64),
+            Precision::P64 => (x as i64, y as i64, i64::MIN),
+        };
+        match op {
+            BinaryOp::UDiv => sy != 0,
+            BinaryOp::SDiv => sy != 0 && !(sx == min && sy == -1),
+            BinaryOp::Lsl | BinaryOp::Lsr | BinaryOp::Asr => y < prec.bits() as u64,
+            _ => true,
+        }
+    } else {
+        true
+    }
+}
+
+/// Appends to `body` code to copy every `Register` it writes into an output
+/// `Global`.
+fn observe(body: &[Action]) -> Vec<Action> {
+    let mut is_written = [false; REGISTERS.len()];
+    for &action in body {
+        let (dest1, dest2) = match action {
+            Action::Move(Variable::Register(r), _) => (Some(r), None),
+            Action::Constant(_, r, _) |
+            Action::Unary(_, _, r, _) |
+            Action::Binary(_, _, r, _, _) |
+            Action::Load(r, _, _) |
+            Action::Store(r, _, _, _) => (Some(r), None),
+            Action::Pop(dest1, dest2) => (dest1, dest2),
+            _ => (None, None),
+        };
+        for r in dest1.into_iter().chain(dest2) {
+            is_written[r.as_usize()] = true;
+        }
+    }
+    let mut program = body.to_vec();
+    for (i, &r) in REGISTERS.iter().enumerate() {
+        if is_written[i] {
+            program.push(Action::Move(Global(FIRST_OUTPUT + i).into(), r.into()));
+        }
+    }
+    program
+}
+
+/// Runs `body` using the reference interpreter. Returns `None` if `body` is
+/// malformed or its behaviour is undefined.
+pub fn reference(body: &[Action], inputs: &Inputs, base: u64) -> Option<Outcome> {
+    let program = observe(body);
+    let convention = Convention::empty(NUM_GLOBALS);
+    let ebb = EBB {actions: program, ending: Ending::Leaf(())};
+    verify(&convention, &ebb, |_| &convention, REGISTERS.len()).ok()?;
+    let mut state = State::new(inputs.all_globals(base));
+    let mut scratch = Scratch::new(base, &inputs.scratch);
+    for &action in &ebb.actions {
+        if let Action::Move(Variable::Global(g), _) = action {
+            if g == BASE { return None; }
+        }
+        if !is_defined(&state, action) { return None; }
+        state.action(action, &mut scratch);
+        if !scratch.is_ok { return None; }
+    }
+    Some(Outcome {globals: state.globals, scratch: scratch.words()})
+}
+
+/// Compiles `body` using `target` and runs it, using `buffer` as the scratch
+/// buffer.
+pub fn run<T: Target>(
+    target: &T,
+    body: &[Action],
+    inputs: &Inputs,
+    buffer: &mut [u64; SCRATCH_WORDS],
+) -> Outcome {
+    let mut lo = target.lowerer(NUM_GLOBALS);
+    let mut pool = Pool::new(NUM_GLOBALS);
+    let entry = lo.here();
+    lo.prologue();
+    lo.actions(&observe(body));
+    lo.epilogue();
+    let base = buffer.as_ptr() as u64;
+    for (i, &x) in inputs.all_globals(base).iter().enumerate() {
+        pool[Global(i)] = Word {u: x};
+    }
+    *buffer = inputs.scratch;
+    lo.execute(&entry, |f| unsafe { f(pool.as_mut().as_mut_ptr()) });
+    let globals = (0..NUM_GLOBALS).map(|i| unsafe { pool[Global(i)].u }).collect();
+    Outcome {globals, scratch: *buffer}
+}
+
+//-----------------------------------------------------------------------------
+
+/// Generates random well-formed code, keeping track of its effect so as to
+/// avoid undefined behaviour.
+///
+/// A `Generator` can be cloned in order to generate alternative continuations.
+#[derive(Debug, Clone)]
+pub struct Generator {
+    /// The code generated so far.
+    pub body: Vec<Action>,
+    state: State,
+    scratch: Scratch,
+    /// The `Register`s that have been written.
+    pub live: Vec<Register>,
+    /// The number of spill `Slot`s, all of which are live.
+    pub slots_used: usize,
+}
+
+impl Generator {
+    /// Constructs a `Generator` whose code will start in `state`, in which
+    /// `live` are the live `Register`s, and every `Slot` is live.
+    pub fn new(state: State, scratch: Scratch, live: Vec<Register>) -> Self {
+        let slots_used = state.slots.len();
+        Generator {body: Vec::new(), state, scratch, live, slots_used}
+    }
+
+    /// Appends a random group of [`Action`]s to `body`, if its behaviour is
+    /// defined. Often does nothing.
+    pub fn push_random(&mut self, random: &mut Random) {
+        let group = self.group(random);
+        self.try_push(group);
+    }
+
+    /// Appends an [`Action`] to `body` that drops all the `Slot`s.
+    pub fn drop_slots(&mut self) {
+        if self.slots_used > 0 {
+            let n = self.slots_used / 2;
+            self.try_push(vec![Action::DropMany(n)]);
+        }
+    }
+
+    /// Returns a random `Register`.
+    pub fn register(&self, random: &mut Random) -> Register {
+        random.choose(&REGISTERS)
+    }
+
+    /// Returns a random live `Variable`.
+    pub fn src(&self, random: &mut Random) -> Variable {
+        let n = NUM_INPUTS + 1 + self.live.len() + self.slots_used;
+        let i = random.below(n);
+        if i <= NUM_INPUTS {
+            Global(i).into()
+        } else if i <= NUM_INPUTS + self.live.len() {
+            self.live[i - NUM_INPUTS - 1].into()
+        } else {
+            Slot(i - NUM_INPUTS - 1 - self.live.len()).into()
+        }
+    }
+
+    /// Returns a random `Variable` that can be written.
+    fn dest(&self, random: &mut Random) -> Variable {
+        match random.below(4) {
+            0 => Global(1 + random.below(NUM_INPUTS)).into(),
+            1 if self.slots_used > 0 => Slot(random.below(self.slots_used)).into(),
+            _ => self.register(random).into(),
+        }
+    }
+
+    /// Returns a random sequence of `Action`s that is well-formed, but whose
+    /// behaviour might not be defined.
+    fn group(&mut self, random: &mut Random) -> Vec<Action> {
+        let prec = random.choose(&ALL_PRECISIONS);
+        match random.below(10) {
+            0 => vec![Action::Move(self.dest(random), self.src(random))],
+            1 => vec![Action::Constant(prec, self.register(random), random.value() as i64)],
+            2 => {
+                let op = random.choose(&ALL_UNARY_OPS);
+                vec![Action::Unary(op, prec, self.register(random), self.src(random))]
+            },
+            3 | 4 => {
+                let op = random.choose(&ALL_BINARY_OPS);
+                if matches!(op, BinaryOp::Lsl | BinaryOp::Lsr | BinaryOp::Asr) {
+                    // Most random shift amounts are undefined.
+                    let amount = self.register(random);
+                    let shift = random.below(prec.bits()) as i64;
+                    vec![
+                        Action::Constant(Precision::P64, amount, shift),
+                        Action::Binary(op, prec, self.register(random), self.src(random), amount.into()),
+                    ]
+                } else {
+                    vec![Action::Binary(op, prec, self.register(random), self.src(random), self.src(random))]
+                }
+            },
+            5 | 6 => {
+                let width = random.choose(&ALL_WIDTHS);
+                let size = width_bytes(width);
+                let offset = random.below(SCRATCH_WORDS * 8 / size) * size;
+                let address = self.register(random);
+                let mut group = vec![
+                    Action::Constant(Precision::P64, address, offset as i64),
+                    Action::Binary(BinaryOp::Add, Precision::P64, address, BASE.into(), address.into()),
+                ];
+                // Choose operands after `address` is live.
+                self.live.push(address);
+                let dest = self.register(random);
+                group.push(if random.below(2) == 0 {
+                    Action::Load(dest, (address.into(), width), AliasMask(1))
+                } else {
+                    Action::Store(dest, self.src(random), (address.into(), width), AliasMask(1))
+                });
+                self.live.pop();
+                group
+            },
+            7 => vec![Action::Push(Some(self.src(random)), Some(self.src(random)))],
+            8 if self.slots_used >= 2 => {
+                let dest1 = self.register(random);
+                let dest2 = self.register(random);
+                let dest2 = if dest1 == dest2 { None } else { Some(dest2) };
+                vec![Action::Pop(Some(dest1), dest2)]
+            },
+            9 if self.slots_used >= 2 => {
+                vec![Action::DropMany(1 + random.below(self.slots_used / 2))]
+            },
+            _ => vec![],
+        }
+    }
+
+    /// Appends `group` to `body` if its behaviour is defined.
+    pub fn try_push(&mut self, group: Vec<Action>) {
+        let mut state = self.state.clone();
+        let mut scratch = self.scratch.clone();
+        for &action in &group {
+            if !is_defined(&state, action) { return; }
+            state.action(action, &mut scratch);
+            if !scratch.is_ok { return; }
+        }
+        for &action in &group {
+            match action {
+                Action::Move(Variable::Register(r), _) |
+                Action::Constant(_, r, _) |
+                Action::Unary(_, _, r, _) |
+                Action::Binary(_, _, r, _, _) |
+                Action::Load(r, _, _) |
+                Action::Store(r, _, _, _) => { self.make_live(r); },
+                Action::Push(_, _) => { self.slots_used += 2; },
+                Action::Pop(dest1, dest2) => {
+                    self.slots_used -= 2;
+                    for r in dest1.into_iter().chain(dest2) { self.make_live(r); }
+                },
+                Action::DropMany(n) => { self.slots_used -= 2 * n; },
+                _ => {},
+            }
+        }
+        self.state = state;
+        self.scratch = scratch;
+        self.body.extend(group);
+    }
+
+    fn make_live(&mut self, r: Register) {
+        if !self.live.contains(&r) { self.live.push(r); }
+    }
+}
+
+/// Returns random well-formed code with at least `length` [`Action`]s, whose
+/// behaviour is defined given `inputs` and `base`.
+pub fn generate(random: &mut Random, inputs: &Inputs, base: u64, length: usize) -> Vec<Action> {
+    let state = State::new(inputs.all_globals(base));
+    let mut g = Generator::new(state, Scratch::new(base, &inputs.scratch), Vec::new());
+    while g.body.len() < length {
+        g.push_random(random);
+    }
+    g.drop_slots();
+    g.body
+}
+
+/// Removes as many elements (typically [`Action`]s) from `body` as possible
+/// while `fails` remains `true`. `fails(body)` must be `true` initially.
+pub fn minimize<T: Clone>(mut body: Vec<T>, mut fails: impl FnMut(&[T]) -> bool) -> Vec<T> {
+    let mut chunk = (body.len() + 1) / 2;
+    while chunk > 0 {
+        let mut i = 0;
+        while i < body.len() {
+            let mut candidate = body.clone();
+            candidate.drain(i..std::cmp::min(i + chunk, body.len()));
+            if fails(&candidate) {
+                body = candidate;
+            } else {
+                i += chunk;
+            }
+        }
+        chunk /= 2;
+    }
+    body
+}
+
+/// Runs `num_cases` random test cases on `target`. Panics with a minimized
+/// test case if the `target` disagrees with [`reference()`].
+pub fn fuzz<T: Target>(target: T, num_cases: u64, length: usize) {
+    let mut buffer = Box::new([0u64; SCRATCH_WORDS]);
+    let base = buffer.as_ptr() as u64;
+    for seed in 0..num_cases {
+        let mut random = Random::new(seed);
+        let inputs = Inputs::random(&mut random);
+        let body = generate(&mut random, &inputs, base, length);
+        let expected = reference(&body, &inputs, base).expect("Generated code is malformed");
+        if run(&target, &body, &inputs, &mut buffer) == expected { continue; }
+        let body = minimize(body, |body| {
+            reference(body, &inputs, base).map_or(false, |expected| {
+                run(&target, body, &inputs, &mut buffer) != expected
+            })
+        });
+        let expected = reference(&body, &inputs, base).unwrap();
+        let observed = run(&target, &body, &inputs, &mut buffer);
+        let mut report = String::new();
+        writeln!(report, "Lowerer disagrees with the reference interpreter (seed {}).", seed).unwrap();
+        writeln!(report, "Globals 1 to {}: {:#x?}", NUM_INPUTS, inputs.globals).unwrap();
+        writeln!(report, "Scratch: {:#x?}", inputs.scratch).unwrap();
+        writeln!(report, "Minimized code:").unwrap();
+        for action in observe(&body) {
+            writeln!(report, "    {:?}", action).unwrap();
+        }
+        writeln!(report, "Expected: {:#x?}", expected).unwrap();
+        writeln!(report, "Observed: {:#x?}", observed).unwrap();
+        panic!("{}", report);
+    }
+}
+
+//-----------------------------------------------------------------------------
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use super::super::{native, interpret};
+
+    #[test]
+    fn native_lowerer() {
+        fuzz(native(), 2000, 60);
+    }
+
+    #[test]
+    fn interpret_lowerer() {
+        fuzz(interpret::Target, 200, 40);
+    }
+
+    #[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64", target_arch = "riscv64")))]
+    #[test]
+    fn memfd_lowerer() {
+        fuzz(super::super::tests::MemfdNative::default(), 200, 40);
+    }
+
+    #[test]
+    fn generate_is_deterministic() {
+        let inputs = Inputs::random(&mut Random::new(1));
+        let body1 = generate(&mut Random::new(2), &inputs, 0x1000, 100);
+        let body2 = generate(&mut Random::new(2), &inputs, 0x1000, 100);
+        assert_eq!(body1, body2);
+        assert!(reference(&body1, &inputs, 0x1000).is_some());
+    }
+
+    #[test]
+    fn minimize_() {
+        let inputs = Inputs::random(&mut Random::new(3));
+        let base = 0x1000;
+        let is_mul = |a: &Action| matches!(a, Action::Binary(BinaryOp::Mul, ..));
+        let body = (0..).map(|seed| {
+            generate(&mut Random::new(seed), &inputs, base, 60)
+        }).find(|body| body.iter().filter(|a| is_mul(a)).count() > 1).unwrap();
+        let minimized = minimize(body.clone(), |body| {
+            reference(body, &inputs, base).is_some() && body.iter().any(is_mul)
+        });
+        assert!(minimized.len() < body.len());
+        assert_eq!(minimized.iter().filter(|a| is_mul(a)).count(), 1);
+    }
+}
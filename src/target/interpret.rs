@@ -0,0 +1,648 @@
+//! A portable [`Target`] that compiles Mijit code to a bytecode, and executes
+//! it using [`code::interpreter`]. It is much slower than a native `Target`,
+//! but it works on any host.
+//!
+//! [`Target`]: super::Target
+
+use std::cell::{Cell};
+
+use crate::util::{AsUsize};
+use crate::error::{Error};
+use super::{buffer, code, Patch, Label, Word, Lower, ExecuteFn, Execute, CrossTarget, RESULT};
+use buffer::{Buffer};
+use code::{
+    Precision, UnaryOp, BinaryOp, Width, AliasMask, Register, Global, Slot,
+    Variable, Action,
+};
+use code::interpreter::{State, NativeMemory};
+
+//-----------------------------------------------------------------------------
+
+/// The bytecode opcodes. Control-flow instructions are followed first by their
+/// target, so that they can all be patched in the same way.
+mod opcode {
+    pub const JUMP: u8 = 0x01;
+    pub const IF_EQ: u8 = 0x02;
+    pub const IF_NE: u8 = 0x03;
+    pub const PROLOGUE: u8 = 0x04;
+    pub const EPILOGUE: u8 = 0x05;
+    pub const IF_ULT: u8 = 0x06;
+    pub const JUMP_TABLE: u8 = 0x07;
+    pub const MOVE: u8 = 0x10;
+    pub const CONSTANT: u8 = 0x11;
+    pub const UNARY: u8 = 0x12;
+    pub const BINARY: u8 = 0x13;
+    pub const LOAD: u8 = 0x14;
+    pub const STORE: u8 = 0x15;
+    pub const PUSH: u8 = 0x16;
+    pub const POP: u8 = 0x17;
+    pub const DROP_MANY: u8 = 0x18;
+    pub const DEBUG: u8 = 0x19;
+}
+
+/// The encoding of a jump target that is not yet known.
+const NO_TARGET: u32 = u32::MAX;
+
+/// The length of a `JUMP` instruction, which is also the length of an entry
+/// in a jump table.
+const JUMP_LENGTH: usize = 5;
+
+/// The tags that precede the index of a [`Variable`].
+const TAG_REGISTER: u8 = 0;
+const TAG_GLOBAL: u8 = 1;
+const TAG_SLOT: u8 = 2;
+const TAG_NONE: u8 = 0xFF;
+
+pub(super) const ALL_PRECISIONS: [Precision; 2] = [Precision::P32, Precision::P64];
+
+pub(super) const ALL_UNARY_OPS: [UnaryOp; 3] = [UnaryOp::Abs, UnaryOp::Negate, UnaryOp::Not];
+
+pub(super) const ALL_BINARY_OPS: [BinaryOp; 16] = {
+    use BinaryOp::*;
+    [Add, Sub, Mul, UDiv, SDiv, Lsl, Lsr, Asr, And, Or, Xor, Lt, Ult, Eq, Max, Min]
+};
+
+pub(super) const ALL_WIDTHS: [Width; 4] = [Width::One, Width::Two, Width::Four, Width::Eight];
+
+/// A decoded bytecode instruction.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Instruction {
+    Jump(Option<usize>),
+    IfEq(Option<usize>, Variable, u64),
+    IfNe(Option<usize>, Variable, u64),
+    IfUlt(Option<usize>, Variable, u64),
+    /// Followed by a table of `Jump` instructions. If the `Variable` is less
+    /// than the `u32`, run the indexed entry, otherwise skip the table.
+    JumpTable(Variable, u32),
+    Prologue,
+    Epilogue,
+    Action(Action),
+}
+
+fn encode_target(target: Option<usize>) -> u32 {
+    match target {
+        Some(target) => {
+            let target = u32::try_from(target).expect("Code too large");
+            assert_ne!(target, NO_TARGET);
+            target
+        },
+        None => NO_TARGET,
+    }
+}
+
+fn decode_target(target: u32) -> Option<usize> {
+    if target == NO_TARGET { None } else { Some(target as usize) }
+}
+
+/// Appends the encoding of an [`Instruction`] to a `Vec<u8>`.
+struct Encoder<'a>(&'a mut Vec<u8>);
+
+impl<'a> Encoder<'a> {
+    fn u8(&mut self, x: u8) { self.0.push(x); }
+
+    fn u32(&mut self, x: u32) { self.0.extend(x.to_le_bytes()); }
+
+    fn u64(&mut self, x: u64) { self.0.extend(x.to_le_bytes()); }
+
+    fn variable(&mut self, v: Option<Variable>) {
+        let (tag, index) = match v {
+            Some(Variable::Register(r)) => (TAG_REGISTER, r.as_usize()),
+            Some(Variable::Global(Global(g))) => (TAG_GLOBAL, g),
+            Some(Variable::Slot(Slot(s))) => (TAG_SLOT, s),
+            None => (TAG_NONE, 0),
+        };
+        self.u8(tag);
+        self.u32(u32::try_from(index).expect("Index too large"));
+    }
+
+    fn instruction(&mut self, instruction: Instruction) {
+        match instruction {
+            Instruction::Jump(target) => {
+                self.u8(opcode::JUMP);
+                self.u32(encode_target(target));
+            },
+            Instruction::IfEq(target, v, value) |
+            Instruction::IfNe(target, v, value) |
+            Instruction::IfUlt(target, v, value) => {
+                self.u8(match instruction {
+                    Instruction::IfEq(..) => opcode::IF_EQ,
+                    Instruction::IfNe(..) => opcode::IF_NE,
+                    _ => opcode::IF_ULT,
+                });
+                self.u32(encode_target(target));
+                self.variable(Some(v));
+                self.u64(value);
+            },
+            Instruction::JumpTable(v, num_cases) => {
+                self.u8(opcode::JUMP_TABLE);
+                self.variable(Some(v));
+                self.u32(num_cases);
+            },
+            Instruction::Prologue => { self.u8(opcode::PROLOGUE); },
+            Instruction::Epilogue => { self.u8(opcode::EPILOGUE); },
+            Instruction::Action(action) => { self.action(action); },
+        }
+    }
+
+    fn action(&mut self, action: Action) {
+        match action {
+            Action::Move(dest, src) => {
+                self.u8(opcode::MOVE);
+                self.variable(Some(dest));
+                self.variable(Some(src));
+            },
+            Action::Constant(prec, dest, value) => {
+                self.u8(opcode::CONSTANT);
+                self.u8(prec as u8);
+                self.variable(Some(dest.into()));
+                self.u64(value as u64);
+            },
+            Action::Unary(op, prec, dest, src) => {
+                self.u8(opcode::UNARY);
+                self.u8(op as u8);
+                self.u8(prec as u8);
+                self.variable(Some(dest.into()));
+                self.variable(Some(src));
+            },
+            Action::Binary(op, prec, dest, src1, src2) => {
+                self.u8(opcode::BINARY);
+                self.u8(op as u8);
+                self.u8(prec as u8);
+                self.variable(Some(dest.into()));
+                self.variable(Some(src1));
+                self.variable(Some(src2));
+            },
+            Action::Load(dest, (addr, width), AliasMask(mask)) => {
+                self.u8(opcode::LOAD);
+                self.variable(Some(dest.into()));
+                self.variable(Some(addr));
+                self.u8(width as u8);
+                self.u32(mask);
+            },
+            Action::Store(dest, src, (addr, width), AliasMask(mask)) => {
+                self.u8(opcode::STORE);
+                self.variable(Some(dest.into()));
+                self.variable(Some(src));
+                self.variable(Some(addr));
+                self.u8(width as u8);
+                self.u32(mask);
+            },
+            Action::Push(src1, src2) => {
+                self.u8(opcode::PUSH);
+                self.variable(src1);
+                self.variable(src2);
+            },
+            Action::Pop(dest1, dest2) => {
+                self.u8(opcode::POP);
+                self.variable(dest1.map(Variable::from));
+                self.variable(dest2.map(Variable::from));
+            },
+            Action::DropMany(n) => {
+                self.u8(opcode::DROP_MANY);
+                self.u32(u32::try_from(n).expect("Too many slots"));
+            },
+            Action::Debug(src) => {
+                self.u8(opcode::DEBUG);
+                self.variable(Some(src));
+            },
+        }
+    }
+}
+
+/// Reads [`Instruction`]s from a `&[u8]`.
+struct Decoder<'a> {
+    code: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn bytes<const N: usize>(&mut self) -> [u8; N] {
+        let ret = self.code[self.pos..self.pos + N].try_into().unwrap();
+        self.pos += N;
+        ret
+    }
+
+    fn u8(&mut self) -> u8 { self.bytes::<1>()[0] }
+
+    fn u32(&mut self) -> u32 { u32::from_le_bytes(self.bytes()) }
+
+    fn u64(&mut self) -> u64 { u64::from_le_bytes(self.bytes()) }
+
+    fn target(&mut self) -> Option<usize> { decode_target(self.u32()) }
+
+    fn precision(&mut self) -> Precision { ALL_PRECISIONS[self.u8() as usize] }
+
+    fn width(&mut self) -> Width { ALL_WIDTHS[self.u8() as usize] }
+
+    fn option_variable(&mut self) -> Option<Variable> {
+        let tag = self.u8();
+        let index = self.u32() as usize;
+        match tag {
+            TAG_REGISTER => Some(self.register_index(index).into()),
+            TAG_GLOBAL => Some(Global(index).into()),
+            TAG_SLOT => Some(Slot(index).into()),
+            TAG_NONE => None,
+            _ => panic!("Bad Variable tag {}", tag),
+        }
+    }
+
+    fn variable(&mut self) -> Variable {
+        self.option_variable().expect("Missing Variable")
+    }
+
+    fn register_index(&self, index: usize) -> Register {
+        u8::try_from(index).ok().and_then(Register::new).expect("Bad Register")
+    }
+
+    fn option_register(&mut self) -> Option<Register> {
+        self.option_variable().map(|v| match v {
+            Variable::Register(r) => r,
+            _ => panic!("Not a Register: {:?}", v),
+        })
+    }
+
+    fn register(&mut self) -> Register {
+        self.option_register().expect("Missing Register")
+    }
+
+    fn instruction(&mut self) -> Instruction {
+        let op = self.u8();
+        match op {
+            opcode::JUMP => Instruction::Jump(self.target()),
+            opcode::IF_EQ => Instruction::IfEq(self.target(), self.variable(), self.u64()),
+            opcode::IF_NE => Instruction::IfNe(self.target(), self.variable(), self.u64()),
+            opcode::IF_ULT => Instruction::IfUlt(self.target(), self.variable(), self.u64()),
+            opcode::JUMP_TABLE => Instruction::JumpTable(self.variable(), self.u32()),
+            opcode::PROLOGUE => Instruction::Prologue,
+            opcode::EPILOGUE => Instruction::Epilogue,
+            _ => Instruction::Action(self.action(op)),
+        }
+    }
+
+    fn action(&mut self, op: u8) -> Action {
+        match op {
+            opcode::MOVE => Action::Move(self.variable(), self.variable()),
+            opcode::CONSTANT => {
+                let prec = self.precision();
+                Action::Constant(prec, self.register(), self.u64() as i64)
+            },
+            opcode::UNARY => {
+                let op = ALL_UNARY_OPS[self.u8() as usize];
+                let prec = self.precision();
+                Action::Unary(op, prec, self.register(), self.variable())
+            },
+            opcode::BINARY => {
+                let op = ALL_BINARY_OPS[self.u8() as usize];
+                let prec = self.precision();
+                Action::Binary(op, prec, self.register(), self.variable(), self.variable())
+            },
+            opcode::LOAD => {
+                let dest = self.register();
+                let addr = (self.variable(), self.width());
+                Action::Load(dest, addr, AliasMask(self.u32()))
+            },
+            opcode::STORE => {
+                let dest = self.register();
+                let src = self.variable();
+                let addr = (self.variable(), self.width());
+                Action::Store(dest, src, addr, AliasMask(self.u32()))
+            },
+            opcode::PUSH => Action::Push(self.option_variable(), self.option_variable()),
+            opcode::POP => Action::Pop(self.option_register(), self.option_register()),
+            opcode::DROP_MANY => Action::DropMany(self.u32() as usize),
+            opcode::DEBUG => Action::Debug(self.variable()),
+            _ => panic!("Bad opcode {:#x} at {}", op, self.pos - 1),
+        }
+    }
+}
+
+//-----------------------------------------------------------------------------
+
+/// Runs the bytecode in `code` starting at `pc`, using `globals` as the
+/// storage for the [`Global`]s. Returns the value of [`RESULT`] at the first
+/// epilogue.
+///
+/// # Safety
+///
+/// The code can read and write arbitrary memory.
+unsafe fn run(code: &[u8], mut pc: usize, globals: &mut [Word]) -> Word {
+    let mut state = State::new(globals.iter().map(|w| w.u).collect());
+    let mut memory = NativeMemory::new();
+    loop {
+        let mut decoder = Decoder {code, pos: pc};
+        let instruction = decoder.instruction();
+        pc = decoder.pos;
+        let jump = match instruction {
+            Instruction::Jump(target) => Some(target),
+            Instruction::IfEq(target, v, value) => (state.get(v) == value).then_some(target),
+            Instruction::IfNe(target, v, value) => (state.get(v) != value).then_some(target),
+            Instruction::IfUlt(target, v, value) => (state.get(v) < value).then_some(target),
+            Instruction::JumpTable(v, num_cases) => {
+                let index = std::cmp::min(state.get(v), u64::from(num_cases));
+                pc += JUMP_LENGTH * index as usize;
+                None
+            },
+            Instruction::Prologue => None,
+            Instruction::Epilogue => {
+                for (word, &x) in globals.iter_mut().zip(&state.globals) {
+                    *word = Word {u: x};
+                }
+                return Word {u: state.get(RESULT)};
+            },
+            Instruction::Action(action) => {
+                state.action(action, &mut memory);
+                None
+            },
+        };
+        if let Some(target) = jump {
+            pc = target.expect("Jump to undefined Label");
+        }
+    }
+}
+
+/// The code that [`trampoline()`] should run.
+#[derive(Copy, Clone)]
+struct Context {
+    code: *const u8,
+    len: usize,
+    target: usize,
+    num_globals: usize,
+}
+
+thread_local! {
+    /// The `Context` of the innermost call to [`Lowerer::try_execute()`].
+    static CONTEXT: Cell<Option<Context>> = const { Cell::new(None) };
+}
+
+/// The [`ExecuteFn`] passed to the callback of [`Lowerer::try_execute()`].
+/// It finds the bytecode in [`CONTEXT`].
+unsafe extern "C" fn trampoline(pool: *mut Word) -> Word {
+    let context = CONTEXT.with(|c| c.get()).expect("Not inside `execute()`");
+    let code = std::slice::from_raw_parts(context.code, context.len);
+    let globals = std::slice::from_raw_parts_mut(pool, context.num_globals);
+    run(code, context.target, globals)
+}
+
+/// Restores the previous value of [`CONTEXT`] when dropped.
+struct RestoreContext(Option<Context>);
+
+impl Drop for RestoreContext {
+    fn drop(&mut self) {
+        CONTEXT.with(|c| c.set(self.0));
+    }
+}
+
+//-----------------------------------------------------------------------------
+
+/// Compiles Mijit code to bytecode.
+pub struct Lowerer {
+    /// The bytecode.
+    code: Vec<u8>,
+    /// The number of [`Global`]s in the [`Pool`].
+    ///
+    /// [`Pool`]: super::Pool
+    num_globals: usize,
+    /// The number of spill [`Slot`]s.
+    slots_used: usize,
+}
+
+impl Lowerer {
+    pub fn new(num_globals: usize) -> Self {
+        Lowerer {code: Vec::new(), num_globals, slots_used: 0}
+    }
+
+    fn write(&mut self, instruction: Instruction) {
+        Encoder(&mut self.code).instruction(instruction);
+    }
+
+    /// Assemble a control-flow instruction whose target is `label`.
+    fn write_jump(&mut self, label: &mut Label, instruction: impl FnOnce(Option<usize>) -> Instruction) {
+        label.push(Patch::new(self.code.len()));
+        self.write(instruction(label.target()));
+    }
+}
+
+impl Lower for Lowerer {
+    fn num_globals(&self) -> usize { self.num_globals }
+
+    fn slots_used_mut(&mut self) -> &mut usize { &mut self.slots_used }
+
+    fn here(&self) -> Label { Label::new(Some(self.code.len())) }
+
+    fn code(&self) -> &[u8] { &self.code }
+
+    /// The code is bytecode, not machine code.
+    fn executable_address(&self) -> Option<usize> { None }
+
+    fn patch(&mut self, patch: Patch, old_target: Option<usize>, new_target: Option<usize>) {
+        let pos = patch.address();
+        assert!(
+            matches!(self.code[pos], opcode::JUMP | opcode::IF_EQ | opcode::IF_NE | opcode::IF_ULT),
+            "not a jump instruction",
+        );
+        let at = pos + 1;
+        let mut decoder = Decoder {code: &self.code, pos: at};
+        assert_eq!(decoder.target(), old_target);
+        self.code[at..at + 4].copy_from_slice(&encode_target(new_target).to_le_bytes());
+    }
+
+    fn jump(&mut self, label: &mut Label) {
+        self.write_jump(label, Instruction::Jump);
+    }
+
+    fn prologue(&mut self) {
+        self.write(Instruction::Prologue);
+    }
+
+    fn epilogue(&mut self) {
+        self.write(Instruction::Epilogue);
+    }
+
+    fn if_eq(&mut self, guard: (Variable, u64), eq_label: &mut Label) {
+        let (discriminant, value) = guard;
+        self.write_jump(eq_label, |target| Instruction::IfEq(target, discriminant, value));
+    }
+
+    fn if_ne(&mut self, guard: (Variable, u64), ne_label: &mut Label) {
+        let (discriminant, value) = guard;
+        self.write_jump(ne_label, |target| Instruction::IfNe(target, discriminant, value));
+    }
+
+    fn if_ult(&mut self, guard: (Variable, u64), lt_label: &mut Label) {
+        let (discriminant, value) = guard;
+        self.write_jump(lt_label, |target| Instruction::IfUlt(target, discriminant, value));
+    }
+
+    fn jump_table(&mut self, discriminant: Variable, cases: &mut [Label], default_: &mut Label) {
+        let num_cases = u32::try_from(cases.len()).expect("Too many cases");
+        self.write(Instruction::JumpTable(discriminant, num_cases));
+        for case in cases {
+            self.jump(case);
+        }
+        // The `JumpTable` skips to here if the discriminant is out of range.
+        self.jump(default_);
+    }
+
+    fn action(&mut self, action: Action) {
+        match action {
+            Action::Push(_, _) => { self.slots_used += 2; },
+            Action::Pop(_, _) => { self.slots_used -= 2; },
+            Action::DropMany(n) => { self.slots_used -= 2 * n; },
+            _ => {},
+        }
+        self.write(Instruction::Action(action));
+    }
+}
+
+impl Execute for Lowerer {
+    fn try_execute<T>(
+        &mut self,
+        label: &Label,
+        callback: impl FnOnce(ExecuteFn) -> T,
+    ) -> Result<T, Error> {
+        let context = Context {
+            code: self.code.as_ptr(),
+            len: self.code.len(),
+            target: label.target().expect("Label is not defined"),
+            num_globals: self.num_globals,
+        };
+        let _restore = RestoreContext(CONTEXT.with(|c| c.replace(Some(context))));
+        Ok(callback(trampoline))
+    }
+}
+
+//-----------------------------------------------------------------------------
+
+/// The bytecode interpreter target, which works on any host.
+#[derive(Debug, Default)]
+pub struct Target;
+
+impl super::Target for Target {
+    type Lowerer = Lowerer;
+
+    /// The interpreter could support up to 255 registers, but the optimizer
+    /// does not need that many.
+    const NUM_REGISTERS: usize = 16;
+
+    fn lowerer(&self, num_globals: usize) -> Self::Lowerer {
+        Lowerer::new(num_globals)
+    }
+}
+
+/// Bytecode is portable, so we ignore `B` and use the usual `Lowerer`.
+impl<B: Buffer> CrossTarget<B> for Target {
+    type CrossLowerer = Lowerer;
+
+    fn cross_lowerer(&self, num_globals: usize) -> Self::CrossLowerer {
+        Lowerer::new(num_globals)
+    }
+}
+
+//-----------------------------------------------------------------------------
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use super::super::{Pool};
+    use code::{REGISTERS};
+    use crate::jit::factorial::{Factorial};
+    use Precision::*;
+
+    const R0: Register = REGISTERS[0];
+    const R1: Register = REGISTERS[1];
+
+    #[test]
+    fn round_trip() {
+        let instructions = [
+            Instruction::Jump(None),
+            Instruction::IfEq(Some(7), Slot(3).into(), !0),
+            Instruction::IfNe(Some(0), R1.into(), 42),
+            Instruction::IfUlt(None, Global(1).into(), 3),
+            Instruction::JumpTable(R0.into(), 256),
+            Instruction::Prologue,
+            Instruction::Epilogue,
+            Instruction::Action(Action::Move(Global(2).into(), R1.into())),
+            Instruction::Action(Action::Constant(P32, R0, -1)),
+            Instruction::Action(Action::Unary(UnaryOp::Not, P64, R0, Slot(0).into())),
+            Instruction::Action(Action::Binary(BinaryOp::Min, P32, R1, R0.into(), Global(0).into())),
+            Instruction::Action(Action::Load(R0, (R1.into(), Width::Two), AliasMask(5))),
+            Instruction::Action(Action::Store(R0, R1.into(), (R0.into(), Width::Eight), AliasMask(!0))),
+            Instruction::Action(Action::Push(None, Some(R0.into()))),
+            Instruction::Action(Action::Pop(Some(R1), None)),
+            Instruction::Action(Action::DropMany(3)),
+            Instruction::Action(Action::Debug(Global(1).into())),
+        ];
+        let mut code = Vec::new();
+        for &instruction in &instructions {
+            Encoder(&mut code).instruction(instruction);
+        }
+        let mut decoder = Decoder {code: &code, pos: 0};
+        for &instruction in &instructions {
+            assert_eq!(decoder.instruction(), instruction);
+        }
+        assert_eq!(decoder.pos, code.len());
+    }
+
+    #[test]
+    fn branches() {
+        let mut lo = Lowerer::new(1);
+        let mut pool = Pool::new(1);
+        let entry = lo.here();
+        let mut else_ = Label::new(None);
+        let mut endif = Label::new(None);
+        lo.prologue();
+        lo.if_ne((Global(0).into(), 3), &mut else_);
+        lo.action(Action::Constant(P64, R0, 10));
+        lo.jump(&mut endif);
+        lo.define(&mut else_);
+        lo.action(Action::Constant(P64, R1, 1));
+        lo.action(Action::Binary(BinaryOp::Add, P64, R0, Global(0).into(), R1.into()));
+        lo.action(Action::Move(Global(0).into(), R0.into()));
+        lo.define(&mut endif);
+        lo.epilogue();
+        for (x, result, global) in [(3, 10, 3), (4, 5, 5)] {
+            pool[Global(0)] = Word {u: x};
+            let observed = lo.execute(&entry, |f| unsafe {
+                f(pool.as_mut().as_mut_ptr())
+            });
+            assert_eq!(observed, Word {u: result});
+            assert_eq!(pool[Global(0)], Word {u: global});
+        }
+    }
+
+    #[test]
+    fn jump_table() {
+        let mut code = Vec::new();
+        Encoder(&mut code).instruction(Instruction::Jump(None));
+        assert_eq!(code.len(), JUMP_LENGTH);
+        let mut lo = Lowerer::new(1);
+        let mut pool = Pool::new(1);
+        let entry = lo.here();
+        let mut cases: Vec<Label> = (0..3).map(|_| Label::new(None)).collect();
+        let mut default_ = Label::new(None);
+        lo.prologue();
+        lo.jump_table(Global(0).into(), &mut cases, &mut default_);
+        for (i, case) in cases.iter_mut().enumerate() {
+            lo.define(case);
+            lo.action(Action::Constant(P64, R0, 10 * i as i64));
+            lo.epilogue();
+        }
+        lo.define(&mut default_);
+        lo.action(Action::Constant(P64, R0, 99));
+        lo.epilogue();
+        for (x, result) in [(0, 0), (1, 10), (2, 20), (3, 99), (!0, 99)] {
+            pool[Global(0)] = Word {u: x};
+            let observed = lo.execute(&entry, |f| unsafe {
+                f(pool.as_mut().as_mut_ptr())
+            });
+            assert_eq!(observed, Word {u: result});
+        }
+    }
+
+    #[test]
+    fn factorial() {
+        let mut factorial = Factorial::new(Target);
+        assert_eq!(factorial.run(5), 120);
+        assert_eq!(factorial.run(10), 3628800);
+    }
+}
@@ -0,0 +1,691 @@
+use super::{buffer, code, Patch, Register, Condition, MemOp, AluOp};
+use buffer::{Buffer};
+use crate::error::{Error};
+use code::{Precision, Width};
+
+use Register::*;
+use Precision::*;
+
+//-----------------------------------------------------------------------------
+
+/// Computes the displacement from `from` to `to`.
+pub fn disp(from: usize, to: usize) -> i64 {
+    if from > i64::MAX as usize || to > i64::MAX as usize {
+        panic!("Displacements greater than isize::MAX are not supported");
+    }
+    (to as i64) - (from as i64)
+}
+
+/// Returns a bitmask representing `x` as a `bits`-bit signed integer.
+pub fn signed(x: i64, bits: usize) -> Option<u32> {
+    let limit: i64 = 1 << (bits - 1);
+    if x >= limit || x < -limit {
+        None
+    } else {
+        Some((x & (2*limit - 1)) as u32)
+    }
+}
+
+/// Computes the offset from `from` to `to`. Returns a dummy value if the
+/// target is `None`. Returns `None` if the offset is not a `bits`-bit signed
+/// integer.
+pub fn jump_offset(from: usize, to: Option<usize>, bits: usize) -> Option<i64> {
+    match to {
+        Some(to) => {
+            let offset = disp(from, to);
+            assert_eq!(offset & 1, 0);
+            signed(offset, bits).map(|_| offset)
+        },
+        None => {
+            Some(-1 << (bits - 1))
+        },
+    }
+}
+
+/// Encodes `offset` in the immediate fields of a conditional branch.
+fn branch_offset(offset: i64) -> u32 {
+    let imm = signed(offset, 13).unwrap();
+    (imm >> 12 & 1) << 31 | (imm >> 5 & 0x3F) << 25 | (imm >> 1 & 0xF) << 8 | (imm >> 11 & 1) << 7
+}
+
+/// The bits of a conditional branch that hold the offset.
+const BRANCH_OFFSET_MASK: u32 = 0xFE000F80;
+
+/// Splits `offset` into a 20-bit part for `AUIPC` and a 12-bit part for
+/// `JALR`, which are returned as bitmasks.
+fn long_offset(offset: i64) -> (u32, u32) {
+    let hi = (offset + 0x800) >> 12;
+    let lo = offset - (hi << 12);
+    (signed(hi, 20).expect("Cannot jump so far") << 12, signed(lo, 12).unwrap() << 20)
+}
+
+/// Returns `x` sign-extended from 12 bits.
+fn low_12_bits(x: i64) -> i64 {
+    (x << 52) >> 52
+}
+
+//-----------------------------------------------------------------------------
+
+/// Major opcodes.
+const LOAD: u32 = 0x03;
+const OP_IMM: u32 = 0x13;
+const AUIPC: u32 = 0x17;
+const OP_IMM_32: u32 = 0x1B;
+const STORE: u32 = 0x23;
+const OP: u32 = 0x33;
+const LUI: u32 = 0x37;
+const OP_32: u32 = 0x3B;
+const BRANCH: u32 = 0x63;
+const JALR: u32 = 0x67;
+
+/// An assembler, implementing a subset of RV64GC. Only uncompressed (32-bit)
+/// instructions are used.
+pub struct Assembler<B: Buffer> {
+    /// The area we're filling with code.
+    buffer: B,
+    /// The write pointer.
+    pos: usize,
+    /// The first error that occurred while writing, if any. Once this is
+    /// set, nothing more is written.
+    error: Option<Error>,
+}
+
+impl<B: Buffer> Assembler<B> {
+    /// Constructs an Assembler.
+    pub fn new() -> Self {
+        Assembler {buffer: B::new(), pos: 0, error: None}
+    }
+
+    /// Applies `callback` to the contained [`Buffer`].
+    pub fn use_buffer<T>(&mut self, callback: impl FnOnce(&mut B) -> T) -> T {
+        callback(&mut self.buffer)
+    }
+
+    /// Borrows the contained [`Buffer`].
+    pub fn buffer(&self) -> &B { &self.buffer }
+
+    /// Get the assembly pointer.
+    pub fn get_pos(&self) -> usize { self.pos }
+
+    /// Returns the first [`Error`] that occurred while writing, if it has not
+    /// already been returned. After an error, nothing more is written, and
+    /// later calls return [`Error::Poisoned`].
+    pub fn take_error(&mut self) -> Result<(), Error> {
+        match self.error.take() {
+            None => Ok(()),
+            Some(e) => {
+                self.error = Some(Error::Poisoned);
+                Err(e)
+            },
+        }
+    }
+
+    /// Change the target of the jump or call instruction at `patch` from
+    /// `old_target` to `new_target`. Each instruction is written atomically,
+    /// but a long jump is two instructions, so this is not safe while the
+    /// code is running.
+    /// - patch - the instruction to modify.
+    /// - old_target - an offset from the beginning of the buffer, or `None`.
+    /// - new_target - an offset from the beginning of the buffer, or `None`.
+    pub fn patch(&mut self, patch: Patch, old_target: Option<usize>, new_target: Option<usize>) {
+        if self.error.is_some() { return; }
+        let at = patch.address();
+        let old = self.buffer.read(at, 4) as u32;
+        match old & 0x7F {
+            BRANCH => {
+                let old_offset = jump_offset(at, old_target, 13).unwrap();
+                let new_offset = jump_offset(at, new_target, 13).expect("Cannot jump so far");
+                assert_eq!(old & BRANCH_OFFSET_MASK, branch_offset(old_offset));
+                let new = (old & !BRANCH_OFFSET_MASK) | branch_offset(new_offset);
+                self.buffer.write_atomic(at, new);
+            },
+            AUIPC => {
+                // `AUIPC` followed by `JALR`.
+                let old2 = self.buffer.read(at + 4, 4) as u32;
+                assert_eq!(old2 & 0x7F, JALR);
+                let (old_hi, old_lo) = long_offset(jump_offset(at, old_target, 32).unwrap());
+                let (new_hi, new_lo) = long_offset(jump_offset(at, new_target, 32).expect("Cannot jump so far"));
+                assert_eq!(old & 0xFFFFF000, old_hi);
+                assert_eq!(old2 & 0xFFF00000, old_lo);
+                self.buffer.write_atomic(at, (old & 0xFFF) | new_hi);
+                self.buffer.write_atomic(at + 4, (old2 & 0xFFFFF) | new_lo);
+            },
+            _ => panic!("not a jump or call instruction"),
+        }
+    }
+
+    /// Writes a 32-bit instruction.
+    fn write_instruction(&mut self, opcode: u32) {
+        if self.error.is_none() {
+            self.error = self.buffer.try_write(self.pos, opcode as u64, 4).err();
+        }
+        self.pos += 4;
+    }
+
+    /// Writes an R-type instruction.
+    fn write_r(&mut self, opcode: u32, rd: Register, rs1: Register, rs2: Register) {
+        self.write_instruction(opcode | (rs2 as u32) << 20 | (rs1 as u32) << 15 | (rd as u32) << 7);
+    }
+
+    /// Writes an I-type instruction.
+    fn write_i(&mut self, opcode: u32, rd: Register, rs1: Register, imm: i64) {
+        let imm = signed(imm, 12).expect("Cannot encode immediate");
+        self.write_instruction(opcode | imm << 20 | (rs1 as u32) << 15 | (rd as u32) << 7);
+    }
+
+    /// Writes an S-type instruction.
+    fn write_s(&mut self, opcode: u32, rs1: Register, rs2: Register, imm: i64) {
+        let imm = signed(imm, 12).expect("Cannot encode immediate");
+        self.write_instruction(opcode | (imm >> 5) << 25 | (rs2 as u32) << 20 | (rs1 as u32) << 15 | (imm & 0x1F) << 7);
+    }
+
+    /// Writes a U-type instruction. `imm` is a 20-bit bitmask.
+    fn write_u(&mut self, opcode: u32, rd: Register, imm: u32) {
+        assert_eq!(imm >> 20, 0);
+        self.write_instruction(opcode | imm << 12 | (rd as u32) << 7);
+    }
+
+    /// Writes an `AUIPC` and `JALR` pair which jumps to a dummy target.
+    ///  - temp - the register that holds the high part of the target.
+    ///  - link - the register that receives the return address.
+    fn write_long_jump(&mut self, temp: Register, link: Register) {
+        let (hi, lo) = long_offset(jump_offset(0, None, 32).unwrap());
+        self.write_instruction(AUIPC | hi | (temp as u32) << 7);
+        self.write_instruction(JALR | lo | (temp as u32) << 15 | (link as u32) << 7);
+    }
+
+    /// Writes instructions to put an immediate constant in `rd`.
+    pub fn const_(&mut self, rd: Register, imm: u64) {
+        let imm = imm as i64;
+        let lo = low_12_bits(imm);
+        if imm == imm as i32 as i64 {
+            let hi = imm.wrapping_sub(lo) as u64 >> 12 & 0xFFFFF;
+            if hi == 0 {
+                self.const_op(AluOp::ADD, P64, rd, ZERO, lo);
+            } else {
+                // `LUI` and `ADDIW` both sign-extend from 32 bits.
+                self.lui(rd, hi as u32);
+                if lo != 0 { self.const_op(AluOp::ADD, P32, rd, rd, lo); }
+            }
+        } else {
+            // Construct the high bits and shift them into place.
+            let hi = imm.wrapping_sub(lo);
+            let shift = hi.trailing_zeros();
+            self.const_(rd, (hi >> shift) as u64);
+            self.const_op(AluOp::SLL, P64, rd, rd, shift as i64);
+            if lo != 0 { self.const_op(AluOp::ADD, P64, rd, rd, lo); }
+        }
+    }
+
+    /// Assembles an instruction that does `rd <- imm << 12`, sign-extended
+    /// from 32 bits.
+    ///  - imm - a 20-bit bitmask.
+    pub fn lui(&mut self, rd: Register, imm: u32) {
+        self.write_u(LUI, rd, imm);
+    }
+
+    /// Assembles a load or store instruction. The address is `base + offset`.
+    /// Some combinations of `op` and `Width` do not exist, and this method
+    /// will panic in those cases.
+    ///  - offset - a 12-bit signed integer.
+    pub fn mem(&mut self, op: MemOp, width: Width, data: Register, address: (Register, i64)) {
+        let (base, offset) = address;
+        match op {
+            MemOp::S => {
+                self.write_s(STORE | (width as u32) << 12, base, data, offset);
+            },
+            MemOp::L => {
+                self.write_i(LOAD | (width as u32) << 12, data, base, offset);
+            },
+            MemOp::LU => {
+                assert_ne!(width, Width::Eight, "Too wide for LU");
+                self.write_i(LOAD | (4 + width as u32) << 12, data, base, offset);
+            },
+        }
+    }
+
+    /// Assembles an instruction that does `rd <- rs1 <op> rs2`.
+    ///  - prec - `P32` to operate on the low 32 bits of the operands and to
+    ///    sign-extend the result from 32 bits.
+    pub fn op(&mut self, op: AluOp, prec: Precision, rd: Register, rs1: Register, rs2: Register) {
+        let opcode = match prec {
+            P32 => { assert!(op.has_p32(), "No 32-bit form"); OP_32 },
+            P64 => OP,
+        };
+        self.write_r(opcode | op.funct7() << 25 | op.funct3() << 12, rd, rs1, rs2);
+    }
+
+    /// Assembles an instruction that does `rd <- rs1 <op> imm`.
+    ///  - prec - `P32` to operate on the low 32 bits of `rs1` and to
+    ///    sign-extend the result from 32 bits.
+    ///  - imm - a 12-bit signed integer, or for a shift, the shift amount.
+    pub fn const_op(&mut self, op: AluOp, prec: Precision, rd: Register, rs1: Register, imm: i64) {
+        assert!(op.has_immediate(), "No immediate form");
+        let opcode = match prec {
+            P32 => { assert!(op.has_p32(), "No 32-bit form"); OP_IMM_32 },
+            P64 => OP_IMM,
+        };
+        let imm = if op.is_shift() {
+            assert!(0 <= imm && imm < (32 << (prec as usize)), "Cannot shift so far");
+            imm | (op.funct7() << 5) as i64
+        } else {
+            imm
+        };
+        self.write_i(opcode | op.funct3() << 12, rd, rs1, imm);
+    }
+
+    /// Assembles a conditional branch to `target`. Conditional branches have
+    /// a range of only 4KB.
+    pub fn jump_if(&mut self, cond: Condition, rs1: Register, rs2: Register, target: Option<usize>) -> Patch {
+        let ret = Patch::new(self.get_pos());
+        let dummy = branch_offset(jump_offset(0, None, 13).unwrap());
+        self.write_r(BRANCH | dummy | (cond as u32) << 12, ZERO, rs1, rs2);
+        self.patch(ret, None, target);
+        ret
+    }
+
+    /// Assembles an indirect jump to `src`.
+    pub fn jump(&mut self, src: Register) {
+        self.write_i(JALR, ZERO, src, 0);
+    }
+
+    /// Assembles an unconditional jump to `target`, which must be within 2GB.
+    /// Corrupts `temp`.
+    pub fn const_jump(&mut self, target: Option<usize>, temp: Register) -> Patch {
+        let ret = Patch::new(self.get_pos());
+        self.write_long_jump(temp, ZERO);
+        self.patch(ret, None, target);
+        ret
+    }
+
+    /// Assembles an indirect jump through a table of unconditional jumps, one
+    /// to each of `targets`, which immediately follows. `index` must be less
+    /// than `targets.len()`. Corrupts `temp0` and `temp1`, either of which
+    /// may be `index`. Returns the table entries.
+    pub fn jump_table(&mut self, index: Register, temp0: Register, temp1: Register, targets: &[Option<usize>]) -> Vec<Patch> {
+        assert_ne!(temp0, temp1);
+        // Each entry is an `AUIPC` and `JALR` pair.
+        self.const_op(AluOp::SLL, P64, temp1, index, 3);
+        let table = self.get_pos() + 12;
+        self.write_u(AUIPC, temp0, 0);
+        self.op(AluOp::ADD, P64, temp0, temp0, temp1);
+        self.write_i(JALR, ZERO, temp0, 12);
+        assert_eq!(self.get_pos(), table);
+        targets.iter().map(|&target| self.const_jump(target, temp0)).collect()
+    }
+
+    /// Assembles an indirect call to `src`.
+    pub fn call(&mut self, src: Register) {
+        self.write_i(JALR, RA, src, 0);
+    }
+
+    /// Assembles a call to `target`, which must be within 2GB.
+    pub fn const_call(&mut self, target: Option<usize>) -> Patch {
+        let ret = Patch::new(self.get_pos());
+        self.write_long_jump(RA, RA);
+        self.patch(ret, None, target);
+        ret
+    }
+
+    /// Assembles a return to `RA`.
+    pub fn ret(&mut self) {
+        self.write_i(JALR, ZERO, RA, 0);
+    }
+
+    /// Push `(src1, src2)`. `src1` ends up at the lower address.
+    pub fn push(&mut self, src1: Register, src2: Register) {
+        self.const_op(AluOp::ADD, P64, SP, SP, -16);
+        self.mem(MemOp::S, Width::Eight, src1, (SP, 0));
+        self.mem(MemOp::S, Width::Eight, src2, (SP, 8));
+    }
+
+    /// Pop `(dest1, dest2)`. `dest1` comes from the lower address.
+    pub fn pop(&mut self, dest1: Register, dest2: Register) {
+        self.mem(MemOp::L, Width::Eight, dest1, (SP, 0));
+        self.mem(MemOp::L, Width::Eight, dest2, (SP, 8));
+        self.const_op(AluOp::ADD, P64, SP, SP, 16);
+    }
+}
+
+impl<B: Buffer> Default for Assembler<B> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+//-----------------------------------------------------------------------------
+
+#[cfg(test)]
+pub mod tests {
+    use super::super::{ALL_CONDITIONS, ALL_ALU_OPS};
+    use super::super::super::tests::{TEST_VALUES};
+    use super::*;
+    use MemOp::*;
+    use Width::*;
+
+    /// Compare the code that has been assembled by `a` to `expected`, as if
+    /// the [`Buffer`] were at offset 0. We have no RISC-V disassembler, so
+    /// `expected` lists the encoded instructions.
+    ///  - `a` - an assembler which has generated some code.
+    ///  - `start_address` - the address (relative to the `Buffer`) at which to
+    ///    start comparing.
+    ///  - `expected` - the expected encodings of the instructions.
+    pub fn disassemble<B: Buffer>(a: &Assembler<B>, start_address: usize, expected: &[u32])
+    -> Result<(), Vec<u32>> {
+        let observed: Vec<u32> = (start_address..a.get_pos()).step_by(4).map(
+            |pc| a.buffer.read(pc, 4) as u32
+        ).collect();
+        // Search for differences.
+        let mut error = false;
+        for i in 0..std::cmp::max(expected.len(), observed.len()) {
+            let e_line = expected.get(i).map_or("missing".into(), |e| format!("{:08X}", e));
+            let o_line = observed.get(i).map_or("missing".into(), |o| format!("{:08X}", o));
+            if e_line != o_line {
+                println!("Difference in line {}", i+1);
+                println!("{:016X}   {}", start_address + 4 * i, o_line);
+                println!("{:>16}   {}", "Expected", e_line);
+                error = true;
+            }
+        }
+        if error { Err(observed) } else { Ok(()) }
+    }
+
+    #[test]
+    fn const_() {
+        let mut a = Assembler::<Vec<u8>>::new();
+        for c in TEST_VALUES {
+            a.const_(A1, c);
+        }
+        disassemble(&a, 0, &[
+            0x00000593, // addi a1, zero, 0
+            0x00100593, // addi a1, zero, 1
+            0x111115B7, // lui a1, 69905
+            0x1115859B, // addiw a1, a1, 273
+            0x800005B7, // lui a1, 524288
+            0xFFF5859B, // addiw a1, a1, -1
+            0x00100593, // addi a1, zero, 1
+            0x01F59593, // slli a1, a1, 31
+            0x000EF5B7, // lui a1, 239
+            0xEEF5859B, // addiw a1, a1, -273
+            0x00C59593, // slli a1, a1, 12
+            0xEEE58593, // addi a1, a1, -274
+            0x00100593, // addi a1, zero, 1
+            0x02059593, // slli a1, a1, 32
+            0xFFE58593, // addi a1, a1, -2
+            0x00100593, // addi a1, zero, 1
+            0x02059593, // slli a1, a1, 32
+            0xFFF58593, // addi a1, a1, -1
+            0x000925B7, // lui a1, 146
+            0xA2B5859B, // addiw a1, a1, -1493
+            0x00C59593, // slli a1, a1, 12
+            0x3C558593, // addi a1, a1, 965
+            0x00D59593, // slli a1, a1, 13
+            0xABD58593, // addi a1, a1, -1347
+            0x00C59593, // slli a1, a1, 12
+            0xDEF58593, // addi a1, a1, -529
+            0x011115B7, // lui a1, 4369
+            0x1115859B, // addiw a1, a1, 273
+            0x00C59593, // slli a1, a1, 12
+            0x11158593, // addi a1, a1, 273
+            0x00C59593, // slli a1, a1, 12
+            0x11158593, // addi a1, a1, 273
+            0x00C59593, // slli a1, a1, 12
+            0x11158593, // addi a1, a1, 273
+            0xFFF00593, // addi a1, zero, -1
+            0x03F59593, // slli a1, a1, 63
+            0xFFF58593, // addi a1, a1, -1
+            0xFFF00593, // addi a1, zero, -1
+            0x03F59593, // slli a1, a1, 63
+            0xFEEEF5B7, // lui a1, 1044207
+            0xEEF5859B, // addiw a1, a1, -273
+            0x00C59593, // slli a1, a1, 12
+            0xEEF58593, // addi a1, a1, -273
+            0x00C59593, // slli a1, a1, 12
+            0xEEF58593, // addi a1, a1, -273
+            0x00C59593, // slli a1, a1, 12
+            0xEEE58593, // addi a1, a1, -274
+            0xFFF6E5B7, // lui a1, 1048430
+            0x5D55859B, // addiw a1, a1, 1493
+            0x00C59593, // slli a1, a1, 12
+            0xC3B58593, // addi a1, a1, -965
+            0x00D59593, // slli a1, a1, 13
+            0x54358593, // addi a1, a1, 1347
+            0x00C59593, // slli a1, a1, 12
+            0x21058593, // addi a1, a1, 528
+            0xFFF00593, // addi a1, zero, -1
+            0x02059593, // slli a1, a1, 32
+            0xFFF00593, // addi a1, zero, -1
+            0x02059593, // slli a1, a1, 32
+            0x00158593, // addi a1, a1, 1
+            0xFFF115B7, // lui a1, 1048337
+            0x1115859B, // addiw a1, a1, 273
+            0x00C59593, // slli a1, a1, 12
+            0x11158593, // addi a1, a1, 273
+            0xFFF00593, // addi a1, zero, -1
+            0x01F59593, // slli a1, a1, 31
+            0xFFF58593, // addi a1, a1, -1
+            0x800005B7, // lui a1, 524288
+            0xEEEEF5B7, // lui a1, 978671
+            0xEEE5859B, // addiw a1, a1, -274
+            0xFFE00593, // addi a1, zero, -2
+            0xFFF00593, // addi a1, zero, -1
+        ]).unwrap();
+    }
+
+    #[test]
+    fn mem() {
+        let mut a = Assembler::<Vec<u8>>::new();
+        for (data, base, offset) in [(A0, SP, -2048), (ZERO, A0, 2047)] {
+            for (op, width) in [
+                (S, One), (L, One), (LU, One),
+                (S, Two), (L, Two), (LU, Two),
+                (S, Four), (L, Four), (LU, Four),
+                (S, Eight), (L, Eight),
+            ] {
+                a.mem(op, width, data, (base, offset));
+            }
+        }
+        disassemble(&a, 0, &[
+            0x80A10023, // sb a0, -2048(sp)
+            0x80010503, // lb a0, -2048(sp)
+            0x80014503, // lbu a0, -2048(sp)
+            0x80A11023, // sh a0, -2048(sp)
+            0x80011503, // lh a0, -2048(sp)
+            0x80015503, // lhu a0, -2048(sp)
+            0x80A12023, // sw a0, -2048(sp)
+            0x80012503, // lw a0, -2048(sp)
+            0x80016503, // lwu a0, -2048(sp)
+            0x80A13023, // sd a0, -2048(sp)
+            0x80013503, // ld a0, -2048(sp)
+            0x7E050FA3, // sb zero, 2047(a0)
+            0x7FF50003, // lb zero, 2047(a0)
+            0x7FF54003, // lbu zero, 2047(a0)
+            0x7E051FA3, // sh zero, 2047(a0)
+            0x7FF51003, // lh zero, 2047(a0)
+            0x7FF55003, // lhu zero, 2047(a0)
+            0x7E052FA3, // sw zero, 2047(a0)
+            0x7FF52003, // lw zero, 2047(a0)
+            0x7FF56003, // lwu zero, 2047(a0)
+            0x7E053FA3, // sd zero, 2047(a0)
+            0x7FF53003, // ld zero, 2047(a0)
+        ]).unwrap();
+    }
+
+    #[test]
+    fn op() {
+        let mut a = Assembler::<Vec<u8>>::new();
+        for op in ALL_ALU_OPS {
+            a.op(op, P64, A0, A1, T6);
+            if op.has_p32() {
+                a.op(op, P32, T6, A0, A1);
+            }
+        }
+        disassemble(&a, 0, &[
+            0x01F58533, // add a0, a1, t6
+            0x00B50FBB, // addw t6, a0, a1
+            0x41F58533, // sub a0, a1, t6
+            0x40B50FBB, // subw t6, a0, a1
+            0x01F59533, // sll a0, a1, t6
+            0x00B51FBB, // sllw t6, a0, a1
+            0x01F5A533, // slt a0, a1, t6
+            0x01F5B533, // sltu a0, a1, t6
+            0x01F5C533, // xor a0, a1, t6
+            0x01F5D533, // srl a0, a1, t6
+            0x00B55FBB, // srlw t6, a0, a1
+            0x41F5D533, // sra a0, a1, t6
+            0x40B55FBB, // sraw t6, a0, a1
+            0x01F5E533, // or a0, a1, t6
+            0x01F5F533, // and a0, a1, t6
+            0x03F58533, // mul a0, a1, t6
+            0x02B50FBB, // mulw t6, a0, a1
+            0x03F5C533, // div a0, a1, t6
+            0x02B54FBB, // divw t6, a0, a1
+            0x03F5D533, // divu a0, a1, t6
+            0x02B55FBB, // divuw t6, a0, a1
+        ]).unwrap();
+    }
+
+    #[test]
+    fn const_op() {
+        let mut a = Assembler::<Vec<u8>>::new();
+        for op in ALL_ALU_OPS {
+            if op.has_immediate() {
+                if op.is_shift() {
+                    a.const_op(op, P64, A0, T6, 63);
+                    a.const_op(op, P32, T6, A0, 31);
+                } else {
+                    a.const_op(op, P64, A0, T6, -2048);
+                    a.const_op(op, P64, T6, A0, 2047);
+                    if op.has_p32() {
+                        a.const_op(op, P32, A0, A0, -1);
+                    }
+                }
+            }
+        }
+        disassemble(&a, 0, &[
+            0x800F8513, // addi a0, t6, -2048
+            0x7FF50F93, // addi t6, a0, 2047
+            0xFFF5051B, // addiw a0, a0, -1
+            0x03FF9513, // slli a0, t6, 63
+            0x01F51F9B, // slliw t6, a0, 31
+            0x800FA513, // slti a0, t6, -2048
+            0x7FF52F93, // slti t6, a0, 2047
+            0x800FB513, // sltiu a0, t6, -2048
+            0x7FF53F93, // sltiu t6, a0, 2047
+            0x800FC513, // xori a0, t6, -2048
+            0x7FF54F93, // xori t6, a0, 2047
+            0x03FFD513, // srli a0, t6, 63
+            0x01F55F9B, // srliw t6, a0, 31
+            0x43FFD513, // srai a0, t6, 63
+            0x41F55F9B, // sraiw t6, a0, 31
+            0x800FE513, // ori a0, t6, -2048
+            0x7FF56F93, // ori t6, a0, 2047
+            0x800FF513, // andi a0, t6, -2048
+            0x7FF57F93, // andi t6, a0, 2047
+        ]).unwrap();
+    }
+
+    #[test]
+    fn jump() {
+        let mut a = Assembler::<Vec<u8>>::new();
+        let target = a.get_pos() + 28; // Somewhere in the middle of the code.
+        a.const_jump(None, T5);
+        a.const_call(None);
+        a.const_jump(Some(target), T5);
+        a.const_call(Some(target));
+        for cond in ALL_CONDITIONS {
+            a.jump_if(cond, A0, T6, Some(target));
+        }
+        a.jump_if(Condition::EQ, A0, ZERO, None);
+        a.jump(A0);
+        a.call(A0);
+        a.ret();
+        disassemble(&a, 0, &[
+            0x80000F17, // auipc t5, 524288
+            0x000F0067, // jalr zero, 0(t5)
+            0x80000097, // auipc ra, 524288
+            0x000080E7, // jalr ra, 0(ra)
+            0x00000F17, // auipc t5, 0
+            0x00CF0067, // jalr zero, 12(t5)
+            0x00000097, // auipc ra, 0
+            0x004080E7, // jalr ra, 4(ra)
+            0xFFF50EE3, // beq a0, t6, -4
+            0xFFF51CE3, // bne a0, t6, -8
+            0xFFF54AE3, // blt a0, t6, -12
+            0xFFF558E3, // bge a0, t6, -16
+            0xFFF566E3, // bltu a0, t6, -20
+            0xFFF574E3, // bgeu a0, t6, -24
+            0x80050063, // beq a0, zero, -4096
+            0x00050067, // jalr zero, 0(a0)
+            0x000500E7, // jalr ra, 0(a0)
+            0x00008067, // jalr zero, 0(ra)
+        ]).unwrap();
+    }
+
+    #[test]
+    fn jump_table() {
+        let mut a = Assembler::<Vec<u8>>::new();
+        let patches = a.jump_table(A0, T5, T6, &[Some(0), None]);
+        assert_eq!(patches.iter().map(Patch::address).collect::<Vec<_>>(), [16, 24]);
+        disassemble(&a, 0, &[
+            0x00351F93, // slli t6, a0, 3
+            0x00000F17, // auipc t5, 0
+            0x01FF0F33, // add t5, t5, t6
+            0x00CF0067, // jalr zero, 12(t5)
+            0x00000F17, // auipc t5, 0
+            0xFF0F0067, // jalr zero, -16(t5)
+            0x80000F17, // auipc t5, 524288
+            0x000F0067, // jalr zero, 0(t5)
+        ]).unwrap();
+    }
+
+    #[test]
+    fn push_pop() {
+        let mut a = Assembler::<Vec<u8>>::new();
+        a.push(ZERO, A0);
+        a.pop(ZERO, A0);
+        a.push(A1, ZERO);
+        a.pop(A1, ZERO);
+        disassemble(&a, 0, &[
+            0xFF010113, // addi sp, sp, -16
+            0x00013023, // sd zero, 0(sp)
+            0x00A13423, // sd a0, 8(sp)
+            0x00013003, // ld zero, 0(sp)
+            0x00813503, // ld a0, 8(sp)
+            0x01010113, // addi sp, sp, 16
+            0xFF010113, // addi sp, sp, -16
+            0x00B13023, // sd a1, 0(sp)
+            0x00013423, // sd zero, 8(sp)
+            0x00013583, // ld a1, 0(sp)
+            0x00813003, // ld zero, 8(sp)
+            0x01010113, // addi sp, sp, 16
+        ]).unwrap();
+    }
+
+    #[test]
+    fn patch() {
+        let mut a = Assembler::<Vec<u8>>::new();
+        let target = Some(8); // Somewhere in the middle of the code.
+        let p1 = a.const_jump(None, T5);
+        let p2 = a.const_call(target);
+        let p3 = a.jump_if(Condition::GEU, A0, A1, target);
+        disassemble(&a, 0, &[
+            0x80000F17, // auipc t5, 524288
+            0x000F0067, // jalr zero, 0(t5)
+            0x00000097, // auipc ra, 0
+            0x000080E7, // jalr ra, 0(ra)
+            0xFEB57CE3, // bgeu a0, a1, -8
+        ]).unwrap();
+        let target2 = Some(0x7FFFF000);
+        a.patch(p1, None, target2);
+        a.patch(p2, target, target2);
+        a.patch(p3, target, None);
+        disassemble(&a, 0, &[
+            0x7FFFFF17, // auipc t5, 524287
+            0x000F0067, // jalr zero, 0(t5)
+            0x7FFFF097, // auipc ra, 524287
+            0xFF8080E7, // jalr ra, -8(ra)
+            0x80B57063, // bgeu a0, a1, -4096
+        ]).unwrap();
+    }
+}
@@ -0,0 +1,151 @@
+/// All RISC-V integer registers, named according to the standard calling
+/// convention. `S0` doubles as the frame pointer; see [`FP`].
+///
+/// The encoding of each register is its index in the register file, which is
+/// also its DWARF register number.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[repr(u8)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum Register {
+    ZERO = 0x00, RA  = 0x01, SP  = 0x02, GP  = 0x03, TP  = 0x04, T0  = 0x05, T1  = 0x06, T2  = 0x07,
+    S0   = 0x08, S1  = 0x09, A0  = 0x0A, A1  = 0x0B, A2  = 0x0C, A3  = 0x0D, A4  = 0x0E, A5  = 0x0F,
+    A6   = 0x10, A7  = 0x11, S2  = 0x12, S3  = 0x13, S4  = 0x14, S5  = 0x15, S6  = 0x16, S7  = 0x17,
+    S8   = 0x18, S9  = 0x19, S10 = 0x1A, S11 = 0x1B, T3  = 0x1C, T4  = 0x1D, T5  = 0x1E, T6  = 0x1F,
+}
+
+/// The frame pointer register `FP` is `S0`.
+pub const FP: Register = Register::S0;
+
+//-----------------------------------------------------------------------------
+
+/// All conditional branch conditions. The encoding is the `funct3` field of
+/// the branch instruction.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[repr(u8)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum Condition {
+    EQ = 0x0, NE = 0x1,
+    LT = 0x4, GE = 0x5,
+    LTU = 0x6, GEU = 0x7,
+}
+
+use Condition::*;
+
+/// All `Condition`s.
+pub const ALL_CONDITIONS: [Condition; 6] = [EQ, NE, LT, GE, LTU, GEU];
+
+impl Condition {
+    /// Changes `EQ` into `NE` and vice versa, and so on.
+    pub fn invert(self) -> Self {
+        match self {
+            EQ => NE, NE => EQ,
+            LT => GE, GE => LT,
+            LTU => GEU, GEU => LTU,
+        }
+    }
+}
+
+//-----------------------------------------------------------------------------
+
+/// All memory access operations.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[repr(u8)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum MemOp {
+    /// Truncate and store.
+    S = 0,
+    /// Load and sign-extend to 64 bits.
+    L = 1,
+    /// Load and zero-extend to 64 bits.
+    LU = 2,
+}
+
+//-----------------------------------------------------------------------------
+
+/// All register-register and register-immediate arithmetic operations. The
+/// encoding is `funct7 << 3 | funct3`.
+///
+/// Not every operation exists in every form. For example, there is no `SUB`
+/// with an immediate operand, and there is no 32-bit `XOR`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[repr(u16)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum AluOp {
+    /// Add.
+    ADD = 0x000,
+    /// Subtract.
+    SUB = 0x100,
+    /// Left shift.
+    SLL = 0x001,
+    /// Signed less than, giving `0` or `1`.
+    SLT = 0x002,
+    /// Unsigned less than, giving `0` or `1`.
+    SLTU = 0x003,
+    /// Bitwise exclusive OR.
+    XOR = 0x004,
+    /// Right shift and zero-extend.
+    SRL = 0x005,
+    /// Right shift and sign-extend.
+    SRA = 0x105,
+    /// Bitwise OR.
+    OR = 0x006,
+    /// Bitwise AND.
+    AND = 0x007,
+    /// Multiply.
+    MUL = 0x008,
+    /// Signed divide.
+    DIV = 0x00C,
+    /// Unsigned divide.
+    DIVU = 0x00D,
+}
+
+use AluOp::*;
+
+/// All `AluOp`s.
+pub const ALL_ALU_OPS: [AluOp; 13] = [ADD, SUB, SLL, SLT, SLTU, XOR, SRL, SRA, OR, AND, MUL, DIV, DIVU];
+
+impl AluOp {
+    /// The `funct3` field of the instruction.
+    pub fn funct3(self) -> u32 { (self as u32) & 7 }
+
+    /// The `funct7` field of the instruction.
+    pub fn funct7(self) -> u32 { (self as u32) >> 3 }
+
+    /// Tests whether the operation has a 32-bit form.
+    pub fn has_p32(self) -> bool {
+        matches!(self, ADD | SUB | SLL | SRL | SRA | MUL | DIV | DIVU)
+    }
+
+    /// Tests whether the operation has a form with an immediate operand.
+    pub fn has_immediate(self) -> bool {
+        !matches!(self, SUB | MUL | DIV | DIVU)
+    }
+
+    /// Tests whether the operation is a shift.
+    pub fn is_shift(self) -> bool {
+        matches!(self, SLL | SRL | SRA)
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invert() {
+        for cc in ALL_CONDITIONS {
+            assert_eq!(cc.invert() as u8, cc as u8 ^ 1);
+            assert_eq!(cc.invert().invert(), cc);
+        }
+    }
+
+    #[test]
+    fn alu_op() {
+        for op in ALL_ALU_OPS {
+            assert_eq!((op.funct7() << 3) | op.funct3(), op as u32);
+            assert!(op.has_immediate() || op.has_p32());
+        }
+    }
+}
@@ -0,0 +1,657 @@
+use crate::util::{AsUsize};
+use crate::error::{Error};
+use super::{
+    buffer, code,
+    Patch, Label, CallFrameInfo, RESULT,
+    Register, FP, Condition, MemOp, AluOp,
+    Assembler, CALLEE_SAVES, CALLER_SAVES, ARGUMENTS, RESULTS,
+};
+use super::assembler::{signed};
+use Register::*;
+use MemOp::*;
+use AluOp::*;
+use buffer::{Buffer};
+use code::{Precision, Variable, Action, UnaryOp, BinaryOp, Width, Global, Slot, debug_word};
+use Precision::*;
+
+/// The [`Register`] used for the pool pointer.
+const POOL: Register = S11;
+
+/// A [`Register`] used as a temporary variable.
+const TEMP0: Register = T5;
+
+/// A [`Register`] used as a temporary variable.
+const TEMP1: Register = T6;
+
+/// The registers available for allocation. This omits:
+///  - `POOL`, which holds the pool base address.
+///  - `TEMP0`, which is used as temporary workspace.
+///  - `TEMP1`, which is used as temporary workspace.
+///  - `FP`, which is used as a frame pointer.
+///  - `RA`, which is used for calls.
+///  - `SP`, `GP` and `TP`, which belong to the platform.
+///  - `ZERO`, obviously.
+pub const ALLOCATABLE_REGISTERS: [Register; 23] = [
+    A0, A1, A2, A3, A4, A5, A6, A7,
+    T0, T1, T2, T3, T4,
+    S1, S2, S3, S4, S5, S6, S7, S8, S9, S10,
+];
+
+impl From<code::Register> for Register {
+    fn from(r: code::Register) -> Self {
+        ALLOCATABLE_REGISTERS[r.as_usize()]
+    }
+}
+
+//-----------------------------------------------------------------------------
+
+/// A low-level analogue of `code::Variable`, which can hold unallocatable
+/// [`Register`]s.
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+enum Value {
+    Register(Register),
+    Global(Global),
+    Slot(Slot),
+}
+
+impl From<Register> for Value {
+    fn from(r: Register) -> Self {
+        Value::Register(r)
+    }
+}
+
+impl From<Global> for Value {
+    fn from(g: Global) -> Self {
+        Value::Global(g)
+    }
+}
+
+impl From<Slot> for Value {
+    fn from(s: Slot) -> Self {
+        Value::Slot(s)
+    }
+}
+
+impl From<code::Register> for Value {
+    fn from(r: code::Register) -> Self {
+        Value::Register(r.into())
+    }
+}
+
+impl From<code::Variable> for Value {
+    fn from(v: code::Variable) -> Self {
+        match v {
+            code::Variable::Register(reg) => reg.into(),
+            code::Variable::Global(global) => global.into(),
+            code::Variable::Slot(slot) => slot.into(),
+        }
+    }
+}
+
+//-----------------------------------------------------------------------------
+
+pub struct Lowerer<B: Buffer> {
+    /// The underlying [`Assembler`].
+    a: Assembler<B>,
+    /// The number of [`Global`]s in the [`Pool`].
+    ///
+    /// [`Pool`]: crate::target::Pool
+    num_globals: usize,
+    /// The number of stack-allocated spill [`Slot`]s.
+    slots_used: usize,
+    /// The amount of code that has been made visible to instruction fetch.
+    published: usize,
+}
+
+impl<B: Buffer> Lowerer<B> {
+    pub fn new(num_globals: usize) -> Self {
+        Self {a: Assembler::new(), num_globals, slots_used: 0, published: 0}
+    }
+
+    /// Apply `callback` to the contained [`Assembler`].
+    pub fn use_assembler<T>(
+        mut self,
+        callback: impl FnOnce(Assembler<B>) -> std::io::Result<(Assembler<B>, T)>,
+    ) -> std::io::Result<(Self, T)> {
+        let (a, ret) = callback(self.a)?;
+        self.a = a;
+        Ok((self, ret))
+    }
+
+    /// Put `value` in `dest`.
+    fn const_(&mut self, dest: impl Into<Register>, value: u64) {
+        let dest = dest.into();
+        self.a.const_(dest, value);
+    }
+
+    /// Conditional branch. The range is only 4KB.
+    fn jump_if(&mut self, cc: Condition, src1: Register, src2: Register, target: &mut Label) {
+        let patch = self.a.jump_if(cc, src1, src2, target.target());
+        target.push(patch);
+    }
+
+    /// Unconditional jump to a constant. Corrupts `TEMP0`.
+    fn const_jump(&mut self, target: &mut Label) {
+        let patch = self.a.const_jump(target.target(), TEMP0);
+        target.push(patch);
+    }
+
+    /// Unconditional call to a constant.
+    #[allow(dead_code)]
+    fn const_call(&mut self, target: &mut Label) {
+        let patch = self.a.const_call(target.target());
+        target.push(patch);
+    }
+
+    /// Assembles `dest <- src + constant`, using `temp` if `constant` does not
+    /// fit in an immediate operand.
+    fn const_add(&mut self, dest: Register, src: Register, constant: u64, temp: Register) {
+        if signed(constant as i64, 12).is_some() {
+            self.a.const_op(ADD, P64, dest, src, constant as i64);
+        } else {
+            self.const_(temp, constant);
+            self.a.op(ADD, P64, dest, src, temp);
+        }
+    }
+
+    /// Move `src` to `dest` if they are different.
+    fn move_(&mut self, dest: impl Into<Register>, src: impl Into<Register>) {
+        let dest = dest.into();
+        let src = src.into();
+        if dest != src {
+            self.a.const_op(ADD, P64, dest, src, 0);
+        }
+    }
+
+    /// If `prec` is `P32`, zero-extends `dest` from 32 bits.
+    fn zero_extend(&mut self, prec: Precision, dest: Register) {
+        if prec == P32 {
+            self.a.const_op(SLL, P64, dest, dest, 32);
+            self.a.const_op(SRL, P64, dest, dest, 32);
+        }
+    }
+
+    /// If `prec` is `P32`, sign-extends `src` from 32 bits into `temp` and
+    /// returns `temp`. Otherwise, returns `src`.
+    fn sign_extend(&mut self, prec: Precision, temp: Register, src: Register) -> Register {
+        match prec {
+            P32 => {
+                self.a.const_op(ADD, P32, temp, src, 0);
+                temp
+            },
+            P64 => src,
+        }
+    }
+
+    /// Constructs a (Register, offset) pair representing `base + offset`.
+    /// Corrupts `temp`.
+    fn address(&mut self, base: Register, offset: u64, temp: Register) -> (Register, i64) {
+        if signed(offset as i64, 12).is_some() {
+            // `offset` fits in an immediate constant.
+            (base, offset as i64)
+        } else {
+            // `offset` needs to be constructed.
+            self.const_(temp, offset);
+            self.a.op(ADD, P64, temp, base, temp);
+            (temp, 0)
+        }
+    }
+
+    /// Access 8 bytes at `address`, which must be 8-byte aligned.
+    /// Corrupts `temp`. If `op` is `L`, `temp` can be `data`.
+    fn mem(&mut self, op: MemOp, data: Register, address: (Register, u64), temp: Register) {
+        let address = self.address(address.0, address.1, temp);
+        self.a.mem(op, Width::Eight, data, address);
+    }
+
+    /// Returns the base and offset of `global`.
+    fn global_address(&self, global: Global) -> (Register, u64) {
+        assert!(global.0 < self.num_globals);
+        (POOL, (global.0 * 8) as u64)
+    }
+
+    /// Returns the base and offset of `slot` in the stack-allocated data.
+    fn slot_address(&self, slot: Slot) -> (Register, u64) {
+        assert!(slot.0 < self.slots_used);
+        (SP, (((self.slots_used - 1) - slot.0) * 8) as u64)
+    }
+
+    /// If `src` is a Register, returns it, otherwise loads it into `reg` and
+    /// returns `reg`.
+    fn src_to_register(&mut self, src: impl Into<Value>, reg: impl Into<Register>) -> Register {
+        let src = src.into();
+        let reg = reg.into();
+        match src {
+            Value::Register(src) => src,
+            Value::Global(global) => {
+                self.mem(L, reg, self.global_address(global), reg);
+                reg
+            },
+            Value::Slot(slot) => {
+                self.mem(L, reg, self.slot_address(slot), reg);
+                reg
+            },
+        }
+    }
+
+    /// Assemble code to perform the given `unary_op`.
+    fn unary_op(
+        &mut self,
+        unary_op: UnaryOp,
+        prec: Precision,
+        dest: code::Register,
+        src: code::Variable,
+    ) {
+        let dest = dest.into();
+        let src = self.src_to_register(src, dest);
+        match unary_op {
+            code::UnaryOp::Abs => {
+                // `TEMP0` is `-1` if `src` is negative, otherwise `0`.
+                let sign_bit = 32 * (prec as i64 + 1) - 1;
+                self.a.const_op(SRA, prec, TEMP0, src, sign_bit);
+                self.a.op(XOR, P64, dest, src, TEMP0);
+                self.a.op(SUB, prec, dest, dest, TEMP0);
+            },
+            code::UnaryOp::Negate => {
+                self.a.op(SUB, prec, dest, ZERO, src);
+            },
+            code::UnaryOp::Not => {
+                self.a.const_op(XOR, P64, dest, src, -1);
+            },
+        };
+        self.zero_extend(prec, dest);
+    }
+
+    /// Assemble code to perform the given `binary_op`.
+    fn binary_op(
+        &mut self,
+        binary_op: BinaryOp,
+        prec: Precision,
+        dest: code::Register,
+        src1: code::Variable,
+        src2: code::Variable,
+    ) {
+        let dest = dest.into();
+        let src1 = self.src_to_register(src1, TEMP0);
+        let src2 = self.src_to_register(src2, TEMP1);
+        match binary_op {
+            code::BinaryOp::Add => {
+                self.a.op(ADD, prec, dest, src1, src2);
+            },
+            code::BinaryOp::Sub => {
+                self.a.op(SUB, prec, dest, src1, src2);
+            },
+            code::BinaryOp::Mul => {
+                self.a.op(MUL, prec, dest, src1, src2);
+            },
+            code::BinaryOp::UDiv => {
+                self.a.op(DIVU, prec, dest, src1, src2);
+            },
+            code::BinaryOp::SDiv => {
+                self.a.op(DIV, prec, dest, src1, src2);
+            },
+            // TODO: Define what happens when you shift too far.
+            code::BinaryOp::Lsl => {
+                self.a.op(SLL, prec, dest, src1, src2);
+            },
+            code::BinaryOp::Lsr => {
+                self.a.op(SRL, prec, dest, src1, src2);
+            },
+            code::BinaryOp::Asr => {
+                self.a.op(SRA, prec, dest, src1, src2);
+            },
+            code::BinaryOp::And => {
+                self.a.op(AND, P64, dest, src1, src2);
+            },
+            code::BinaryOp::Or => {
+                self.a.op(OR, P64, dest, src1, src2);
+            },
+            code::BinaryOp::Xor => {
+                self.a.op(XOR, P64, dest, src1, src2);
+            },
+            code::BinaryOp::Lt => {
+                let src1 = self.sign_extend(prec, TEMP0, src1);
+                let src2 = self.sign_extend(prec, TEMP1, src2);
+                self.a.op(SLT, P64, dest, src1, src2);
+                self.a.op(SUB, P64, dest, ZERO, dest);
+            },
+            code::BinaryOp::Ult => {
+                // Sign-extension preserves the unsigned order.
+                let src1 = self.sign_extend(prec, TEMP0, src1);
+                let src2 = self.sign_extend(prec, TEMP1, src2);
+                self.a.op(SLTU, P64, dest, src1, src2);
+                self.a.op(SUB, P64, dest, ZERO, dest);
+            },
+            code::BinaryOp::Eq => {
+                self.a.op(SUB, prec, dest, src1, src2);
+                self.a.const_op(SLTU, P64, dest, dest, 1);
+                self.a.op(SUB, P64, dest, ZERO, dest);
+            },
+            code::BinaryOp::Max | code::BinaryOp::Min => {
+                let src1 = self.sign_extend(prec, TEMP0, src1);
+                self.move_(TEMP0, src1);
+                let src2 = self.sign_extend(prec, TEMP1, src2);
+                self.move_(TEMP1, src2);
+                // Compute a mask which is `-1` to select `TEMP0`.
+                if binary_op == code::BinaryOp::Max {
+                    self.a.op(SLT, P64, dest, TEMP1, TEMP0);
+                } else {
+                    self.a.op(SLT, P64, dest, TEMP0, TEMP1);
+                }
+                self.a.op(SUB, P64, dest, ZERO, dest);
+                // `dest <- TEMP1 ^ ((TEMP0 ^ TEMP1) & mask)`.
+                self.a.op(XOR, P64, TEMP0, TEMP0, TEMP1);
+                self.a.op(AND, P64, TEMP0, TEMP0, dest);
+                self.a.op(XOR, P64, dest, TEMP1, TEMP0);
+            },
+        };
+        self.zero_extend(prec, dest);
+    }
+}
+
+//-----------------------------------------------------------------------------
+
+impl<B: Buffer> super::Lower for Lowerer<B> {
+    fn num_globals(&self) -> usize { self.num_globals }
+
+    fn slots_used_mut(&mut self) -> &mut usize { &mut self.slots_used }
+
+    fn here(&self) -> Label { Label::new(Some(self.a.get_pos())) }
+
+    fn code(&self) -> &[u8] { self.a.buffer() }
+
+    fn executable_address(&self) -> Option<usize> { self.a.buffer().executable_address() }
+
+    fn take_error(&mut self) -> Result<(), Error> { self.a.take_error() }
+
+    fn release(&mut self, start: usize, end: usize) {
+        self.a.use_buffer(|b| b.release(start, end));
+    }
+
+    fn publish(&mut self) {
+        let (start, end) = (self.published, self.a.get_pos());
+        self.a.use_buffer(|b| b.sync(start, end));
+        self.published = end;
+    }
+
+    fn patch(&mut self, patch: Patch, old_target: Option<usize>, new_target: Option<usize>) {
+        self.publish();
+        self.a.patch(patch, old_target, new_target);
+        let at = patch.address();
+        self.a.use_buffer(|b| b.sync(at, at + 8));
+    }
+
+    fn jump(&mut self, label: &mut Label) {
+        self.const_jump(label);
+    }
+
+    fn prologue(&mut self) {
+        self.a.push(FP, RA);
+        self.move_(FP, SP);
+        for rs in CALLEE_SAVES.chunks(2).rev() {
+            self.a.push(rs[0], *rs.get(1).unwrap_or(&ZERO));
+        }
+        self.move_(POOL, ARGUMENTS[0]);
+    }
+
+    fn epilogue(&mut self) {
+        self.move_(RESULTS[0], RESULT);
+        for rs in CALLEE_SAVES.chunks(2) {
+            self.a.pop(rs[0], *rs.get(1).unwrap_or(&ZERO));
+        }
+        self.a.pop(FP, RA);
+        self.a.ret();
+    }
+
+    fn call_frame_info(&self, slots_used: Option<usize>) -> Option<CallFrameInfo> {
+        // DWARF register numbers are the same as the encodings.
+        let mut cfi = CallFrameInfo::new(RA as u8, 4, -8);
+        if slots_used.is_some() {
+            // `FP` points at the saved `(FP, RA)` pair, below the CFA.
+            cfi.def_cfa(FP as u8, 16);
+            cfi.offset(FP as u8, -16);
+            cfi.offset(RA as u8, -8);
+            let num_pairs = (CALLEE_SAVES.len() + 1) / 2;
+            for (i, rs) in CALLEE_SAVES.chunks(2).enumerate() {
+                let offset = -16 * (2 + (num_pairs - 1 - i) as i64);
+                for (j, &r) in rs.iter().enumerate() {
+                    cfi.offset(r as u8, offset + 8 * j as i64);
+                }
+            }
+        } else {
+            cfi.def_cfa(SP as u8, 0);
+            cfi.same_value(RA as u8);
+        }
+        Some(cfi)
+    }
+
+    fn if_eq(
+        &mut self,
+        guard: (Variable, u64),
+        eq_label: &mut Label,
+    ) {
+        let (discriminant, value) = guard;
+        let discriminant = self.src_to_register(discriminant, TEMP0);
+        let value = if value == 0 { ZERO } else { self.const_(TEMP1, value); TEMP1 };
+        // We can't assume a conditional branch can jump more than 4KB.
+        // Therefore, conditionally branch past an unconditional branch.
+        let skip = &mut Label::new(None);
+        self.jump_if(Condition::NE, discriminant, value, skip);
+        self.const_jump(eq_label);
+        self.define(skip);
+    }
+
+    fn if_ne(
+        &mut self,
+        guard: (Variable, u64),
+        ne_label: &mut Label,
+    ) {
+        let (discriminant, value) = guard;
+        let discriminant = self.src_to_register(discriminant, TEMP0);
+        let value = if value == 0 { ZERO } else { self.const_(TEMP1, value); TEMP1 };
+        // We can't assume a conditional branch can jump more than 4KB.
+        // Therefore, conditionally branch past an unconditional branch.
+        let skip = &mut Label::new(None);
+        self.jump_if(Condition::EQ, discriminant, value, skip);
+        self.const_jump(ne_label);
+        self.define(skip);
+    }
+
+    fn if_ult(
+        &mut self,
+        guard: (Variable, u64),
+        lt_label: &mut Label,
+    ) {
+        let (discriminant, value) = guard;
+        let discriminant = self.src_to_register(discriminant, TEMP0);
+        let value = if value == 0 { ZERO } else { self.const_(TEMP1, value); TEMP1 };
+        // We can't assume a conditional branch can jump more than 4KB.
+        // Therefore, conditionally branch past an unconditional branch.
+        let skip = &mut Label::new(None);
+        self.jump_if(Condition::GEU, discriminant, value, skip);
+        self.const_jump(lt_label);
+        self.define(skip);
+    }
+
+    fn jump_table(
+        &mut self,
+        discriminant: Variable,
+        cases: &mut [Label],
+        default_: &mut Label,
+    ) {
+        let discriminant = self.src_to_register(discriminant, TEMP0);
+        self.const_(TEMP1, cases.len() as u64);
+        let skip = &mut Label::new(None);
+        self.jump_if(Condition::LTU, discriminant, TEMP1, skip);
+        self.const_jump(default_);
+        self.define(skip);
+        let targets: Vec<_> = cases.iter().map(Label::target).collect();
+        let patches = self.a.jump_table(discriminant, TEMP0, TEMP1, &targets);
+        for (case, patch) in cases.iter_mut().zip(patches) {
+            case.push(patch);
+        }
+    }
+
+    fn action(
+        &mut self,
+        action: Action,
+    ) {
+        match action {
+            Action::Move(dest, src) => {
+                // `dest_to_register()` would generate less efficient code.
+                match dest {
+                    code::Variable::Register(dest) => {
+                        let src = self.src_to_register(src, dest);
+                        self.move_(dest, src);
+                    },
+                    code::Variable::Global(global) => {
+                        let src = self.src_to_register(src, TEMP0);
+                        self.mem(S, src, self.global_address(global), TEMP1);
+                    },
+                    code::Variable::Slot(slot) => {
+                        let src = self.src_to_register(src, TEMP0);
+                        self.mem(S, src, self.slot_address(slot), TEMP1);
+                    },
+                }
+            },
+            Action::Constant(prec, dest, value) => {
+                let value = match prec {
+                    P32 => u64::from(value as u32),
+                    P64 => value as u64,
+                };
+                self.const_(dest, value);
+            },
+            Action::Unary(op, prec, dest, src) => {
+                self.unary_op(op, prec, dest, src);
+            },
+            Action::Binary(op, prec, dest, src1, src2) => {
+                self.binary_op(op, prec, dest, src1, src2);
+            },
+            Action::Load(dest, (addr, width), _) => {
+                let dest = dest.into();
+                let base = self.src_to_register(addr, dest);
+                let op = if width == Width::Eight { L } else { LU };
+                self.a.mem(op, width, dest, (base, 0));
+            },
+            Action::Store(dest, src, (addr, width), _) => {
+                let dest = Register::from(dest);
+                let src = self.src_to_register(src, TEMP0);
+                let base = self.src_to_register(addr, dest);
+                self.move_(dest, base);
+                self.a.mem(S, width, src, (base, 0));
+            },
+            Action::Push(src1, src2) => {
+                let src1 = src1.map_or(ZERO, |src1| self.src_to_register(src1, TEMP0));
+                let src2 = src2.map_or(ZERO, |src2| self.src_to_register(src2, TEMP1));
+                *self.slots_used_mut() += 2;
+                self.a.push(src1, src2);
+            },
+            Action::Pop(dest1, dest2) => {
+                assert!(*self.slots_used_mut() >= 2);
+                if dest1.is_none() && dest2.is_none() {
+                    self.const_add(SP, SP, 16, TEMP0);
+                } else {
+                    let dest1 = dest1.map_or(ZERO, Register::from);
+                    let dest2 = dest2.map_or(ZERO, Register::from);
+                    self.a.pop(dest1, dest2);
+                }
+                *self.slots_used_mut() -= 2;
+            },
+            Action::DropMany(n) => {
+                assert!(*self.slots_used_mut() >= 2 * n);
+                self.const_add(SP, SP, n as u64 * 16, TEMP0);
+                *self.slots_used_mut() -= 2 * n;
+            },
+            Action::Debug(x) => {
+                // Read `x` before moving `SP`.
+                let x = self.src_to_register(x, TEMP0);
+                for rs in CALLER_SAVES.chunks(2).rev() {
+                    self.a.push(rs[0], rs[1]);
+                }
+                self.move_(ARGUMENTS[0], x);
+                self.const_(TEMP0, debug_word as *const () as u64);
+                self.a.call(TEMP0);
+                for rs in CALLER_SAVES.chunks(2) {
+                    self.a.pop(rs[0], rs[1]);
+                }
+            },
+        };
+    }
+}
+
+//-----------------------------------------------------------------------------
+
+impl<B: buffer::Execute> super::Execute for Lowerer<B> {
+    fn try_execute<T>(
+        &mut self,
+        label: &Label,
+        callback: impl FnOnce(super::ExecuteFn) -> T,
+    ) -> Result<T, Error> {
+        let target = label.target().expect("Label is not defined");
+        super::Lower::publish(self);
+        self.a.use_buffer(|b| {
+            b.try_execute(|bytes| {
+                let f = unsafe { std::mem::transmute::<&u8, super::ExecuteFn>(&bytes[target]) };
+                callback(f)
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use super::super::assembler::tests::{disassemble};
+    use super::super::Condition::EQ;
+    use super::super::super::{Lower as _};
+
+    /// Conditional branches can only reach +/-4KB, so this must be nearby.
+    const LABEL: usize = 0x00000680;
+
+    #[test]
+    fn allocatable_regs() {
+        for &r in &ALLOCATABLE_REGISTERS {
+            assert_ne!(r, POOL);
+            assert_ne!(r, TEMP0);
+            assert_ne!(r, TEMP1);
+            assert!(!matches!(r, ZERO | RA | SP | GP | TP | FP));
+        }
+    }
+
+    /// Test that we can patch jumps and calls.
+    #[test]
+    fn steal() {
+        let mut lo = Lowerer::<Vec<u8>>::new(0);
+        let start = lo.here().target().unwrap();
+        let mut label = Label::new(None);
+        lo.jump_if(EQ, A0, A1, &mut label);
+        lo.const_jump(&mut label);
+        lo.const_call(&mut label);
+        disassemble(&lo.a, start, &[
+            0x80B50063, // beq a0, a1, -4096
+            0x80000F17, // auipc t5, 524288
+            0x000F0067, // jalr zero, 0(t5)
+            0x80000097, // auipc ra, 524288
+            0x000080E7, // jalr ra, 0(ra)
+        ]).unwrap();
+        let mut new_label = Label::new(Some(LABEL));
+        lo.steal(&mut label, &mut new_label);
+        label = new_label;
+        disassemble(&lo.a, start, &[
+            0x68B50063, // beq a0, a1, 1664
+            0x00000F17, // auipc t5, 0
+            0x67CF0067, // jalr zero, 1660(t5)
+            0x00000097, // auipc ra, 0
+            0x674080E7, // jalr ra, 1652(ra)
+        ]).unwrap();
+        let mut new_label = Label::new(Some(LABEL));
+        lo.steal(&mut label, &mut new_label);
+        disassemble(&lo.a, start, &[
+            0x68B50063, // beq a0, a1, 1664
+            0x00000F17, // auipc t5, 0
+            0x67CF0067, // jalr zero, 1660(t5)
+            0x00000097, // auipc ra, 0
+            0x674080E7, // jalr ra, 1652(ra)
+        ]).unwrap();
+    }
+}
@@ -0,0 +1,330 @@
+use super::{buffer, code, Word, Patch, Label, CallFrameInfo};
+use buffer::{Buffer};
+use code::{Variable, Action};
+use crate::error::{Error, expect};
+
+/// Wraps a contiguous block of executable memory, and provides methods for
+/// assembling machine code into it. The machine code accesses a [`Pool`],
+/// which is passed to it when it is called. The code does not own the `Pool`,
+/// so the same code can run many virtual machine instances.
+///
+/// The low-level memory address of the executable memory will remain constant
+/// while the code is executing, but it could change at other times, e.g.
+/// because the buffer grows and gets reallocated. Therefore, be wary of
+/// absolute memory addresses. `Lower` itself always expresses addresses using
+/// [`Label`]. Some buffers guarantee that the code never moves; see
+/// [`EntryPoint`].
+///
+/// [`Pool`]: super::Pool
+pub trait Lower {
+    /// The number of [`Global`]s in the [`Pool`] expected by the code.
+    ///
+    /// [`Global`]: code::Global
+    /// [`Pool`]: super::Pool
+    fn num_globals(&self) -> usize;
+
+    /// The number of stack-allocated spill [`Slot`]s. Spill `Slot`s are created
+    /// by [`Push`] instructions and destroyed by [`Pop`] instructions. The
+    /// number of spill slots at a `jump()` or `Switch` must match the number at
+    /// its `Label`.
+    ///
+    /// Do not mutate the number of spill slots (other that using `Push` and
+    /// `Pop`) when the current assembly address is reachable.
+    ///
+    /// [`Push`]: Action::Push
+    /// [`Pop`]: Action::Pop
+    fn slots_used_mut(&mut self) -> &mut usize;
+
+    /// Returns the current assembly address as a fresh [`Label`].
+    fn here(&self) -> Label;
+
+    /// Returns the code assembled so far. A [`Label`]'s target is an index
+    /// into this slice. The address of the slice is only valid until the next
+    /// time the code is modified.
+    fn code(&self) -> &[u8];
+
+    /// Returns the address at which [`code()`] runs, which might differ from
+    /// the address of `code()` itself, or `None` if the code cannot run
+    /// natively. Like the address of `code()`, it is only valid until the
+    /// next time the code is modified.
+    ///
+    /// [`code()`]: Lower::code
+    fn executable_address(&self) -> Option<usize>;
+
+    /// Returns the first [`Error`] that occurred while assembling code, if it
+    /// has not already been returned, e.g. because the buffer could not
+    /// grow. After an error, the code is incomplete, and later calls return
+    /// [`Error::Poisoned`]. By default, this does nothing.
+    fn take_error(&mut self) -> Result<(), Error> { Ok(()) }
+
+    /// Promises that the code at `start..end` will never run again. The
+    /// memory it occupies may be returned to the operating system, but the
+    /// addresses will not be reused. By default, this does nothing.
+    fn release(&mut self, _start: usize, _end: usize) {}
+
+    /// Makes the code assembled so far visible to instruction fetch on all
+    /// threads. By default, this does nothing.
+    fn publish(&mut self) {}
+
+    /// Modify the instruction at `patch` so that instead of jumping to
+    /// `old_target` it jumps to `new_target`.
+    ///
+    /// While the code is running on other threads, only jumps assembled by
+    /// [`jump()`] may be patched. The modified jump becomes visible to
+    /// those threads atomically, except on RISC-V.
+    ///
+    /// Normally you should prefer `steal()` or `define()`, which call this.
+    ///
+    /// [`jump()`]: Lower::jump
+    fn patch(&mut self, patch: Patch, old_target: Option<usize>, new_target: Option<usize>);
+
+    /// Modifies all instructions that jump to `loser` so that they instead
+    /// jump to `winner`.
+    fn steal(&mut self, loser: &mut Label, winner: &mut Label) {
+        let loser_target = loser.target();
+        for patch in loser.drain() {
+            self.patch(patch, loser_target, winner.target());
+            winner.push(patch);
+        }
+    }
+
+    /// Define `label`, which must not previously have been defined.
+    fn define(&mut self, label: &mut Label) {
+        assert!(!label.is_defined());
+        // Use `steal()` to modify all instructions that jump to `label`.
+        let mut new = self.here();
+        self.steal(label, &mut new);
+        *label = new;
+    }
+
+    /// Assemble an unconditional jump to `label`.
+    fn jump(&mut self, label: &mut Label);
+
+    /// Assemble Mijit's function prologue. The function takes one argument:
+    ///  - The pool pointer.
+    fn prologue(&mut self);
+
+    /// Assemble Mijit's function epilogue. The function returns one result:
+    ///  - The exit code, which is moved from `RESULT`.
+    fn epilogue(&mut self);
+
+    /// Describes the stack frame built by `prologue()`, for debuggers.
+    ///  - slots_used - the number of spill slots allocated, or `None` to
+    ///    describe the state on entry, before `prologue()` has run.
+    ///
+    /// Returns `None` if this `Lower` cannot describe its stack frames.
+    fn call_frame_info(&self, _slots_used: Option<usize>) -> Option<CallFrameInfo> { None }
+
+    /// Assemble code that branches to `eq_label` if the equality test passes.
+    fn if_eq(
+        &mut self,
+        guard: (Variable, u64),
+        eq_label: &mut Label,
+    );
+
+    /// Assemble code that branches to `ne_label` if the equality test fails.
+    fn if_ne(
+        &mut self,
+        guard: (Variable, u64),
+        ne_label: &mut Label,
+    );
+
+    /// Assemble code that branches to `lt_label` if the discriminant is less
+    /// than the value, treating both as unsigned.
+    fn if_ult(
+        &mut self,
+        guard: (Variable, u64),
+        lt_label: &mut Label,
+    );
+
+    /// Assemble code that branches to `cases[i]` if `discriminant` is `i`,
+    /// and otherwise to `default_`. This is a bounds check followed by an
+    /// indirect jump through a table of jump instructions, one per case.
+    /// Each table entry can be patched in the same way as a [`jump()`].
+    ///
+    /// [`jump()`]: Lower::jump
+    fn jump_table(
+        &mut self,
+        discriminant: Variable,
+        cases: &mut [Label],
+        default_: &mut Label,
+    );
+
+    /// Assemble code that branches to `cases[i]` if `discriminant` is `i`,
+    /// and otherwise to `default_`, choosing a strategy according to the
+    /// number of cases:
+    ///  - A few cases are tested one by one using [`if_eq()`].
+    ///  - A moderate number of cases are found by a binary search, using
+    ///    [`if_ult()`].
+    ///  - Many cases use a [`jump_table()`].
+    ///
+    /// [`if_eq()`]: Lower::if_eq
+    /// [`if_ult()`]: Lower::if_ult
+    /// [`jump_table()`]: Lower::jump_table
+    fn switch(
+        &mut self,
+        discriminant: Variable,
+        cases: &mut [Label],
+        default_: &mut Label,
+    ) {
+        if cases.len() <= MAX_LINEAR_CASES {
+            for (index, case) in cases.iter_mut().enumerate() {
+                self.if_eq((discriminant, index as u64), case);
+            }
+            self.jump(default_);
+        } else if cases.len() < MIN_JUMP_TABLE_CASES {
+            let mut in_range = Label::new(None);
+            self.if_ult((discriminant, cases.len() as u64), &mut in_range);
+            self.jump(default_);
+            self.define(&mut in_range);
+            binary_search(self, discriminant, cases, 0);
+        } else {
+            self.jump_table(discriminant, cases, default_);
+        }
+    }
+
+    /// Assemble code to perform the given `action`.
+    fn action(&mut self, action: Action);
+
+    /// Call `action()` repeatedly.
+    fn actions(&mut self, actions: &[Action]) {
+        for &action in actions {
+            self.action(action);
+        }
+    }
+}
+
+/// The largest number of cases for which [`Lower::switch()`] tests each case
+/// in turn.
+const MAX_LINEAR_CASES: usize = 4;
+
+/// The smallest number of cases for which [`Lower::switch()`] uses a jump
+/// table.
+const MIN_JUMP_TABLE_CASES: usize = 16;
+
+/// Assemble code that branches to `cases[i]` if `discriminant` is `base + i`.
+/// The discriminant must be in the range `base .. base + cases.len()`.
+fn binary_search<L: Lower + ?Sized>(
+    lo: &mut L,
+    discriminant: Variable,
+    cases: &mut [Label],
+    base: u64,
+) {
+    if cases.len() <= 2 {
+        let (last, others) = cases.split_last_mut().expect("No cases");
+        for (index, case) in others.iter_mut().enumerate() {
+            lo.if_eq((discriminant, base + index as u64), case);
+        }
+        lo.jump(last);
+    } else {
+        let mid = cases.len() / 2;
+        let (lower, upper) = cases.split_at_mut(mid);
+        let mut is_lower = Label::new(None);
+        lo.if_ult((discriminant, base + mid as u64), &mut is_lower);
+        binary_search(lo, discriminant, upper, base + mid as u64);
+        lo.define(&mut is_lower);
+        binary_search(lo, discriminant, lower, base);
+    }
+}
+
+//-----------------------------------------------------------------------------
+
+/// The type of the generated code.
+pub type ExecuteFn = unsafe extern "C" fn(
+    /* pool */ *mut Word,
+) -> /* result */ Word;
+
+/// Add to [`Lower`] the ability to execute the compiled code.
+pub trait Execute: Sized + Lower {
+    /// [`publish()`] the code, make the memory backing `self` executable, pass
+    /// the code at `label` to `callback`, then make the memory writeable (and
+    /// not executable) again.
+    ///
+    /// `callback` is typically something like
+    /// `|f| f(pool.as_mut().as_mut_ptr())`, where `pool` is a [`Pool`] with
+    /// [`num_globals()`] `Global`s.
+    ///
+    /// If we can't change the memory permissions, you get an [`Err`] and `self`
+    /// is gone.
+    ///
+    /// [`publish()`]: Lower::publish
+    /// [`Pool`]: super::Pool
+    /// [`num_globals()`]: Lower::num_globals
+    fn try_execute<T>(
+        &mut self,
+        label: &Label,
+        callback: impl FnOnce(ExecuteFn) -> T,
+    ) -> Result<T, Error>;
+
+    /// Like [`try_execute()`], but panics on error.
+    ///
+    /// [`try_execute()`]: Self::try_execute
+    fn execute<T>(
+        &mut self,
+        label: &Label,
+        callback: impl FnOnce(ExecuteFn) -> T,
+    ) -> T {
+        expect(self.try_execute(label, callback))
+    }
+}
+
+/// Add to [`Execute`] the ability to obtain a function pointer to the compiled
+/// code, which remains valid while more code is assembled. This requires a
+/// [`Stable`] buffer.
+///
+/// RISC-V does not implement this trait, because its long jumps are two
+/// instructions, and cannot be patched while other threads are running them.
+///
+/// [`Stable`]: buffer::Stable
+pub trait EntryPoint: Execute {
+    /// Returns a pointer to the code at `label`, which must be defined. It
+    /// can be called at any time until `self` is dropped, passing the words
+    /// of the [`Pool`]. Other threads may call it too, but they only see
+    /// code assembled before the last call to [`publish()`].
+    ///
+    /// [`Pool`]: super::Pool
+    /// [`publish()`]: Lower::publish
+    fn entry_point(&self, label: &Label) -> ExecuteFn;
+}
+
+//-----------------------------------------------------------------------------
+
+/// Represents a compilation target. Most importantly, this defines the CPU
+/// architecture that Mijit will generate code for. It also defines how Mijit
+/// will allocate and make executable the memory for the code.
+///
+/// A type that implements `Target` is usually a zero-sized struct, but it can
+/// contain flags and other configuration data. `Default::default()` recommends
+/// the best configuation for the machine Mijit is running on.
+pub trait Target: Default {
+    type Lowerer: Lower + Execute;
+
+    /// The number of registers available for allocation.
+    const NUM_REGISTERS: usize;
+
+    /// Construct a [`Lowerer`] for this `Target`.
+    ///  - `num_globals` - The number of [`Global`]s in each per-VM [`Pool`].
+    ///
+    /// [`Global`]: code::Global
+    /// [`Pool`]: super::Pool
+    fn lowerer(&self, num_globals: usize) -> Self::Lowerer;
+}
+
+/// A [`Target`] whose code can be assembled into a [`Buffer`] of type `B`.
+///
+/// A `Target` need not match the machine Mijit is running on. For example,
+/// AArch64 code can be assembled on an x86_64 host into a `Vec<u8>`, in order
+/// to inspect it or to compare it with a known byte sequence. Only
+/// [`Target::lowerer()`] gives code that can be executed.
+pub trait CrossTarget<B: Buffer>: Target {
+    /// A [`Lower`] that assembles code for this `Target` into a `B`.
+    type CrossLowerer: Lower;
+
+    /// Construct a [`CrossLowerer`] for this `Target`. This works on any host.
+    ///  - `num_globals` - The number of [`Global`]s in each per-VM [`Pool`].
+    ///
+    /// [`CrossLowerer`]: Self::CrossLowerer
+    /// [`Global`]: code::Global
+    /// [`Pool`]: super::Pool
+    fn cross_lowerer(&self, num_globals: usize) -> Self::CrossLowerer;
+}
@@ -0,0 +1,89 @@
+use std::marker::{PhantomData};
+
+use super::{buffer, code, Patch, Label, Word, Lower, ExecuteFn, Execute, EntryPoint, CallFrameInfo, RESULT};
+use buffer::{Buffer, Mmap};
+
+mod enums;
+pub use enums::{Register, ALL_REGISTERS, DWARF_RIP, BinaryOp, ALL_BINARY_OPS, ShiftOp, ALL_SHIFT_OPS, Condition, ALL_CONDITIONS, Width, ALL_WIDTHS};
+use Register::*;
+
+mod assembler;
+pub use assembler::{Assembler};
+
+mod lowerer;
+pub use lowerer::{Lowerer, ALLOCATABLE_REGISTERS};
+
+/// In the System V amd64 calling convention, these registers must be preserved
+/// by subroutines, as must `RSP`.
+pub const CALLEE_SAVES: [Register; 6] = [RB, RBP, R12, R13, R14, R15];
+
+/// In the System V amd64 calling convention, these registers may be
+/// corrupted by subroutines.
+pub const CALLER_SAVES: [Register; 9] = [RDI, RSI, RD, RC, R8, R9, R10, R11, RA];
+
+/// In the System V amd64 calling convention, these registers hold the integer-
+/// or pointer-type function arguments.
+pub const ARGUMENTS: [Register; 6] = [RDI, RSI, RD, RC, R8, R9];
+
+/// In the System V amd64 calling convention, these registers hold the integer-
+/// or pointer-type function results.
+pub const RESULTS: [Register; 2] = [RA, RD];
+
+/// The x86_64/libc compilation target.
+/// The code is held in an [`Mmap`], which is the most portable [`Buffer`].
+/// To use a different `Buffer`, see [`BufferTarget`].
+#[derive(Default)]
+pub struct Target;
+
+impl super::Target for Target {
+    type Lowerer = Lowerer<Mmap>;
+
+    const NUM_REGISTERS: usize = ALLOCATABLE_REGISTERS.len();
+
+    fn lowerer(&self, num_globals: usize) -> Self::Lowerer {
+        Lowerer::new(num_globals)
+    }
+}
+
+impl<B: Buffer> super::CrossTarget<B> for Target {
+    type CrossLowerer = Lowerer<B>;
+
+    fn cross_lowerer(&self, num_globals: usize) -> Self::CrossLowerer {
+        Lowerer::new(num_globals)
+    }
+}
+
+/// Like [`Target`], but the executable code is held in a `B`.
+/// On Linux, [`Memfd`] avoids changing memory permissions, and allows the
+/// code to be modified while it runs. See [`MemfdTarget`].
+///
+/// [`Memfd`]: buffer::Memfd
+pub struct BufferTarget<B: buffer::Execute>(PhantomData<B>);
+
+impl<B: buffer::Execute> Default for BufferTarget<B> {
+    fn default() -> Self { BufferTarget(PhantomData) }
+}
+
+impl<B: buffer::Execute> super::Target for BufferTarget<B> {
+    type Lowerer = Lowerer<B>;
+
+    const NUM_REGISTERS: usize = ALLOCATABLE_REGISTERS.len();
+
+    fn lowerer(&self, num_globals: usize) -> Self::Lowerer {
+        Lowerer::new(num_globals)
+    }
+}
+
+impl<B: buffer::Execute, C: Buffer> super::CrossTarget<C> for BufferTarget<B> {
+    type CrossLowerer = Lowerer<C>;
+
+    fn cross_lowerer(&self, num_globals: usize) -> Self::CrossLowerer {
+        Lowerer::new(num_globals)
+    }
+}
+
+/// A [`BufferTarget`] that uses a [`Memfd`].
+///
+/// [`Memfd`]: buffer::Memfd
+#[cfg(target_os = "linux")]
+pub type MemfdTarget = BufferTarget<buffer::Memfd>;
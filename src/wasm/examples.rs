@@ -0,0 +1,81 @@
+//! Example WebAssembly functions, used by the tests and the benchmarks.
+
+use super::{BinOp, RelOp, Instr, ValType, Function};
+use Instr::*;
+use ValType::*;
+
+/// `fib(n: i32) -> i32`, computed recursively.
+pub fn fib() -> Function {
+    Function {params: 1, results: 1, locals: 0, body: vec![
+        Block,
+            LocalGet(0),
+            I32Const(2),
+            Compare(I32, RelOp::GeU),
+            BrIf(0),
+            LocalGet(0),
+            Return,
+        End,
+        LocalGet(0),
+        I32Const(1),
+        Binary(I32, BinOp::Sub),
+        Call(0),
+        LocalGet(0),
+        I32Const(2),
+        Binary(I32, BinOp::Sub),
+        Call(0),
+        Binary(I32, BinOp::Add),
+    ]}
+}
+
+/// `sieve(n: i32) -> i32` counts the primes less than `n`, and leaves a `1`
+/// in each composite byte of memory.
+pub fn sieve() -> Function {
+    // Locals: n, i, j, count.
+    Function {params: 1, results: 1, locals: 3, body: vec![
+        I32Const(2),
+        LocalSet(1),
+        Block,
+            Loop,
+                LocalGet(1),
+                LocalGet(0),
+                Compare(I32, RelOp::GeU),
+                BrIf(1),
+                Block,
+                    LocalGet(1),
+                    Load8U(0),
+                    BrIf(0),
+                    LocalGet(3),
+                    I32Const(1),
+                    Binary(I32, BinOp::Add),
+                    LocalSet(3),
+                    LocalGet(1),
+                    LocalGet(1),
+                    Binary(I32, BinOp::Mul),
+                    LocalSet(2),
+                    Block,
+                        Loop,
+                            LocalGet(2),
+                            LocalGet(0),
+                            Compare(I32, RelOp::GeU),
+                            BrIf(1),
+                            LocalGet(2),
+                            I32Const(1),
+                            Store8(0),
+                            LocalGet(2),
+                            LocalGet(1),
+                            Binary(I32, BinOp::Add),
+                            LocalSet(2),
+                            Br(0),
+                        End,
+                    End,
+                End,
+                LocalGet(1),
+                I32Const(1),
+                Binary(I32, BinOp::Add),
+                LocalSet(1),
+                Br(0),
+            End,
+        End,
+        LocalGet(3),
+    ]}
+}
@@ -0,0 +1,492 @@
+//! An implementation of a subset of [WebAssembly] in Mijit.
+//! This is a larger example of a [`Machine`] than [`brainfuck`], and closer
+//! to a real language.
+//!
+//! The subset includes `i32` and `i64` arithmetic (but not division),
+//! locals, linear memory, `block`, `loop`, `br`, `br_if`, `call` and
+//! `return`. Modules are not parsed from the binary format; instead they are
+//! assembled in Rust as a [`Module`], and checked by [`validate()`].
+//!
+//! Because the number of values on the WebAssembly stack before each
+//! instruction is known in advance, every local and stack value has a fixed
+//! offset in the frame of its function. Each value is a 64-bit word, and
+//! `i32` values are zero-extended. A frame is laid out as follows:
+//!  - the locals, starting with the parameters.
+//!  - the frame pointer of the caller.
+//!  - the return site number, which identifies the `call` instruction.
+//!  - the stack.
+//!
+//! The arguments of a `call` on the caller's stack become the first locals
+//! of the callee, and the result is returned in the same place.
+//!
+//! Accesses to linear memory are bounds-checked, as are calls against the
+//! size of the stack.
+//!
+//! [WebAssembly]: https://webassembly.github.io/spec/core/
+//! [`brainfuck`]: crate::brainfuck
+
+use std::fmt::{self, Debug, Formatter};
+
+use super::code::{self, BinaryOp, Width, Register, REGISTERS, Global, Marshal, Machine, Case, Switch};
+use BinaryOp::*;
+use Width::*;
+use code::builder::{build_block, Builder};
+use super::target::{Native, Target, Word, Pool, native};
+use super::jit::{MachineJit};
+
+mod module;
+pub use module::{PAGE_SIZE, ValType, BinOp, RelOp, Instr, Function, Module, Problem, ValidationError, FunctionInfo, validate};
+
+pub mod examples;
+
+/// The number of 64-bit words in the stack.
+pub const STACK_WORDS: usize = 1 << 16;
+
+//-----------------------------------------------------------------------------
+
+const R1: Register = REGISTERS[1];
+const R2: Register = REGISTERS[2];
+const FP: Register = REGISTERS[3];
+const MEMORY: Register = REGISTERS[4];
+const MEMORY_SIZE: Register = REGISTERS[5];
+const STACK_LIMIT: Register = REGISTERS[6];
+const COND: Register = REGISTERS[7];
+
+/// The `Global`s used by the [`Wasm`] machine.
+pub mod global {
+    use super::{Global};
+    /// The address of the frame of the current function.
+    pub const FP: Global = Global(0);
+    /// The address of the linear memory.
+    pub const MEMORY: Global = Global(1);
+    /// The number of bytes of linear memory.
+    pub const MEMORY_SIZE: Global = Global(2);
+    /// The address of the end of the stack.
+    pub const STACK_LIMIT: Global = Global(3);
+    /// The value tested by the current [`State`](super::State).
+    pub const COND: Global = Global(4);
+}
+
+/// The stack, including locals.
+const AM_STACK: code::AliasMask = code::AliasMask(0x1);
+
+/// The linear memory.
+const AM_MEMORY: code::AliasMask = code::AliasMask(0x2);
+
+/// The states of the [`Wasm`] machine. Each is labelled with the index of a
+/// function and the index of an instruction in its body.
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+pub enum State {
+    /// About to execute the instruction.
+    Code(usize, usize),
+    /// Executing a `br_if`. `COND` holds the condition.
+    Branch(usize, usize),
+    /// Executing a memory access or a `call`. `COND` is non-zero if the
+    /// access is out of bounds or the stack would overflow.
+    Checked(usize, usize),
+    /// Returning from a function. `COND` holds the return site number.
+    Return,
+}
+
+/// The reasons for the [`Wasm`] machine to return to the host.
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+pub enum Trap {
+    /// The function called by the host has returned.
+    Return,
+    /// Executed `unreachable`.
+    Unreachable,
+    /// A memory access was outside the linear memory.
+    MemoryOutOfBounds,
+    /// A `call` would overflow the stack.
+    StackOverflow,
+}
+
+/// Returns the offset in the frame of the local with index `local`.
+fn local(local: usize) -> i64 { 8 * local as i64 }
+
+/// Returns the offset of the stack value at `height` in the frame of a
+/// function described by `info`.
+fn slot(info: &FunctionInfo, height: usize) -> i64 { local(info.num_locals + 2 + height) }
+
+/// Assembles code to compute `op(src1, src2)` into `dest` using `ty`
+/// arithmetic.
+fn binary(b: &mut Builder<()>, ty: ValType, op: BinaryOp, dest: Register, src1: Register, src2: Register) {
+    match ty {
+        ValType::I32 => b.binary32(op, dest, src1, src2),
+        ValType::I64 => b.binary64(op, dest, src1, src2),
+    }
+}
+
+/// Assembles code to compute `op(src, value)` into `dest` using `ty`
+/// arithmetic.
+fn const_binary(b: &mut Builder<()>, ty: ValType, op: BinaryOp, dest: Register, src: Register, value: i32) {
+    match ty {
+        ValType::I32 => b.const_binary32(op, dest, src, value),
+        ValType::I64 => b.const_binary64(op, dest, src, value.into()),
+    }
+}
+
+/// If `instr` accesses linear memory, returns its offset, its [`Width`], the
+/// number of bytes it accesses, and whether it is a store.
+fn memory_access(instr: Instr) -> Option<(u32, Width, i64, bool)> {
+    match instr {
+        Instr::Load(ValType::I32, offset) => Some((offset, Four, 4, false)),
+        Instr::Load(ValType::I64, offset) => Some((offset, Eight, 8, false)),
+        Instr::Load8U(offset) => Some((offset, One, 1, false)),
+        Instr::Store(ValType::I32, offset) => Some((offset, Four, 4, true)),
+        Instr::Store(ValType::I64, offset) => Some((offset, Eight, 8, true)),
+        Instr::Store8(offset) => Some((offset, One, 1, true)),
+        _ => None,
+    }
+}
+
+/// Assembles code for an `instr` that does not access memory or affect
+/// control flow. `height` is the number of values on the stack before it.
+fn simple(b: &mut Builder<()>, info: &FunctionInfo, height: usize, instr: Instr) {
+    let top = |depth: usize| slot(info, height - depth);
+    match instr {
+        Instr::Block | Instr::Loop | Instr::End | Instr::Drop => {},
+        Instr::LocalGet(index) => {
+            b.load(R1, (FP, local(index)), Eight, AM_STACK);
+            b.store(R1, (FP, top(0)), Eight, AM_STACK);
+        },
+        Instr::LocalSet(index) | Instr::LocalTee(index) => {
+            b.load(R1, (FP, top(1)), Eight, AM_STACK);
+            b.store(R1, (FP, local(index)), Eight, AM_STACK);
+        },
+        Instr::I32Const(value) => {
+            b.const_(R1, (value as u32).into());
+            b.store(R1, (FP, top(0)), Eight, AM_STACK);
+        },
+        Instr::I64Const(value) => {
+            b.const_(R1, value);
+            b.store(R1, (FP, top(0)), Eight, AM_STACK);
+        },
+        Instr::Eqz(ty) => {
+            b.load(R1, (FP, top(1)), Eight, AM_STACK);
+            const_binary(b, ty, Eq, R1, R1, 0);
+            b.const_binary64(And, R1, R1, 1);
+            b.store(R1, (FP, top(1)), Eight, AM_STACK);
+        },
+        Instr::Compare(ty, op) => {
+            b.load(R1, (FP, top(2)), Eight, AM_STACK);
+            b.load(R2, (FP, top(1)), Eight, AM_STACK);
+            // Express `op` as `Eq`, `Lt` or `Ult`, perhaps swapping the
+            // operands and/or negating the result.
+            let (op, swap, negate) = match op {
+                RelOp::Eq => (Eq, false, false),
+                RelOp::Ne => (Eq, false, true),
+                RelOp::LtS => (Lt, false, false),
+                RelOp::LtU => (Ult, false, false),
+                RelOp::GtS => (Lt, true, false),
+                RelOp::GtU => (Ult, true, false),
+                RelOp::LeS => (Lt, true, true),
+                RelOp::LeU => (Ult, true, true),
+                RelOp::GeS => (Lt, false, true),
+                RelOp::GeU => (Ult, false, true),
+            };
+            let (src1, src2) = if swap { (R2, R1) } else { (R1, R2) };
+            binary(b, ty, op, R1, src1, src2);
+            b.const_binary64(And, R1, R1, 1);
+            if negate { b.const_binary64(Xor, R1, R1, 1); }
+            b.store(R1, (FP, top(2)), Eight, AM_STACK);
+        },
+        Instr::Binary(ty, op) => {
+            b.load(R1, (FP, top(2)), Eight, AM_STACK);
+            b.load(R2, (FP, top(1)), Eight, AM_STACK);
+            let op = match op {
+                BinOp::Add => Add,
+                BinOp::Sub => Sub,
+                BinOp::Mul => Mul,
+                BinOp::And => And,
+                BinOp::Or => Or,
+                BinOp::Xor => Xor,
+                BinOp::Shl | BinOp::ShrS | BinOp::ShrU => {
+                    // WebAssembly takes the shift amount modulo the width.
+                    let bits = if ty == ValType::I32 { 32 } else { 64 };
+                    const_binary(b, ty, And, R2, R2, bits - 1);
+                    match op { BinOp::Shl => Lsl, BinOp::ShrS => Asr, _ => Lsr }
+                },
+            };
+            binary(b, ty, op, R1, R1, R2);
+            b.store(R1, (FP, top(2)), Eight, AM_STACK);
+        },
+        Instr::I32WrapI64 | Instr::I64ExtendI32U => {
+            b.load(R1, (FP, top(1)), Four, AM_STACK);
+            b.store(R1, (FP, top(1)), Eight, AM_STACK);
+        },
+        Instr::I64ExtendI32S => {
+            b.load(R1, (FP, top(1)), Eight, AM_STACK);
+            b.const_binary64(Lsl, R1, R1, 32);
+            b.const_binary64(Asr, R1, R1, 32);
+            b.store(R1, (FP, top(1)), Eight, AM_STACK);
+        },
+        _ => panic!("Not a simple instruction: {:?}", instr),
+    }
+}
+
+/// The specification of the WebAssembly virtual machine, for a particular
+/// [`Module`].
+#[derive(Debug)]
+pub struct Wasm {
+    /// The code.
+    module: Module,
+    /// The result of [`validate()`].
+    infos: Vec<FunctionInfo>,
+    /// The function index and instruction index of each `call`. The return
+    /// site number of each `call` is one more than its index in this list.
+    /// Return site number zero means return to the host.
+    return_sites: Vec<(usize, usize)>,
+}
+
+impl Wasm {
+    /// Validates `module` and constructs a `Wasm` to run it.
+    pub fn new(module: Module) -> Result<Self, ValidationError> {
+        let infos = validate(&module)?;
+        let return_sites = infos.iter().enumerate().flat_map(|(function, info)| {
+            info.calls.iter().map(move |&pc| (function, pc))
+        }).collect();
+        Ok(Wasm {module, infos, return_sites})
+    }
+
+    /// Returns the code.
+    pub fn module(&self) -> &Module { &self.module }
+
+    /// Returns the [`FunctionInfo`] for the function with index `function`.
+    pub fn info(&self, function: usize) -> &FunctionInfo { &self.infos[function] }
+
+    /// Returns the return site number of the `call` at `pc` in `function`.
+    fn return_site(&self, function: usize, pc: usize) -> usize {
+        self.return_sites.iter().position(|&site| site == (function, pc)).expect("Not a call") + 1
+    }
+
+    /// Returns a `Case` that executes instructions from `pc` in `function`
+    /// until one that needs a `Switch`, and starts to execute that one.
+    fn code_case(&self, function: usize, mut pc: usize) -> Case<Result<State, Trap>> {
+        let body = &self.module.functions[function].body;
+        let info = &self.infos[function];
+        let mut b = Builder::<()>::new();
+        let new_state = loop {
+            let height = info.heights[pc];
+            let top = |depth: usize| slot(info, height - depth);
+            let instr = if pc < body.len() { body[pc] } else { Instr::Return };
+            if let Some((offset, _, size, is_store)) = memory_access(instr) {
+                let address = if is_store { top(2) } else { top(1) };
+                b.load(R1, (FP, address), Four, AM_STACK);
+                b.const_binary64(Add, R1, R1, i64::from(offset) + size);
+                b.binary64(Ult, COND, MEMORY_SIZE, R1);
+                break Ok(State::Checked(function, pc));
+            }
+            match instr {
+                Instr::Unreachable => break Err(Trap::Unreachable),
+                Instr::Br(_) => break Ok(State::Code(function, info.targets[&pc])),
+                Instr::BrIf(_) => {
+                    b.load(COND, (FP, top(1)), Four, AM_STACK);
+                    break Ok(State::Branch(function, pc));
+                },
+                Instr::Return => {
+                    if self.module.functions[function].results > 0 {
+                        b.load(R1, (FP, top(1)), Eight, AM_STACK);
+                        b.store(R1, (FP, local(0)), Eight, AM_STACK);
+                    }
+                    b.load(COND, (FP, local(info.num_locals + 1)), Eight, AM_STACK);
+                    b.load(R1, (FP, local(info.num_locals)), Eight, AM_STACK);
+                    b.move_(FP, R1);
+                    break Ok(State::Return);
+                },
+                Instr::Call(callee) => {
+                    let params = self.module.functions[callee].params;
+                    let frame_end = slot(info, height - params) + local(self.infos[callee].frame_size());
+                    b.const_binary64(Add, R1, FP, frame_end);
+                    b.binary64(Ult, COND, STACK_LIMIT, R1);
+                    break Ok(State::Checked(function, pc));
+                },
+                _ => simple(&mut b, info, height, instr),
+            }
+            pc += 1;
+        };
+        Case {actions: b.block().into(), new_state}
+    }
+
+    /// Returns a `Case` that completes the memory access or `call` at `pc`
+    /// in `function`, given that it passed its check.
+    fn checked_case(&self, function: usize, pc: usize) -> Case<Result<State, Trap>> {
+        let instr = self.module.functions[function].body[pc];
+        let info = &self.infos[function];
+        let height = info.heights[pc];
+        let top = |depth: usize| slot(info, height - depth);
+        let mut b = Builder::<()>::new();
+        let new_state = if let Some((offset, width, _, is_store)) = memory_access(instr) {
+            let address = if is_store { top(2) } else { top(1) };
+            b.load(R1, (FP, address), Four, AM_STACK);
+            b.const_binary64(Add, R1, R1, offset.into());
+            b.binary64(Add, R1, MEMORY, R1);
+            if is_store {
+                b.load(R2, (FP, top(1)), Eight, AM_STACK);
+                b.store(R2, (R1, 0), width, AM_MEMORY);
+            } else {
+                b.load(R2, (R1, 0), width, AM_MEMORY);
+                b.store(R2, (FP, top(1)), Eight, AM_STACK);
+            }
+            State::Code(function, pc + 1)
+        } else if let Instr::Call(callee) = instr {
+            let callee_function = &self.module.functions[callee];
+            let callee_info = &self.infos[callee];
+            b.const_binary64(Add, R1, FP, slot(info, height - callee_function.params));
+            b.store(FP, (R1, local(callee_info.num_locals)), Eight, AM_STACK);
+            b.const_(R2, self.return_site(function, pc) as i64);
+            b.store(R2, (R1, local(callee_info.num_locals + 1)), Eight, AM_STACK);
+            b.const_(R2, 0);
+            for index in callee_function.params..callee_info.num_locals {
+                b.store(R2, (R1, local(index)), Eight, AM_STACK);
+            }
+            b.move_(FP, R1);
+            State::Code(callee, 0)
+        } else {
+            panic!("Not a memory access or call: {:?}", instr);
+        };
+        Case {actions: b.block().into(), new_state: Ok(new_state)}
+    }
+}
+
+/// Returns a `Case` with no actions.
+fn goto(new_state: Result<State, Trap>) -> Case<Result<State, Trap>> {
+    Case {actions: Vec::new(), new_state}
+}
+
+impl Machine for Wasm {
+    type State = State;
+    type Trap = Trap;
+
+    fn num_globals(&self) -> usize { 5 }
+
+    fn marshal(&self, _: Self::State) -> Marshal {
+        Marshal {
+            prologue: build_block(&|b| {
+                b.move_(FP, global::FP);
+                b.move_(MEMORY, global::MEMORY);
+                b.move_(MEMORY_SIZE, global::MEMORY_SIZE);
+                b.move_(STACK_LIMIT, global::STACK_LIMIT);
+                b.move_(COND, global::COND);
+            }),
+            epilogue: build_block(&|b| {
+                b.move_(global::FP, FP);
+                b.move_(global::MEMORY, MEMORY);
+                b.move_(global::MEMORY_SIZE, MEMORY_SIZE);
+                b.move_(global::STACK_LIMIT, STACK_LIMIT);
+                b.move_(global::COND, COND);
+            }),
+        }
+    }
+
+    fn code(&self, state: Self::State) -> Switch<Case<Result<Self::State, Self::Trap>>> {
+        match state {
+            State::Code(function, pc) => Switch::always(self.code_case(function, pc)),
+            State::Branch(function, pc) => Switch::if_(
+                COND.into(),
+                goto(Ok(State::Code(function, self.infos[function].targets[&pc]))),
+                goto(Ok(State::Code(function, pc + 1))),
+            ),
+            State::Checked(function, pc) => {
+                let trap = match self.module.functions[function].body[pc] {
+                    Instr::Call(_) => Trap::StackOverflow,
+                    _ => Trap::MemoryOutOfBounds,
+                };
+                Switch::if_(COND.into(), goto(Err(trap)), self.checked_case(function, pc))
+            },
+            State::Return => Switch::new(
+                COND.into(),
+                std::iter::once(goto(Err(Trap::Return))).chain(
+                    self.return_sites.iter().map(|&(function, pc)| goto(Ok(State::Code(function, pc + 1))))
+                ).collect(),
+                goto(Err(Trap::Return)),
+            ),
+        }
+    }
+
+    fn initial_states(&self) -> Vec<Self::State> {
+        (0..self.module.functions.len()).map(|function| State::Code(function, 0)).collect()
+    }
+}
+
+//-----------------------------------------------------------------------------
+
+/// A WebAssembly virtual machine, which runs a [`Module`] using Mijit.
+pub struct VM<T: Target = Native> {
+    /// The compiled code.
+    jit: MachineJit<Wasm, T>,
+    /// The `Global`s.
+    pool: Pool,
+    /// The linear memory.
+    memory: Vec<u8>,
+    /// The stack, including locals.
+    stack: Vec<u64>,
+}
+
+impl VM {
+    /// Validates and compiles `module` for the [`Native`] target, and
+    /// allocates its linear memory, which is initially zero.
+    pub fn new(module: Module) -> Result<Self, ValidationError> {
+        Self::with_target(module, native())
+    }
+}
+
+impl<T: Target> VM<T> {
+    /// Validates and compiles `module` for `target`, and allocates its linear
+    /// memory, which is initially zero.
+    pub fn with_target(module: Module, target: T) -> Result<Self, ValidationError> {
+        let memory = vec![0; module.memory_pages * PAGE_SIZE];
+        let jit = MachineJit::new(Wasm::new(module)?, target);
+        let pool = jit.new_pool();
+        let stack = vec![0; STACK_WORDS];
+        Ok(VM {jit, pool, memory, stack})
+    }
+
+    /// Returns the linear memory.
+    pub fn memory(&self) -> &[u8] { &self.memory }
+
+    /// Returns the linear memory.
+    pub fn memory_mut(&mut self) -> &mut [u8] { &mut self.memory }
+
+    /// Calls the function with index `function`, passing `args`, which
+    /// should be zero-extended if they are `i32`s. Returns the result, if
+    /// any, or the [`Trap`] that stopped execution.
+    /// Panics if the wrong number of arguments is passed.
+    pub fn call(&mut self, function: usize, args: &[u64]) -> Result<Option<u64>, Trap> {
+        let wasm = self.jit.machine();
+        let results = wasm.module().functions[function].results;
+        let info = wasm.info(function);
+        assert_eq!(args.len(), wasm.module().functions[function].params, "Wrong number of arguments");
+        if info.frame_size() > self.stack.len() { return Err(Trap::StackOverflow); }
+        let num_locals = info.num_locals;
+        let stack = self.stack.as_mut_ptr_range();
+        self.stack[..args.len()].copy_from_slice(args);
+        self.stack[args.len()..num_locals].fill(0);
+        self.stack[num_locals] = stack.start as u64;
+        self.stack[num_locals + 1] = 0;
+        self.pool[global::FP] = Word {mp: stack.start.cast()};
+        self.pool[global::MEMORY] = Word {mp: self.memory.as_mut_ptr().cast()};
+        self.pool[global::MEMORY_SIZE] = Word {u: self.memory.len() as u64};
+        self.pool[global::STACK_LIMIT] = Word {mp: stack.end.cast()};
+        self.pool[global::COND] = Word {u: 0};
+        // Safety: all accesses to linear memory are checked against
+        // `MEMORY_SIZE`, and all frames are checked against `STACK_LIMIT`.
+        match unsafe { self.jit.run(&mut self.pool, &State::Code(function, 0)) } {
+            Trap::Return => Ok(if results > 0 { Some(self.stack[0]) } else { None }),
+            trap => Err(trap),
+        }
+    }
+}
+
+impl<T: Target> Debug for VM<T> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("VM")
+            .field("module", self.jit.machine().module())
+            .finish()
+    }
+}
+
+//-----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests;
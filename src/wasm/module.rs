@@ -0,0 +1,265 @@
+use std::collections::{HashMap};
+use std::fmt::{self, Display, Formatter};
+
+/// The number of bytes in a page of linear memory.
+pub const PAGE_SIZE: usize = 1 << 16;
+
+/// The types of WebAssembly values.
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+pub enum ValType {
+    I32,
+    I64,
+}
+
+/// Binary arithmetic operations.
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    And,
+    Or,
+    Xor,
+    Shl,
+    ShrS,
+    ShrU,
+}
+
+/// Comparison operations.
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+pub enum RelOp {
+    Eq,
+    Ne,
+    LtS,
+    LtU,
+    GtS,
+    GtU,
+    LeS,
+    LeU,
+    GeS,
+    GeU,
+}
+
+/// The subset of WebAssembly instructions that we support.
+///
+/// Blocks have no parameters or results. Memory instructions have an
+/// alignment hint, which we omit, and an offset, which we include.
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+pub enum Instr {
+    Unreachable,
+    Block,
+    Loop,
+    End,
+    Br(usize),
+    BrIf(usize),
+    Return,
+    Call(usize),
+    Drop,
+    LocalGet(usize),
+    LocalSet(usize),
+    LocalTee(usize),
+    /// `i32.load` or `i64.load`.
+    Load(ValType, u32),
+    /// `i32.load8_u`.
+    Load8U(u32),
+    /// `i32.store` or `i64.store`.
+    Store(ValType, u32),
+    /// `i32.store8`.
+    Store8(u32),
+    I32Const(i32),
+    I64Const(i64),
+    Eqz(ValType),
+    Compare(ValType, RelOp),
+    Binary(ValType, BinOp),
+    I32WrapI64,
+    I64ExtendI32S,
+    I64ExtendI32U,
+}
+
+/// A WebAssembly function.
+///
+/// Values are untyped 64-bit words, so we only need to know how many
+/// parameters, results and locals there are.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Function {
+    /// The number of parameters, which are the first locals.
+    pub params: usize,
+    /// The number of results, which must be `0` or `1`.
+    pub results: usize,
+    /// The number of locals that are not parameters.
+    pub locals: usize,
+    /// The instructions, not including the final `end`.
+    pub body: Vec<Instr>,
+}
+
+/// A WebAssembly module.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Module {
+    /// The size of the linear memory, in units of [`PAGE_SIZE`].
+    pub memory_pages: usize,
+    /// The functions, which are called by index.
+    pub functions: Vec<Function>,
+}
+
+//-----------------------------------------------------------------------------
+
+/// The ways in which a [`Function`] can be invalid.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// An instruction pops more values than are on the stack.
+    StackUnderflow,
+    /// There are the wrong number of values on the stack at the end of the
+    /// function.
+    WrongResults,
+    /// A function has more than one result.
+    TooManyResults,
+    /// A local index is out of range.
+    NoSuchLocal,
+    /// A label index is out of range.
+    NoSuchLabel,
+    /// A function index is out of range.
+    NoSuchFunction,
+    /// An `end` does not match a `block` or `loop`.
+    UnmatchedEnd,
+    /// A `block` or `loop` has no matching `end`.
+    MissingEnd,
+}
+
+/// Reports that a [`Module`] is invalid.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ValidationError {
+    /// The index of the invalid [`Function`].
+    pub function: usize,
+    /// The index of the invalid instruction in the `Function`'s body.
+    pub pc: usize,
+    /// What is wrong.
+    pub problem: Problem,
+}
+
+impl Display for ValidationError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{:?} in function {} at instruction {}", self.problem, self.function, self.pc)
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// The facts about a [`Function`] that [`validate()`] computes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionInfo {
+    /// The number of locals, including parameters.
+    pub num_locals: usize,
+    /// The number of values on the stack before each reachable instruction,
+    /// and at the end of the function.
+    pub heights: Vec<usize>,
+    /// The maximum number of values on the stack.
+    pub max_height: usize,
+    /// The index of the instruction after each `br` and `br_if`.
+    pub targets: HashMap<usize, usize>,
+    /// The index of each reachable `call`.
+    pub calls: Vec<usize>,
+}
+
+impl FunctionInfo {
+    /// The number of 64-bit words in the `Function`'s frame: the locals, the
+    /// caller's frame pointer and return site, and the stack.
+    pub fn frame_size(&self) -> usize { self.num_locals + 2 + self.max_height }
+}
+
+/// A `block` or `loop` whose `end` we have not yet reached.
+struct Label {
+    /// The number of values on the stack at the start of the block.
+    height: usize,
+    /// The index of the instruction after the `loop`, or `None` for a `block`.
+    start: Option<usize>,
+    /// The `br` and `br_if` instructions that jump to the `end` of a `block`.
+    branches: Vec<usize>,
+    /// `true` if the block is itself unreachable.
+    unreachable: bool,
+}
+
+/// Checks that `module` is within our subset of WebAssembly, and computes a
+/// [`FunctionInfo`] for each [`Function`].
+///
+/// Types are not checked: a function that is not type-correct does not
+/// behave as WebAssembly specifies, but it is still memory-safe.
+pub fn validate(module: &Module) -> Result<Vec<FunctionInfo>, ValidationError> {
+    module.functions.iter().enumerate().map(|(index, function)| {
+        let error = |pc, problem| ValidationError {function: index, pc, problem};
+        if function.results > 1 { return Err(error(0, Problem::TooManyResults)); }
+        let num_locals = function.params + function.locals;
+        let mut heights = Vec::with_capacity(function.body.len() + 1);
+        let mut targets = HashMap::new();
+        let mut calls = Vec::new();
+        let mut labels: Vec<Label> = Vec::new();
+        let mut height = 0;
+        let mut max_height = 0;
+        // `true` after an unconditional jump, until the end of the block.
+        let mut unreachable = false;
+        for (pc, &instr) in function.body.iter().enumerate() {
+            heights.push(height);
+            let (pops, pushes) = match instr {
+                Instr::Block | Instr::Loop => {
+                    labels.push(Label {
+                        height,
+                        start: if instr == Instr::Loop { Some(pc + 1) } else { None },
+                        branches: Vec::new(),
+                        unreachable,
+                    });
+                    continue;
+                },
+                Instr::End => {
+                    let label = labels.pop().ok_or(error(pc, Problem::UnmatchedEnd))?;
+                    for branch in label.branches { targets.insert(branch, pc + 1); }
+                    height = label.height;
+                    unreachable = label.unreachable;
+                    continue;
+                },
+                _ if unreachable => continue,
+                Instr::Unreachable => (0, 0),
+                Instr::Br(depth) | Instr::BrIf(depth) => {
+                    if depth >= labels.len() { return Err(error(pc, Problem::NoSuchLabel)); }
+                    let label = labels.len() - 1 - depth;
+                    if let Some(start) = labels[label].start {
+                        targets.insert(pc, start);
+                    } else {
+                        labels[label].branches.push(pc);
+                    }
+                    if instr == Instr::Br(depth) { (0, 0) } else { (1, 0) }
+                },
+                Instr::Return => (function.results, 0),
+                Instr::Call(callee) => {
+                    let callee = module.functions.get(callee).ok_or(error(pc, Problem::NoSuchFunction))?;
+                    calls.push(pc);
+                    (callee.params, callee.results)
+                },
+                Instr::Drop => (1, 0),
+                Instr::LocalGet(local) | Instr::LocalSet(local) | Instr::LocalTee(local) => {
+                    if local >= num_locals { return Err(error(pc, Problem::NoSuchLocal)); }
+                    match instr {
+                        Instr::LocalGet(_) => (0, 1),
+                        Instr::LocalSet(_) => (1, 0),
+                        _ => (1, 1),
+                    }
+                },
+                Instr::Load(_, _) | Instr::Load8U(_) => (1, 1),
+                Instr::Store(_, _) | Instr::Store8(_) => (2, 0),
+                Instr::I32Const(_) | Instr::I64Const(_) => (0, 1),
+                Instr::Eqz(_) => (1, 1),
+                Instr::Compare(_, _) | Instr::Binary(_, _) => (2, 1),
+                Instr::I32WrapI64 | Instr::I64ExtendI32S | Instr::I64ExtendI32U => (1, 1),
+            };
+            if height < pops { return Err(error(pc, Problem::StackUnderflow)); }
+            height = height - pops + pushes;
+            max_height = std::cmp::max(max_height, height);
+            if matches!(instr, Instr::Unreachable | Instr::Br(_) | Instr::Return) {
+                unreachable = true;
+            }
+        }
+        let pc = function.body.len();
+        if !labels.is_empty() { return Err(error(pc, Problem::MissingEnd)); }
+        if !unreachable && height != function.results { return Err(error(pc, Problem::WrongResults)); }
+        heights.push(height);
+        Ok(FunctionInfo {num_locals, heights, max_height, targets, calls})
+    }).collect()
+}
@@ -0,0 +1,211 @@
+use super::*;
+use super::examples::{fib, sieve};
+use Instr::*;
+use ValType::*;
+
+/// `fib64(n: i32) -> i64`, computed using a loop.
+fn fib64() -> Function {
+    // Locals: n, a, b, t.
+    Function {params: 1, results: 1, locals: 3, body: vec![
+        I64Const(1),
+        LocalSet(2),
+        Block,
+            Loop,
+                LocalGet(0),
+                Eqz(I32),
+                BrIf(1),
+                LocalGet(1),
+                LocalGet(2),
+                Binary(I64, BinOp::Add),
+                LocalSet(3),
+                LocalGet(2),
+                LocalSet(1),
+                LocalGet(3),
+                LocalSet(2),
+                LocalGet(0),
+                I32Const(1),
+                Binary(I32, BinOp::Sub),
+                LocalSet(0),
+                Br(0),
+            End,
+        End,
+        LocalGet(1),
+    ]}
+}
+
+/// Returns a [`Function`] with parameters `a` and `b` that returns `a op b`.
+fn binary(op: Instr) -> Function {
+    Function {params: 2, results: 1, locals: 0, body: vec![LocalGet(0), LocalGet(1), op]}
+}
+
+#[test]
+pub fn validation() {
+    let error = |body: Vec<Instr>| {
+        let module = Module {memory_pages: 0, functions: vec![
+            Function {params: 1, results: 1, locals: 0, body},
+        ]};
+        validate(&module).map(|_| ()).map_err(|e| (e.pc, e.problem))
+    };
+    assert_eq!(error(vec![LocalGet(0)]), Ok(()));
+    assert_eq!(error(vec![LocalGet(0), Drop, Unreachable, Drop]), Ok(()));
+    assert_eq!(error(vec![Block, Br(0), Drop, End, LocalGet(0)]), Ok(()));
+    assert_eq!(error(vec![Binary(I32, BinOp::Add)]), Err((0, Problem::StackUnderflow)));
+    assert_eq!(error(vec![]), Err((0, Problem::WrongResults)));
+    assert_eq!(error(vec![LocalGet(0), LocalGet(0)]), Err((2, Problem::WrongResults)));
+    assert_eq!(error(vec![LocalGet(1)]), Err((0, Problem::NoSuchLocal)));
+    assert_eq!(error(vec![Block, Br(1), End]), Err((1, Problem::NoSuchLabel)));
+    assert_eq!(error(vec![LocalGet(0), Call(1)]), Err((1, Problem::NoSuchFunction)));
+    assert_eq!(error(vec![LocalGet(0), End]), Err((1, Problem::UnmatchedEnd)));
+    assert_eq!(error(vec![Loop, LocalGet(0)]), Err((2, Problem::MissingEnd)));
+    let module = Module {memory_pages: 0, functions: vec![
+        Function {params: 0, results: 2, locals: 0, body: vec![]},
+    ]};
+    assert_eq!(validate(&module).map_err(|e| e.problem), Err(Problem::TooManyResults));
+}
+
+#[test]
+pub fn programs() {
+    let module = Module {memory_pages: 1, functions: vec![fib(), fib64(), sieve()]};
+    let mut vm = VM::new(module).unwrap();
+    let mut expected = (0, 1);
+    for n in 0..20 {
+        assert_eq!(vm.call(0, &[n]), Ok(Some(expected.0)), "fib({})", n);
+        expected = (expected.1, expected.0 + expected.1);
+    }
+    assert_eq!(vm.call(1, &[90]), Ok(Some(2880067194370816120)));
+    assert_eq!(vm.call(2, &[10000]), Ok(Some(1229)));
+    assert_eq!(vm.memory()[9973], 0);
+    assert_eq!(vm.memory()[9999], 1);
+    assert_eq!(vm.memory()[10000], 0);
+}
+
+#[test]
+pub fn arithmetic() {
+    let ops = [
+        Binary(I32, BinOp::Add),
+        Binary(I32, BinOp::Sub),
+        Binary(I32, BinOp::Mul),
+        Binary(I32, BinOp::Shl),
+        Binary(I32, BinOp::ShrS),
+        Binary(I32, BinOp::ShrU),
+        Binary(I64, BinOp::Sub),
+        Binary(I64, BinOp::Mul),
+        Binary(I64, BinOp::And),
+        Binary(I64, BinOp::Or),
+        Binary(I64, BinOp::Xor),
+        Binary(I64, BinOp::ShrS),
+        Compare(I32, RelOp::Ne),
+        Compare(I32, RelOp::LtS),
+        Compare(I32, RelOp::LtU),
+        Compare(I32, RelOp::GtS),
+        Compare(I32, RelOp::LeU),
+        Compare(I64, RelOp::GeS),
+        Compare(I64, RelOp::GtU),
+    ];
+    let mut functions: Vec<_> = ops.iter().map(|&op| binary(op)).collect();
+    for op in [Eqz(I32), Eqz(I64), I32WrapI64, I64ExtendI32S, I64ExtendI32U] {
+        functions.push(Function {params: 1, results: 1, locals: 0, body: vec![LocalGet(0), op]});
+    }
+    let mut vm = VM::new(Module {memory_pages: 0, functions}).unwrap();
+    let values: [u64; 5] = [0, 3, 0x7FFFFFFF, 0xFFFFFFFD, 0xFFFFFFFF00000021];
+    for a in values {
+        for b in values {
+            let (a32, b32) = (a as u32, b as u32);
+            let (s32, t32) = (a32 as i32, b32 as i32);
+            let expected = [
+                u64::from(a32.wrapping_add(b32)),
+                u64::from(a32.wrapping_sub(b32)),
+                u64::from(a32.wrapping_mul(b32)),
+                u64::from(a32 << (b32 & 31)),
+                u64::from((s32 >> (b32 & 31)) as u32),
+                u64::from(a32 >> (b32 & 31)),
+                a.wrapping_sub(b),
+                a.wrapping_mul(b),
+                a & b,
+                a | b,
+                a ^ b,
+                ((a as i64) >> (b & 63)) as u64,
+                u64::from(a32 != b32),
+                u64::from(s32 < t32),
+                u64::from(a32 < b32),
+                u64::from(s32 > t32),
+                u64::from(a32 <= b32),
+                u64::from(a as i64 >= b as i64),
+                u64::from(a > b),
+            ];
+            for (function, &expected) in expected.iter().enumerate() {
+                // Only the bottom 32 bits of `i32` arguments are meaningful.
+                let args = if function < 6 || (12..17).contains(&function) {
+                    [u64::from(a32), u64::from(b32)]
+                } else {
+                    [a, b]
+                };
+                assert_eq!(vm.call(function, &args), Ok(Some(expected)), "{:?}({:?})", ops[function], args);
+            }
+        }
+        let a32 = a as u32;
+        let expected = [
+            u64::from(a32 == 0),
+            u64::from(a == 0),
+            u64::from(a32),
+            a32 as i32 as i64 as u64,
+            u64::from(a32),
+        ];
+        for (i, &expected) in expected.iter().enumerate() {
+            assert_eq!(vm.call(ops.len() + i, &[a]), Ok(Some(expected)), "unary {}({:#x})", i, a);
+        }
+    }
+}
+
+#[test]
+pub fn memory() {
+    let module = Module {memory_pages: 1, functions: vec![
+        // `store(address: i32, value: i64)`.
+        Function {params: 2, results: 0, locals: 0, body: vec![
+            LocalGet(0), LocalGet(1), Store(I64, 0),
+        ]},
+        // `load(address: i32) -> i32`.
+        Function {params: 1, results: 1, locals: 0, body: vec![
+            LocalGet(0), Load(I32, 4),
+        ]},
+        // `load8(address: i32) -> i32`.
+        Function {params: 1, results: 1, locals: 0, body: vec![
+            LocalGet(0), Load8U(0),
+        ]},
+    ]};
+    let mut vm = VM::new(module).unwrap();
+    assert_eq!(vm.call(0, &[8, 0x0123456789ABCDEF]), Ok(None));
+    assert_eq!(vm.memory()[8..16], 0x0123456789ABCDEFu64.to_le_bytes());
+    assert_eq!(vm.call(1, &[8]), Ok(Some(0x01234567)));
+    assert_eq!(vm.call(2, &[15]), Ok(Some(0x01)));
+    vm.memory_mut()[100] = 42;
+    assert_eq!(vm.call(2, &[100]), Ok(Some(42)));
+    // Bounds checks.
+    let last = PAGE_SIZE as u64;
+    assert_eq!(vm.call(0, &[last - 8, 1]), Ok(None));
+    assert_eq!(vm.call(0, &[last - 7, 1]), Err(Trap::MemoryOutOfBounds));
+    assert_eq!(vm.call(1, &[last - 8]), Ok(Some(0)));
+    assert_eq!(vm.call(1, &[last - 7]), Err(Trap::MemoryOutOfBounds));
+    assert_eq!(vm.call(1, &[0xFFFFFFFC]), Err(Trap::MemoryOutOfBounds));
+    assert_eq!(vm.call(2, &[last - 1]), Ok(Some(0)));
+    assert_eq!(vm.call(2, &[last]), Err(Trap::MemoryOutOfBounds));
+}
+
+#[test]
+pub fn traps() {
+    let module = Module {memory_pages: 0, functions: vec![
+        fib(),
+        // Recurses forever.
+        Function {params: 1, results: 0, locals: 0, body: vec![LocalGet(0), Call(1)]},
+        // Traps if its argument is zero.
+        Function {params: 1, results: 0, locals: 0, body: vec![
+            Block, LocalGet(0), BrIf(0), Unreachable, End,
+        ]},
+    ]};
+    let mut vm = VM::new(module).unwrap();
+    assert_eq!(vm.call(1, &[0]), Err(Trap::StackOverflow));
+    assert_eq!(vm.call(2, &[1]), Ok(None));
+    assert_eq!(vm.call(2, &[0]), Err(Trap::Unreachable));
+    // The `VM` still works after a `Trap`.
+    assert_eq!(vm.call(0, &[10]), Ok(Some(55)));
+}
@@ -0,0 +1,99 @@
+/*
+ * Exercises the C API. Built and run by `tests/capi.rs`.
+ * Prints "ok" and returns 0 on success.
+ */
+
+#include <stdio.h>
+#include <string.h>
+
+#include "mijit.h"
+
+#define CHECK(condition) do { \
+    if (!(condition)) { \
+        fprintf(stderr, "%s:%d: check failed: %s\n", __FILE__, __LINE__, #condition); \
+        return 1; \
+    } \
+} while (0)
+
+#define N mijit_global(0)
+#define RESULT mijit_global(1)
+#define R1 mijit_register(1)
+
+enum { START, LOOP, HALT, SELECT, LOAD };
+
+int main(void) {
+    mijit_jit *jit = mijit_jit_new(2);
+    CHECK(mijit_jit_num_globals(jit) == 2);
+    mijit_entry start, loop, halt, select, load;
+    CHECK(mijit_jit_new_entry(jit, NULL, NULL, START, &start) == MIJIT_OK);
+    CHECK(mijit_jit_new_entry(jit, NULL, NULL, LOOP, &loop) == MIJIT_OK);
+    CHECK(mijit_jit_new_entry(jit, NULL, NULL, HALT, &halt) == MIJIT_OK);
+    CHECK(mijit_jit_new_entry(jit, NULL, NULL, SELECT, &select) == MIJIT_OK);
+    CHECK(mijit_jit_new_entry(jit, NULL, NULL, LOAD, &load) == MIJIT_OK);
+    mijit_entry bad;
+    CHECK(mijit_jit_new_entry(jit, NULL, NULL, -1, &bad) == MIJIT_NEGATIVE_EXIT_VALUE);
+
+    // start: RESULT = 1; goto loop.
+    mijit_builder *b = mijit_builder_new();
+    mijit_builder_const(b, R1, 1);
+    mijit_builder_move(b, RESULT, R1);
+    CHECK(mijit_jit_define(jit, start, mijit_builder_jump(b, loop)) == MIJIT_OK);
+
+    // loop: if (N) { RESULT *= N; N -= 1; goto loop; } else goto halt.
+    b = mijit_builder_new();
+    mijit_builder_binary64(b, MIJIT_MUL, R1, RESULT, N);
+    mijit_builder_move(b, RESULT, R1);
+    mijit_builder_const_binary64(b, MIJIT_SUB, R1, N, 1);
+    mijit_builder_move(b, N, R1);
+    mijit_ebb *if_true = mijit_builder_jump(b, loop);
+    mijit_ebb *if_false = mijit_builder_jump(mijit_builder_new(), halt);
+    mijit_ebb *ebb = mijit_builder_if(mijit_builder_new(), N, if_true, if_false);
+    CHECK(mijit_jit_define(jit, loop, ebb) == MIJIT_OK);
+
+    // Entries cannot be defined twice.
+    ebb = mijit_builder_jump(mijit_builder_new(), halt);
+    CHECK(mijit_jit_define(jit, start, ebb) == MIJIT_ALREADY_DEFINED);
+    CHECK(strlen(mijit_error_message()) > 0);
+
+    // select: RESULT = [10, 20, 30][N] or 99; goto halt.
+    mijit_ebb *cases[3];
+    for (int i = 0; i < 3; i++) {
+        b = mijit_builder_new();
+        mijit_builder_const(b, R1, 10 * (i + 1));
+        mijit_builder_move(b, RESULT, R1);
+        cases[i] = mijit_builder_jump(b, halt);
+    }
+    b = mijit_builder_new();
+    mijit_builder_const(b, R1, 99);
+    mijit_builder_move(b, RESULT, R1);
+    ebb = mijit_builder_index(mijit_builder_new(), N, cases, 3, mijit_builder_jump(b, halt));
+    CHECK(mijit_jit_define(jit, select, ebb) == MIJIT_OK);
+
+    // load: RESULT = ((uint64_t *) N)[1]; goto halt.
+    b = mijit_builder_new();
+    mijit_builder_load(b, R1, N, 8, MIJIT_EIGHT, 1);
+    mijit_builder_move(b, RESULT, R1);
+    CHECK(mijit_jit_define(jit, load, mijit_builder_jump(b, halt)) == MIJIT_OK);
+
+    mijit_pool *pool = mijit_pool_new(jit);
+    int64_t exit_value;
+    mijit_pool_set(pool, 0, 5);
+    CHECK(mijit_jit_run(jit, pool, start, &exit_value) == MIJIT_OK);
+    CHECK(exit_value == HALT);
+    CHECK(mijit_pool_get(pool, 1) == 120);
+    for (uint64_t n = 0; n < 5; n++) {
+        mijit_pool_set(pool, 0, n);
+        CHECK(mijit_jit_run(jit, pool, select, &exit_value) == MIJIT_OK);
+        CHECK(exit_value == HALT);
+        CHECK(mijit_pool_get(pool, 1) == (n < 3 ? 10 * (n + 1) : 99));
+    }
+    uint64_t array[2] = {1, 42};
+    mijit_pool_set(pool, 0, (uint64_t) (uintptr_t) array);
+    CHECK(mijit_jit_run(jit, pool, load, &exit_value) == MIJIT_OK);
+    CHECK(mijit_pool_get(pool, 1) == 42);
+
+    mijit_pool_free(pool);
+    mijit_jit_free(jit);
+    printf("ok\n");
+    return 0;
+}
@@ -0,0 +1,82 @@
+/*
+ * Checks `include/mijit.h` against `src/capi.rs`. Compiled (but not run) by
+ * `tests/capi.rs`, which passes the `RUST_*` macros.
+ */
+
+#include <stddef.h>
+
+#include "mijit.h"
+
+/* Checks that `f` has type `type`. */
+#define SIGNATURE(f, type) _Static_assert(_Generic(&f, type: 1, default: 0), #f " has the wrong type")
+
+/* Values of `Status`, computed by `tests/capi.rs`. */
+_Static_assert(MIJIT_OK == RUST_OK, "MIJIT_OK");
+_Static_assert(MIJIT_ALREADY_DEFINED == RUST_ALREADY_DEFINED, "MIJIT_ALREADY_DEFINED");
+_Static_assert(MIJIT_NEGATIVE_EXIT_VALUE == RUST_NEGATIVE_EXIT_VALUE, "MIJIT_NEGATIVE_EXIT_VALUE");
+_Static_assert(MIJIT_STACK_UNDERFLOW == RUST_STACK_UNDERFLOW, "MIJIT_STACK_UNDERFLOW");
+_Static_assert(MIJIT_SLOTS_MISMATCH == RUST_SLOTS_MISMATCH, "MIJIT_SLOTS_MISMATCH");
+_Static_assert(MIJIT_GLOBALS_MISMATCH == RUST_GLOBALS_MISMATCH, "MIJIT_GLOBALS_MISMATCH");
+_Static_assert(MIJIT_INVALID == RUST_INVALID, "MIJIT_INVALID");
+_Static_assert(MIJIT_OUT_OF_MEMORY == RUST_OUT_OF_MEMORY, "MIJIT_OUT_OF_MEMORY");
+_Static_assert(MIJIT_MEMORY == RUST_MEMORY, "MIJIT_MEMORY");
+_Static_assert(MIJIT_POISONED == RUST_POISONED, "MIJIT_POISONED");
+_Static_assert(sizeof(mijit_status) == RUST_SIZEOF_STATUS, "sizeof(mijit_status)");
+
+/* Layout of `CVariable`, computed by `tests/capi.rs`. */
+_Static_assert(sizeof(mijit_variable) == RUST_SIZEOF_VARIABLE, "sizeof(mijit_variable)");
+_Static_assert(offsetof(mijit_variable, kind) == RUST_OFFSETOF_KIND, "offsetof(mijit_variable, kind)");
+_Static_assert(offsetof(mijit_variable, index) == RUST_OFFSETOF_INDEX, "offsetof(mijit_variable, index)");
+
+/* The kinds of variable, as decoded by `impl From<CVariable> for Variable`. */
+_Static_assert(MIJIT_KIND_REGISTER == 0, "MIJIT_KIND_REGISTER");
+_Static_assert(MIJIT_KIND_GLOBAL == 1, "MIJIT_KIND_GLOBAL");
+_Static_assert(MIJIT_KIND_SLOT == 2, "MIJIT_KIND_SLOT");
+
+/* The operations, in the order of the tables in `unary_op()` etc. */
+_Static_assert(MIJIT_ABS == 0 && MIJIT_NEGATE == 1 && MIJIT_NOT == 2, "UnaryOp");
+_Static_assert(
+    MIJIT_ADD == 0 && MIJIT_SUB == 1 && MIJIT_MUL == 2 && MIJIT_UDIV == 3 &&
+    MIJIT_SDIV == 4 && MIJIT_LSL == 5 && MIJIT_LSR == 6 && MIJIT_ASR == 7 &&
+    MIJIT_AND == 8 && MIJIT_OR == 9 && MIJIT_XOR == 10 && MIJIT_LT == 11 &&
+    MIJIT_ULT == 12 && MIJIT_EQ == 13 && MIJIT_MAX == 14 && MIJIT_MIN == 15,
+    "BinaryOp"
+);
+_Static_assert(MIJIT_ONE == 0 && MIJIT_TWO == 1 && MIJIT_FOUR == 2 && MIJIT_EIGHT == 3, "Width");
+_Static_assert(MIJIT_IA == 0 && MIJIT_IB == 1 && MIJIT_DA == 2 && MIJIT_DB == 3, "Increment");
+
+/* The signatures of the functions in `src/capi.rs`. */
+_Static_assert(sizeof(mijit_entry) == sizeof(size_t), "mijit_entry");
+SIGNATURE(mijit_error_message, const char *(*)(void));
+SIGNATURE(mijit_jit_new, mijit_jit *(*)(size_t));
+SIGNATURE(mijit_jit_free, void (*)(mijit_jit *));
+SIGNATURE(mijit_jit_num_globals, size_t (*)(const mijit_jit *));
+SIGNATURE(mijit_jit_new_entry, mijit_status (*)(mijit_jit *, mijit_builder *, mijit_builder *, int64_t, size_t *));
+SIGNATURE(mijit_jit_define, mijit_status (*)(mijit_jit *, size_t, mijit_ebb *));
+SIGNATURE(mijit_jit_run, mijit_status (*)(mijit_jit *, mijit_pool *, size_t, int64_t *));
+SIGNATURE(mijit_pool_new, mijit_pool *(*)(const mijit_jit *));
+SIGNATURE(mijit_pool_free, void (*)(mijit_pool *));
+SIGNATURE(mijit_pool_get, uint64_t (*)(const mijit_pool *, size_t));
+SIGNATURE(mijit_pool_set, void (*)(mijit_pool *, size_t, uint64_t));
+SIGNATURE(mijit_builder_new, mijit_builder *(*)(void));
+SIGNATURE(mijit_builder_free, void (*)(mijit_builder *));
+SIGNATURE(mijit_builder_move, void (*)(mijit_builder *, mijit_variable, mijit_variable));
+SIGNATURE(mijit_builder_const, void (*)(mijit_builder *, mijit_variable, int64_t));
+SIGNATURE(mijit_builder_unary64, void (*)(mijit_builder *, uint32_t, mijit_variable, mijit_variable));
+SIGNATURE(mijit_builder_unary32, void (*)(mijit_builder *, uint32_t, mijit_variable, mijit_variable));
+SIGNATURE(mijit_builder_binary64, void (*)(mijit_builder *, uint32_t, mijit_variable, mijit_variable, mijit_variable));
+SIGNATURE(mijit_builder_binary32, void (*)(mijit_builder *, uint32_t, mijit_variable, mijit_variable, mijit_variable));
+SIGNATURE(mijit_builder_const_binary64, void (*)(mijit_builder *, uint32_t, mijit_variable, mijit_variable, int64_t));
+SIGNATURE(mijit_builder_const_binary32, void (*)(mijit_builder *, uint32_t, mijit_variable, mijit_variable, int32_t));
+SIGNATURE(mijit_builder_load, void (*)(mijit_builder *, mijit_variable, mijit_variable, int64_t, uint32_t, uint32_t));
+SIGNATURE(mijit_builder_store, void (*)(mijit_builder *, mijit_variable, mijit_variable, int64_t, uint32_t, uint32_t));
+SIGNATURE(mijit_builder_array_load, void (*)(mijit_builder *, mijit_variable, mijit_variable, mijit_variable, uint32_t, uint32_t));
+SIGNATURE(mijit_builder_array_store, void (*)(mijit_builder *, mijit_variable, mijit_variable, mijit_variable, uint32_t, uint32_t));
+SIGNATURE(mijit_builder_increment_load, void (*)(mijit_builder *, uint32_t, mijit_variable, mijit_variable, uint32_t, uint32_t));
+SIGNATURE(mijit_builder_increment_store, void (*)(mijit_builder *, uint32_t, mijit_variable, mijit_variable, uint32_t, uint32_t));
+SIGNATURE(mijit_builder_debug, void (*)(mijit_builder *, mijit_variable));
+SIGNATURE(mijit_builder_guard, void (*)(mijit_builder *, mijit_variable, bool, mijit_ebb *));
+SIGNATURE(mijit_builder_jump, mijit_ebb *(*)(mijit_builder *, size_t));
+SIGNATURE(mijit_builder_index, mijit_ebb *(*)(mijit_builder *, mijit_variable, mijit_ebb *const *, size_t, mijit_ebb *));
+SIGNATURE(mijit_builder_if, mijit_ebb *(*)(mijit_builder *, mijit_variable, mijit_ebb *, mijit_ebb *));
+SIGNATURE(mijit_ebb_free, void (*)(mijit_ebb *));
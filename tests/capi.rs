@@ -0,0 +1,111 @@
+//! Tests of the C API, which is declared in `include/mijit.h`.
+
+use std::ffi::{OsStr};
+use std::mem::{size_of};
+use std::path::{PathBuf};
+use std::process::{Command};
+
+use mijit::capi::{Status, CVariable};
+
+/// Returns the directory containing `libmijit.a`.
+fn lib_dir() -> PathBuf {
+    // This test is `target/<profile>/deps/capi-<hash>`.
+    let exe = std::env::current_exe().unwrap();
+    exe.parent().unwrap().parent().unwrap().to_path_buf()
+}
+
+/// Runs the C compiler with `args`, and panics if it fails.
+fn cc(args: &[&OsStr]) {
+    let status = Command::new("cc")
+        .arg("-Wall")
+        .arg("-Werror")
+        .args(args)
+        .status()
+        .unwrap_or_else(|e| panic!("Cannot run a C compiler: {}", e));
+    assert!(status.success(), "Failed to compile {:?}", args);
+}
+
+// The libraries to link with are platform-specific; see `include/mijit.h`.
+#[cfg(target_os = "linux")]
+#[test]
+fn factorial() {
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let lib_dir = lib_dir();
+    let binary = lib_dir.join("capi-factorial");
+    let include = root.join("include");
+    let source = root.join("tests/c/factorial.c");
+    let lib = lib_dir.join("libmijit.a");
+    let mut args = vec![
+        OsStr::new("-std=c99"),
+        OsStr::new("-I"), include.as_os_str(),
+        source.as_os_str(),
+        lib.as_os_str(),
+    ];
+    args.extend(["-lgcc_s", "-lutil", "-lrt", "-lpthread", "-lm", "-ldl"].map(OsStr::new));
+    args.extend([OsStr::new("-o"), binary.as_os_str()]);
+    cc(&args);
+    let output = Command::new(&binary).output().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "ok\n");
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn header_asserts() {
+    // Compile `tests/c/header.c`, telling it the values that Rust uses.
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let include = root.join("include");
+    let source = root.join("tests/c/header.c");
+    let defines: Vec<String> = [
+        ("OK", Status::Ok as usize),
+        ("ALREADY_DEFINED", Status::AlreadyDefined as usize),
+        ("NEGATIVE_EXIT_VALUE", Status::NegativeExitValue as usize),
+        ("STACK_UNDERFLOW", Status::StackUnderflow as usize),
+        ("SLOTS_MISMATCH", Status::SlotsMismatch as usize),
+        ("GLOBALS_MISMATCH", Status::GlobalsMismatch as usize),
+        ("INVALID", Status::Invalid as usize),
+        ("OUT_OF_MEMORY", Status::OutOfMemory as usize),
+        ("MEMORY", Status::Memory as usize),
+        ("POISONED", Status::Poisoned as usize),
+        ("SIZEOF_STATUS", size_of::<Status>()),
+        ("SIZEOF_VARIABLE", size_of::<CVariable>()),
+        ("OFFSETOF_KIND", offset_of(|v| &v.kind)),
+        ("OFFSETOF_INDEX", offset_of(|v| &v.index)),
+    ].iter().map(|(name, value)| format!("-DRUST_{}={}", name, value)).collect();
+    let mut args = vec![
+        OsStr::new("-std=c11"),
+        OsStr::new("-fsyntax-only"),
+        OsStr::new("-I"), include.as_os_str(),
+        source.as_os_str(),
+    ];
+    args.extend(defines.iter().map(OsStr::new));
+    cc(&args);
+}
+
+/// Returns the offset within a [`CVariable`] of the field selected by `field`.
+fn offset_of(field: impl Fn(&CVariable) -> &u32) -> usize {
+    let v = CVariable {kind: 0, index: 0};
+    field(&v) as *const u32 as usize - &v as *const CVariable as usize
+}
+
+#[test]
+fn header() {
+    // Every function in `src/capi.rs` is declared in `include/mijit.h`, and
+    // its signature is checked by `tests/c/header.c`.
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let source = std::fs::read_to_string(root.join("src/capi.rs")).unwrap();
+    let header = std::fs::read_to_string(root.join("include/mijit.h")).unwrap();
+    let asserts = std::fs::read_to_string(root.join("tests/c/header.c")).unwrap();
+    let mut count = 0;
+    for line in source.lines() {
+        if let Some(i) = line.find("extern \"C\" fn ") {
+            let rest = &line[i + 14..];
+            let name = &rest[..rest.find('(').unwrap()];
+            assert!(header.contains(&format!(" *{}(", name)) || header.contains(&format!(" {}(", name)), "{} is not declared", name);
+            assert!(asserts.contains(&format!("SIGNATURE({},", name)), "{} is not checked", name);
+            count += 1;
+        }
+    }
+    assert!(count > 20);
+}